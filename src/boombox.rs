@@ -1,111 +1,479 @@
 //! Boombox implements the overall music listening experiance by bringing
 //! together the event system, user interface and audio file player
 
-use std::ops::ControlFlow::{self, Break, Continue};
+use std::fs;
+use std::io::{self, Write};
 
+use crate::crossfade::{self, Crossfade};
 use crate::error::{AfqueueError, ErrorContext, ErrorCtx};
-use crate::events::{self, Event, EventQueue};
-use crate::player::{PlaybackContext, PlaybackVolume};
-use crate::ui::TerminalUI;
+use crate::events::{self, Event, EventQueue, EventSource};
+use crate::ffi::unistd;
+use crate::keymap::{Action, Keymap};
+use crate::loudness;
+use crate::player::{
+    AudioFilePlayer, AudioOutput, Deck, DefaultOutput, PlaybackContext, PlaybackVolume,
+    DEFAULT_VOLUME_STEP_DB,
+};
+use crate::playlist::{self, Playlist, ScanOptions, Track};
+use crate::prompt::{Entry, PathPrompt};
+use crate::replaygain::{GainMode, ReplayGain};
+use crate::ui::{TerminalSize, TerminalUI, Tty};
 
 const UI_TICK_DURATION_MICROSECONDS: i64 = 33333; // 30FPS
 const UPDATE_PROGRESS_TICK_FREQUENCY: usize = 30; // Every second
+const DEFAULT_SEEK_STEP_SECONDS: f64 = 10.0;
+// Going to the previous track past this point restarts the current one instead
+const RESTART_THRESHOLD_SECONDS: f64 = 3.0;
+const ADD_PROMPT: &str = "Add: ";
+
+/// Where to go once a track has stopped playing.
+enum Navigation {
+    /// The track played through to its end
+    Finished,
+    NextTrack,
+    PreviousTrack,
+    /// The track was taken out of the queue, leaving the next in its place
+    Removed,
+    Exit,
+}
+
+/// What is shown about a track while it plays.
+struct TrackInfo {
+    metadata: Vec<(String, String)>,
+    duration: f64,
+    /// Loudness saved by an earlier scan, if any
+    measured: Option<loudness::cache::Entry>,
+}
+
+/// The track up next in the queue, opened ahead of time to play once the
+/// current one is done with.
+struct Following {
+    track: Track,
+    /// Left empty should it not open, to fail once its turn comes instead
+    opened: Option<(TrackInfo, LinedUp)>,
+}
+
+/// How the next track is lined up to follow on.
+enum LinedUp {
+    /// Handed over to through the same output, without a gap
+    Gapless,
+    /// Faded in over the end of the current track, through an output of its
+    /// own
+    Crossfade(PlaybackContext),
+    /// Played after a gap, as it can't share the output. Kept open in case
+    /// it is to be faded in after all.
+    Apart(PlaybackContext),
+}
+
+/// The next track fading in over the end of the current one, which carries
+/// on playing once the current one stops.
+struct Fade<O: AudioOutput> {
+    track: Track,
+    deck: Deck<O>,
+    info: TrackInfo,
+    /// Time into the outgoing track that the fade started at
+    starts_at: f64,
+    length: f64,
+}
+
+/// Behaviour of the boombox that can be tweaked by the user.
+pub struct Settings {
+    /// How many seconds to skip forward or back by when seeking.
+    pub seek_step: f64,
+    /// How to search through directories, including those added while playing
+    pub scan: ScanOptions,
+    pub crossfade: Crossfade,
+    pub replay_gain: GainMode,
+    /// Gain in decibels for tracks without ReplayGain tags
+    pub fallback_preamp: f64,
+    /// Decibels to turn the volume up or down by at a time
+    pub volume_step: f64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            seek_step: DEFAULT_SEEK_STEP_SECONDS,
+            scan: ScanOptions::default(),
+            crossfade: Crossfade::default(),
+            replay_gain: GainMode::default(),
+            fallback_preamp: 0.0,
+            volume_step: DEFAULT_VOLUME_STEP_DB,
+        }
+    }
+}
 
 //TODO: Figure out what error context is useful to add to the below
 
-//TODO: Can we get away without the lifetime?
-pub struct Boombox<'a> {
-    queue: EventQueue,
-    ui: TerminalUI<'a>,
+pub struct Boombox<
+    O: AudioOutput = DefaultOutput,
+    E: EventSource = EventQueue,
+    W: Write = io::StdoutLock<'static>,
+    S: TerminalSize = Tty,
+> {
+    queue: E,
+    ui: TerminalUI<W, S>,
     volume: PlaybackVolume,
+    settings: Settings,
+    playlist: Playlist,
+    /// Track in the queue that editing applies to
+    selected: usize,
+    showing_help: bool,
+    /// Set while asking for files to add
+    prompt: Option<PathPrompt>,
+    /// Something to say until the next key press
+    notice: Option<String>,
+    /// Set from when the next track starts fading in, until it takes over
+    fade: Option<Fade<O>>,
+}
+
+impl<O: AudioOutput> Boombox<O> {
+    /// Take over the terminal, with key presses read from stdin.
+    pub fn initialise(
+        settings: Settings,
+        keymap: Keymap,
+        playlist: Playlist,
+    ) -> Result<Self, AfqueueError> {
+        let queue = events::build_event_queue(keymap, unistd::STDIN_FILENO)?;
+        let ui = TerminalUI::activate()?;
+        Ok(Boombox::new(queue, ui, settings, playlist))
+    }
+
+    pub fn shutdown(self) -> Result<(), AfqueueError> {
+        self.queue.close()?;
+        self.ui.deactivate()?;
+        Ok(())
+    }
 }
 
-impl<'a> Boombox<'a> {
-    pub fn initialise() -> Result<Self, AfqueueError> {
-        //TODO: Pass in file descriptor to build_event_queue
-        let queue = events::build_event_queue()?;
-        Ok(Boombox {
+impl<O: AudioOutput, E: EventSource, W: Write, S: TerminalSize> Boombox<O, E, W, S> {
+    pub fn new(queue: E, ui: TerminalUI<W, S>, settings: Settings, playlist: Playlist) -> Self {
+        Boombox {
             queue,
-            ui: TerminalUI::activate()?,
-            volume: PlaybackVolume::new(),
-        })
+            ui,
+            volume: PlaybackVolume::new(settings.volume_step),
+            settings,
+            playlist,
+            selected: 0,
+            showing_help: false,
+            prompt: None,
+            notice: None,
+            fade: None,
+        }
+    }
+
+    /// Play through the queue until it runs out, or the user quits.
+    pub fn play_queue(&mut self) -> Result<(), AfqueueError> {
+        while self.play_current()? {}
+        // Whatever faded in last isn't wanted once the queue is done with
+        if let Some(fade) = self.fade.take() {
+            fade.deck.discard()?;
+        }
+        Ok(())
     }
 
-    //TODO: Might it be nicer for boombox to pull from a playlist?
-    pub fn play_file(&mut self, path: &str) -> Result<ControlFlow<()>, AfqueueError> {
-        self.play(path)
-            .with(ErrorCtx::PlayingBack(path.to_string()))
+    /// Play the current track of the queue, then move along the queue as
+    /// asked. Returns whether there is anything more to play.
+    fn play_current(&mut self) -> Result<bool, AfqueueError> {
+        // Editing the queue while it plays moves tracks around, this one included
+        let Some(track) = self.playlist.current().cloned() else {
+            return Ok(false);
+        };
+        let navigation = self.play(&track).with(ErrorCtx::PlayingBack(track.path))?;
+        match navigation {
+            Navigation::Finished => self.playlist.finished(),
+            Navigation::NextTrack => self.playlist.next(),
+            Navigation::PreviousTrack => self.playlist.previous(),
+            Navigation::Removed => {}
+            Navigation::Exit => return Ok(false),
+        }
+        Ok(self.playlist.current().is_some())
     }
 
-    fn play(&mut self, path: &str) -> Result<ControlFlow<()>, AfqueueError> {
-        let context = PlaybackContext::new(path)?;
-        let metadata = context.file_metadata()?;
-        let estimated_duration = context.estimated_duration()?;
+    /// Play `track`, along with any that follow on from it without a break,
+    /// until playback stops.
+    fn play(&mut self, track: &Track) -> Result<Navigation, AfqueueError> {
+        let faded_in = self.faded_in(track)?;
+        let already_playing = faded_in.is_some();
+        let (mut player, mut info) = match faded_in {
+            Some(fade) => (fade.deck, fade.info),
+            None => {
+                let (context, info) = open(track)?;
+                let notifier = self.queue.create_callback_notifier();
+                (Deck::<O>::new(context, notifier)?, info)
+            }
+        };
         let mut meter_state = [0f32, 0f32];
-        let notifier = self.queue.create_callback_notifier();
-        let mut handler = context.new_audio_callback_handler(notifier);
-        let mut player = context.new_audio_player(&mut handler)?;
+        let mut following = None;
 
         let timer_set = true;
-        let mut exit_requested = false;
+        let mut navigation = Navigation::Finished;
         let mut paused = false;
         let mut tick_count = 0;
 
-        //TODO: Duplicated below
-
-        self.ui.clear_screen()?;
-        self.ui.display_filename(path)?;
-        self.ui.display_meter(&meter_state)?;
-        self.ui.display_playback_state(paused)?;
-        self.ui.display_volume(self.volume.gain())?;
-        self.ui.display_metadata(&metadata)?;
-        self.ui.flush()?;
+        // Start the queue off following the new track
+        self.select(self.playlist.position());
+        self.redraw(&meter_state, paused, &info.metadata)?;
 
-        player.set_volume(&self.volume)?;
-        player.start_playback()?;
+        if already_playing {
+            // Any of the fade that was left is cut short, and the timer went
+            // off along with the track faded out
+            player.set_fade(1.0)?;
+            self.queue
+                .enable_ui_timer_event(UI_TICK_DURATION_MICROSECONDS)?;
+        } else {
+            player.set_pre_gain(self.pre_gain(self.playlist.position(), &info))?;
+            player.set_volume(&self.volume)?;
+            player.start_playback()?;
+        }
+        self.follow_on(&mut player, &mut following, &info)?;
 
         // Timer will fire periodically, but wont create duplicates events if we leave
         // it on the queue
 
         'event_loop: loop {
             let event = self.queue.next_event();
+            let is_key = matches!(event, Event::Action(_) | Event::UnboundKey | Event::Key(_));
+
+            // Whatever key is pressed while help is showing just closes it
+            if self.showing_help && is_key {
+                self.showing_help = false;
+                self.redraw(&meter_state, paused, &info.metadata)?;
+                self.display_progress(&mut player, info.duration)?;
+                continue;
+            }
+
+            // Notices only stay up until the next key press
+            if self.notice.is_some() && is_key {
+                self.notice = None;
+                self.redraw(&meter_state, paused, &info.metadata)?;
+                self.display_progress(&mut player, info.duration)?;
+            }
 
             match event {
-                Event::PauseKeyPressed => {
+                Event::Action(Action::Pause) => {
                     //TODO: Might be worth updating playback progress on pause
                     if paused {
                         player.resume()?;
                     } else {
                         player.pause()?;
                     }
+                    // Both tracks hold still part way through a fade
+                    if let Some(fade) = &mut self.fade {
+                        match paused {
+                            true => fade.deck.resume()?,
+                            false => fade.deck.pause()?,
+                        }
+                    }
                     paused = !paused;
-                    self.ui.display_playback_state(paused)?;
+                    self.display_state(paused)?;
                     self.ui.flush()?;
                 }
-                Event::VolumeDownKeyPressed => {
+                Event::Action(Action::VolumeDown) => {
                     self.volume.decrement();
-                    player.set_volume(&self.volume)?;
-                    self.ui.display_volume(self.volume.gain())?;
+                    self.apply_volume(&mut player)?;
+                    self.display_volume()?;
                     self.ui.flush()?;
                 }
-                Event::VolumeUpKeyPressed => {
+                Event::Action(Action::VolumeUp) => {
                     self.volume.increment();
-                    player.set_volume(&self.volume)?;
-                    self.ui.display_volume(self.volume.gain())?;
+                    self.apply_volume(&mut player)?;
+                    self.display_volume()?;
+                    self.ui.flush()?;
+                }
+                Event::Action(Action::ToggleMute) => {
+                    self.volume.toggle_mute();
+                    self.apply_volume(&mut player)?;
+                    self.display_volume()?;
                     self.ui.flush()?;
                 }
-                Event::NextTrackKeyPressed => {
+                Event::Action(Action::SeekForward) => {
+                    // Nothing to seek within if the player isn't running
+                    if let Some(time) = player.get_playback_time()? {
+                        let target = time + self.settings.seek_step;
+                        self.seek(&mut player, target, &info, &mut following)?;
+                    }
+                }
+                Event::Action(Action::SeekBackward) => {
+                    if let Some(time) = player.get_playback_time()? {
+                        let target = time - self.settings.seek_step;
+                        self.seek(&mut player, target, &info, &mut following)?;
+                    }
+                }
+                Event::Action(Action::SeekTo(percentage)) => {
+                    let target = info.duration * percentage as f64 / 100.0;
+                    self.seek(&mut player, target, &info, &mut following)?;
+                }
+                Event::Action(Action::NextTrack) => {
                     player.stop()?;
+                    navigation = Navigation::NextTrack;
+                }
+                Event::Action(Action::PreviousTrack) => {
+                    let time = player.get_playback_time()?.unwrap_or(0.0);
+                    if time > RESTART_THRESHOLD_SECONDS {
+                        self.seek(&mut player, 0.0, &info, &mut following)?;
+                    } else {
+                        player.stop()?;
+                        navigation = Navigation::PreviousTrack;
+                    }
+                }
+                Event::Action(Action::RestartTrack) => {
+                    self.seek(&mut player, 0.0, &info, &mut following)?;
+                }
+                Event::Action(Action::ToggleShuffle) => {
+                    let shuffle = self.playlist.shuffle().cycle();
+                    self.playlist.set_shuffle(shuffle);
+                    // Stay with the current track, rather than wherever the
+                    // selected one has gone
+                    self.select(self.playlist.position());
+                    self.follow_on(&mut player, &mut following, &info)?;
+                    self.display_state(paused)?;
+                    self.display_progress(&mut player, info.duration)?;
+                    self.display_queue()?;
+                    self.ui.flush()?;
+                }
+                Event::Action(Action::ToggleRepeat) => {
+                    let repeat = self.playlist.repeat().cycle();
+                    self.playlist.set_repeat(repeat);
+                    self.follow_on(&mut player, &mut following, &info)?;
+                    self.display_state(paused)?;
+                    self.display_progress(&mut player, info.duration)?;
+                    self.ui.flush()?;
+                }
+                Event::Action(Action::ToggleCrossfade) => {
+                    let crossfade = &mut self.settings.crossfade;
+                    crossfade.enabled = !crossfade.enabled;
+                    self.notice = Some(match crossfade.enabled {
+                        true => format!("Crossfading over {} seconds", crossfade.seconds),
+                        false => "Crossfading off".to_string(),
+                    });
+                    self.cancel_fade(&mut player)?;
+                    self.follow_on(&mut player, &mut following, &info)?;
+                    self.display_queue()?;
+                    self.ui.flush()?;
+                }
+                Event::Action(Action::CycleReplayGain) => {
+                    let mode = self.settings.replay_gain.cycle();
+                    self.settings.replay_gain = mode;
+                    self.notice = Some(format!("ReplayGain: {}", mode.name()));
+                    player.set_pre_gain(self.pre_gain(self.playlist.position(), &info))?;
+                    // As well as whatever is fading in after it
+                    let upcoming = self.playlist.upcoming_position();
+                    let fading_in = self
+                        .fade
+                        .as_ref()
+                        .map(|fade| upcoming.map_or(1.0, |index| self.pre_gain(index, &fade.info)));
+                    if let (Some(fade), Some(scale)) = (&mut self.fade, fading_in) {
+                        fade.deck.set_pre_gain(scale)?;
+                    }
+                    // And whatever is lined up to follow on
+                    self.follow_on(&mut player, &mut following, &info)?;
+                    self.display_queue()?;
+                    self.ui.flush()?;
+                }
+                Event::Action(Action::SelectUp) => {
+                    self.select(self.selected.saturating_sub(1));
+                    self.display_queue()?;
+                    self.ui.flush()?;
+                }
+                Event::Action(Action::SelectDown) => {
+                    self.select(self.selected + 1);
+                    self.display_queue()?;
+                    self.ui.flush()?;
+                }
+                Event::Action(Action::MoveUp) => {
+                    if let Some(above) = self.selected.checked_sub(1) {
+                        self.playlist.move_track(self.selected, above);
+                        self.select(above);
+                        self.follow_on(&mut player, &mut following, &info)?;
+                    }
+                    self.display_queue()?;
+                    self.ui.flush()?;
+                }
+                Event::Action(Action::MoveDown) => {
+                    let below = self.selected + 1;
+                    if below < self.playlist.tracks().len() {
+                        self.playlist.move_track(self.selected, below);
+                        self.select(below);
+                        self.follow_on(&mut player, &mut following, &info)?;
+                    }
+                    self.display_queue()?;
+                    self.ui.flush()?;
+                }
+                Event::Action(Action::PlayNext) => {
+                    let moved_to = self.playlist.play_next(self.selected);
+                    self.select(moved_to);
+                    self.follow_on(&mut player, &mut following, &info)?;
+                    self.display_queue()?;
+                    self.ui.flush()?;
+                }
+                Event::Action(Action::Remove) => {
+                    let removing_current = self.selected == self.playlist.position();
+                    self.playlist.remove(self.selected);
+                    if removing_current {
+                        player.stop()?;
+                        navigation = Navigation::Removed;
+                    } else {
+                        self.select(self.selected);
+                        self.follow_on(&mut player, &mut following, &info)?;
+                        self.display_queue()?;
+                        self.ui.flush()?;
+                    }
+                }
+                Event::Action(Action::AddTracks) => {
+                    self.prompt = Some(PathPrompt::new());
+                    self.queue.set_text_entry(true);
+                    self.display_queue()?;
+                    self.ui.flush()?;
+                }
+                Event::Key(key) => {
+                    let Some(prompt) = &mut self.prompt else {
+                        continue;
+                    };
+                    let entry = prompt.handle(key);
+                    if entry != Entry::Editing {
+                        self.prompt = None;
+                        self.queue.set_text_entry(false);
+                    }
+                    if let Entry::Done(path) = entry {
+                        self.add(path);
+                        self.follow_on(&mut player, &mut following, &info)?;
+                    }
+                    // The prompt may have been drawn over another pane
+                    self.redraw(&meter_state, paused, &info.metadata)?;
+                    self.display_progress(&mut player, info.duration)?;
+                    self.ui.flush()?;
+                }
+                Event::Action(Action::ScrollQueueUp) => {
+                    self.ui.scroll_queue(-1);
+                    self.display_queue()?;
+                    self.ui.flush()?;
+                }
+                Event::Action(Action::ScrollQueueDown) => {
+                    self.ui.scroll_queue(1);
+                    self.display_queue()?;
+                    self.ui.flush()?;
+                }
+                Event::Action(Action::ShowHelp) => {
+                    self.showing_help = true;
+                    self.display_help()?;
+                    self.ui.flush()?;
                 }
-                Event::ExitKeyPressed => {
+                Event::UnboundKey => {}
+                Event::Action(Action::Quit) => {
                     player.stop()?;
-                    exit_requested = true;
+                    navigation = Navigation::Exit;
                 }
                 Event::PlaybackStarted => {
                     self.queue
                         .enable_ui_timer_event(UI_TICK_DURATION_MICROSECONDS)?;
                 }
                 Event::PlaybackFinished => {
+                    // The track that followed on may have played out before
+                    // a tick came round to notice it
+                    if matches!(navigation, Navigation::Finished) && player.moved_on()? {
+                        self.playlist.finished();
+                    }
                     break 'event_loop;
                 }
                 Event::UITick => {
@@ -132,42 +500,1174 @@ impl<'a> Boombox<'a> {
                     // permission! So get_playback_time might not return a value.
 
                     meter_state = player.get_meter_level()?;
-                    self.ui.display_meter(&meter_state)?;
+                    if let Some(fade) = &mut self.fade {
+                        let incoming = fade.deck.get_meter_level()?;
+                        meter_state = [0, 1].map(|side| meter_state[side].max(incoming[side]));
+                    }
 
-                    if tick_count % UPDATE_PROGRESS_TICK_FREQUENCY == 0 {
-                        if let Some(progress) = player.get_playback_time()? {
-                            self.ui
-                                .display_playback_progress(progress, estimated_duration)?;
+                    if player.moved_on()? {
+                        self.playlist.finished();
+                        if let Some((next, LinedUp::Gapless)) =
+                            following.take().and_then(|following| following.opened)
+                        {
+                            info = next;
                         }
+                        self.select(self.playlist.position());
+                        self.redraw(&meter_state, paused, &info.metadata)?;
+                        self.display_progress(&mut player, info.duration)?;
+                        self.follow_on(&mut player, &mut following, &info)?;
+                    }
+
+                    // The track faded out stops playing once the fade is done,
+                    // leaving the one faded in to take over
+                    if self.crossfade(&mut player, &mut following, info.duration)? {
+                        player.stop()?;
+                    }
+
+                    // Leave the help overlay be, rather than drawing over it
+                    if !self.showing_help {
+                        self.ui.display_meter(&meter_state)?;
+
+                        if tick_count % UPDATE_PROGRESS_TICK_FREQUENCY == 0 {
+                            self.display_progress(&mut player, info.duration)?;
+                        }
+                        self.ui.flush()?;
                     }
-                    self.ui.flush()?;
                     tick_count += 1;
                 }
                 Event::TerminalResized => {
                     self.ui.update_size()?;
-                    self.ui.clear_screen()?;
-                    self.ui.display_filename(path)?;
-                    self.ui.display_meter(&meter_state)?;
-                    self.ui.display_playback_state(paused)?;
-                    self.ui.display_volume(self.volume.gain())?;
-                    self.ui.display_metadata(&metadata)?;
-                    self.ui.flush()?;
+                    self.redraw(&meter_state, paused, &info.metadata)?;
                 }
             }
         }
         if timer_set {
             self.queue.disable_ui_timer_event()?;
         }
-        if exit_requested {
-            Ok(Break(()))
-        } else {
-            Ok(Continue(()))
+        Ok(navigation)
+    }
+
+    /// Line up whatever is next in the queue to follow on from the track
+    /// playing, described by `current`. Either fading in over its end when
+    /// crossfading, or without a break should it be able to share the
+    /// player's output. Once the player has started reading the track lined
+    /// up before, or it has started fading in, it is too late to change it.
+    fn follow_on(
+        &mut self,
+        player: &mut AudioFilePlayer<O>,
+        following: &mut Option<Following>,
+        current: &TrackInfo,
+    ) -> Result<(), AfqueueError> {
+        if player.is_handing_over() || self.fade.is_some() {
+            return Ok(());
+        }
+        let Some(track) = self.playlist.upcoming().cloned() else {
+            player.follow_with(None, 1.0)?;
+            *following = None;
+            return Ok(());
+        };
+        // Hold on to what was opened while the same track is up next, rather
+        // than opening it all over again
+        let opened = match following.take() {
+            Some(next) if next.track == track => next.opened.and_then(|(info, lined_up)| {
+                let context = match lined_up {
+                    LinedUp::Gapless => player.take_following()?,
+                    LinedUp::Crossfade(context) | LinedUp::Apart(context) => context,
+                };
+                Some((context, info))
+            }),
+            // Anything that won't open is left to fail once its turn comes
+            _ => open(&track).ok(),
+        };
+
+        let crossfade = self.settings.crossfade;
+        // Tracks too short to fade over are left to play out as they are
+        let fades = |info: &TrackInfo| {
+            crossfade.enabled
+                && current.duration > crossfade.seconds
+                && info.duration > crossfade.seconds
+                && crossfade::fades_between(&current.metadata, &info.metadata)
+        };
+        let opened = match opened {
+            Some((context, info)) if fades(&info) => {
+                player.follow_with(None, 1.0)?;
+                Some((info, LinedUp::Crossfade(context)))
+            }
+            Some((context, info)) => {
+                let upcoming = self.playlist.upcoming_position();
+                let scale = upcoming.map_or(1.0, |index| self.pre_gain(index, &info));
+                match player.follow_with(Some(context), scale)? {
+                    Some(context) => Some((info, LinedUp::Apart(context))),
+                    None => Some((info, LinedUp::Gapless)),
+                }
+            }
+            None => {
+                player.follow_with(None, 1.0)?;
+                None
+            }
+        };
+        *following = Some(Following { track, opened });
+        Ok(())
+    }
+
+    /// Start fading in the track lined up to follow on by crossfading, once
+    /// the end of the current track is near enough, then carry on with the
+    /// fade each time after. Returns true once the fade is done.
+    fn crossfade(
+        &mut self,
+        player: &mut AudioFilePlayer<O>,
+        following: &mut Option<Following>,
+        duration: f64,
+    ) -> Result<bool, AfqueueError> {
+        let Some(time) = player.get_playback_time()? else {
+            return Ok(false);
+        };
+        let crossfade = self.settings.crossfade;
+        if self.fade.is_none() {
+            match following.take() {
+                Some(Following {
+                    track,
+                    opened: Some((info, LinedUp::Crossfade(context))),
+                }) if time >= duration - crossfade.seconds => {
+                    let upcoming = self.playlist.upcoming_position();
+                    let scale = upcoming.map_or(1.0, |index| self.pre_gain(index, &info));
+                    let notifier = self.queue.create_callback_notifier();
+                    let mut deck = Deck::<O>::new(context, notifier)?;
+                    deck.set_fade(0.0)?;
+                    deck.set_pre_gain(scale)?;
+                    deck.set_volume(&self.volume)?;
+                    deck.start_playback()?;
+                    self.fade = Some(Fade {
+                        track,
+                        deck,
+                        info,
+                        starts_at: time,
+                        length: duration - time,
+                    });
+                }
+                other => {
+                    *following = other;
+                    return Ok(false);
+                }
+            }
         }
+
+        let Some(fade) = &mut self.fade else {
+            return Ok(false);
+        };
+        let progress = match fade.length > 0.0 {
+            true => (time - fade.starts_at) / fade.length,
+            false => 1.0,
+        };
+        player.set_fade(crossfade.curve.fade_out(progress))?;
+        fade.deck.set_fade(crossfade.curve.fade_in(progress))?;
+        Ok(progress >= 1.0)
     }
 
-    pub fn shutdown(self) -> Result<(), AfqueueError> {
-        self.queue.close()?;
-        self.ui.deactivate()?;
+    /// Stop the next track fading in, returning the current one to full
+    /// volume. Returns whether there was a fade to stop.
+    fn cancel_fade(&mut self, player: &mut AudioFilePlayer<O>) -> Result<bool, AfqueueError> {
+        let Some(fade) = self.fade.take() else {
+            return Ok(false);
+        };
+        fade.deck.discard()?;
+        player.set_fade(1.0)?;
+        Ok(true)
+    }
+
+    /// Take the track that faded in over the end of the last, if it is
+    /// `track`. Otherwise the queue has moved elsewhere, and it is stopped.
+    fn faded_in(&mut self, track: &Track) -> Result<Option<Fade<O>>, AfqueueError> {
+        match self.fade.take() {
+            Some(fade) if fade.track == *track => Ok(Some(fade)),
+            Some(fade) => {
+                fade.deck.discard()?;
+                Ok(None)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Scale to play the track at `index` in the queue at, described by
+    /// `info`, going by its ReplayGain tags or measured loudness.
+    fn pre_gain(&self, index: usize, info: &TrackInfo) -> f32 {
+        let album = match self.settings.replay_gain {
+            GainMode::Off => return 1.0,
+            GainMode::Track => false,
+            GainMode::Album => true,
+            GainMode::Auto => self.playlist.in_album(index),
+        };
+        // Tags win out over measurements, which are only used without them
+        let replay_gain = match (ReplayGain::from_metadata(&info.metadata), &info.measured) {
+            (tagged, Some(measured)) if tagged.is_empty() => ReplayGain::from_measured(measured),
+            (tagged, _) => tagged,
+        };
+        replay_gain.scale(album, self.settings.fallback_preamp)
+    }
+
+    /// Set the tracks playing to the volume.
+    fn apply_volume(&mut self, player: &mut AudioFilePlayer<O>) -> Result<(), AfqueueError> {
+        player.set_volume(&self.volume)?;
+        if let Some(fade) = &mut self.fade {
+            fade.deck.set_volume(&self.volume)?;
+        }
+        Ok(())
+    }
+
+    fn seek(
+        &mut self,
+        player: &mut AudioFilePlayer<O>,
+        time: f64,
+        current: &TrackInfo,
+        following: &mut Option<Following>,
+    ) -> Result<(), AfqueueError> {
+        // Moving away from the end leaves the next track to fade in again
+        // once it comes back round
+        if self.cancel_fade(player)? {
+            self.follow_on(player, following, current)?;
+        }
+
+        let duration = current.duration;
+        // Seeking to (or past) the end will finish the track. A track that
+        // can't be sought within can still play on from where it is.
+        if let Err(err) = player.seek(time.clamp(0.0, duration)) {
+            self.notice = Some(format!("Couldn't seek: {err}"));
+            self.display_queue()?;
+        }
+
+        // Update progress right away, rather than waiting for the next time
+        // it is due.
+        self.display_progress(player, duration)?;
+        self.ui.flush()?;
+        Ok(())
+    }
+
+    fn display_progress(
+        &mut self,
+        player: &mut AudioFilePlayer<O>,
+        duration: f64,
+    ) -> Result<(), AfqueueError> {
+        if let Some(progress) = player.get_playback_time()? {
+            self.ui.display_playback_progress(progress, duration)?;
+        }
+        Ok(())
+    }
+
+    /// Select the track at `index`, or the last one if there aren't that
+    /// many, scrolling it into view.
+    fn select(&mut self, index: usize) {
+        let last = self.playlist.tracks().len().saturating_sub(1);
+        self.selected = index.min(last);
+        self.ui.reset_queue_scroll();
+    }
+
+    /// Expand `path` into tracks to go on the end of the queue.
+    fn add(&mut self, path: String) {
+        // Unlike on the command line, a path that isn't there shouldn't
+        // stop everything once its turn comes
+        if let Err(err) = fs::metadata(&path) {
+            self.notice = Some(format!("Couldn't add '{path}': {err}"));
+            return;
+        }
+        let notice = match playlist::load_paths([path.clone()], &self.settings.scan) {
+            Ok((tracks, _)) if tracks.is_empty() => format!("Nothing to play in '{path}'"),
+            Ok((tracks, skipped)) => {
+                let added = match tracks.len() {
+                    1 => "Added 1 track".to_string(),
+                    count => format!("Added {count} tracks"),
+                };
+                self.playlist.append(tracks);
+                match skipped.len() {
+                    0 => added,
+                    skipped => format!("{added}, skipping {skipped}"),
+                }
+            }
+            Err(err) => err.to_string(),
+        };
+        self.notice = Some(notice);
+    }
+
+    fn display_volume(&mut self) -> Result<(), AfqueueError> {
+        let volume = &self.volume;
+        self.ui.display_volume(volume.gain(), volume.is_muted())?;
+        Ok(())
+    }
+
+    fn display_state(&mut self, paused: bool) -> Result<(), AfqueueError> {
+        let (shuffle, repeat) = (self.playlist.shuffle(), self.playlist.repeat());
+        self.ui.display_playback_state(paused, shuffle, repeat)?;
         Ok(())
     }
+
+    /// Draw the queue, along with anything on the bottom row over it.
+    fn display_queue(&mut self) -> Result<(), AfqueueError> {
+        let (tracks, current) = (self.playlist.tracks(), self.playlist.position());
+        self.ui.display_queue(tracks, current, self.selected)?;
+        if let Some(prompt) = &self.prompt {
+            self.ui.display_prompt(ADD_PROMPT, prompt.text())?;
+        } else if let Some(notice) = &self.notice {
+            self.ui.display_notice(notice)?;
+        }
+        Ok(())
+    }
+
+    /// Draw the whole screen from scratch.
+    fn redraw(
+        &mut self,
+        meter_state: &[f32; 2],
+        paused: bool,
+        metadata: &[(String, String)],
+    ) -> Result<(), AfqueueError> {
+        self.ui.update_layout(metadata.len());
+        self.ui.clear_screen()?;
+        let filename = self.playlist.current().map_or("", |track| &track.path);
+        self.ui.display_filename(filename)?;
+        self.ui.display_meter(meter_state)?;
+        self.display_state(paused)?;
+        self.display_volume()?;
+        self.ui.display_metadata(metadata)?;
+        self.display_queue()?;
+        if self.showing_help {
+            self.display_help()?;
+        }
+        self.ui.flush()?;
+        Ok(())
+    }
+
+    /// Overlay the bindings from the keymap in use.
+    fn display_help(&mut self) -> Result<(), AfqueueError> {
+        let bindings: Vec<(String, String)> = self
+            .queue
+            .keymap()
+            .bindings()
+            .iter()
+            .map(|(action, keys)| {
+                let keys: Vec<String> = keys.iter().map(|key| key.to_string()).collect();
+                (keys.join(", "), action.description())
+            })
+            .collect();
+        self.ui.display_help(&bindings)?;
+        Ok(())
+    }
+}
+
+/// Open `track` ready to play, along with what there is to show about it.
+fn open(track: &Track) -> Result<(PlaybackContext, TrackInfo), AfqueueError> {
+    let mut context = PlaybackContext::new(&track.path)?;
+    if let Some(section) = track.section {
+        context.select_section(section.start, section.end)?;
+    }
+    let mut metadata = context.file_metadata()?;
+    // What the playlist says is more specific to the track
+    for (key, value) in &track.metadata {
+        match metadata.iter_mut().find(|(existing, _)| existing == key) {
+            Some((_, existing)) => existing.clone_from(value),
+            None => metadata.push((key.clone(), value.clone())),
+        }
+    }
+    let duration = context.estimated_duration()?;
+    let measured = loudness::cache::load(track);
+    let info = TrackInfo {
+        metadata,
+        duration,
+        measured,
+    };
+    Ok((context, info))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::crossfade::FadeCurve;
+    use crate::events::script::{ScriptedEvents, Step};
+    use crate::loudness::Loudness;
+    use crate::output::scripted::{Command, ScriptedBackend, ScriptedOutput, VirtualClock};
+    use crate::player::decibels_to_gain;
+    use crate::playlist::{PlayOrder, Repeat, Section};
+    use crate::source::wav::encode::Wav;
+    use crate::temp_dir::TempDir;
+    use crate::ui::vt100::VirtualTerminal;
+
+    use self::Step::{Press, Remove, Wait};
+
+    const SAMPLE_RATE: u32 = 8000;
+
+    type TestBoombox = Boombox<ScriptedOutput, ScriptedEvents, VirtualTerminal, VirtualTerminal>;
+
+    /// Silent WAV files, removed again once dropped.
+    struct Tracks {
+        dir: TempDir,
+        paths: Vec<String>,
+    }
+
+    impl Tracks {
+        /// Write a file of each length in seconds, named after the test.
+        fn new(test: &str, lengths: &[u32]) -> Self {
+            let rates: Vec<u32> = lengths.iter().map(|_| SAMPLE_RATE).collect();
+            Tracks::with_rates(test, lengths, &rates)
+        }
+
+        /// Write files of each length, at the matching sample rate.
+        fn with_rates(test: &str, lengths: &[u32], rates: &[u32]) -> Self {
+            let dir = TempDir::new(test, &[], b"");
+            let paths = lengths
+                .iter()
+                .zip(rates)
+                .enumerate()
+                .map(|(index, (&seconds, &rate))| {
+                    let path = dir.join(&format!("{}.wav", index + 1));
+                    // 8 bit mono PCM, which has a single byte for each frame
+                    let silence = vec![0x80; (seconds * rate) as usize];
+                    let wav = Wav {
+                        sample_rate: rate,
+                        ..Wav::pcm(1, 8, silence)
+                    };
+                    fs::write(&path, wav.build()).unwrap();
+                    path.to_str().unwrap().to_string()
+                })
+                .collect();
+            Tracks { dir, paths }
+        }
+
+        /// A queue playing each file through.
+        fn queue(&self) -> Vec<Track> {
+            self.paths.iter().cloned().map(Track::new).collect()
+        }
+    }
+
+    fn secs(seconds: f64) -> Step {
+        Wait(Duration::from_secs_f64(seconds))
+    }
+
+    struct Run {
+        backend: ScriptedBackend,
+        terminal: VirtualTerminal,
+        clock: VirtualClock,
+    }
+
+    /// Play through `tracks` the same way afqueue does, following `script`.
+    fn run(tracks: &Tracks, script: impl IntoIterator<Item = Step>) -> Run {
+        run_with_levels(tracks.queue(), [0.0, 0.0], script)
+    }
+
+    fn run_with_levels(
+        queue: Vec<Track>,
+        levels: [f32; 2],
+        script: impl IntoIterator<Item = Step>,
+    ) -> Run {
+        run_playlist(Playlist::new(queue, PlayOrder::default()), levels, script)
+    }
+
+    fn run_playlist(
+        playlist: Playlist,
+        levels: [f32; 2],
+        script: impl IntoIterator<Item = Step>,
+    ) -> Run {
+        run_with_settings(playlist, Settings::default(), levels, script)
+    }
+
+    fn run_with_settings(
+        playlist: Playlist,
+        settings: Settings,
+        levels: [f32; 2],
+        script: impl IntoIterator<Item = Step>,
+    ) -> Run {
+        let backend = ScriptedBackend::install(VirtualClock::default());
+        backend.set_levels(levels);
+        run_boombox(playlist, settings, Keymap::default(), backend, script)
+    }
+
+    /// Play through `tracks` with the bindings from a keymap file.
+    fn run_with_keymap(
+        tracks: &Tracks,
+        config: &str,
+        script: impl IntoIterator<Item = Step>,
+    ) -> Run {
+        let path = tracks.dir.join("keymap");
+        fs::write(&path, config).unwrap();
+        let keymap = Keymap::load(&path).unwrap();
+        let playlist = Playlist::new(tracks.queue(), PlayOrder::default());
+        let backend = ScriptedBackend::install(VirtualClock::default());
+        run_boombox(playlist, Settings::default(), keymap, backend, script)
+    }
+
+    fn run_boombox(
+        playlist: Playlist,
+        settings: Settings,
+        keymap: Keymap,
+        backend: ScriptedBackend,
+        script: impl IntoIterator<Item = Step>,
+    ) -> Run {
+        let clock = backend.clock();
+        let events = ScriptedEvents::new(keymap, clock.clone(), script).unwrap();
+        let terminal = VirtualTerminal::new(20, 40);
+        let ui = TerminalUI::new(terminal.clone(), terminal.clone()).unwrap();
+        let mut boombox: TestBoombox = Boombox::new(events, ui, settings, playlist);
+
+        boombox.play_queue().unwrap();
+
+        Run {
+            backend,
+            terminal,
+            clock,
+        }
+    }
+
+    impl Run {
+        /// Volume each track was started at.
+        fn starting_volumes(&self) -> Vec<f32> {
+            let commands = self.backend.commands();
+            commands
+                .windows(2)
+                .filter_map(|pair| match pair {
+                    [Command::SetVolume(gain), Command::Start] => Some(*gain),
+                    _ => None,
+                })
+                .collect()
+        }
+
+        /// Number of times an output was started, rather than following on.
+        fn starts(&self) -> usize {
+            let commands = self.backend.commands();
+            commands.iter().filter(|&&c| c == Command::Start).count()
+        }
+
+        /// Times sought to, in the order asked for.
+        fn seeks(&self) -> Vec<f64> {
+            let commands = self.backend.commands();
+            commands
+                .iter()
+                .filter_map(|command| match command {
+                    Command::Seek(time) => Some(*time),
+                    _ => None,
+                })
+                .collect()
+        }
+
+        /// Position in the queue of each track in turn shown playing, going
+        /// by which is marked as current. Playing the same track again from
+        /// the start doesn't show up.
+        fn played(&self) -> Vec<usize> {
+            let mut played = Vec::new();
+            for lines in self.terminal.history() {
+                let current = lines.iter().find_map(|line| {
+                    let entry = line.strip_prefix("▶ ")?;
+                    let (number, _) = entry.trim_start().split_once('.')?;
+                    number.parse::<usize>().ok()
+                });
+                if let Some(number) = current.filter(|&n| played.last() != Some(&(n - 1))) {
+                    played.push(number - 1);
+                }
+            }
+            played
+        }
+
+        fn shows(&self, line: &str) -> bool {
+            self.terminal.lines().iter().any(|shown| shown == line)
+        }
+    }
+
+    #[test]
+    fn plays_each_track_through_to_the_end() {
+        let tracks = Tracks::new("plays_each_track", &[2, 1, 3]);
+        let run = run(&tracks, [secs(10.0)]);
+
+        assert_eq!(run.played(), [0, 1, 2]);
+        // Being in the same format, each follows on without a restart
+        assert_eq!(
+            run.backend.commands(),
+            [Command::SetVolume(1.0), Command::Start]
+        );
+        // Each track is noticed finishing within a tick or so of its end
+        assert!((run.clock.now() - 6.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn next_track_keeps_the_volume() {
+        let tracks = Tracks::new("next_track_keeps_volume", &[2, 5, 5]);
+        let run = run(
+            &tracks,
+            [
+                secs(1.0),
+                Press("["),
+                Press("\x1b[B"),
+                // Carry on into the second track
+                secs(2.0),
+                Press("n"),
+                secs(1.0),
+                Press("q"),
+            ],
+        );
+
+        assert_eq!(run.played(), [0, 1, 2]);
+        // Only the skipped to track starts afresh
+        assert_eq!(run.starting_volumes(), [1.0, decibels_to_gain(-6.0)]);
+        assert!(run.shows("Volume: -6.0 dB (50%)"));
+        assert!(run.shows("▶ 3. 3.wav"));
+    }
+
+    #[test]
+    fn muting_returns_to_the_same_volume() {
+        let tracks = Tracks::new("muting", &[5]);
+        let script = [Press("["), Press("m"), Press("m"), Press("m"), Press("]")];
+        let run = run(&tracks, script.into_iter().chain([secs(0.1), Press("q")]));
+
+        let volumes: Vec<f32> = run
+            .backend
+            .commands()
+            .into_iter()
+            .filter_map(|command| match command {
+                Command::SetVolume(gain) => Some(gain),
+                _ => None,
+            })
+            .collect();
+        let quieter = decibels_to_gain(-3.0);
+        // Turning up while muted carries on from before muting
+        assert_eq!(volumes, [1.0, quieter, 0.0, quieter, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn following_track_takes_over_once_heard() {
+        let tracks = Tracks::new("following_takes_over", &[2, 3]);
+        let run = run(&tracks, [secs(2.5), Press("5"), Press("q")]);
+
+        assert_eq!(run.played(), [0, 1]);
+        // Seeking is within the track now playing
+        assert_eq!(
+            run.backend.commands(),
+            [
+                Command::SetVolume(1.0),
+                Command::Start,
+                Command::Seek(1.5),
+                Command::Stop,
+            ]
+        );
+        assert!(run.shows("▶ 2. 2.wav"));
+        assert!(run.shows("⏵ 00:02 / 00:03"));
+    }
+
+    #[test]
+    fn tracks_in_another_format_start_afresh() {
+        let tracks = Tracks::with_rates("another_format", &[1, 1], &[8000, 11025]);
+        let run = run(&tracks, [secs(5.0)]);
+
+        assert_eq!(run.played(), [0, 1]);
+        assert_eq!(run.starting_volumes(), [1.0, 1.0]);
+    }
+
+    /// Settings that crossfade over `seconds`, changing gain evenly.
+    fn crossfading(seconds: f64) -> Settings {
+        Settings {
+            crossfade: Crossfade {
+                enabled: true,
+                seconds,
+                curve: FadeCurve::Linear,
+            },
+            ..Settings::default()
+        }
+    }
+
+    #[test]
+    fn crossfading_overlaps_the_next_track() {
+        let tracks = Tracks::new("crossfading_overlaps", &[6, 6]);
+        let playlist = Playlist::new(tracks.queue(), PlayOrder::default());
+        let run = run_with_settings(
+            playlist,
+            crossfading(2.0),
+            [0.0, 0.0],
+            [secs(8.0), Press("q")],
+        );
+
+        assert_eq!(run.played(), [0, 1]);
+        // The second starts out silent, and takes over at full volume
+        assert_eq!(run.starting_volumes(), [1.0, 0.0]);
+        let commands = run.backend.commands();
+        assert!(commands.ends_with(&[Command::SetVolume(1.0), Command::Stop]));
+        // Half way through, each is at half volume
+        let halves = commands
+            .windows(2)
+            .filter(|pair| pair == &[Command::SetVolume(0.5), Command::SetVolume(0.5)]);
+        assert_eq!(halves.count(), 1);
+        assert!(run.shows("▶ 2. 2.wav"));
+    }
+
+    #[test]
+    fn gapless_albums_play_on_without_fading() {
+        let tracks = Tracks::new("gapless_albums", &[6, 6]);
+        let queue: Vec<Track> = tracks
+            .paths
+            .iter()
+            .map(|path| Track {
+                metadata: vec![
+                    ("album".to_string(), "Live".to_string()),
+                    ("gapless".to_string(), "1".to_string()),
+                ],
+                ..Track::new(path.clone())
+            })
+            .collect();
+        let playlist = Playlist::new(queue, PlayOrder::default());
+        let run = run_with_settings(playlist, crossfading(2.0), [0.0, 0.0], [secs(15.0)]);
+
+        assert_eq!(run.played(), [0, 1]);
+        assert_eq!(
+            run.backend.commands(),
+            [Command::SetVolume(1.0), Command::Start]
+        );
+    }
+
+    #[test]
+    fn next_track_is_only_opened_again_once_it_changes() {
+        let tracks = Tracks::new("next_track_opened_once", &[6, 6, 1]);
+        let second = tracks.paths[1].clone().leak();
+        let presses = ["x", "x", "j", "j", "d"].map(Press);
+        // Gone from under it, but still open from before
+        let script = [secs(1.0), Remove(second)]
+            .into_iter()
+            .chain(presses)
+            .chain([secs(15.0)]);
+        let run = run(&tracks, script);
+
+        assert_eq!(run.played(), [0, 1]);
+        assert_eq!(run.starts(), 1);
+    }
+
+    #[test]
+    fn crossfading_can_be_toggled_while_playing() {
+        let tracks = Tracks::new("crossfading_toggled", &[12, 12]);
+        let run = run(
+            &tracks,
+            [
+                Press("x"),
+                // Part way into the fade, then back to the start
+                secs(10.0),
+                Press("r"),
+                secs(1.0),
+                Press("q"),
+            ],
+        );
+
+        assert_eq!(run.played(), [0]);
+        assert_eq!(run.starting_volumes(), [1.0, 0.0]);
+        // Going back stops the second track, leaving the first as it was
+        let commands = run.backend.commands();
+        let restart = commands.iter().position(|c| *c == Command::Seek(0.0));
+        assert_eq!(
+            commands[restart.unwrap() - 2..restart.unwrap()],
+            [Command::Stop, Command::SetVolume(1.0)]
+        );
+    }
+
+    #[test]
+    fn replay_gain_scales_the_volume() {
+        let tracks = Tracks::new("replay_gain", &[6, 6]);
+        let queue: Vec<Track> = tracks
+            .paths
+            .iter()
+            .map(|path| Track {
+                metadata: vec![
+                    ("album".to_string(), "Live".to_string()),
+                    ("replaygain_track_gain".to_string(), "-6.02 dB".to_string()),
+                    ("replaygain_album_gain".to_string(), "-12.04 dB".to_string()),
+                ],
+                ..Track::new(path.clone())
+            })
+            .collect();
+        let playlist = Playlist::new(queue, PlayOrder::default());
+        let settings = Settings {
+            replay_gain: GainMode::Track,
+            ..Settings::default()
+        };
+        let run = run_with_settings(
+            playlist,
+            settings,
+            [0.0, 0.0],
+            [secs(1.0), Press("g"), secs(1.0), Press("q")],
+        );
+
+        // Track gain to start with, then album gain once cycled on to it
+        let starting = run.starting_volumes();
+        assert_eq!(starting.len(), 1);
+        assert!((starting[0] - 0.5).abs() < 1e-3);
+        let commands = run.backend.commands();
+        let Some(Command::SetVolume(cycled)) = commands.iter().rev().nth(1) else {
+            panic!("volume not changed before stopping");
+        };
+        assert!((cycled - 0.25).abs() < 1e-3);
+    }
+
+    #[test]
+    fn replay_gain_changes_as_the_next_track_is_heard() {
+        let tracks = Tracks::new("replay_gain_follows_on", &[2, 2]);
+        let gains = ["-6.02 dB", "-12.04 dB"];
+        let queue: Vec<Track> = tracks
+            .queue()
+            .into_iter()
+            .zip(gains)
+            .map(|(track, gain)| Track {
+                metadata: vec![("replaygain_track_gain".to_string(), gain.to_string())],
+                ..track
+            })
+            .collect();
+        let playlist = Playlist::new(queue, PlayOrder::default());
+        let settings = Settings {
+            replay_gain: GainMode::Track,
+            ..Settings::default()
+        };
+        let run = run_with_settings(playlist, settings, [1.0, 1.0], [secs(5.0)]);
+
+        // The meter reads back the volume, at half then a quarter width
+        let meters: Vec<usize> = run
+            .terminal
+            .history()
+            .iter()
+            .filter(|lines| lines.iter().any(|line| line == "▶ 2. 2.wav"))
+            .filter_map(|lines| lines.iter().find(|line| line.starts_with('█')))
+            .map(|bar| bar.chars().count())
+            .collect();
+        assert!(!meters.is_empty());
+        assert!(meters.iter().all(|&width| width == 10), "{meters:?}");
+    }
+
+    #[test]
+    fn measured_loudness_stands_in_for_tags() {
+        let tracks = Tracks::new("measured_loudness", &[2]);
+        let queue = tracks.queue();
+        let measured = Loudness {
+            integrated: Some(-12.0),
+            range: 0.0,
+            true_peak: 0.5,
+        };
+        let entry = loudness::cache::Entry {
+            track: measured,
+            album: measured,
+        };
+        loudness::cache::save([(&queue[0], entry)]).unwrap();
+
+        let playlist = Playlist::new(queue, PlayOrder::default());
+        let settings = Settings {
+            replay_gain: GainMode::Track,
+            ..Settings::default()
+        };
+        let run = run_with_settings(playlist, settings, [0.0, 0.0], [secs(3.0)]);
+
+        // 6dB down to reach -18 LUFS
+        let starting = run.starting_volumes();
+        assert!((starting[0] - 0.501).abs() < 1e-3);
+    }
+
+    #[test]
+    fn pausing_holds_the_track() {
+        let tracks = Tracks::new("pausing_holds_the_track", &[2]);
+        let run = run(
+            &tracks,
+            [secs(1.0), Press("p"), secs(5.0), Press("p"), Press("q")],
+        );
+
+        // Still playing when quitting, even though the track is shorter than
+        // the time that passed
+        assert_eq!(
+            run.backend.commands(),
+            [
+                Command::SetVolume(1.0),
+                Command::Start,
+                Command::Pause,
+                Command::Resume,
+                Command::Stop,
+            ]
+        );
+        assert!(run.shows("⏵ 00:01 / 00:02"));
+    }
+
+    #[test]
+    fn previous_restarts_a_track_once_it_is_underway() {
+        let tracks = Tracks::new("previous_restarts", &[5, 10]);
+        let run = run(
+            &tracks,
+            [
+                // Only just into the second track, so go back to the first
+                secs(6.0),
+                Press("b"),
+                // Far enough in to restart instead
+                secs(4.0),
+                Press("b"),
+                Press("q"),
+            ],
+        );
+
+        assert_eq!(run.played(), [0, 1, 0]);
+        assert!(run.backend.commands().contains(&Command::Seek(0.0)));
+        assert!(run.shows("⏵ 00:00 / 00:05"));
+    }
+
+    #[test]
+    fn previous_on_the_first_track_plays_it_again() {
+        let tracks = Tracks::new("previous_on_first", &[5, 5]);
+        let run = run(&tracks, [secs(1.0), Press("b"), Press("q")]);
+
+        assert_eq!(run.played(), [0]);
+        // Started again, rather than sought back to the start
+        assert_eq!(run.starts(), 2);
+        assert!(!run.backend.commands().contains(&Command::Seek(0.0)));
+    }
+
+    #[test]
+    fn restart_goes_back_to_the_start_however_far_in() {
+        let tracks = Tracks::new("restart_goes_back", &[10, 10]);
+        let run = run(
+            &tracks,
+            [
+                secs(1.0),
+                Press("r"),
+                secs(8.0),
+                Press("r"),
+                secs(2.0),
+                Press("q"),
+            ],
+        );
+
+        assert_eq!(run.played(), [0]);
+        assert_eq!(run.seeks(), [0.0, 0.0]);
+        assert!(run.shows("⏵ 00:02 / 00:10"));
+    }
+
+    #[test]
+    fn seeking_steps_either_way_within_the_track() {
+        let tracks = Tracks::new("seeking_steps", &[60]);
+        let presses = [".", ".", ",", ",", ","].map(Press);
+        let script = [secs(2.0)].into_iter().chain(presses).chain([Press("q")]);
+        let run = run(&tracks, script);
+
+        // Going back stops at the start rather than leaving the track
+        let seeks = run.seeks();
+        let expected = [12.0, 22.0, 12.0, 2.0, 0.0];
+        assert_eq!(seeks.len(), expected.len());
+        for (seek, expected) in seeks.iter().zip(expected) {
+            assert!((seek - expected).abs() < 0.1, "{seeks:?}");
+        }
+        assert_eq!(run.played(), [0]);
+        assert!(run.shows("⏵ 00:00 / 01:00"));
+    }
+
+    #[test]
+    fn number_keys_jump_through_the_track() {
+        let tracks = Tracks::new("number_keys_jump", &[50]);
+        let run = run(&tracks, [Press("7"), Press("2"), secs(3.0), Press("q")]);
+
+        assert_eq!(run.seeks(), [35.0, 10.0]);
+        assert!(run.shows("⏵ 00:13 / 00:50"));
+    }
+
+    #[test]
+    fn seeking_past_the_end_moves_on() {
+        let tracks = Tracks::new("seeking_past_the_end", &[5, 60]);
+        let run = run(&tracks, [Press("."), secs(1.0), Press("5"), Press("q")]);
+
+        assert_eq!(run.played(), [0, 1]);
+        assert!(run.backend.commands().contains(&Command::Seek(5.0)));
+        assert!(run.backend.commands().contains(&Command::Seek(30.0)));
+        assert!(run.shows("⏵ 00:30 / 01:00"));
+    }
+
+    #[test]
+    fn failing_to_seek_is_shown_without_stopping() {
+        let tracks = Tracks::new("failing_to_seek", &[60]);
+        let backend = ScriptedBackend::install(VirtualClock::default());
+        backend.fail_seeks();
+        let playlist = Playlist::new(tracks.queue(), PlayOrder::default());
+        let script = [secs(2.0), Press("."), secs(1.0), Press("q")];
+        let run = run_boombox(
+            playlist,
+            Settings::default(),
+            Keymap::default(),
+            backend,
+            script,
+        );
+
+        // Carrying on from where it was, with the reason shown until the
+        // next key press
+        let history = run.terminal.history();
+        let shown = |line: &str| {
+            history
+                .iter()
+                .flatten()
+                .any(|shown| shown.starts_with(line))
+        };
+        assert!(shown("Couldn't seek: encountered IO error"));
+        assert!(shown("⏵ 00:03 / 01:00"));
+    }
+
+    #[test]
+    fn sections_stop_where_the_next_begins() {
+        let tracks = Tracks::new("sections_stop", &[6]);
+        let path = &tracks.paths[0];
+        let queue = [(0.0, Some(2.0)), (2.0, Some(4.5)), (4.5, None)]
+            .into_iter()
+            .map(|(start, end)| Track {
+                section: Some(Section { start, end }),
+                ..Track::new(path.clone())
+            })
+            .collect();
+        let run = run_with_levels(queue, [0.0, 0.0], [secs(10.0)]);
+
+        assert_eq!(run.played(), [0, 1, 2]);
+        assert!(!run.backend.commands().contains(&Command::Stop));
+        assert!((run.clock.now() - 6.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn seeking_is_within_the_section() {
+        let tracks = Tracks::new("seeking_within_section", &[60]);
+        let track = Track {
+            section: Some(Section {
+                start: 10.0,
+                end: Some(40.0),
+            }),
+            ..Track::new(tracks.paths[0].clone())
+        };
+        let run = run_with_levels(vec![track], [0.0, 0.0], [Press("5"), Press("q")]);
+
+        assert!(run.backend.commands().contains(&Command::Seek(15.0)));
+        assert!(run.shows("⏵ 00:15 / 00:30"));
+    }
+
+    #[test]
+    fn keys_close_help_instead_of_acting() {
+        let tracks = Tracks::new("keys_close_help", &[5, 5]);
+        let run = run(&tracks, [Press("?"), Press("n"), Press("q")]);
+
+        assert_eq!(run.played(), [0]);
+        assert_eq!(
+            run.backend.commands(),
+            [Command::SetVolume(1.0), Command::Start, Command::Stop]
+        );
+    }
+
+    #[test]
+    fn help_lists_the_keymap_in_use() {
+        let tracks = Tracks::new("help_lists_keymap", &[5]);
+        let run = run_with_keymap(&tracks, "v = restart\nr = none\n", [Press("?"), secs(10.0)]);
+
+        let lines = run.terminal.lines();
+        let restart = lines
+            .iter()
+            .find(|line| line.contains("Restart track"))
+            .expect("restart should be listed");
+        assert!(restart.contains("│ v "), "{restart}");
+        assert!(lines.iter().any(|line| line.contains("Key bindings")));
+    }
+
+    #[test]
+    fn closing_help_shows_what_was_underneath() {
+        let tracks = Tracks::new("closing_help", &[5]);
+        let run = run(
+            &tracks,
+            [Press("?"), secs(1.0), Press("x"), secs(1.0), Press("q")],
+        );
+
+        let lines = run.terminal.lines();
+        assert!(!lines.iter().any(|line| line.contains("Key bindings")));
+        assert!(lines[0].starts_with("Playing: "));
+        assert!(run.shows("⏵ 00:02 / 00:05"));
+        assert!(run.shows("Properties:"));
+    }
+
+    #[test]
+    fn meter_shows_levels_after_volume() {
+        let tracks = Tracks::new("meter_shows_levels", &[5]);
+        let mut script = vec![Press("["); 2];
+        script.extend([secs(0.1), Press("q")]);
+        let run = run_with_levels(tracks.queue(), [1.0, 0.5], script);
+
+        // Two steps down all but halves the volume, and so the levels
+        assert_eq!(run.terminal.lines()[2..4], ["█".repeat(20), "█".repeat(10)]);
+    }
+
+    #[test]
+    fn queue_follows_playback_once_scrolled_away() {
+        let tracks = Tracks::new("queue_follows_playback", &[1; 12]);
+        let page_down = "\x1b[6~";
+
+        let scrolled = run(&tracks, [Press(page_down), Press("q")]);
+        assert!(scrolled.shows("  12. 12.wav"));
+        assert!(!scrolled.shows("▶  1. 1.wav"));
+
+        // The next track brings the queue back round to it
+        let followed = run(&tracks, [Press(page_down), secs(1.5), Press("q")]);
+        assert_eq!(followed.played(), [0, 1]);
+        assert!(followed.shows("   1. 1.wav"));
+        assert!(followed.shows("▶  2. 2.wav"));
+    }
+
+    #[test]
+    fn repeating_one_plays_the_track_again_until_skipped() {
+        let tracks = Tracks::new("repeating_one", &[2, 2]);
+        let order = PlayOrder {
+            repeat: Repeat::One,
+            ..PlayOrder::default()
+        };
+        let playlist = Playlist::new(tracks.queue(), order);
+        let run = run_playlist(
+            playlist,
+            [0.0, 0.0],
+            [secs(5.0), Press("n"), secs(1.0), Press("R"), Press("q")],
+        );
+
+        assert_eq!(run.played(), [0, 1]);
+        // Skipped a second into the first track's third time round
+        let history = run.terminal.history();
+        let skipped = history
+            .iter()
+            .rfind(|lines| lines.iter().any(|line| line == "Queue (1/2):"))
+            .unwrap();
+        assert!(skipped.iter().any(|line| line == "⏵ 🔂 00:01 / 00:02"));
+        assert!(run.shows("⏵ 00:01 / 00:02"));
+    }
+
+    #[test]
+    fn modes_are_shown_by_the_playback_state() {
+        let tracks = Tracks::new("modes_are_shown", &[5, 5]);
+        let run = run(&tracks, [Press("s"), Press("R"), secs(1.0), Press("q")]);
+
+        assert!(run.shows("⏵ 🔀🔁 00:01 / 00:05"));
+    }
+
+    #[test]
+    fn queue_can_be_rearranged_while_playing() {
+        let tracks = Tracks::new("queue_rearranged", &[5, 5, 5]);
+        let run = run(
+            &tracks,
+            [
+                // Have the third track play next
+                Press("j"),
+                Press("j"),
+                Press("N"),
+                Press("n"),
+                // Then take it out again, moving on to the second
+                Press("d"),
+                Press("q"),
+            ],
+        );
+
+        assert_eq!(run.played(), [0, 1]);
+        // Each of the first, third and second
+        assert_eq!(run.starts(), 3);
+        assert!(run.shows("Queue (2/2):"));
+        assert!(run.shows("▶ 2. 2.wav"));
+    }
+
+    #[test]
+    fn files_can_be_added_while_playing() {
+        let tracks = Tracks::new("files_added", &[2, 1]);
+        let queue = vec![Track::new(tracks.paths[0].clone())];
+        // Bound keys in the path are typed in rather than acted on
+        let path: &'static str = tracks.paths[1].clone().leak();
+        let script = [Press("a"), Press(path), Press("\r"), secs(5.0)];
+        let run = run_with_levels(queue, [0.0, 0.0], script);
+
+        assert_eq!(run.played(), [0, 1]);
+        assert!(run.shows("Queue (2/2):"));
+    }
 }
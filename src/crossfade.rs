@@ -0,0 +1,166 @@
+//! Fading the next track in over the end of the one playing.
+//!
+//! For the length of the fade both tracks play at once, each through an
+//! output of its own, with the gain of one falling as the other rises
+//! according to a `FadeCurve`. Albums tagged as gapless are meant to run
+//! straight on from one track to the next, so are left to do just that.
+
+use std::f64::consts::FRAC_PI_2;
+
+pub const DEFAULT_CROSSFADE_SECONDS: f64 = 5.0;
+
+// Logarithmic fades start this far below full volume, rather than from
+// silence which is infinitely far down.
+const LOGARITHMIC_RANGE_DB: f64 = 60.0;
+
+// Properties that mark a track as part of a gapless album, as set by iTunes
+// and tag editors that follow its lead.
+const GAPLESS_KEYS: [&str; 4] = ["gapless", "gapless playback", "itunpgap", "pgap"];
+
+/// How gain changes over the course of a fade.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum FadeCurve {
+    /// Keeps the combined power of both tracks steady, so that unrelated
+    /// tracks don't dip in loudness half way through
+    #[default]
+    EqualPower,
+    Linear,
+    /// Even steps in decibels, which sounds closer to even steps in loudness
+    Logarithmic,
+}
+
+impl FadeCurve {
+    /// Look up a curve by the name given on the command line.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "equal-power" => Some(FadeCurve::EqualPower),
+            "linear" => Some(FadeCurve::Linear),
+            "logarithmic" | "log" => Some(FadeCurve::Logarithmic),
+            _ => None,
+        }
+    }
+
+    /// Gain of the track fading in, `progress` of the way through the fade.
+    pub fn fade_in(self, progress: f64) -> f32 {
+        let progress = progress.clamp(0.0, 1.0);
+        let gain = match self {
+            FadeCurve::EqualPower => (progress * FRAC_PI_2).sin(),
+            FadeCurve::Linear => progress,
+            FadeCurve::Logarithmic if progress == 0.0 => 0.0,
+            FadeCurve::Logarithmic => {
+                let decibels = LOGARITHMIC_RANGE_DB * (progress - 1.0);
+                10f64.powf(decibels / 20.0)
+            }
+        };
+        gain.clamp(0.0, 1.0) as f32
+    }
+
+    /// Gain of the track fading out, which mirrors the one fading in.
+    pub fn fade_out(self, progress: f64) -> f32 {
+        self.fade_in(1.0 - progress.clamp(0.0, 1.0))
+    }
+}
+
+/// How tracks are faded between, if at all.
+#[derive(Debug, Clone, Copy)]
+pub struct Crossfade {
+    pub enabled: bool,
+    /// How long the tracks overlap for
+    pub seconds: f64,
+    pub curve: FadeCurve,
+}
+
+impl Default for Crossfade {
+    fn default() -> Self {
+        Crossfade {
+            enabled: false,
+            seconds: DEFAULT_CROSSFADE_SECONDS,
+            curve: FadeCurve::default(),
+        }
+    }
+}
+
+/// Whether a track with the properties `outgoing` should fade into one with
+/// `incoming`, which it shouldn't when both are from the same gapless album.
+pub fn fades_between(outgoing: &[(String, String)], incoming: &[(String, String)]) -> bool {
+    let same_album = album(outgoing) == album(incoming);
+    !(same_album && is_gapless(outgoing) && is_gapless(incoming))
+}
+
+fn album(metadata: &[(String, String)]) -> Option<&str> {
+    metadata
+        .iter()
+        .find(|(key, _)| key == "album")
+        .map(|(_, value)| value.as_str())
+}
+
+fn is_gapless(metadata: &[(String, String)]) -> bool {
+    metadata.iter().any(|(key, value)| {
+        let value = value.trim();
+        GAPLESS_KEYS.contains(&key.to_ascii_lowercase().as_str())
+            && ["1", "true", "yes"]
+                .iter()
+                .any(|truthy| value.eq_ignore_ascii_case(truthy))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CURVES: [FadeCurve; 3] = [
+        FadeCurve::EqualPower,
+        FadeCurve::Linear,
+        FadeCurve::Logarithmic,
+    ];
+
+    fn tags(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn curves_run_from_silence_to_full_volume() {
+        for curve in CURVES {
+            assert_eq!(curve.fade_in(0.0), 0.0, "{curve:?}");
+            assert_eq!(curve.fade_in(1.0), 1.0, "{curve:?}");
+            assert_eq!(curve.fade_out(0.0), 1.0, "{curve:?}");
+            assert_eq!(curve.fade_out(1.0), 0.0, "{curve:?}");
+
+            let steps: Vec<f32> = (0..=20)
+                .map(|step| curve.fade_in(step as f64 / 20.0))
+                .collect();
+            assert!(steps.windows(2).all(|pair| pair[0] < pair[1]), "{curve:?}");
+        }
+    }
+
+    #[test]
+    fn equal_power_keeps_power_steady() {
+        for step in 0..=10 {
+            let progress = step as f64 / 10.0;
+            let (fading_in, fading_out) = (
+                FadeCurve::EqualPower.fade_in(progress),
+                FadeCurve::EqualPower.fade_out(progress),
+            );
+            assert!((fading_in.powi(2) + fading_out.powi(2) - 1.0).abs() < 1e-6);
+        }
+        assert_eq!(FadeCurve::Linear.fade_in(0.25), 0.25);
+        // Half way through is 30dB down
+        assert!((FadeCurve::Logarithmic.fade_in(0.5) - 0.031_622_776).abs() < 1e-6);
+    }
+
+    #[test]
+    fn gapless_albums_are_not_faded() {
+        let gapless = tags(&[("album", "Live"), ("ITUNPGAP", "1")]);
+        let also_gapless = tags(&[("album", "Live"), ("gapless", "true")]);
+        let other_album = tags(&[("album", "Studio"), ("gapless", "1")]);
+        let untagged = tags(&[("album", "Live")]);
+
+        assert!(!fades_between(&gapless, &also_gapless));
+        assert!(fades_between(&gapless, &other_album));
+        assert!(fades_between(&gapless, &untagged));
+        assert!(fades_between(&untagged, &untagged));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::keymap::KeymapError;
 use crate::player::PlaybackError;
 use std::error::Error;
 use std::fmt;
@@ -53,15 +54,31 @@ impl From<PlaybackError> for AfqueueError {
     }
 }
 
+impl From<KeymapError> for AfqueueError {
+    fn from(err: KeymapError) -> AfqueueError {
+        AfqueueError::new(Box::new(err))
+    }
+}
+
 #[derive(Debug)]
 pub enum ErrorCtx {
     PlayingBack(String),
+    LoadingKeymap(String),
+    LoadingPlaylist(String),
+    SavingPlaylist(String),
+    SavingLoudness,
+    ScanningDirectory(String),
 }
 
 impl fmt::Display for ErrorCtx {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             ErrorCtx::PlayingBack(filepath) => write!(f, "playing back file '{filepath}'"),
+            ErrorCtx::LoadingKeymap(filepath) => write!(f, "loading keymap '{filepath}'"),
+            ErrorCtx::LoadingPlaylist(filepath) => write!(f, "loading playlist '{filepath}'"),
+            ErrorCtx::SavingPlaylist(filepath) => write!(f, "saving playlist '{filepath}'"),
+            ErrorCtx::SavingLoudness => write!(f, "saving loudness measurements"),
+            ErrorCtx::ScanningDirectory(path) => write!(f, "searching directory '{path}'"),
         }
     }
 }
@@ -1,305 +1,181 @@
 use std::ffi::c_void;
 
 use std::io;
-use std::ptr;
+use std::time::{Duration, Instant};
 
-use crate::ffi::kqueue::{self as kq, kevent, kqueue, Kevent, Kqueue};
+use crate::ffi::unistd;
+use crate::keymap::{Action, Keymap};
 
-const AUDIO_QUEUE_PLAYBACK_STARTED: u64 = 39;
-const AUDIO_QUEUE_PLAYBACK_FINISHED: u64 = 40;
-const UI_TIMER_TICK: u64 = 41;
+mod input;
+#[cfg(test)]
+pub mod script;
+use self::input::InputDecoder;
+pub use self::input::{Key, KeyPress, Modifiers};
 
-const KEVENT_BUFFER_SIZE: usize = 10;
-const INPUT_BUFFER_SIZE: usize = 10;
+// Each platform provides a `Poller` that blocks waiting for input, timer,
+// signal or user events, along with a `CallbackNotifier` used to raise user
+// events from other threads.
+
+#[cfg(target_os = "macos")]
+mod kqueue;
+#[cfg(target_os = "macos")]
+pub use self::kqueue::CallbackNotifier;
+#[cfg(target_os = "macos")]
+use self::kqueue::Poller;
+
+#[cfg(target_os = "linux")]
+mod epoll;
+#[cfg(target_os = "linux")]
+pub use self::epoll::CallbackNotifier;
+#[cfg(target_os = "linux")]
+use self::epoll::Poller;
+
+const INPUT_BUFFER_SIZE: usize = 64;
+
+// How long to wait for the rest of an escape sequence before deciding that
+// the escape key was pressed on its own.
+const ESCAPE_TIMEOUT: Duration = Duration::from_millis(50);
 
 #[derive(Debug)]
 pub enum Event {
-    NextTrackKeyPressed,
-    PauseKeyPressed,
-    ExitKeyPressed,
-    VolumeUpKeyPressed,
-    VolumeDownKeyPressed,
+    /// A key bound to an action was pressed
+    Action(Action),
+    /// A key without any binding was pressed
+    UnboundKey,
+    /// A key was pressed while entering text, whether bound or not
+    Key(KeyPress),
     PlaybackStarted,
     PlaybackFinished,
     UITick,
     TerminalResized,
 }
 
+/// Something a poller has observed happening.
+enum Readiness {
+    InputAvailable,
+    PlaybackStarted,
+    PlaybackFinished,
+    UITimerFired,
+    TerminalResized,
+}
+
+/// Somewhere for the boombox to get events from.
+pub trait EventSource {
+    /// Block until the next event happens.
+    fn next_event(&mut self) -> Event;
+
+    /// Bindings used to turn key presses into actions.
+    fn keymap(&self) -> &Keymap;
+
+    fn create_callback_notifier(&self) -> CallbackNotifier;
+
+    /// While entering text, key presses come through as they are rather than
+    /// as the actions they are bound to.
+    fn set_text_entry(&mut self, enabled: bool);
+
+    fn enable_ui_timer_event(&mut self, usec: i64) -> io::Result<()>;
+
+    fn disable_ui_timer_event(&mut self) -> io::Result<()>;
+}
+
 pub struct EventQueue {
-    queue: Kqueue,
-    queue_reader: KQueueReader,
+    poller: Poller,
     input_reader: InputReader,
+    keymap: Keymap,
+    text_entry: bool,
 }
 
-impl EventQueue {
-    pub fn next_event(&mut self) -> Event {
+impl EventSource for EventQueue {
+    fn next_event(&mut self) -> Event {
         // To get the next event we:
-        // - Start by taking the next buffered char from stdin.
-        // - If this char maps to a valid event then return, otherwise try again.
-        // - If nothing buffered on std, instead perform a blocking read on the kqueue.
-        // - If kqueue returns a user event, then return it.
-        // - If the kqueue indicates that stdin has input to read, attempt to fill stdin
+        // - Start by taking the next key press decoded from stdin.
+        // - If there is one, return it as either the action it is bound to, or as
+        //   an unbound key. While entering text, return it as it is.
+        // - If nothing buffered on std, instead perform a blocking wait on the poller.
+        // - If the poller returns a user event, then return it.
+        // - If the poller indicates that stdin has input to read, attempt to fill stdin
         //   and try again from the top. TODO: Flow chart would be nice
 
         loop {
-            if let Some(input_char) = self.input_reader.read() {
-                match input_char {
-                    'n' => return Event::NextTrackKeyPressed,
-                    'q' => return Event::ExitKeyPressed,
-                    'p' => return Event::PauseKeyPressed,
-                    ']' => return Event::VolumeUpKeyPressed,
-                    '[' => return Event::VolumeDownKeyPressed,
-                    _ => continue,
-                }
+            if let Some(key) = self.input_reader.read() {
+                return self.key_event(key);
             }
 
-            let queue_event = self.queue_reader.read();
+            // A partial escape sequence means we can only wait so long, before
+            // taking it to be a key press in its own right.
+            let timeout = self.input_reader.pending_timeout();
 
-            match (queue_event.ident, queue_event.filter) {
-                (kq::STDIN_FILE_NUM, kq::EVFILT_READ) => {
+            match self.poller.wait(timeout) {
+                None => {
+                    if let Some(key) = self.input_reader.expire() {
+                        return self.key_event(key);
+                    }
+                }
+                Some(Readiness::InputAvailable) => {
                     self.input_reader.fill_buffer();
                     continue;
                 }
-                (AUDIO_QUEUE_PLAYBACK_STARTED, kq::EVFILT_USER) => return Event::PlaybackStarted,
-                (AUDIO_QUEUE_PLAYBACK_FINISHED, kq::EVFILT_USER) => return Event::PlaybackFinished,
-                (UI_TIMER_TICK, kq::EVFILT_TIMER) => return Event::UITick,
-                (kq::SIGWINCH, kq::EVFILT_SIGNAL) => return Event::TerminalResized,
-                _ => continue,
+                Some(Readiness::PlaybackStarted) => return Event::PlaybackStarted,
+                Some(Readiness::PlaybackFinished) => return Event::PlaybackFinished,
+                Some(Readiness::UITimerFired) => return Event::UITick,
+                Some(Readiness::TerminalResized) => return Event::TerminalResized,
             }
         }
     }
 
-    pub fn create_callback_notifier(&self) -> CallbackNotifier {
-        CallbackNotifier { queue: self.queue }
+    fn keymap(&self) -> &Keymap {
+        &self.keymap
     }
 
-    pub fn enable_ui_timer_event(&mut self, usec: i64) -> io::Result<()> {
-        unsafe {
-            let ui_timer_event = Kevent {
-                ident: UI_TIMER_TICK,
-                filter: kq::EVFILT_TIMER,
-                flags: kq::EV_ADD | kq::EV_ENABLE,
-                fflags: kq::NOTE_USECONDS,
-                data: usec,
-                udata: 0,
-            };
-
-            let changelist = [ui_timer_event];
-
-            let result = kevent(
-                self.queue,
-                changelist.as_ptr(),
-                changelist.len() as i32,
-                ptr::null_mut(),
-                0,
-                ptr::null(),
-            );
-
-            if result < 0 {
-                return Err(io::Error::last_os_error());
-            }
-            Ok(())
-        }
+    fn create_callback_notifier(&self) -> CallbackNotifier {
+        self.poller.create_callback_notifier()
     }
 
-    pub fn disable_ui_timer_event(&mut self) -> io::Result<()> {
-        unsafe {
-            let ui_timer_event = Kevent {
-                ident: UI_TIMER_TICK,
-                filter: kq::EVFILT_TIMER,
-                flags: kq::EV_DELETE,
-                fflags: 0,
-                data: 0,
-                udata: 0,
-            };
-
-            let changelist = [ui_timer_event];
-
-            let result = kevent(
-                self.queue,
-                changelist.as_ptr(),
-                changelist.len() as i32,
-                ptr::null_mut(),
-                0,
-                ptr::null(),
-            );
-
-            if result < 0 {
-                return Err(io::Error::last_os_error());
-            }
-            Ok(())
-        }
+    fn set_text_entry(&mut self, enabled: bool) {
+        self.text_entry = enabled;
     }
 
-    pub fn close(self) -> io::Result<()> {
-        //TODO: Could this be drop instead?
-        unsafe {
-            let result = kq::close(self.queue);
-            if result < 0 {
-                Err(io::Error::last_os_error())
-            } else {
-                Ok(())
-            }
-        }
+    fn enable_ui_timer_event(&mut self, usec: i64) -> io::Result<()> {
+        self.poller.enable_timer(usec)
     }
-}
 
-#[derive(Clone)]
-pub struct CallbackNotifier {
-    queue: Kqueue,
+    fn disable_ui_timer_event(&mut self) -> io::Result<()> {
+        self.poller.disable_timer()
+    }
 }
 
-impl CallbackNotifier {
-    pub fn trigger_playback_started_event(&mut self) -> io::Result<()> {
-        unsafe {
-            let playback_started_event = Kevent {
-                ident: AUDIO_QUEUE_PLAYBACK_STARTED,
-                filter: kq::EVFILT_USER,
-                flags: 0,
-                fflags: kq::NOTE_TRIGGER,
-                data: 0,
-                udata: 0,
-            };
-
-            let changelist = [playback_started_event];
-
-            let result = kevent(
-                self.queue,
-                changelist.as_ptr(),
-                changelist.len() as i32,
-                ptr::null_mut(),
-                0,
-                ptr::null(),
-            );
-
-            if result < 0 {
-                return Err(io::Error::last_os_error());
-            }
-            Ok(())
+impl EventQueue {
+    fn key_event(&self, key: KeyPress) -> Event {
+        if self.text_entry {
+            return Event::Key(key);
+        }
+        match self.keymap.action_for(&key) {
+            Some(action) => Event::Action(action),
+            None => Event::UnboundKey,
         }
     }
 
-    pub fn trigger_playback_finished_event(&mut self) -> io::Result<()> {
-        //TODO: Extract function for writing to kqueue
-        unsafe {
-            let playback_finished_event = Kevent {
-                ident: AUDIO_QUEUE_PLAYBACK_FINISHED,
-                filter: kq::EVFILT_USER,
-                flags: 0,
-                fflags: kq::NOTE_TRIGGER,
-                data: 0,
-                udata: 0,
-            };
-
-            let changelist = [playback_finished_event];
-
-            let result = kevent(
-                self.queue,
-                changelist.as_ptr(),
-                changelist.len() as i32,
-                ptr::null_mut(),
-                0,
-                ptr::null(),
-            );
-
-            if result < 0 {
-                return Err(io::Error::last_os_error());
-            }
-            Ok(())
-        }
+    pub fn close(self) -> io::Result<()> {
+        //TODO: Could this be drop instead?
+        self.poller.close()
     }
 }
-//TODO: Refactor, think about abstractions that might make it a little easier
-// to follow
-pub fn build_event_queue() -> io::Result<EventQueue> {
-    unsafe {
-        // Create a new Kqueue
-        let kqueue = kqueue();
-        if kqueue < 0 {
-            return Err(io::Error::last_os_error());
-        }
-
-        // Describe the events we are interested in...
-
-        // New input available on stdin
-        // TODO: See if EV_ENABLE is actually needed?
-        let stdin_event = Kevent {
-            ident: kq::STDIN_FILE_NUM,
-            filter: kq::EVFILT_READ,
-            flags: kq::EV_ADD | kq::EV_ENABLE,
-            fflags: 0,
-            data: 0,
-            udata: 0,
-        };
-
-        // TODO: Maybe using a unique ident per file along with a EV_ONESHOT would be
-        // easier? i.e using udata to signal the audio queue thats stopped
-
-        let playback_started_event = Kevent {
-            ident: AUDIO_QUEUE_PLAYBACK_STARTED,
-            filter: kq::EVFILT_USER,
-            flags: kq::EV_ADD | kq::EV_CLEAR,
-            fflags: 0,
-            data: 0,
-            udata: 0,
-        };
-
-        // End of audio queue playback
-        let playback_finished_event = Kevent {
-            ident: AUDIO_QUEUE_PLAYBACK_FINISHED,
-            filter: kq::EVFILT_USER,
-            flags: kq::EV_ADD | kq::EV_CLEAR,
-            fflags: 0,
-            data: 0,
-            udata: 0,
-        };
-
-        // Terminal resizing
-        let terminal_resized_event = Kevent {
-            ident: kq::SIGWINCH,
-            filter: kq::EVFILT_SIGNAL,
-            flags: kq::EV_ADD,
-            fflags: 0,
-            data: 0,
-            udata: 0,
-        };
-
-        // Register interest in all events
-        let changelist = [
-            stdin_event,
-            terminal_resized_event,
-            playback_started_event,
-            playback_finished_event,
-        ];
-
-        let result = kevent(
-            kqueue,
-            changelist.as_ptr(),
-            changelist.len() as i32,
-            ptr::null_mut(),
-            0,
-            ptr::null(),
-        );
-
-        if result < 0 {
-            return Err(io::Error::last_os_error());
-        }
-
-        let handle = EventQueue {
-            queue: kqueue,
-            queue_reader: KQueueReader::new(kqueue),
-            input_reader: InputReader::new(kq::STDIN_FILE_NUM as i32),
-        };
 
-        Ok(handle)
-    }
+/// Build a queue of events, with key presses read from `input`.
+pub fn build_event_queue(keymap: Keymap, input: i32) -> io::Result<EventQueue> {
+    Ok(EventQueue {
+        poller: Poller::new(input)?,
+        input_reader: InputReader::new(input),
+        keymap,
+        text_entry: false,
+    })
 }
 
 //TODO: Try and replace this with a std::io::Stdin buffered reader
 struct InputReader {
     buffer: [u8; INPUT_BUFFER_SIZE],
-    next: usize,
-    filled: usize,
+    decoder: InputDecoder,
+    pending_since: Option<Instant>,
     file_descriptor: i32,
 }
 
@@ -307,19 +183,19 @@ impl InputReader {
     fn new(file_descriptor: i32) -> Self {
         InputReader {
             buffer: [0; INPUT_BUFFER_SIZE],
-            next: 0,
-            filled: 0,
+            decoder: InputDecoder::new(),
+            pending_since: None,
             file_descriptor,
         }
     }
 
     fn fill_buffer(&mut self) {
         unsafe {
-            // NOTE: It's possible that the kqueue filter watching standard input might
+            // NOTE: It's possible that the poller watching standard input might
             // spuriouly trigger. So this wont be guarenteed to read any bytes, even if
-            // kqueue has reported there is input to read.
+            // the poller has reported there is input to read.
 
-            let result = kq::read(
+            let result = unistd::read(
                 self.file_descriptor,
                 self.buffer.as_mut_ptr() as *mut c_void,
                 self.buffer.len(),
@@ -330,62 +206,29 @@ impl InputReader {
                 panic!("{}", io::Error::last_os_error());
             }
 
-            self.next = 0;
-            self.filled = result as usize;
+            self.decoder.push(&self.buffer[..result as usize]);
         }
     }
 
-    fn read(&mut self) -> Option<char> {
-        if self.next == self.filled {
-            return None;
-        }
-        let next_char = self.buffer[self.next] as char;
-        self.next += 1;
-        Some(next_char)
+    fn read(&mut self) -> Option<KeyPress> {
+        let key = self.decoder.next_key()?;
+        self.pending_since = None;
+        Some(key)
     }
-}
 
-//TODO: Would this be more ideomatic if we implemented the buf reader trait?
-struct KQueueReader {
-    buffer: [Kevent; KEVENT_BUFFER_SIZE],
-    kqueue: Kqueue,
-    next: usize,
-    filled: usize,
-}
-
-impl KQueueReader {
-    fn new(kqueue: Kqueue) -> Self {
-        KQueueReader {
-            kqueue,
-            buffer: [Kevent::default(); KEVENT_BUFFER_SIZE],
-            next: 0,
-            filled: 0,
+    /// How much longer to wait for the rest of a partially read key, if any.
+    fn pending_timeout(&mut self) -> Option<Duration> {
+        if !self.decoder.is_pending() {
+            self.pending_since = None;
+            return None;
         }
+        let since = *self.pending_since.get_or_insert_with(Instant::now);
+        Some(ESCAPE_TIMEOUT.saturating_sub(since.elapsed()))
     }
 
-    fn read(&mut self) -> Kevent {
-        unsafe {
-            if self.next == self.filled {
-                let result = kevent(
-                    self.kqueue,
-                    ptr::null(),
-                    0,
-                    self.buffer.as_mut_ptr(),
-                    self.buffer.len() as i32,
-                    ptr::null(),
-                );
-
-                if result < 0 {
-                    //TODO: Dont panic, expose error
-                    panic!("{}", io::Error::last_os_error());
-                }
-
-                self.next = 0;
-                self.filled = result as usize;
-            }
-            let item = self.buffer[self.next];
-            self.next += 1;
-            item
-        }
+    /// Stop waiting on a partially read key, and take it as it is.
+    fn expire(&mut self) -> Option<KeyPress> {
+        self.pending_since = None;
+        self.decoder.expire()
     }
 }
@@ -0,0 +1,354 @@
+//! Poller implementation backed by epoll.
+//!
+//! Unlike kqueue, epoll can only watch file descriptors. So each kind of event
+//! is routed through a file descriptor of its own:
+//! - the input file descriptor, normally stdin, for key presses
+//! - a timerfd for UI ticks
+//! - a signalfd for SIGWINCH
+//! - an eventfd each for playback starting and finishing
+
+use std::ffi::c_void;
+use std::io;
+use std::ptr;
+use std::time::{Duration, Instant};
+
+use super::Readiness;
+use crate::ffi::epoll::{self as ep, EpollEvent, ITimerSpec, SigSet, Timespec};
+use crate::ffi::unistd;
+
+const INPUT_TOKEN: u64 = 0;
+const UI_TIMER_TOKEN: u64 = 1;
+const SIGNAL_TOKEN: u64 = 2;
+const PLAYBACK_STARTED_TOKEN: u64 = 3;
+const PLAYBACK_FINISHED_TOKEN: u64 = 4;
+
+const EPOLL_EVENT_BUFFER_SIZE: usize = 10;
+
+pub struct Poller {
+    epoll: i32,
+    timer: i32,
+    signals: i32,
+    playback_started: i32,
+    playback_finished: i32,
+    buffer: [EpollEvent; EPOLL_EVENT_BUFFER_SIZE],
+    next: usize,
+    filled: usize,
+}
+
+impl Poller {
+    /// Create a poller watching `input` for key presses.
+    pub fn new(input: i32) -> io::Result<Self> {
+        unsafe {
+            // SIGWINCH must be blocked for it to be delivered via the signalfd.
+            // This needs to happen before any other threads are spawned, as
+            // they inherit the signal mask of the thread that spawned them.
+            let mut mask = SigSet::default();
+            ep::sigemptyset(&mut mask);
+            ep::sigaddset(&mut mask, ep::SIGWINCH);
+            check(ep::sigprocmask(ep::SIG_BLOCK, &mask, ptr::null_mut()))?;
+
+            let epoll = check(ep::epoll_create1(ep::EPOLL_CLOEXEC))?;
+
+            // Timer is left disarmed until UI ticks are enabled
+            let timer = check(ep::timerfd_create(
+                ep::CLOCK_MONOTONIC,
+                ep::TFD_NONBLOCK | ep::TFD_CLOEXEC,
+            ))?;
+
+            let signals = check(ep::signalfd(-1, &mask, ep::SFD_NONBLOCK | ep::SFD_CLOEXEC))?;
+
+            let playback_started = check(ep::eventfd(0, ep::EFD_NONBLOCK | ep::EFD_CLOEXEC))?;
+            let playback_finished = check(ep::eventfd(0, ep::EFD_NONBLOCK | ep::EFD_CLOEXEC))?;
+
+            // Register interest in all events
+            watch(epoll, input, INPUT_TOKEN)?;
+            watch(epoll, timer, UI_TIMER_TOKEN)?;
+            watch(epoll, signals, SIGNAL_TOKEN)?;
+            watch(epoll, playback_started, PLAYBACK_STARTED_TOKEN)?;
+            watch(epoll, playback_finished, PLAYBACK_FINISHED_TOKEN)?;
+
+            Ok(Poller {
+                epoll,
+                timer,
+                signals,
+                playback_started,
+                playback_finished,
+                buffer: [EpollEvent::default(); EPOLL_EVENT_BUFFER_SIZE],
+                next: 0,
+                filled: 0,
+            })
+        }
+    }
+
+    /// Block until something happens, or `timeout` passes, in which case
+    /// `None` is returned.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> Option<Readiness> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let remaining =
+                deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            let token = self.read(remaining)?;
+
+            // Timer, signal and user event file descriptors stay readable until
+            // drained. Draining also ensures that, like kqueue's EV_CLEAR,
+            // multiple triggers are coalesced into a single event. If there
+            // turns out to be nothing to drain, the wake up was spurious.
+            match token {
+                INPUT_TOKEN => return Some(Readiness::InputAvailable),
+                UI_TIMER_TOKEN if drain(self.timer, ep::COUNTER_SIZE) => {
+                    return Some(Readiness::UITimerFired)
+                }
+                SIGNAL_TOKEN if drain(self.signals, ep::SIGNALFD_SIGINFO_SIZE) => {
+                    return Some(Readiness::TerminalResized)
+                }
+                PLAYBACK_STARTED_TOKEN if drain(self.playback_started, ep::COUNTER_SIZE) => {
+                    return Some(Readiness::PlaybackStarted)
+                }
+                PLAYBACK_FINISHED_TOKEN if drain(self.playback_finished, ep::COUNTER_SIZE) => {
+                    return Some(Readiness::PlaybackFinished)
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    pub fn create_callback_notifier(&self) -> CallbackNotifier {
+        CallbackNotifier {
+            playback_started: self.playback_started,
+            playback_finished: self.playback_finished,
+        }
+    }
+
+    pub fn enable_timer(&mut self, usec: i64) -> io::Result<()> {
+        let period = Timespec {
+            tv_sec: usec / 1_000_000,
+            tv_nsec: (usec % 1_000_000) * 1000,
+        };
+        let spec = ITimerSpec {
+            it_interval: period,
+            it_value: period,
+        };
+        unsafe {
+            check(ep::timerfd_settime(self.timer, 0, &spec, ptr::null_mut()))?;
+        }
+        Ok(())
+    }
+
+    pub fn disable_timer(&mut self) -> io::Result<()> {
+        let spec = ITimerSpec::default();
+        unsafe {
+            check(ep::timerfd_settime(self.timer, 0, &spec, ptr::null_mut()))?;
+        }
+        Ok(())
+    }
+
+    pub fn close(self) -> io::Result<()> {
+        let descriptors = [
+            self.epoll,
+            self.timer,
+            self.signals,
+            self.playback_started,
+            self.playback_finished,
+        ];
+        for descriptor in descriptors {
+            unsafe {
+                check(unistd::close(descriptor))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the token of the next ready file descriptor, blocking until one is
+    /// available or `timeout` passes.
+    fn read(&mut self, timeout: Option<Duration>) -> Option<u64> {
+        // Round up, so as not to wake up just before the timeout is due
+        let timeout = match timeout {
+            Some(timeout) => timeout.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32,
+            None => -1, // Block indefinitely
+        };
+        unsafe {
+            while self.next == self.filled {
+                let result = ep::epoll_wait(
+                    self.epoll,
+                    self.buffer.as_mut_ptr(),
+                    self.buffer.len() as i32,
+                    timeout,
+                );
+
+                if result < 0 {
+                    let error = io::Error::last_os_error();
+                    if error.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    //TODO: Dont panic, expose error
+                    panic!("{error}");
+                }
+
+                if result == 0 {
+                    return None;
+                }
+
+                self.next = 0;
+                self.filled = result as usize;
+            }
+            let item = self.buffer[self.next];
+            self.next += 1;
+            Some(item.data)
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct CallbackNotifier {
+    playback_started: i32,
+    playback_finished: i32,
+}
+
+impl CallbackNotifier {
+    pub fn trigger_playback_started_event(&mut self) -> io::Result<()> {
+        increment(self.playback_started)
+    }
+
+    pub fn trigger_playback_finished_event(&mut self) -> io::Result<()> {
+        increment(self.playback_finished)
+    }
+}
+
+/// Register interest in `descriptor` becoming readable, tagging it with
+/// `token` so it can be identified when ready.
+fn watch(epoll: i32, descriptor: i32, token: u64) -> io::Result<()> {
+    let mut event = EpollEvent {
+        events: ep::EPOLLIN,
+        data: token,
+    };
+    unsafe {
+        check(ep::epoll_ctl(
+            epoll,
+            ep::EPOLL_CTL_ADD,
+            descriptor,
+            &mut event,
+        ))?;
+    }
+    Ok(())
+}
+
+/// Bump the counter of an eventfd, making it readable.
+fn increment(descriptor: i32) -> io::Result<()> {
+    let value = 1u64.to_ne_bytes();
+    unsafe {
+        let result = unistd::write(descriptor, value.as_ptr() as *const c_void, value.len());
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Read everything pending on a non blocking descriptor, `size` bytes at a
+/// time, returning true if anything was read.
+fn drain(descriptor: i32, size: usize) -> bool {
+    let mut buffer = [0u8; ep::SIGNALFD_SIGINFO_SIZE];
+    let mut drained = false;
+    loop {
+        let result = unsafe { unistd::read(descriptor, buffer.as_mut_ptr() as *mut c_void, size) };
+        if result <= 0 {
+            return drained;
+        }
+        drained = true;
+    }
+}
+
+fn check(result: i32) -> io::Result<i32> {
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::fd::AsRawFd;
+    use std::os::unix::net::UnixStream;
+    use std::time::Duration;
+
+    use super::*;
+
+    /// Microseconds between ticks, short enough to not hold up the tests.
+    const TICK: i64 = 1000;
+
+    /// Long enough for anything expected to have happened.
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn playback_notifications_are_coalesced_until_waited_for() {
+        let (_input, input_reader) = UnixStream::pair().unwrap();
+        let mut poller = Poller::new(input_reader.as_raw_fd()).unwrap();
+        let mut notifier = poller.create_callback_notifier();
+
+        notifier.trigger_playback_started_event().unwrap();
+        notifier.trigger_playback_started_event().unwrap();
+        assert!(matches!(
+            poller.wait(Some(TIMEOUT)),
+            Some(Readiness::PlaybackStarted)
+        ));
+
+        notifier.trigger_playback_finished_event().unwrap();
+        assert!(matches!(
+            poller.wait(Some(TIMEOUT)),
+            Some(Readiness::PlaybackFinished)
+        ));
+        poller.close().unwrap();
+    }
+
+    #[test]
+    fn input_is_reported_as_available() {
+        let (mut input, input_reader) = UnixStream::pair().unwrap();
+        let mut poller = Poller::new(input_reader.as_raw_fd()).unwrap();
+
+        std::io::Write::write_all(&mut input, b"q").unwrap();
+        assert!(matches!(
+            poller.wait(Some(TIMEOUT)),
+            Some(Readiness::InputAvailable)
+        ));
+        poller.close().unwrap();
+    }
+
+    #[test]
+    fn timer_ticks_until_disabled_and_can_be_rearmed() {
+        let (_input, input_reader) = UnixStream::pair().unwrap();
+        let mut poller = Poller::new(input_reader.as_raw_fd()).unwrap();
+
+        poller.enable_timer(TICK).unwrap();
+        assert!(matches!(
+            poller.wait(Some(TIMEOUT)),
+            Some(Readiness::UITimerFired)
+        ));
+
+        // Ticks that were due aren't delivered once disabled
+        poller.disable_timer().unwrap();
+        assert!(poller.wait(Some(Duration::from_millis(20))).is_none());
+
+        poller.enable_timer(TICK).unwrap();
+        assert!(matches!(
+            poller.wait(Some(TIMEOUT)),
+            Some(Readiness::UITimerFired)
+        ));
+        poller.close().unwrap();
+    }
+
+    #[test]
+    fn resizing_is_delivered_through_the_signalfd() {
+        let (_input, input_reader) = UnixStream::pair().unwrap();
+        // Blocks SIGWINCH on this thread, so raising it leaves it pending
+        let mut poller = Poller::new(input_reader.as_raw_fd()).unwrap();
+
+        unsafe {
+            check(ep::raise(ep::SIGWINCH)).unwrap();
+        }
+        assert!(matches!(
+            poller.wait(Some(TIMEOUT)),
+            Some(Readiness::TerminalResized)
+        ));
+        poller.close().unwrap();
+    }
+}
@@ -0,0 +1,547 @@
+//! Decoding of raw terminal input into key presses.
+//!
+//! Terminals send most keys as a single byte, but arrows, function keys and
+//! friends arrive as escape sequences, either CSI (`ESC [ ...`) or SS3
+//! (`ESC O ...`). Alt is signalled by prefixing a key with `ESC`, and Ctrl
+//! folds letters down into the C0 control codes.
+//!
+//! As the escape key on its own also sends `ESC`, a lone `ESC` can't be told
+//! apart from the start of a sequence until either more bytes arrive or enough
+//! time has passed that none are coming, see `InputDecoder::expire`.
+
+const ESC: u8 = 0x1b;
+
+/// A key on the keyboard, without any modifiers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Key {
+    Char(char),
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Insert,
+    Delete,
+    /// Function keys F1 to F12
+    F(u8),
+    Enter,
+    Tab,
+    BackTab,
+    Backspace,
+    Escape,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub alt: bool,
+    pub ctrl: bool,
+}
+
+impl Modifiers {
+    pub const NONE: Modifiers = Modifiers {
+        shift: false,
+        alt: false,
+        ctrl: false,
+    };
+
+    /// Decode the modifier parameter of a CSI sequence, which is one more than
+    /// a bitmask of shift (1), alt (2) and ctrl (4).
+    fn from_parameter(parameter: u16) -> Self {
+        let bits = parameter.saturating_sub(1);
+        Modifiers {
+            shift: bits & 1 != 0,
+            alt: bits & 2 != 0,
+            ctrl: bits & 4 != 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct KeyPress {
+    pub key: Key,
+    pub modifiers: Modifiers,
+}
+
+impl KeyPress {
+    pub fn new(key: Key, modifiers: Modifiers) -> Self {
+        KeyPress { key, modifiers }
+    }
+
+    pub fn plain(key: Key) -> Self {
+        KeyPress::new(key, Modifiers::NONE)
+    }
+
+    fn with_alt(mut self) -> Self {
+        self.modifiers.alt = true;
+        self
+    }
+}
+
+/// Outcome of trying to decode a key from the start of some input.
+#[derive(Debug, PartialEq)]
+enum Decoded {
+    /// A key press, made up of the given number of bytes
+    Key(KeyPress, usize),
+    /// A well formed sequence we don't understand, to be skipped over
+    Unknown(usize),
+    /// More input is needed to tell what the key is
+    Incomplete,
+}
+
+/// Turns a stream of bytes from the terminal into key presses.
+#[derive(Default)]
+pub struct InputDecoder {
+    pending: Vec<u8>,
+}
+
+impl InputDecoder {
+    pub fn new() -> Self {
+        InputDecoder::default()
+    }
+
+    /// Queue up more input to be decoded.
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.pending.extend_from_slice(bytes);
+    }
+
+    /// Take the next complete key press, if there is one.
+    pub fn next_key(&mut self) -> Option<KeyPress> {
+        loop {
+            if self.pending.is_empty() {
+                return None;
+            }
+            match decode(&self.pending) {
+                Decoded::Key(key, length) => {
+                    self.pending.drain(..length);
+                    return Some(key);
+                }
+                Decoded::Unknown(length) => {
+                    self.pending.drain(..length);
+                }
+                Decoded::Incomplete => return None,
+            }
+        }
+    }
+
+    /// Whether there is input left over that could be the start of a key.
+    pub fn is_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Give up waiting for the rest of a partial key.
+    ///
+    /// A dangling `ESC` is taken to be the escape key itself, with anything
+    /// after it decoded afresh. Any other partial input is a truncated UTF-8
+    /// character, which is replaced as a whole.
+    pub fn expire(&mut self) -> Option<KeyPress> {
+        let first = *self.pending.first()?;
+        self.pending.drain(..1);
+        if first == ESC {
+            return Some(KeyPress::plain(Key::Escape));
+        }
+        let continuation = self.pending.iter().take_while(|&&b| b & 0xc0 == 0x80);
+        self.pending.drain(..continuation.count());
+        Some(KeyPress::plain(Key::Char(char::REPLACEMENT_CHARACTER)))
+    }
+}
+
+fn decode(input: &[u8]) -> Decoded {
+    match input {
+        [] => Decoded::Incomplete,
+        [ESC] => Decoded::Incomplete,
+        [ESC, b'[', rest @ ..] => decode_csi(rest),
+        [ESC, b'O', rest @ ..] => decode_ss3(rest),
+        // Escape pressed twice, the first can't be starting a sequence
+        [ESC, ESC, ..] => Decoded::Key(KeyPress::plain(Key::Escape), 1),
+        [ESC, rest @ ..] => match decode_plain(rest) {
+            Decoded::Key(key, length) => Decoded::Key(key.with_alt(), length + 1),
+            other => other,
+        },
+        _ => decode_plain(input),
+    }
+}
+
+/// Decode a key sent as a single byte, or a UTF-8 character.
+fn decode_plain(input: &[u8]) -> Decoded {
+    let first = input[0];
+    let key = match first {
+        b'\r' | b'\n' => Key::Enter,
+        b'\t' => Key::Tab,
+        0x7f | 0x08 => Key::Backspace,
+        ESC => Key::Escape,
+        0x00 => return ctrl(' '),
+        0x01..=0x1a => return ctrl((b'a' + first - 1) as char),
+        0x1c..=0x1f => return ctrl((b'\\' + first - 0x1c) as char),
+        0x20..=0x7e => Key::Char(first as char),
+        _ => return decode_utf8(input),
+    };
+    Decoded::Key(KeyPress::plain(key), 1)
+}
+
+fn ctrl(c: char) -> Decoded {
+    let modifiers = Modifiers {
+        ctrl: true,
+        ..Modifiers::NONE
+    };
+    Decoded::Key(KeyPress::new(Key::Char(c), modifiers), 1)
+}
+
+fn decode_utf8(input: &[u8]) -> Decoded {
+    let length = match input[0] {
+        0xc2..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf4 => 4,
+        _ => 0,
+    };
+
+    let replacement = Decoded::Key(KeyPress::plain(Key::Char(char::REPLACEMENT_CHARACTER)), 1);
+    if length == 0 {
+        return replacement;
+    }
+
+    // Bail out as soon as a byte isn't a continuation, rather than waiting
+    // for the full length to arrive.
+    let available = input.len().min(length);
+    if input[1..available].iter().any(|b| b & 0xc0 != 0x80) {
+        return replacement;
+    }
+    if available < length {
+        return Decoded::Incomplete;
+    }
+
+    match std::str::from_utf8(&input[..length]) {
+        Ok(text) => {
+            let c = text.chars().next().expect("decoded at least one char");
+            Decoded::Key(KeyPress::plain(Key::Char(c)), length)
+        }
+        Err(_) => replacement,
+    }
+}
+
+/// Decode a control sequence, `input` being everything after `ESC [`.
+fn decode_csi(input: &[u8]) -> Decoded {
+    // Parameter bytes, then intermediate bytes, then a single final byte
+    let parameters_end = input
+        .iter()
+        .position(|b| !(0x30..=0x3f).contains(b))
+        .unwrap_or(input.len());
+    let final_index = input[parameters_end..]
+        .iter()
+        .position(|b| !(0x20..=0x2f).contains(b))
+        .map(|i| i + parameters_end);
+
+    let Some(final_index) = final_index else {
+        return Decoded::Incomplete;
+    };
+    // Prefix, plus everything up to and including the final byte
+    let length = 2 + final_index + 1;
+    let final_byte = input[final_index];
+
+    if !(0x40..=0x7e).contains(&final_byte) {
+        // Malformed, leave the offending byte to be decoded on its own
+        return Decoded::Unknown(length - 1);
+    }
+    if final_index != parameters_end {
+        return Decoded::Unknown(length);
+    }
+
+    let Some(parameters) = parse_parameters(&input[..parameters_end]) else {
+        return Decoded::Unknown(length);
+    };
+    let modifiers = match parameters.get(1) {
+        Some(&parameter) => Modifiers::from_parameter(parameter),
+        None => Modifiers::NONE,
+    };
+
+    let key = match (final_byte, parameters.first().copied().unwrap_or(1)) {
+        (b'~', number) => match number {
+            1 | 7 => Key::Home,
+            2 => Key::Insert,
+            3 => Key::Delete,
+            4 | 8 => Key::End,
+            5 => Key::PageUp,
+            6 => Key::PageDown,
+            11..=15 => Key::F((number - 10) as u8),
+            17..=21 => Key::F((number - 11) as u8),
+            23 | 24 => Key::F((number - 12) as u8),
+            _ => return Decoded::Unknown(length),
+        },
+        (b'Z', _) => Key::BackTab,
+        (final_byte, _) => match cursor_key(final_byte) {
+            Some(key) => key,
+            None => return Decoded::Unknown(length),
+        },
+    };
+    Decoded::Key(KeyPress::new(key, modifiers), length)
+}
+
+/// Decode a single shift sequence, `input` being everything after `ESC O`.
+fn decode_ss3(input: &[u8]) -> Decoded {
+    match input.first() {
+        None => Decoded::Incomplete,
+        Some(&b) => match cursor_key(b) {
+            Some(key) => Decoded::Key(KeyPress::plain(key), 3),
+            None => Decoded::Unknown(3),
+        },
+    }
+}
+
+/// Keys identified by the final byte of either a CSI or SS3 sequence.
+fn cursor_key(final_byte: u8) -> Option<Key> {
+    match final_byte {
+        b'A' => Some(Key::Up),
+        b'B' => Some(Key::Down),
+        b'C' => Some(Key::Right),
+        b'D' => Some(Key::Left),
+        b'H' => Some(Key::Home),
+        b'F' => Some(Key::End),
+        b'P'..=b'S' => Some(Key::F(final_byte - b'P' + 1)),
+        _ => None,
+    }
+}
+
+/// Parse semicolon separated numeric parameters, where empty means default.
+fn parse_parameters(bytes: &[u8]) -> Option<Vec<u16>> {
+    if bytes.is_empty() {
+        return Some(Vec::new());
+    }
+    bytes
+        .split(|&b| b == b';')
+        .map(|parameter| match parameter {
+            [] => Some(1),
+            digits => std::str::from_utf8(digits).ok()?.parse().ok(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(bytes: &[u8]) -> Vec<KeyPress> {
+        let mut decoder = InputDecoder::new();
+        decoder.push(bytes);
+        std::iter::from_fn(|| decoder.next_key()).collect()
+    }
+
+    fn keys(keys: &[Key]) -> Vec<KeyPress> {
+        keys.iter().copied().map(KeyPress::plain).collect()
+    }
+
+    fn with(key: Key, shift: bool, alt: bool, ctrl: bool) -> KeyPress {
+        KeyPress::new(key, Modifiers { shift, alt, ctrl })
+    }
+
+    #[test]
+    fn decodes_printable_ascii() {
+        assert_eq!(
+            decode_all(b"n ]"),
+            keys(&[Key::Char('n'), Key::Char(' '), Key::Char(']')])
+        );
+    }
+
+    #[test]
+    fn decodes_control_keys() {
+        assert_eq!(
+            decode_all(b"\r\t\x7f"),
+            keys(&[Key::Enter, Key::Tab, Key::Backspace])
+        );
+        assert_eq!(
+            decode_all(b"\x01\x1a\x00"),
+            vec![
+                with(Key::Char('a'), false, false, true),
+                with(Key::Char('z'), false, false, true),
+                with(Key::Char(' '), false, false, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_arrows_in_both_csi_and_ss3_form() {
+        let expected = keys(&[Key::Up, Key::Down, Key::Right, Key::Left]);
+        assert_eq!(decode_all(b"\x1b[A\x1b[B\x1b[C\x1b[D"), expected);
+        assert_eq!(decode_all(b"\x1bOA\x1bOB\x1bOC\x1bOD"), expected);
+    }
+
+    #[test]
+    fn decodes_navigation_keys() {
+        assert_eq!(
+            decode_all(b"\x1b[H\x1b[F\x1b[1~\x1b[4~\x1bOH\x1bOF"),
+            keys(&[
+                Key::Home,
+                Key::End,
+                Key::Home,
+                Key::End,
+                Key::Home,
+                Key::End
+            ])
+        );
+        assert_eq!(
+            decode_all(b"\x1b[5~\x1b[6~\x1b[2~\x1b[3~"),
+            keys(&[Key::PageUp, Key::PageDown, Key::Insert, Key::Delete])
+        );
+    }
+
+    #[test]
+    fn decodes_function_keys() {
+        let input = b"\x1bOP\x1bOQ\x1bOR\x1bOS\x1b[15~\x1b[17~\x1b[18~\x1b[19~\
+            \x1b[20~\x1b[21~\x1b[23~\x1b[24~";
+        let expected: Vec<Key> = (1..=12).map(Key::F).collect();
+        assert_eq!(decode_all(input), keys(&expected));
+
+        // Linux console and older xterms send F1 to F5 differently
+        assert_eq!(
+            decode_all(b"\x1b[11~\x1b[12~\x1b[13~\x1b[14~"),
+            keys(&[Key::F(1), Key::F(2), Key::F(3), Key::F(4)])
+        );
+    }
+
+    #[test]
+    fn decodes_modifier_parameters() {
+        assert_eq!(
+            decode_all(b"\x1b[1;2A\x1b[1;3D\x1b[1;5C\x1b[1;8B"),
+            vec![
+                with(Key::Up, true, false, false),
+                with(Key::Left, false, true, false),
+                with(Key::Right, false, false, true),
+                with(Key::Down, true, true, true),
+            ]
+        );
+        assert_eq!(
+            decode_all(b"\x1b[5;5~\x1b[1;2P\x1b[15;3~"),
+            vec![
+                with(Key::PageUp, false, false, true),
+                with(Key::F(1), true, false, false),
+                with(Key::F(5), false, true, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_back_tab() {
+        assert_eq!(decode_all(b"\x1b[Z"), keys(&[Key::BackTab]));
+    }
+
+    #[test]
+    fn escape_prefix_means_alt() {
+        assert_eq!(
+            decode_all(b"\x1bn\x1b\x01\x1b\xc3\xa9"),
+            vec![
+                with(Key::Char('n'), false, true, false),
+                with(Key::Char('a'), false, true, true),
+                with(Key::Char('é'), false, true, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_utf8_characters() {
+        assert_eq!(
+            decode_all("é€🎵".as_bytes()),
+            keys(&[Key::Char('é'), Key::Char('€'), Key::Char('🎵')])
+        );
+    }
+
+    #[test]
+    fn replaces_invalid_utf8() {
+        assert_eq!(
+            decode_all(b"\xff\xc3n"),
+            keys(&[
+                Key::Char(char::REPLACEMENT_CHARACTER),
+                Key::Char(char::REPLACEMENT_CHARACTER),
+                Key::Char('n'),
+            ])
+        );
+    }
+
+    #[test]
+    fn skips_unknown_sequences() {
+        assert_eq!(
+            decode_all(b"\x1b[99~\x1b[?25h\x1b[ q\x1bOxn"),
+            keys(&[Key::Char('n')])
+        );
+    }
+
+    #[test]
+    fn abandons_malformed_sequences() {
+        assert_eq!(
+            decode_all(b"\x1b[1\rn"),
+            keys(&[Key::Enter, Key::Char('n')])
+        );
+    }
+
+    #[test]
+    fn waits_for_sequences_split_across_reads() {
+        let mut decoder = InputDecoder::new();
+        for &byte in b"\x1b[1;5" {
+            decoder.push(&[byte]);
+            assert_eq!(decoder.next_key(), None);
+            assert!(decoder.is_pending());
+        }
+        decoder.push(b"A");
+        assert_eq!(decoder.next_key(), Some(with(Key::Up, false, false, true)));
+        assert!(!decoder.is_pending());
+
+        decoder.push(&"€".as_bytes()[..2]);
+        assert_eq!(decoder.next_key(), None);
+        decoder.push(&"€".as_bytes()[2..]);
+        assert_eq!(decoder.next_key(), Some(KeyPress::plain(Key::Char('€'))));
+    }
+
+    #[test]
+    fn lone_escape_is_only_reported_once_expired() {
+        let mut decoder = InputDecoder::new();
+        decoder.push(b"\x1b");
+        assert_eq!(decoder.next_key(), None);
+        assert_eq!(decoder.expire(), Some(KeyPress::plain(Key::Escape)));
+        assert!(!decoder.is_pending());
+        assert_eq!(decoder.expire(), None);
+    }
+
+    #[test]
+    fn expiring_a_partial_sequence_keeps_what_follows_the_escape() {
+        let mut decoder = InputDecoder::new();
+        decoder.push(b"\x1b[");
+        assert_eq!(decoder.next_key(), None);
+        assert_eq!(decoder.expire(), Some(KeyPress::plain(Key::Escape)));
+        assert_eq!(decoder.next_key(), Some(KeyPress::plain(Key::Char('['))));
+    }
+
+    #[test]
+    fn double_escape_reports_first_immediately() {
+        let mut decoder = InputDecoder::new();
+        decoder.push(b"\x1b\x1b");
+        assert_eq!(decoder.next_key(), Some(KeyPress::plain(Key::Escape)));
+        assert_eq!(decoder.next_key(), None);
+        assert_eq!(decoder.expire(), Some(KeyPress::plain(Key::Escape)));
+    }
+
+    #[test]
+    fn expiring_truncated_utf8_replaces_it() {
+        let mut decoder = InputDecoder::new();
+        decoder.push(b"\xe2\x82");
+        assert_eq!(decoder.next_key(), None);
+        assert_eq!(
+            decoder.expire(),
+            Some(KeyPress::plain(Key::Char(char::REPLACEMENT_CHARACTER)))
+        );
+        // Only the one replacement for the whole character
+        assert_eq!(decoder.next_key(), None);
+        assert!(!decoder.is_pending());
+
+        // Even when it follows an escape
+        decoder.push(b"\x1b\xf0\x9f\x8e");
+        assert_eq!(decoder.next_key(), None);
+        assert_eq!(decoder.expire(), Some(KeyPress::plain(Key::Escape)));
+        assert_eq!(decoder.next_key(), None);
+        assert_eq!(
+            decoder.expire(),
+            Some(KeyPress::plain(Key::Char(char::REPLACEMENT_CHARACTER)))
+        );
+        assert!(!decoder.is_pending());
+    }
+}
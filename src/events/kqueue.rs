@@ -0,0 +1,285 @@
+//! Poller implementation backed by kqueue.
+
+use std::io;
+use std::ptr;
+use std::time::{Duration, Instant};
+
+use super::Readiness;
+use crate::ffi::kqueue::{self as kq, kevent, kqueue, Kevent, Kqueue, Timespec};
+use crate::ffi::unistd;
+
+const AUDIO_QUEUE_PLAYBACK_STARTED: u64 = 39;
+const AUDIO_QUEUE_PLAYBACK_FINISHED: u64 = 40;
+const UI_TIMER_TICK: u64 = 41;
+
+const KEVENT_BUFFER_SIZE: usize = 10;
+
+pub struct Poller {
+    queue: Kqueue,
+    input: u64,
+    queue_reader: KQueueReader,
+}
+
+impl Poller {
+    //TODO: Refactor, think about abstractions that might make it a little easier
+    // to follow
+    /// Create a poller watching `input` for key presses.
+    pub fn new(input: i32) -> io::Result<Self> {
+        unsafe {
+            // Create a new Kqueue
+            let kqueue = kqueue();
+            if kqueue < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            // Describe the events we are interested in...
+
+            // New input available, normally on stdin
+            // TODO: See if EV_ENABLE is actually needed?
+            let input_event = Kevent {
+                ident: input as u64,
+                filter: kq::EVFILT_READ,
+                flags: kq::EV_ADD | kq::EV_ENABLE,
+                fflags: 0,
+                data: 0,
+                udata: 0,
+            };
+
+            // TODO: Maybe using a unique ident per file along with a EV_ONESHOT would be
+            // easier? i.e using udata to signal the audio queue thats stopped
+
+            let playback_started_event = Kevent {
+                ident: AUDIO_QUEUE_PLAYBACK_STARTED,
+                filter: kq::EVFILT_USER,
+                flags: kq::EV_ADD | kq::EV_CLEAR,
+                fflags: 0,
+                data: 0,
+                udata: 0,
+            };
+
+            // End of audio queue playback
+            let playback_finished_event = Kevent {
+                ident: AUDIO_QUEUE_PLAYBACK_FINISHED,
+                filter: kq::EVFILT_USER,
+                flags: kq::EV_ADD | kq::EV_CLEAR,
+                fflags: 0,
+                data: 0,
+                udata: 0,
+            };
+
+            // Terminal resizing
+            let terminal_resized_event = Kevent {
+                ident: kq::SIGWINCH,
+                filter: kq::EVFILT_SIGNAL,
+                flags: kq::EV_ADD,
+                fflags: 0,
+                data: 0,
+                udata: 0,
+            };
+
+            // Register interest in all events
+            let changelist = [
+                input_event,
+                terminal_resized_event,
+                playback_started_event,
+                playback_finished_event,
+            ];
+
+            let result = kevent(
+                kqueue,
+                changelist.as_ptr(),
+                changelist.len() as i32,
+                ptr::null_mut(),
+                0,
+                ptr::null(),
+            );
+
+            if result < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(Poller {
+                queue: kqueue,
+                input: input as u64,
+                queue_reader: KQueueReader::new(kqueue),
+            })
+        }
+    }
+
+    /// Block until something happens, or `timeout` passes, in which case
+    /// `None` is returned.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> Option<Readiness> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let remaining =
+                deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            let queue_event = self.queue_reader.read(remaining)?;
+
+            match (queue_event.ident, queue_event.filter) {
+                (ident, kq::EVFILT_READ) if ident == self.input => {
+                    return Some(Readiness::InputAvailable)
+                }
+                (AUDIO_QUEUE_PLAYBACK_STARTED, kq::EVFILT_USER) => {
+                    return Some(Readiness::PlaybackStarted)
+                }
+                (AUDIO_QUEUE_PLAYBACK_FINISHED, kq::EVFILT_USER) => {
+                    return Some(Readiness::PlaybackFinished)
+                }
+                (UI_TIMER_TICK, kq::EVFILT_TIMER) => return Some(Readiness::UITimerFired),
+                (kq::SIGWINCH, kq::EVFILT_SIGNAL) => return Some(Readiness::TerminalResized),
+                _ => continue,
+            }
+        }
+    }
+
+    pub fn create_callback_notifier(&self) -> CallbackNotifier {
+        CallbackNotifier { queue: self.queue }
+    }
+
+    pub fn enable_timer(&mut self, usec: i64) -> io::Result<()> {
+        let ui_timer_event = Kevent {
+            ident: UI_TIMER_TICK,
+            filter: kq::EVFILT_TIMER,
+            flags: kq::EV_ADD | kq::EV_ENABLE,
+            fflags: kq::NOTE_USECONDS,
+            data: usec,
+            udata: 0,
+        };
+
+        register(self.queue, ui_timer_event)
+    }
+
+    pub fn disable_timer(&mut self) -> io::Result<()> {
+        let ui_timer_event = Kevent {
+            ident: UI_TIMER_TICK,
+            filter: kq::EVFILT_TIMER,
+            flags: kq::EV_DELETE,
+            fflags: 0,
+            data: 0,
+            udata: 0,
+        };
+
+        register(self.queue, ui_timer_event)
+    }
+
+    pub fn close(self) -> io::Result<()> {
+        unsafe {
+            let result = unistd::close(self.queue);
+            if result < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct CallbackNotifier {
+    queue: Kqueue,
+}
+
+impl CallbackNotifier {
+    pub fn trigger_playback_started_event(&mut self) -> io::Result<()> {
+        let playback_started_event = Kevent {
+            ident: AUDIO_QUEUE_PLAYBACK_STARTED,
+            filter: kq::EVFILT_USER,
+            flags: 0,
+            fflags: kq::NOTE_TRIGGER,
+            data: 0,
+            udata: 0,
+        };
+
+        register(self.queue, playback_started_event)
+    }
+
+    pub fn trigger_playback_finished_event(&mut self) -> io::Result<()> {
+        let playback_finished_event = Kevent {
+            ident: AUDIO_QUEUE_PLAYBACK_FINISHED,
+            filter: kq::EVFILT_USER,
+            flags: 0,
+            fflags: kq::NOTE_TRIGGER,
+            data: 0,
+            udata: 0,
+        };
+
+        register(self.queue, playback_finished_event)
+    }
+}
+
+/// Apply a single change to the kqueue.
+fn register(queue: Kqueue, change: Kevent) -> io::Result<()> {
+    unsafe {
+        let changelist = [change];
+
+        let result = kevent(
+            queue,
+            changelist.as_ptr(),
+            changelist.len() as i32,
+            ptr::null_mut(),
+            0,
+            ptr::null(),
+        );
+
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+//TODO: Would this be more ideomatic if we implemented the buf reader trait?
+struct KQueueReader {
+    buffer: [Kevent; KEVENT_BUFFER_SIZE],
+    kqueue: Kqueue,
+    next: usize,
+    filled: usize,
+}
+
+impl KQueueReader {
+    fn new(kqueue: Kqueue) -> Self {
+        KQueueReader {
+            kqueue,
+            buffer: [Kevent::default(); KEVENT_BUFFER_SIZE],
+            next: 0,
+            filled: 0,
+        }
+    }
+
+    /// Read the next event, blocking until one is available or `timeout`
+    /// passes.
+    fn read(&mut self, timeout: Option<Duration>) -> Option<Kevent> {
+        let timeout = timeout.map(|timeout| Timespec {
+            tv_sec: timeout.as_secs() as i64,
+            tv_nsec: timeout.subsec_nanos() as i64,
+        });
+        unsafe {
+            if self.next == self.filled {
+                let result = kevent(
+                    self.kqueue,
+                    ptr::null(),
+                    0,
+                    self.buffer.as_mut_ptr(),
+                    self.buffer.len() as i32,
+                    // Null blocks indefinitely
+                    timeout.as_ref().map_or(ptr::null(), |timeout| timeout),
+                );
+
+                if result < 0 {
+                    //TODO: Dont panic, expose error
+                    panic!("{}", io::Error::last_os_error());
+                }
+
+                if result == 0 {
+                    return None;
+                }
+
+                self.next = 0;
+                self.filled = result as usize;
+            }
+            let item = self.buffer[self.next];
+            self.next += 1;
+            Some(item)
+        }
+    }
+}
@@ -0,0 +1,147 @@
+//! Event source for tests, which follows a script of key presses and waits.
+//!
+//! Key presses are written down a socket that the underlying `EventQueue` reads
+//! from, so they are decoded and looked up in the keymap as they would be when
+//! typed. Playback events still arrive through the queue's poller. Rather than
+//! using a real timer, UI ticks are produced by waits in the script, each
+//! moving a `VirtualClock` on by one tick. The next step of the script is only
+//! taken once there is nothing else to report, which keeps runs repeatable.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use super::{build_event_queue, CallbackNotifier, Event, EventQueue, EventSource, Readiness};
+use crate::keymap::Keymap;
+use crate::output::scripted::VirtualClock;
+
+/// Something for a script to do.
+#[derive(Debug, Clone, Copy)]
+pub enum Step {
+    /// Type some input, which may include escape sequences
+    Press(&'static str),
+    /// Let time pass
+    Wait(Duration),
+    /// Delete a file, as if something else had while playing
+    Remove(&'static str),
+}
+
+pub struct ScriptedEvents {
+    /// Only taken when closing the queue
+    queue: Option<EventQueue>,
+    input: UnixStream,
+    // Kept open for the queue to read from
+    _input_reader: UnixStream,
+    script: VecDeque<Step>,
+    clock: VirtualClock,
+    tick: Option<Duration>,
+}
+
+impl ScriptedEvents {
+    pub fn new(
+        keymap: Keymap,
+        clock: VirtualClock,
+        script: impl IntoIterator<Item = Step>,
+    ) -> io::Result<Self> {
+        let (input, input_reader) = UnixStream::pair()?;
+        let queue = build_event_queue(keymap, input_reader.as_raw_fd())?;
+        Ok(ScriptedEvents {
+            queue: Some(queue),
+            input,
+            _input_reader: input_reader,
+            script: script.into_iter().collect(),
+            clock,
+            tick: None,
+        })
+    }
+
+    fn queue(&self) -> &EventQueue {
+        self.queue.as_ref().expect("event queue closed")
+    }
+
+    /// Take whatever event the queue has ready, without waiting.
+    fn poll(&mut self) -> Option<Event> {
+        let queue = self.queue.as_mut().expect("event queue closed");
+        loop {
+            if let Some(key) = queue.input_reader.read() {
+                return Some(queue.key_event(key));
+            }
+            match queue.poller.wait(Some(Duration::ZERO))? {
+                Readiness::InputAvailable => queue.input_reader.fill_buffer(),
+                Readiness::PlaybackStarted => return Some(Event::PlaybackStarted),
+                Readiness::PlaybackFinished => return Some(Event::PlaybackFinished),
+                // The real timer is never enabled, and nothing resizes
+                Readiness::UITimerFired | Readiness::TerminalResized => {}
+            }
+        }
+    }
+}
+
+impl EventSource for ScriptedEvents {
+    fn next_event(&mut self) -> Event {
+        loop {
+            if let Some(event) = self.poll() {
+                return event;
+            }
+
+            // Nothing else is happening, so carry on with the script
+            match self.script.pop_front() {
+                Some(Step::Press(input)) => {
+                    self.input
+                        .write_all(input.as_bytes())
+                        .expect("failed to write scripted input");
+                }
+                Some(Step::Remove(path)) => {
+                    fs::remove_file(path).expect("failed to remove scripted file");
+                }
+                Some(Step::Wait(duration)) => {
+                    let Some(tick) = self.tick else {
+                        self.clock.advance(duration.as_secs_f64());
+                        continue;
+                    };
+                    let step = duration.min(tick);
+                    self.clock.advance(step.as_secs_f64());
+                    if duration > step {
+                        self.script.push_front(Step::Wait(duration - step));
+                    }
+                    return Event::UITick;
+                }
+                None => panic!("ran out of script while waiting for an event"),
+            }
+        }
+    }
+
+    fn keymap(&self) -> &Keymap {
+        self.queue().keymap()
+    }
+
+    fn create_callback_notifier(&self) -> CallbackNotifier {
+        self.queue().create_callback_notifier()
+    }
+
+    fn set_text_entry(&mut self, enabled: bool) {
+        let queue = self.queue.as_mut().expect("event queue closed");
+        queue.set_text_entry(enabled);
+    }
+
+    fn enable_ui_timer_event(&mut self, usec: i64) -> io::Result<()> {
+        self.tick = Some(Duration::from_micros(usec as u64));
+        Ok(())
+    }
+
+    fn disable_ui_timer_event(&mut self) -> io::Result<()> {
+        self.tick = None;
+        Ok(())
+    }
+}
+
+impl Drop for ScriptedEvents {
+    fn drop(&mut self) {
+        if let Some(queue) = self.queue.take() {
+            queue.close().expect("failed to close event queue");
+        }
+    }
+}
@@ -33,6 +33,9 @@ pub type AudioFileTypeID = u32;
 /// Constant value identifying an audio file property.
 pub type AudioFilePropertyID = u32;
 
+/// Constant value identifying an audio converter property.
+pub type AudioConverterPropertyID = u32;
+
 /// Constant value identifying an audio queue property.
 pub type AudioQueuePropertyID = u32;
 
@@ -92,9 +95,36 @@ pub const AUDIO_FILE_PROPERTY_PACKET_SIZE_UPPER_BOUND: AudioFilePropertyID = u4c
 /// The value of this property is represented by an f64.
 pub const AUDIO_FILE_PROPERTY_ESTIMATED_DURATION: AudioFilePropertyID = u4cc!(*b"edur");
 
+/// Constant used to find which frames of an audio file are actual audio.
+///
+/// Using this constant with `audio_file_get_property` will return how many
+/// frames of encoder delay come before the audio, and of padding after it.
+/// Formats that don't record this will return an unsupported property error.
+///
+/// The value of this property is represented by an AudioFilePacketTableInfo.
+pub const AUDIO_FILE_PROPERTY_PACKET_TABLE_INFO: AudioFilePropertyID = u4cc!(*b"pnfo");
+
+/// Constant used to find the packet containing a particular frame.
+///
+/// Using this constant with `audio_file_get_property`, passing in an
+/// `AudioFramePacketTranslation` with the `frame` field set, will fill in the
+/// packet and offset of the frame within that packet. This is only necessary
+/// for formats where the number of frames in each packet varies.
+///
+/// The value of this property is represented by an AudioFramePacketTranslation.
+pub const AUDIO_FILE_PROPERTY_FRAME_TO_PACKET: AudioFilePropertyID = u4cc!(*b"frpk");
+
 /// Error returned when trying to access an unsupported audio file property
 pub const AUDIO_FILE_ERROR_UNSUPPORTED_PROPERTY: OSStatus = i4cc!(*b"pty?");
 
+/// Constant used to supply a decoder with a formats cookie data.
+///
+/// If the format being decoded has a magic cookie, then this property must be
+/// set on the audio converter before converting any audio.
+///
+/// The value of this property is represented by a pointer.
+pub const AUDIO_CONVERTER_DECOMPRESSION_MAGIC_COOKIE: AudioConverterPropertyID = u4cc!(*b"dmgc");
+
 /// Constant used to interact with an audio queues cookie data.
 ///
 /// If an audio format requires a magic cookie, then this property must be set
@@ -207,7 +237,7 @@ pub struct AudioStreamBasicDescription {
     /// Number of bits of sample data for each channel.
     pub bits_per_channel: u32,
     /// Pads out the structure to force an even 8 byte alignment
-    reserved: u32,
+    pub reserved: u32,
 }
 
 /// Supplementary information used to describe variable sized audio packets.
@@ -231,6 +261,103 @@ pub struct AudioStreamPacketDescription {
     pub data_byte_size: u32,
 }
 
+/// Used to convert between a frame position and the packet containing it.
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct AudioFramePacketTranslation {
+    /// Position of the frame from the start of the audio.
+    pub frame: i64,
+    /// Index of the packet containing the frame.
+    pub packet: i64,
+    /// The number of frames between the start of the packet and the frame.
+    pub frame_offset_in_packet: u32,
+}
+
+/// Describes the frames of an audio file that are actual audio, rather than
+/// priming frames added by the encoder at the start, or remainder frames
+/// added to pad out the last packet.
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct AudioFilePacketTableInfo {
+    /// The number of frames of audio, excluding priming and remainder frames.
+    pub number_valid_frames: i64,
+    /// The number of frames of encoder delay before the audio starts.
+    pub priming_frames: i32,
+    /// The number of frames of padding after the audio ends.
+    pub remainder_frames: i32,
+}
+
+/// A change to an audio queue parameter, made as the buffer it is enqueued
+/// with starts playing.
+#[derive(Debug)]
+#[repr(C)]
+pub struct AudioQueueParameterEvent {
+    /// The parameter to change.
+    pub id: AudioQueueParameterID,
+    /// The value to change it to.
+    pub value: AudioQueueParameterValue,
+}
+
+/// A buffer of audio data, along with how many interleaved channels it holds.
+#[derive(Debug)]
+#[repr(C)]
+pub struct AudioBuffer {
+    /// The number of interleaved channels in the buffer.
+    pub number_channels: u32,
+    /// The number of bytes in the buffer pointed to by `data`.
+    pub data_byte_size: u32,
+    /// Pointer to the audio data.
+    pub data: *mut c_void,
+}
+
+/// A list of audio buffers, one for each channel of non interleaved audio or
+/// just the one for interleaved audio.
+///
+/// The list is declared with space for a single buffer, which is all that is
+/// needed for interleaved audio.
+#[derive(Debug)]
+#[repr(C)]
+pub struct AudioBufferList {
+    /// The number of buffers in the list.
+    pub number_buffers: u32,
+    /// The buffers themselves.
+    pub buffers: [AudioBuffer; 1],
+}
+
+/// A reference to an opaque type representing an audio converter object.
+///
+/// An audio converter converts audio from one format to another, such as
+/// decoding compressed audio to linear PCM.
+pub type AudioConverterRef = *mut OpaqueAudioConverter;
+
+/// Callback to supply an audio converter with audio to convert.
+///
+/// This type defines a callback function that is called each time an audio
+/// converter, specified by `in_audio_converter`, needs more audio during a
+/// call to `audio_converter_fill_complex_buffer`.
+///
+/// On entry, `io_number_data_packets` holds the number of packets the
+/// converter would like. The callback should point the buffers of `io_data`
+/// at the packets it has, and set `io_number_data_packets` to how many
+/// packets that is. For formats that need packet descriptions, the callback
+/// should also point `out_data_packet_description` at a description of each.
+/// The data must remain valid until the callback is next called.
+///
+/// Supplying no packets and returning 0 indicates there is no more audio to
+/// come. Returning any other status instead stops the conversion, with that
+/// status passed back to the caller of `audio_converter_fill_complex_buffer`
+/// along with whatever was converted so far.
+///
+/// Custom data can be passed to the callback via the `in_user_data`
+/// parameter.
+pub type AudioConverterComplexInputDataProc = unsafe extern "C" fn(
+    in_audio_converter: AudioConverterRef,
+    io_number_data_packets: *mut u32,
+    io_data: *mut AudioBufferList,
+    out_data_packet_description: *mut *mut AudioStreamPacketDescription,
+    in_user_data: *mut c_void,
+) -> OSStatus;
+
 /// A reference to an audio queue buffer.
 pub type AudioQueueBufferRef = *mut AudioQueueBuffer;
 
@@ -384,6 +511,9 @@ pub type AudioQueueTimelineRef = *const OpaqueAudioQueueTimeline;
 /// An opaque data type that represents an audio file.
 pub enum OpaqueAudioFileID {}
 
+/// An opaque data type that represents an audio converter.
+pub enum OpaqueAudioConverter {}
+
 /// An opaque data type that represents an audio queue.
 pub enum OpaqueAudioQueue {}
 
@@ -506,6 +636,65 @@ extern "C" {
         out_buffer: *mut c_void,
     ) -> OSStatus;
 
+    /// Create a new audio converter.
+    ///
+    /// Creates a converter from the format described by `in_source_format` to
+    /// that described by `in_destination_format`. Upon success, the reference
+    /// pointed to by `out_audio_converter` will be set to the new converter.
+    ///
+    /// Returns an error if there is no way of converting between the formats.
+    #[link_name = "AudioConverterNew"]
+    pub fn audio_converter_new(
+        in_source_format: *const AudioStreamBasicDescription,
+        in_destination_format: *const AudioStreamBasicDescription,
+        out_audio_converter: *mut AudioConverterRef,
+    ) -> OSStatus;
+
+    /// Dispose of an audio converter, along with its resources.
+    #[link_name = "AudioConverterDispose"]
+    pub fn audio_converter_dispose(in_audio_converter: AudioConverterRef) -> OSStatus;
+
+    /// Set a property of an audio converter.
+    ///
+    /// For the audio converter specified by `in_audio_converter`, set the
+    /// `in_property_id` property. The property and its size are supplied by
+    /// the `in_property_data` and `in_property_data_size` parameters
+    /// respectively.
+    ///
+    /// Returns an error if unsuccessful.
+    #[link_name = "AudioConverterSetProperty"]
+    pub fn audio_converter_set_property(
+        in_audio_converter: AudioConverterRef,
+        in_property_id: AudioConverterPropertyID,
+        in_property_data_size: u32,
+        in_property_data: *const c_void,
+    ) -> OSStatus;
+
+    /// Convert audio supplied by a callback, for formats of any kind.
+    ///
+    /// The converter `in_audio_converter` calls `in_input_data_proc` as many
+    /// times as it needs to for audio to convert, passing it
+    /// `in_input_data_proc_user_data`.
+    ///
+    /// On calling this function, `io_output_data_packet_size` should hold the
+    /// number of packets that the buffers of `out_output_data` have room for.
+    /// On return, it will hold the number of packets actually converted. For
+    /// linear PCM output, a packet is a single frame. If the output format
+    /// needs packet descriptions, `out_packet_description` should point to
+    /// room for one per packet, otherwise it can be null.
+    ///
+    /// Returns an error if unsuccessful, or whatever status the callback
+    /// stopped the conversion with.
+    #[link_name = "AudioConverterFillComplexBuffer"]
+    pub fn audio_converter_fill_complex_buffer(
+        in_audio_converter: AudioConverterRef,
+        in_input_data_proc: AudioConverterComplexInputDataProc,
+        in_input_data_proc_user_data: *mut c_void,
+        io_output_data_packet_size: *mut u32,
+        out_output_data: *mut AudioBufferList,
+        out_packet_description: *mut AudioStreamPacketDescription,
+    ) -> OSStatus;
+
     /// Create a new audio queue for playback.
     ///
     /// The `in_format` parameter describes the format of the audio data to be
@@ -604,6 +793,33 @@ extern "C" {
         in_packet_descs: *const AudioStreamPacketDescription,
     ) -> OSStatus;
 
+    /// Add a buffer to the buffer queue of an audio queue, with options.
+    ///
+    /// Behaves as `audio_queue_enqueue_buffer`, while also allowing
+    /// `in_trim_frames_at_start` and `in_trim_frames_at_end` to be skipped
+    /// over when playing back the buffer, e.g to leave out encoder delay.
+    ///
+    /// Parameter changes can be scheduled to take effect along with the
+    /// buffer via `in_param_values`, along with a time to play the buffer at
+    /// via `in_start_time`. Pass 0 and null to do neither. If
+    /// `out_actual_start_time` is not null, it will be filled in with the
+    /// time the buffer will actually start playing at.
+    ///
+    /// Returns an error code on failure.
+    #[link_name = "AudioQueueEnqueueBufferWithParameters"]
+    pub fn audio_queue_enqueue_buffer_with_parameters(
+        in_aq: AudioQueueRef,
+        in_buffer: AudioQueueBufferRef,
+        in_number_packet_descriptions: u32,
+        in_packet_descs: *const AudioStreamPacketDescription,
+        in_trim_frames_at_start: u32,
+        in_trim_frames_at_end: u32,
+        in_number_param_values: u32,
+        in_param_values: *const AudioQueueParameterEvent,
+        in_start_time: *const AudioTimeStamp,
+        out_actual_start_time: *mut AudioTimeStamp,
+    ) -> OSStatus;
+
     /// Begin playing or recording audio data.
     ///
     /// Start the audio queue `in_aq`.
@@ -640,6 +856,16 @@ extern "C" {
     #[link_name = "AudioQueueStop"]
     pub fn audio_queue_stop(in_aq: AudioQueueRef, in_immediate: bool) -> OSStatus;
 
+    /// Reset an audio queue.
+    ///
+    /// Immediately discards any buffers enqueued on `in_aq`, returning them via
+    /// the queue's callback, and resets any decoder state. Unlike stopping, the
+    /// queue is left running.
+    ///
+    /// Returns an error on failure.
+    #[link_name = "AudioQueueReset"]
+    pub fn audio_queue_reset(in_aq: AudioQueueRef) -> OSStatus;
+
     /// Dispose of an audio queue.
     ///
     /// Dispose of the audio queue `in_aq` and all of its associated resources,
@@ -0,0 +1,119 @@
+//! Selected FFI bindings to epoll and the Linux specific file descriptors used
+//! alongside it (timerfd, signalfd and eventfd).
+
+use std::ffi::c_int;
+
+#[link(name = "c")]
+extern "C" {
+
+    /// Create a new epoll instance, returning a file descriptor referring to
+    /// it.
+    pub fn epoll_create1(flags: c_int) -> c_int;
+
+    /// Add, modify or remove the file descriptor `fd` from the interest list
+    /// of the epoll instance `epfd`.
+    pub fn epoll_ctl(epfd: c_int, op: c_int, fd: c_int, event: *mut EpollEvent) -> c_int;
+
+    /// Wait for events on the epoll instance `epfd`, writing up to
+    /// `maxevents` events to `events`. A `timeout` of -1 blocks indefinitely.
+    pub fn epoll_wait(
+        epfd: c_int,
+        events: *mut EpollEvent,
+        maxevents: c_int,
+        timeout: c_int,
+    ) -> c_int;
+
+    /// Create a timer that delivers expirations via a file descriptor.
+    pub fn timerfd_create(clockid: c_int, flags: c_int) -> c_int;
+
+    /// Arm or disarm the timer referred to by `fd`.
+    ///
+    /// A zeroed `new_value` disarms the timer.
+    pub fn timerfd_settime(
+        fd: c_int,
+        flags: c_int,
+        new_value: *const ITimerSpec,
+        old_value: *mut ITimerSpec,
+    ) -> c_int;
+
+    /// Create a file descriptor that accepts the signals in `mask`.
+    ///
+    /// Signals should first be blocked via `sigprocmask`, to prevent them
+    /// being handled in the default manner.
+    pub fn signalfd(fd: c_int, mask: *const SigSet, flags: c_int) -> c_int;
+
+    /// Create a file descriptor that can be used as an event wait / notify
+    /// mechanism, backed by a 64 bit counter.
+    pub fn eventfd(initval: u32, flags: c_int) -> c_int;
+
+    /// Initialise `set` to contain no signals.
+    pub fn sigemptyset(set: *mut SigSet) -> c_int;
+
+    /// Add `signum` to `set`.
+    pub fn sigaddset(set: *mut SigSet, signum: c_int) -> c_int;
+
+    /// Examine and change the set of blocked signals for the calling thread.
+    ///
+    /// Threads spawned afterwards inherit the signal mask.
+    pub fn sigprocmask(how: c_int, set: *const SigSet, oldset: *mut SigSet) -> c_int;
+
+    /// Send `sig` to the calling thread.
+    #[cfg(test)]
+    pub fn raise(sig: c_int) -> c_int;
+}
+
+pub const EPOLL_CLOEXEC: c_int = 0o2000000;
+pub const EPOLL_CTL_ADD: c_int = 1;
+pub const EPOLLIN: u32 = 0x001;
+
+pub const CLOCK_MONOTONIC: c_int = 1;
+pub const TFD_NONBLOCK: c_int = 0o4000;
+pub const TFD_CLOEXEC: c_int = 0o2000000;
+pub const SFD_NONBLOCK: c_int = 0o4000;
+pub const SFD_CLOEXEC: c_int = 0o2000000;
+pub const EFD_NONBLOCK: c_int = 0o4000;
+pub const EFD_CLOEXEC: c_int = 0o2000000;
+
+pub const SIG_BLOCK: c_int = 0;
+pub const SIGWINCH: c_int = 28;
+
+/// Size of the `signalfd_siginfo` structure read from a signalfd.
+pub const SIGNALFD_SIGINFO_SIZE: usize = 128;
+
+/// Size of the counter read from and written to eventfd and timerfd.
+pub const COUNTER_SIZE: usize = 8;
+
+/// Describes an event of interest to, or reported by, an epoll instance.
+///
+/// The kernel packs this structure on x86_64.
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(target_arch = "x86_64", repr(C, packed))]
+#[cfg_attr(not(target_arch = "x86_64"), repr(C))]
+pub struct EpollEvent {
+    /// Bit mask of event types
+    pub events: u32,
+    /// Opaque user data, returned as is when the event fires
+    pub data: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct Timespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
+}
+
+/// Initial expiration and interval of a timer.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct ITimerSpec {
+    pub it_interval: Timespec,
+    pub it_value: Timespec,
+}
+
+/// A set of signals, sized to match glibc's `sigset_t`.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct SigSet {
+    bits: [u64; 16],
+}
@@ -8,7 +8,10 @@ use std::ffi::{c_int, c_ulong, c_ushort};
 // For now, use ioctl instead
 
 //TODO: Derive this magic number properly
+#[cfg(target_os = "macos")]
 pub const TIOCGWINSZ: c_ulong = 0x40087468;
+#[cfg(target_os = "linux")]
+pub const TIOCGWINSZ: c_ulong = 0x5413;
 
 #[link(name = "c")]
 extern "C" {
@@ -1,7 +1,5 @@
 //! Selected FFI bindings to Kqueue.
 
-use std::ffi::c_void;
-
 //TODO: Doc all the kqueue stuff
 //TODO: Make more use of std::ffi c types
 
@@ -20,10 +18,6 @@ extern "C" {
         nevents: i32,
         timeout: *const Timespec,
     ) -> i32;
-
-    pub fn read(descriptor: i32, buffer: *mut c_void, count: usize) -> isize;
-
-    pub fn close(descriptor: i32) -> i32;
 }
 
 pub const EVFILT_READ: i16 = -1;
@@ -37,14 +31,13 @@ pub const EV_CLEAR: u16 = 0x20;
 pub const NOTE_TRIGGER: u32 = 0x01000000;
 pub const NOTE_USECONDS: u32 = 0x00000002;
 
-pub const STDIN_FILE_NUM: u64 = 0;
 pub const SIGWINCH: u64 = 28;
 
 #[derive(Debug)]
 #[repr(C)]
 pub struct Timespec {
-    tv_sec: i64,
-    tv_nsec: i64,
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
 }
 
 #[derive(Debug, Clone, Copy, Default)]
@@ -2,7 +2,11 @@
 
 //TODO: Document termios stuff
 
-use std::ffi::{c_int, c_uchar, c_ulong};
+#[cfg(target_os = "linux")]
+use std::ffi::c_uint;
+#[cfg(target_os = "macos")]
+use std::ffi::c_ulong;
+use std::ffi::{c_int, c_uchar};
 
 #[link(name = "c")]
 extern "C" {
@@ -10,13 +14,25 @@ extern "C" {
     pub fn tcsetattr(descriptor: c_int, optional_actions: c_int, termios: *const Termios) -> c_int;
 }
 
+#[cfg(target_os = "macos")]
 pub type TCFlagT = c_ulong;
-pub type Cct = c_uchar;
+#[cfg(target_os = "macos")]
 pub type SpeedT = c_ulong;
 
+#[cfg(target_os = "linux")]
+pub type TCFlagT = c_uint;
+#[cfg(target_os = "linux")]
+pub type SpeedT = c_uint;
+
+pub type Cct = c_uchar;
+
 /// Size of the `c_cc` control chars array.
+#[cfg(target_os = "macos")]
 pub const NCCS: usize = 20;
+#[cfg(target_os = "linux")]
+pub const NCCS: usize = 32;
 
+#[cfg(target_os = "macos")]
 #[repr(C)]
 #[derive(Copy, Clone, Default, Debug)]
 pub struct Termios {
@@ -36,29 +52,79 @@ pub struct Termios {
     pub c_ospeed: SpeedT,
 }
 
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Copy, Clone, Default, Debug)]
+pub struct Termios {
+    /// input flags
+    pub c_iflag: TCFlagT,
+    /// output flags
+    pub c_oflag: TCFlagT,
+    /// control flags
+    pub c_cflag: TCFlagT,
+    /// local flags
+    pub c_lflag: TCFlagT,
+    /// line discipline
+    pub c_line: Cct,
+    /// control chars
+    pub c_cc: [Cct; NCCS],
+    /// input speed
+    pub c_ispeed: SpeedT,
+    /// output speed
+    pub c_ospeed: SpeedT,
+}
+
+#[cfg(target_os = "macos")]
+mod flags {
+    use super::TCFlagT;
+
+    pub const ECHO: TCFlagT = 0x00000008;
+    pub const ICANON: TCFlagT = 0x00000100;
+    pub const ISIG: TCFlagT = 0x00000080;
+    pub const IXON: TCFlagT = 0x00000200;
+    pub const IEXTEN: TCFlagT = 0x00000400;
+    pub const ICRNL: TCFlagT = 0x00000100;
+    pub const OPOST: TCFlagT = 0x00000001;
+    pub const BRKINT: TCFlagT = 0x00000002;
+}
+
+#[cfg(target_os = "linux")]
+mod flags {
+    use super::TCFlagT;
+
+    pub const ECHO: TCFlagT = 0o0000010;
+    pub const ICANON: TCFlagT = 0o0000002;
+    pub const ISIG: TCFlagT = 0o0000001;
+    pub const IXON: TCFlagT = 0o0002000;
+    pub const IEXTEN: TCFlagT = 0o0100000;
+    pub const ICRNL: TCFlagT = 0o0000400;
+    pub const OPOST: TCFlagT = 0o0000001;
+    pub const BRKINT: TCFlagT = 0o0000002;
+}
+
 /// Enable echoing
-pub const ECHO: TCFlagT = 0x00000008;
+pub const ECHO: TCFlagT = flags::ECHO;
 
 /// Canonicalize input lines (edit and submit input line by line)
-pub const ICANON: TCFlagT = 0x00000100;
+pub const ICANON: TCFlagT = flags::ICANON;
 
 /// Translate interupt, quit and suspend characters into corresponding signals
-pub const ISIG: TCFlagT = 0x00000080;
+pub const ISIG: TCFlagT = flags::ISIG;
 
 /// Enable output flow control
-pub const IXON: TCFlagT = 0x00000200;
+pub const IXON: TCFlagT = flags::IXON;
 
 /// Enable extended input procesing
-pub const IEXTEN: TCFlagT = 0x00000400;
+pub const IEXTEN: TCFlagT = flags::IEXTEN;
 
 /// Enable translation of carriage returns to newlines
-pub const ICRNL: TCFlagT = 0x00000100;
+pub const ICRNL: TCFlagT = flags::ICRNL;
 
 /// Enable output post processing
-pub const OPOST: TCFlagT = 0x00000001;
+pub const OPOST: TCFlagT = flags::OPOST;
 
 /// Enable sending SIGINT on break
-pub const BRKINT: TCFlagT = 0x00000002;
+pub const BRKINT: TCFlagT = flags::BRKINT;
 
 /// Drain output, flush input
 pub const TCSAFLUSH: c_int = 2;
@@ -0,0 +1,15 @@
+//! Selected FFI bindings to POSIX file descriptor operations.
+
+use std::ffi::c_void;
+
+#[link(name = "c")]
+extern "C" {
+    pub fn read(descriptor: i32, buffer: *mut c_void, count: usize) -> isize;
+
+    #[cfg(target_os = "linux")]
+    pub fn write(descriptor: i32, buffer: *const c_void, count: usize) -> isize;
+
+    pub fn close(descriptor: i32) -> i32;
+}
+
+pub const STDIN_FILENO: i32 = 0;
@@ -0,0 +1,692 @@
+//! Mapping of key presses to the actions they trigger.
+//!
+//! Bindings start out as the defaults below, and can be changed by a config
+//! file at `$XDG_CONFIG_HOME/afqueue/keymap` (or `~/.config/afqueue/keymap`).
+//! Each line of the file binds a key chord to a named action:
+//!
+//! ```text
+//! # Comments start with a hash
+//! space = pause
+//! ctrl+right = next
+//! shift+left = seek-backward
+//! n = none
+//! ```
+//!
+//! Binding a chord replaces its default action, and binding it to `none`
+//! removes it altogether. Shift only goes with letters and named keys, for
+//! symbols bind the character typed instead, e.g `! = mute` not `shift+1`.
+//! Ctrl goes with letters but not shift, as terminals send both the same.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::events::{Key, KeyPress, Modifiers};
+
+/// Something the user can ask afqueue to do.
+///
+/// Actions are ordered as they are listed in help.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Action {
+    NextTrack,
+    PreviousTrack,
+    RestartTrack,
+    Pause,
+    VolumeUp,
+    VolumeDown,
+    ToggleMute,
+    SeekForward,
+    SeekBackward,
+    /// Seek to a percentage of the way through the track
+    SeekTo(u8),
+    ToggleShuffle,
+    ToggleRepeat,
+    ToggleCrossfade,
+    CycleReplayGain,
+    SelectUp,
+    SelectDown,
+    MoveUp,
+    MoveDown,
+    PlayNext,
+    Remove,
+    AddTracks,
+    ScrollQueueUp,
+    ScrollQueueDown,
+    ShowHelp,
+    Quit,
+}
+
+const NAMED_ACTIONS: [(&str, Action); 24] = [
+    ("next", Action::NextTrack),
+    ("previous", Action::PreviousTrack),
+    ("restart", Action::RestartTrack),
+    ("pause", Action::Pause),
+    ("volume-up", Action::VolumeUp),
+    ("volume-down", Action::VolumeDown),
+    ("mute", Action::ToggleMute),
+    ("seek-forward", Action::SeekForward),
+    ("seek-backward", Action::SeekBackward),
+    ("shuffle", Action::ToggleShuffle),
+    ("repeat", Action::ToggleRepeat),
+    ("crossfade", Action::ToggleCrossfade),
+    ("replay-gain", Action::CycleReplayGain),
+    ("select-up", Action::SelectUp),
+    ("select-down", Action::SelectDown),
+    ("move-up", Action::MoveUp),
+    ("move-down", Action::MoveDown),
+    ("play-next", Action::PlayNext),
+    ("remove", Action::Remove),
+    ("add", Action::AddTracks),
+    ("scroll-queue-up", Action::ScrollQueueUp),
+    ("scroll-queue-down", Action::ScrollQueueDown),
+    ("help", Action::ShowHelp),
+    ("quit", Action::Quit),
+];
+
+const SEEK_TO_PREFIX: &str = "seek-to-";
+
+impl Action {
+    fn from_name(name: &str) -> Option<Action> {
+        if let Some(percentage) = name.strip_prefix(SEEK_TO_PREFIX) {
+            return match percentage.parse() {
+                Ok(percentage) if percentage <= 100 => Some(Action::SeekTo(percentage)),
+                _ => None,
+            };
+        }
+        NAMED_ACTIONS
+            .iter()
+            .find(|(action_name, _)| *action_name == name)
+            .map(|(_, action)| *action)
+    }
+
+    /// What the action does, in words.
+    pub fn description(&self) -> String {
+        let description = match self {
+            Action::NextTrack => "Skip to next track",
+            Action::PreviousTrack => "Back to previous track",
+            Action::RestartTrack => "Restart track",
+            Action::Pause => "Toggle paused",
+            Action::VolumeUp => "Volume up",
+            Action::VolumeDown => "Volume down",
+            Action::ToggleMute => "Toggle mute",
+            Action::SeekForward => "Seek forward",
+            Action::SeekBackward => "Seek backward",
+            Action::SeekTo(percentage) => return format!("Jump to {percentage}%"),
+            Action::ToggleShuffle => "Cycle shuffle mode",
+            Action::ToggleRepeat => "Cycle repeat mode",
+            Action::ToggleCrossfade => "Toggle crossfading",
+            Action::CycleReplayGain => "Cycle ReplayGain mode",
+            Action::SelectUp => "Select previous in queue",
+            Action::SelectDown => "Select next in queue",
+            Action::MoveUp => "Move selected up",
+            Action::MoveDown => "Move selected down",
+            Action::PlayNext => "Play selected next",
+            Action::Remove => "Remove selected",
+            Action::AddTracks => "Add files to queue",
+            Action::ScrollQueueUp => "Scroll queue up",
+            Action::ScrollQueueDown => "Scroll queue down",
+            Action::ShowHelp => "Show key bindings",
+            Action::Quit => "Exit",
+        };
+        description.to_string()
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Action::SeekTo(percentage) = self {
+            return write!(f, "{SEEK_TO_PREFIX}{percentage}");
+        }
+        let (name, _) = NAMED_ACTIONS
+            .iter()
+            .find(|(_, action)| action == self)
+            .expect("all other actions are named");
+        write!(f, "{name}")
+    }
+}
+
+const NAMED_KEYS: [(&str, Key); 16] = [
+    ("up", Key::Up),
+    ("down", Key::Down),
+    ("left", Key::Left),
+    ("right", Key::Right),
+    ("home", Key::Home),
+    ("end", Key::End),
+    ("pageup", Key::PageUp),
+    ("pagedown", Key::PageDown),
+    ("insert", Key::Insert),
+    ("delete", Key::Delete),
+    ("enter", Key::Enter),
+    ("tab", Key::Tab),
+    ("backtab", Key::BackTab),
+    ("backspace", Key::Backspace),
+    ("escape", Key::Escape),
+    ("space", Key::Char(' ')),
+];
+
+/// Parse a key chord, such as `q`, `ctrl+left` or `alt+shift+f5`.
+fn parse_chord(chord: &str) -> Option<KeyPress> {
+    let mut modifiers = Modifiers::NONE;
+
+    // Split off modifiers, taking care that `+` is also a key in its own right
+    let mut rest = chord;
+    while let Some((modifier, remainder)) = rest.split_once('+') {
+        if remainder.is_empty() {
+            break;
+        }
+        match modifier.to_ascii_lowercase().as_str() {
+            "shift" => modifiers.shift = true,
+            "alt" => modifiers.alt = true,
+            "ctrl" => modifiers.ctrl = true,
+            _ => return None,
+        }
+        rest = remainder;
+    }
+
+    let lowercase = rest.to_ascii_lowercase();
+    let named = NAMED_KEYS.iter().find(|(name, _)| *name == lowercase);
+    let key = if let Some((_, key)) = named {
+        *key
+    } else if let Some(number) = lowercase.strip_prefix('f').filter(|n| !n.is_empty()) {
+        match number.parse() {
+            Ok(number @ 1..=12) => Key::F(number),
+            _ => return None,
+        }
+    } else {
+        let mut chars = rest.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Key::Char(c),
+            _ => return None,
+        }
+    };
+
+    normalise(KeyPress::new(key, modifiers))
+}
+
+impl fmt::Display for KeyPress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let modifiers = [
+            (self.modifiers.ctrl, "ctrl"),
+            (self.modifiers.alt, "alt"),
+            (self.modifiers.shift, "shift"),
+        ];
+        for (_, modifier) in modifiers.iter().filter(|(held, _)| *held) {
+            write!(f, "{modifier}+")?;
+        }
+        match NAMED_KEYS.iter().find(|(_, key)| *key == self.key) {
+            Some((name, _)) => write!(f, "{name}"),
+            None => match self.key {
+                Key::Char(c) => write!(f, "{c}"),
+                Key::F(number) => write!(f, "f{number}"),
+                _ => unreachable!("all other keys are named"),
+            },
+        }
+    }
+}
+
+/// Fold shift into the character for printable keys, as that is how the
+/// terminal reports them.
+///
+/// Returns `None` for shift with a character that has no upper case, such as
+/// `shift+1`, as which character that types depends on the keyboard layout.
+/// Likewise for ctrl with a character that the terminal can't tell apart from
+/// another key, such as `ctrl+i` for tab or `ctrl+shift+a` for `ctrl+a`.
+fn normalise(mut key: KeyPress) -> Option<KeyPress> {
+    if let Key::Char(c) = key.key {
+        if key.modifiers.shift && !key.modifiers.ctrl {
+            if !c.is_lowercase() && !c.is_uppercase() {
+                return None;
+            }
+            key.key = Key::Char(c.to_uppercase().next().unwrap_or(c));
+            key.modifiers.shift = false;
+        }
+        if key.modifiers.ctrl {
+            let c = c.to_ascii_lowercase();
+            if key.modifiers.shift || !is_sent_with_ctrl(c) {
+                return None;
+            }
+            key.key = Key::Char(c);
+        }
+    }
+    Some(key)
+}
+
+/// Whether the terminal sends `c` held with ctrl as a control byte of its own,
+/// rather than one read as some other key.
+fn is_sent_with_ctrl(c: char) -> bool {
+    match c {
+        // Backspace, tab, and the two bytes read as enter
+        'h' | 'i' | 'j' | 'm' => false,
+        'a'..='z' | ' ' | '\\' | ']' | '^' | '_' => true,
+        _ => false,
+    }
+}
+
+#[derive(Debug)]
+pub enum KeymapError {
+    Io(io::Error),
+    /// A line that isn't of the form `chord = action`
+    Syntax(usize, String),
+    UnknownKey(usize, String),
+    UnknownAction(usize, String),
+    /// The same chord bound to two different actions
+    Conflict {
+        line: usize,
+        chord: String,
+        first: Action,
+        second: Action,
+    },
+}
+
+impl fmt::Display for KeymapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeymapError::Io(err) => write!(f, "{err}"),
+            KeymapError::Syntax(line, text) => {
+                write!(f, "expected 'key = action' on line {line}, found '{text}'")
+            }
+            KeymapError::UnknownKey(line, key) => {
+                write!(f, "unknown key '{key}' on line {line}")
+            }
+            KeymapError::UnknownAction(line, action) => {
+                write!(f, "unknown action '{action}' on line {line}")
+            }
+            KeymapError::Conflict {
+                line,
+                chord,
+                first,
+                second,
+            } => write!(
+                f,
+                "'{chord}' bound to both '{first}' and '{second}' on line {line}"
+            ),
+        }
+    }
+}
+
+impl Error for KeymapError {}
+
+pub struct Keymap {
+    bindings: HashMap<KeyPress, Action>,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        let mut bindings = HashMap::from([
+            (KeyPress::plain(Key::Char('n')), Action::NextTrack),
+            (KeyPress::plain(Key::Char('b')), Action::PreviousTrack),
+            (KeyPress::plain(Key::Char('r')), Action::RestartTrack),
+            (KeyPress::plain(Key::Char('p')), Action::Pause),
+            (KeyPress::plain(Key::Char(']')), Action::VolumeUp),
+            (KeyPress::plain(Key::Up), Action::VolumeUp),
+            (KeyPress::plain(Key::Char('[')), Action::VolumeDown),
+            (KeyPress::plain(Key::Down), Action::VolumeDown),
+            (KeyPress::plain(Key::Char('m')), Action::ToggleMute),
+            (KeyPress::plain(Key::Char('.')), Action::SeekForward),
+            (KeyPress::plain(Key::Right), Action::SeekForward),
+            (KeyPress::plain(Key::Char(',')), Action::SeekBackward),
+            (KeyPress::plain(Key::Left), Action::SeekBackward),
+            (KeyPress::plain(Key::Char('s')), Action::ToggleShuffle),
+            (KeyPress::plain(Key::Char('R')), Action::ToggleRepeat),
+            (KeyPress::plain(Key::Char('x')), Action::ToggleCrossfade),
+            (KeyPress::plain(Key::Char('g')), Action::CycleReplayGain),
+            (KeyPress::plain(Key::Char('k')), Action::SelectUp),
+            (KeyPress::plain(Key::Char('j')), Action::SelectDown),
+            (KeyPress::plain(Key::Char('K')), Action::MoveUp),
+            (KeyPress::plain(Key::Char('J')), Action::MoveDown),
+            (KeyPress::plain(Key::Char('N')), Action::PlayNext),
+            (KeyPress::plain(Key::Char('d')), Action::Remove),
+            (KeyPress::plain(Key::Delete), Action::Remove),
+            (KeyPress::plain(Key::Char('a')), Action::AddTracks),
+            (KeyPress::plain(Key::PageUp), Action::ScrollQueueUp),
+            (KeyPress::plain(Key::PageDown), Action::ScrollQueueDown),
+            (KeyPress::plain(Key::Char('?')), Action::ShowHelp),
+            (KeyPress::plain(Key::Char('q')), Action::Quit),
+        ]);
+        for tenths in 0..=9 {
+            let key = KeyPress::plain(Key::Char((b'0' + tenths) as char));
+            bindings.insert(key, Action::SeekTo(tenths * 10));
+        }
+        Keymap { bindings }
+    }
+}
+
+impl Keymap {
+    /// Load the keymap at `path`, falling back to the defaults if there isn't
+    /// one.
+    pub fn load(path: &Path) -> Result<Self, KeymapError> {
+        match fs::read_to_string(path) {
+            Ok(config) => Keymap::default().apply(&config),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Keymap::default()),
+            Err(err) => Err(KeymapError::Io(err)),
+        }
+    }
+
+    /// Layer the bindings in `config` on top of this keymap.
+    fn apply(mut self, config: &str) -> Result<Self, KeymapError> {
+        let mut bound: HashMap<KeyPress, Option<Action>> = HashMap::new();
+
+        for (index, line) in config.lines().enumerate() {
+            let number = index + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            // Action names never contain `=`, but the key might be `=` itself
+            let Some((chord, name)) = line.rsplit_once('=') else {
+                return Err(KeymapError::Syntax(number, line.to_string()));
+            };
+            let (chord, name) = (chord.trim(), name.trim());
+            if chord.is_empty() || name.is_empty() {
+                return Err(KeymapError::Syntax(number, line.to_string()));
+            }
+
+            let key = parse_chord(chord)
+                .ok_or_else(|| KeymapError::UnknownKey(number, chord.to_string()))?;
+            let action = match name {
+                "none" => None,
+                name => Some(
+                    Action::from_name(name)
+                        .ok_or_else(|| KeymapError::UnknownAction(number, name.to_string()))?,
+                ),
+            };
+
+            if let Some(&Some(first)) = bound.get(&key) {
+                if let Some(second) = action.filter(|&second| second != first) {
+                    return Err(KeymapError::Conflict {
+                        line: number,
+                        chord: chord.to_string(),
+                        first,
+                        second,
+                    });
+                }
+            }
+            bound.insert(key, action);
+        }
+
+        for (key, action) in bound {
+            match action {
+                Some(action) => self.bindings.insert(key, action),
+                None => self.bindings.remove(&key),
+            };
+        }
+        Ok(self)
+    }
+
+    pub fn action_for(&self, key: &KeyPress) -> Option<Action> {
+        self.bindings.get(key).copied()
+    }
+
+    /// Every bound action along with the keys that trigger it, in help order.
+    pub fn bindings(&self) -> Vec<(Action, Vec<KeyPress>)> {
+        let mut by_action: Vec<(Action, Vec<KeyPress>)> = Vec::new();
+        for (key, action) in &self.bindings {
+            match by_action.iter_mut().find(|(bound, _)| bound == action) {
+                Some((_, keys)) => keys.push(*key),
+                None => by_action.push((*action, vec![*key])),
+            }
+        }
+        by_action.sort_by_key(|(action, _)| *action);
+        for (_, keys) in &mut by_action {
+            // Keep the listing stable, with plain keys ahead of named ones
+            keys.sort_by_key(|key| (key.to_string().chars().count(), key.to_string()));
+        }
+        by_action
+    }
+}
+
+/// Location of the keymap config file, following the XDG base directory spec.
+pub fn config_path() -> Option<PathBuf> {
+    let xdg_config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute());
+    let config_home = match xdg_config_home {
+        Some(path) => path,
+        None => Path::new(&std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_home.join("afqueue").join("keymap"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chord(chord: &str) -> KeyPress {
+        parse_chord(chord).unwrap_or_else(|| panic!("'{chord}' should parse"))
+    }
+
+    fn with(key: Key, shift: bool, alt: bool, ctrl: bool) -> KeyPress {
+        KeyPress::new(key, Modifiers { shift, alt, ctrl })
+    }
+
+    fn applied(config: &str) -> Keymap {
+        Keymap::default()
+            .apply(config)
+            .expect("config should apply")
+    }
+
+    #[test]
+    fn defaults_match_the_original_keys() {
+        let keymap = Keymap::default();
+        let action = |c| keymap.action_for(&KeyPress::plain(Key::Char(c)));
+        assert_eq!(action('n'), Some(Action::NextTrack));
+        assert_eq!(action('p'), Some(Action::Pause));
+        assert_eq!(action(']'), Some(Action::VolumeUp));
+        assert_eq!(action('['), Some(Action::VolumeDown));
+        assert_eq!(action('q'), Some(Action::Quit));
+        assert_eq!(action('7'), Some(Action::SeekTo(70)));
+        assert_eq!(action('z'), None);
+    }
+
+    #[test]
+    fn chords_combine_modifiers_in_any_order_and_case() {
+        assert_eq!(chord("q"), KeyPress::plain(Key::Char('q')));
+        assert_eq!(chord("ctrl+right"), with(Key::Right, false, false, true));
+        assert_eq!(chord("Shift+Alt+F5"), with(Key::F(5), true, true, false));
+        assert_eq!(chord("alt+shift+f5"), chord("shift+alt+f5"));
+        assert_eq!(
+            chord("ctrl+alt+pageup"),
+            with(Key::PageUp, false, true, true)
+        );
+        assert_eq!(chord("space"), KeyPress::plain(Key::Char(' ')));
+        // Plus is a key of its own, as well as what joins chords together
+        assert_eq!(chord("+"), KeyPress::plain(Key::Char('+')));
+        assert_eq!(chord("alt++"), with(Key::Char('+'), false, true, false));
+    }
+
+    #[test]
+    fn chords_match_how_the_terminal_reports_them() {
+        // Shift is folded into letters, but ctrl letters all come through in
+        // lower case
+        assert_eq!(chord("shift+a"), KeyPress::plain(Key::Char('A')));
+        assert_eq!(chord("shift+é"), KeyPress::plain(Key::Char('É')));
+        assert_eq!(
+            chord("alt+shift+x"),
+            with(Key::Char('X'), false, true, false)
+        );
+        assert_eq!(chord("ctrl+X"), with(Key::Char('x'), false, false, true));
+        assert_eq!(chord("shift+left"), with(Key::Left, true, false, false));
+        assert_eq!(chord("ctrl+shift+left"), with(Key::Left, true, false, true));
+
+        for chord in ["shift+1", "shift+.", "alt+shift+[", "shift+space"] {
+            assert_eq!(parse_chord(chord), None, "{chord}");
+        }
+        // Ctrl letters are sent the same with or without shift, and some
+        // are the same bytes as other keys
+        for chord in [
+            "ctrl+shift+x",
+            "ctrl+i",
+            "ctrl+M",
+            "alt+ctrl+h",
+            "ctrl+j",
+            "ctrl+1",
+        ] {
+            assert_eq!(parse_chord(chord), None, "{chord}");
+        }
+    }
+
+    #[test]
+    fn unknown_chords_are_not_parsed() {
+        for chord in ["", "qq", "f0", "f13", "hyper+x", "ctrl+", "ctrl+nope"] {
+            assert_eq!(parse_chord(chord), None, "{chord}");
+        }
+    }
+
+    #[test]
+    fn chords_are_shown_as_they_are_written() {
+        for text in [
+            "q",
+            "ctrl+alt+shift+f12",
+            "alt+A",
+            "ctrl+space",
+            "+",
+            "backtab",
+        ] {
+            assert_eq!(chord(text).to_string(), text);
+        }
+    }
+
+    #[test]
+    fn bindings_are_listed_in_help_order() {
+        let keymap = applied("ctrl+v = restart\nv = restart\n");
+        let bindings = keymap.bindings();
+        let actions: Vec<Action> = bindings.iter().map(|(action, _)| *action).collect();
+        let mut sorted = actions.clone();
+        sorted.sort();
+        assert_eq!(actions, sorted);
+
+        let (_, keys) = bindings
+            .iter()
+            .find(|(action, _)| *action == Action::RestartTrack)
+            .unwrap();
+        let keys: Vec<String> = keys.iter().map(KeyPress::to_string).collect();
+        assert_eq!(keys, ["r", "v", "ctrl+v"]);
+    }
+
+    #[test]
+    fn config_overrides_and_removes_defaults() {
+        let keymap = applied(
+            "# Swap next and quit around\n\
+             \n\
+             n = quit\n\
+             q = next\n\
+             ctrl+n = next\n\
+             shift+tab = seek-to-25\n\
+             = = volume-up\n\
+             p = none\n",
+        );
+        let action = |text| keymap.action_for(&chord(text));
+        assert_eq!(action("n"), Some(Action::Quit));
+        assert_eq!(action("q"), Some(Action::NextTrack));
+        assert_eq!(action("ctrl+n"), Some(Action::NextTrack));
+        assert_eq!(action("shift+tab"), Some(Action::SeekTo(25)));
+        assert_eq!(action("="), Some(Action::VolumeUp));
+        assert_eq!(action("p"), None);
+        // Everything else is left as it was
+        assert_eq!(action("]"), Some(Action::VolumeUp));
+    }
+
+    #[test]
+    fn chords_can_be_bound_again_to_the_same_action() {
+        let keymap = applied("x = pause\nx = pause\nA = restart\nshift+a = restart\n");
+        assert_eq!(keymap.action_for(&chord("x")), Some(Action::Pause));
+        assert_eq!(keymap.action_for(&chord("A")), Some(Action::RestartTrack));
+
+        // Or unbound, and bound again
+        let keymap = applied("x = pause\nx = none\nx = restart\n");
+        assert_eq!(keymap.action_for(&chord("x")), Some(Action::RestartTrack));
+    }
+
+    #[test]
+    fn lines_without_an_equals_sign_are_syntax_errors() {
+        for line in ["pause", "x =", "= next", "  =  "] {
+            let config = format!("# Comment\n\n{line}\n");
+            match Keymap::default().apply(&config) {
+                Err(KeymapError::Syntax(3, text)) => assert_eq!(text, line.trim()),
+                other => panic!("'{line}' gave {:?}", other.err()),
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_keys_are_errors() {
+        let result = Keymap::default().apply("x = pause\nshift+1 = restart\n");
+        assert!(matches!(
+            result,
+            Err(KeymapError::UnknownKey(2, ref chord)) if chord == "shift+1"
+        ));
+        let err = Keymap::default().apply("super+x = next").err().unwrap();
+        assert_eq!(err.to_string(), "unknown key 'super+x' on line 1");
+        let result = Keymap::default().apply("ctrl+shift+n = next\n");
+        assert!(matches!(
+            result,
+            Err(KeymapError::UnknownKey(1, ref chord)) if chord == "ctrl+shift+n"
+        ));
+    }
+
+    #[test]
+    fn unknown_actions_are_errors() {
+        for action in ["dance", "seek-to-101", "seek-to-", "Next"] {
+            let result = Keymap::default().apply(&format!("x = {action}"));
+            assert!(
+                matches!(result, Err(KeymapError::UnknownAction(1, ref name)) if name == action),
+                "{action}"
+            );
+        }
+        let err = Keymap::default().apply("x = dance").err().unwrap();
+        assert_eq!(err.to_string(), "unknown action 'dance' on line 1");
+    }
+
+    #[test]
+    fn binding_a_chord_to_two_actions_is_a_conflict() {
+        // Written differently, but the same chord
+        let result = Keymap::default().apply("A = pause\n\nshift+a = restart\n");
+        let Err(err) = result else {
+            panic!("conflict should be an error");
+        };
+        assert!(matches!(
+            err,
+            KeymapError::Conflict {
+                line: 3,
+                first: Action::Pause,
+                second: Action::RestartTrack,
+                ..
+            }
+        ));
+        assert_eq!(
+            err.to_string(),
+            "'shift+a' bound to both 'pause' and 'restart' on line 3"
+        );
+    }
+
+    #[test]
+    fn overriding_a_default_is_not_a_conflict() {
+        // Only bindings within the config can conflict with each other, the
+        // defaults are there to be replaced
+        let keymap = applied("n = pause\np = next\n");
+        assert_eq!(keymap.action_for(&chord("n")), Some(Action::Pause));
+        assert_eq!(keymap.action_for(&chord("p")), Some(Action::NextTrack));
+
+        let result = Keymap::default().apply("n = pause\nn = quit\n");
+        assert!(matches!(
+            result,
+            Err(KeymapError::Conflict {
+                line: 2,
+                first: Action::Pause,
+                second: Action::Quit,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn missing_config_gives_the_defaults() {
+        let path = std::env::temp_dir().join(format!("afqueue-{}-no-keymap", std::process::id()));
+        let keymap = Keymap::load(&path).unwrap();
+        assert_eq!(keymap.action_for(&chord("q")), Some(Action::Quit));
+    }
+}
@@ -0,0 +1,429 @@
+//! Measuring loudness as set out by EBU R128 and ITU-R BS.1770.
+//!
+//! Audio is first K-weighted, a filter that roughly follows how loud the ear
+//! finds each frequency, then its power taken over 400ms blocks, each
+//! overlapping the last by 300ms. Integrated loudness is the average over the
+//! blocks, gated so that silence and passages well below the rest don't drag
+//! it down. Loudness range is the spread of loudness over 3 second windows,
+//! and true peak the loudest point between samples as well as at them, found
+//! by oversampling four times.
+//!
+//! Loudness is in LUFS, decibels relative to full scale, and the range in LU,
+//! decibels between one loudness and another.
+
+use std::f64::consts::PI;
+
+use crate::player::{PlaybackContext, PlaybackResult};
+use crate::playlist::Track;
+
+pub mod cache;
+
+// Power is measured over 100ms steps, four of which make up a block and 30 a
+// window for the loudness range
+const STEP_SECONDS: f64 = 0.1;
+const BLOCK_STEPS: usize = 4;
+const SHORT_TERM_STEPS: usize = 30;
+
+const ABSOLUTE_GATE: f64 = -70.0;
+const RELATIVE_GATE: f64 = -10.0;
+const RANGE_RELATIVE_GATE: f64 = -20.0;
+
+// The range runs between these percentiles of short term loudness, leaving
+// out the odd quiet moment or sudden loud one
+const RANGE_LOW_PERCENTILE: f64 = 0.10;
+const RANGE_HIGH_PERCENTILE: f64 = 0.95;
+
+const OVERSAMPLING: usize = 4;
+const TAPS_PER_PHASE: usize = 12;
+
+/// Loudness of a track or album, worked out from a `Measurement`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Loudness {
+    /// Integrated loudness in LUFS, `None` if it is all but silent
+    pub integrated: Option<f64>,
+    /// Loudness range in LU
+    pub range: f64,
+    /// True peak, as a fraction of full scale
+    pub true_peak: f64,
+}
+
+/// What there is to know about the loudness of some audio, which can be
+/// combined with others to find the loudness of them all together.
+#[derive(Debug, Clone, Default)]
+pub struct Measurement {
+    /// Mean power of each block
+    blocks: Vec<f64>,
+    /// Mean power of each 3 second window
+    short_terms: Vec<f64>,
+    true_peak: f64,
+}
+
+impl Measurement {
+    /// Measure `track` by reading it through from start to finish.
+    pub fn of(track: &Track) -> PlaybackResult<Self> {
+        let mut context = PlaybackContext::new(&track.path)?;
+        if let Some(section) = track.section {
+            context.select_section(section.start, section.end)?;
+        }
+        let format = *context.format();
+        let mut meter = Meter::new(format.sample_rate, format.channels_per_frame as usize);
+        context.read_samples(|samples| meter.add(samples))?;
+        Ok(meter.finish())
+    }
+
+    /// Measurement of several runs of audio together, such as the tracks of
+    /// an album.
+    pub fn combine<'a>(measurements: impl IntoIterator<Item = &'a Measurement>) -> Self {
+        let mut combined = Measurement::default();
+        for measurement in measurements {
+            combined.blocks.extend(&measurement.blocks);
+            combined.short_terms.extend(&measurement.short_terms);
+            combined.true_peak = combined.true_peak.max(measurement.true_peak);
+        }
+        combined
+    }
+
+    pub fn loudness(&self) -> Loudness {
+        Loudness {
+            integrated: integrated(&self.blocks),
+            range: range(&self.short_terms),
+            true_peak: self.true_peak,
+        }
+    }
+}
+
+/// Measures audio as it is read through.
+pub struct Meter {
+    channels: usize,
+    weights: Vec<f64>,
+    filters: [Biquad; 2],
+    /// State of each filter, for each channel
+    states: Vec<[[f64; 2]; 2]>,
+    step_frames: usize,
+    /// Weighted sum of squares of the step so far
+    step_sum: f64,
+    step_count: usize,
+    /// Mean power of each step finished so far
+    steps: Vec<f64>,
+    true_peak: TruePeak,
+}
+
+impl Meter {
+    pub fn new(sample_rate: f64, channels: usize) -> Self {
+        let channels = channels.max(1);
+        Meter {
+            channels,
+            weights: channel_weights(channels),
+            filters: k_weighting(sample_rate),
+            states: vec![[[0.0; 2]; 2]; channels],
+            step_frames: ((sample_rate * STEP_SECONDS).round() as usize).max(1),
+            step_sum: 0.0,
+            step_count: 0,
+            steps: Vec::new(),
+            true_peak: TruePeak::new(channels),
+        }
+    }
+
+    /// Measure a run of interleaved samples, carrying on from the last.
+    pub fn add(&mut self, samples: &[f32]) {
+        let [shelf, high_pass] = &self.filters;
+        for frame in samples.chunks_exact(self.channels) {
+            let mut power = 0.0;
+            for (channel, &sample) in frame.iter().enumerate() {
+                let sample = sample as f64;
+                self.true_peak.add(channel, sample);
+                let [shelf_state, high_pass_state] = &mut self.states[channel];
+                let weighted = high_pass.apply(high_pass_state, shelf.apply(shelf_state, sample));
+                power += self.weights[channel] * weighted * weighted;
+            }
+
+            self.step_sum += power;
+            self.step_count += 1;
+            if self.step_count == self.step_frames {
+                self.steps.push(self.step_sum / self.step_frames as f64);
+                self.step_sum = 0.0;
+                self.step_count = 0;
+            }
+        }
+    }
+
+    /// Measurement of everything added, leaving out any part of a step at
+    /// the end.
+    pub fn finish(self) -> Measurement {
+        Measurement {
+            blocks: mean_over(&self.steps, BLOCK_STEPS),
+            short_terms: mean_over(&self.steps, SHORT_TERM_STEPS),
+            true_peak: self.true_peak.peak,
+        }
+    }
+}
+
+/// Mean power over each run of `length` steps, moving on a step at a time.
+fn mean_over(steps: &[f64], length: usize) -> Vec<f64> {
+    steps
+        .windows(length)
+        .map(|window| window.iter().sum::<f64>() / length as f64)
+        .collect()
+}
+
+fn integrated(blocks: &[f64]) -> Option<f64> {
+    let audible: Vec<f64> = blocks
+        .iter()
+        .copied()
+        .filter(|&power| loudness(power) > ABSOLUTE_GATE)
+        .collect();
+    let gate = loudness(mean(&audible)?) + RELATIVE_GATE;
+    let gated: Vec<f64> = audible
+        .into_iter()
+        .filter(|&power| loudness(power) > gate)
+        .collect();
+    mean(&gated).map(loudness)
+}
+
+fn range(short_terms: &[f64]) -> f64 {
+    let audible: Vec<f64> = short_terms
+        .iter()
+        .copied()
+        .filter(|&power| loudness(power) > ABSOLUTE_GATE)
+        .collect();
+    let Some(mean) = mean(&audible) else {
+        return 0.0;
+    };
+    let gate = loudness(mean) + RANGE_RELATIVE_GATE;
+    let mut gated: Vec<f64> = audible
+        .into_iter()
+        .map(loudness)
+        .filter(|&loudness| loudness > gate)
+        .collect();
+    gated.sort_by(f64::total_cmp);
+
+    let percentile = |fraction: f64| {
+        let index = ((gated.len() - 1) as f64 * fraction).round() as usize;
+        gated[index]
+    };
+    match gated.is_empty() {
+        true => 0.0,
+        false => percentile(RANGE_HIGH_PERCENTILE) - percentile(RANGE_LOW_PERCENTILE),
+    }
+}
+
+fn mean(powers: &[f64]) -> Option<f64> {
+    match powers.is_empty() {
+        true => None,
+        false => Some(powers.iter().sum::<f64>() / powers.len() as f64),
+    }
+}
+
+/// Loudness in LUFS of K-weighted audio with the given mean power.
+fn loudness(power: f64) -> f64 {
+    -0.691 + 10.0 * power.log10()
+}
+
+/// How much each channel counts towards loudness. Surround channels count for
+/// more, and the LFE channel not at all, going by the usual order for 5.1.
+fn channel_weights(channels: usize) -> Vec<f64> {
+    match channels {
+        5 => vec![1.0, 1.0, 1.0, 1.41, 1.41],
+        6 => vec![1.0, 1.0, 1.0, 0.0, 1.41, 1.41],
+        _ => vec![1.0; channels],
+    }
+}
+
+/// A second order IIR filter, with its coefficients normalised so that the
+/// first feedback one is 1.
+#[derive(Debug, Clone, Copy)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+}
+
+impl Biquad {
+    /// Filter the next sample, in transposed direct form II.
+    fn apply(&self, state: &mut [f64; 2], sample: f64) -> f64 {
+        let filtered = self.b[0] * sample + state[0];
+        state[0] = self.b[1] * sample - self.a[0] * filtered + state[1];
+        state[1] = self.b[2] * sample - self.a[1] * filtered;
+        filtered
+    }
+}
+
+/// The two stages of K-weighting: a shelf boosting higher frequencies, much
+/// as the head does, then a high pass cutting out the lowest.
+///
+/// BS.1770 only gives coefficients for 48kHz, so these are worked back from
+/// the filters they describe to suit any sample rate.
+fn k_weighting(sample_rate: f64) -> [Biquad; 2] {
+    let shelf = {
+        let gain = 3.999_843_853_973_347;
+        let frequency = 1_681.974_450_955_533;
+        let q = 0.707_175_236_955_419_6;
+        let k = (PI * frequency / sample_rate).tan();
+        let high = 10f64.powf(gain / 20.0);
+        let band = high.powf(0.499_666_774_154_541_6);
+        let a0 = 1.0 + k / q + k * k;
+        Biquad {
+            b: [
+                (high + band * k / q + k * k) / a0,
+                2.0 * (k * k - high) / a0,
+                (high - band * k / q + k * k) / a0,
+            ],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        }
+    };
+    let high_pass = {
+        let frequency = 38.135_470_876_024_44;
+        let q = 0.500_327_037_323_877_3;
+        let k = (PI * frequency / sample_rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        Biquad {
+            b: [1.0, -2.0, 1.0],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        }
+    };
+    [shelf, high_pass]
+}
+
+/// Finds the loudest point of the audio, including between samples, by
+/// upsampling with a windowed sinc filter. The filter is split into a phase
+/// for each point in between, so that only those points need working out.
+struct TruePeak {
+    phases: [[f64; TAPS_PER_PHASE]; OVERSAMPLING],
+    /// Latest samples of each channel, newest first
+    history: Vec<[f64; TAPS_PER_PHASE]>,
+    peak: f64,
+}
+
+impl TruePeak {
+    fn new(channels: usize) -> Self {
+        let taps = OVERSAMPLING * TAPS_PER_PHASE;
+        let middle = (taps - 1) as f64 / 2.0;
+        let mut phases = [[0.0; TAPS_PER_PHASE]; OVERSAMPLING];
+        for tap in 0..taps {
+            // Cut off at the Nyquist frequency of the audio before upsampling
+            let time = (tap as f64 - middle) / OVERSAMPLING as f64;
+            let sinc = (PI * time).sin() / (PI * time);
+            let window = 0.5 - 0.5 * (2.0 * PI * (tap + 1) as f64 / (taps + 1) as f64).cos();
+            phases[tap % OVERSAMPLING][tap / OVERSAMPLING] = sinc * window;
+        }
+        // Each phase passes steady levels through unchanged
+        for phase in &mut phases {
+            let sum: f64 = phase.iter().sum();
+            phase.iter_mut().for_each(|coefficient| *coefficient /= sum);
+        }
+        TruePeak {
+            phases,
+            history: vec![[0.0; TAPS_PER_PHASE]; channels],
+            peak: 0.0,
+        }
+    }
+
+    fn add(&mut self, channel: usize, sample: f64) {
+        let history = &mut self.history[channel];
+        history.copy_within(..TAPS_PER_PHASE - 1, 1);
+        history[0] = sample;
+
+        let mut peak = sample.abs();
+        for phase in &self.phases {
+            let interpolated: f64 = phase.iter().zip(history.iter()).map(|(c, s)| c * s).sum();
+            peak = peak.max(interpolated.abs());
+        }
+        self.peak = self.peak.max(peak);
+    }
+}
+
+/// Convert a level as a fraction of full scale to decibels.
+pub fn decibels(level: f64) -> f64 {
+    20.0 * level.log10()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Low enough to keep tests quick, while still well above the tones used
+    const RATE: f64 = 8000.0;
+
+    /// Stereo sine wave, the same in both channels, `level` decibels below
+    /// full scale.
+    fn sine(frequency: f64, level: f64, seconds: f64, phase: f64) -> Vec<f32> {
+        let amplitude = 10f64.powf(level / 20.0);
+        let frames = (seconds * RATE) as usize;
+        (0..frames)
+            .flat_map(|frame| {
+                let time = frame as f64 / RATE;
+                let sample = amplitude * (2.0 * PI * frequency * time + phase).sin();
+                [sample as f32; 2]
+            })
+            .collect()
+    }
+
+    fn measure(runs: &[Vec<f32>]) -> Measurement {
+        let mut meter = Meter::new(RATE, 2);
+        for run in runs {
+            meter.add(run);
+        }
+        meter.finish()
+    }
+
+    #[test]
+    fn steady_tone_is_as_loud_as_its_level() {
+        // A 1kHz tone at -23dBFS in both channels is -23 LUFS
+        let loudness = measure(&[sine(1000.0, -23.0, 5.0, 0.0)]).loudness();
+        let integrated = loudness.integrated.unwrap();
+        assert!((integrated + 23.0).abs() < 0.1, "{integrated}");
+        assert!(loudness.range.abs() < 0.1);
+        assert!((decibels(loudness.true_peak) + 23.0).abs() < 0.1);
+
+        // Much the same at other sample rates
+        let mut meter = Meter::new(44100.0, 1);
+        let samples: Vec<f32> = (0..44100)
+            .map(|frame| (2.0 * PI * 1000.0 * frame as f64 / 44100.0).sin() as f32)
+            .collect();
+        meter.add(&samples);
+        let integrated = meter.finish().loudness().integrated.unwrap();
+        assert!((integrated + 3.01).abs() < 0.1, "{integrated}");
+    }
+
+    #[test]
+    fn silence_and_quiet_passages_are_gated_out() {
+        let tone = sine(1000.0, -23.0, 10.0, 0.0);
+        let silence = vec![0.0; tone.len() / 2];
+        let quiet = sine(1000.0, -50.0, 5.0, 0.0);
+        let loudness = measure(&[tone, silence, quiet]).loudness();
+        let integrated = loudness.integrated.unwrap();
+        assert!((integrated + 23.0).abs() < 0.1, "{integrated}");
+
+        assert_eq!(measure(&[vec![0.0; 16000]]).loudness().integrated, None);
+    }
+
+    #[test]
+    fn range_spans_loud_and_quiet_parts() {
+        // Much as in EBU Tech 3342, a tone at -20dBFS then at -30dBFS
+        let loud = sine(1000.0, -20.0, 10.0, 0.0);
+        let quiet = sine(1000.0, -30.0, 10.0, 0.0);
+        let range = measure(&[loud, quiet]).loudness().range;
+        assert!((range - 10.0).abs() < 0.1, "{range}");
+    }
+
+    #[test]
+    fn true_peak_finds_peaks_between_samples() {
+        // A quarter of the sample rate, sampled half way up each side
+        let samples = sine(RATE / 4.0, 0.0, 1.0, PI / 4.0);
+        let sample_peak = samples.iter().fold(0f32, |peak, s| peak.max(s.abs()));
+        assert!((decibels(sample_peak as f64) + 3.01).abs() < 0.01);
+
+        let true_peak = decibels(measure(&[samples]).true_peak);
+        assert!(true_peak > -0.5, "{true_peak}");
+    }
+
+    #[test]
+    fn albums_are_measured_as_a_whole() {
+        let loud = measure(&[sine(1000.0, -10.0, 4.0, 0.0)]);
+        let quiet = measure(&[sine(1000.0, -20.0, 4.0, 0.0)]);
+        let album = Measurement::combine([&loud, &quiet]).loudness();
+        let integrated = album.integrated.unwrap();
+        // Mean power of -10 and -20, which is nearer the louder
+        assert!((integrated + 12.6).abs() < 0.1, "{integrated}");
+        assert!((decibels(album.true_peak) + 10.0).abs() < 0.1);
+    }
+}
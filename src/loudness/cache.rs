@@ -0,0 +1,248 @@
+//! Keeping loudness measurements alongside the tracks measured, so that they
+//! can be used again without reading the tracks all the way through.
+//!
+//! Each directory of tracks gets a `.afqueue-loudness` file, with a line for
+//! each track made up of tab separated fields:
+//!
+//! ```text
+//! name | start | size | modified | loudness | range | peak | album loudness | album range | album peak
+//! ```
+//!
+//! Tracks are picked out by file name along with the second they start at
+//! within the file, and their measurements only used while the file keeps the
+//! same size and modification time. Loudness is in LUFS, or `-` for silence,
+//! and peaks a fraction of full scale.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use super::Loudness;
+use crate::playlist::Track;
+
+const FILE_NAME: &str = ".afqueue-loudness";
+
+const HEADER: &str = "# Loudness measured by afqueue --save-loudness";
+const FIELD_COUNT: usize = 10;
+
+/// Loudness of a track, along with that of the album it was measured as part
+/// of.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Entry {
+    pub track: Loudness,
+    pub album: Loudness,
+}
+
+/// Size and modification time of a file, as a check it hasn't changed.
+type Stamp = (u64, u64);
+
+/// A line of the file, keyed by file name and start.
+type Lines = Vec<((String, String), String)>;
+
+/// The measurements saved for `track`, if there are any for it as it is now.
+pub fn load(track: &Track) -> Option<Entry> {
+    let path = Path::new(&track.path);
+    let (directory, name) = split(path)?;
+    let stamp = stamp(path).ok()?;
+    let text = fs::read_to_string(directory.join(FILE_NAME)).ok()?;
+    let key = (name.to_string(), start(track));
+    text.lines()
+        .filter_map(parse_line)
+        .find(|(found, found_stamp, _)| *found == key && *found_stamp == stamp)
+        .map(|(_, _, entry)| entry)
+}
+
+/// Save measurements for each track, in place of any saved before.
+pub fn save<'a>(entries: impl IntoIterator<Item = (&'a Track, Entry)>) -> io::Result<()> {
+    // Gather up the tracks in each directory, so each file is written once
+    let mut directories: BTreeMap<&Path, Lines> = BTreeMap::new();
+    for (track, entry) in entries {
+        let path = Path::new(&track.path);
+        // Names that would break up the line can't be saved
+        let Some((directory, name)) = split(path).filter(|(_, name)| !name.contains(['\t', '\n']))
+        else {
+            continue;
+        };
+        let key = (name.to_string(), start(track));
+        let line = format_line(&key, stamp(path)?, &entry);
+        directories.entry(directory).or_default().push((key, line));
+    }
+
+    for (directory, lines) in directories {
+        let path = directory.join(FILE_NAME);
+        let existing = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err),
+        };
+
+        // Keep what was saved for other tracks
+        let mut text = format!("{HEADER}\n");
+        for line in existing.lines() {
+            let kept = match parse_line(line) {
+                Some((key, _, _)) => !lines.iter().any(|(saving, _)| *saving == key),
+                None => false,
+            };
+            if kept {
+                text.push_str(line);
+                text.push('\n');
+            }
+        }
+        for (_, line) in lines {
+            text.push_str(&line);
+            text.push('\n');
+        }
+        fs::write(path, text)?;
+    }
+    Ok(())
+}
+
+fn split(path: &Path) -> Option<(&Path, &str)> {
+    let name = path.file_name()?.to_str()?;
+    let directory = path.parent()?;
+    Some((directory, name))
+}
+
+fn start(track: &Track) -> String {
+    track
+        .section
+        .map_or(0.0, |section| section.start)
+        .to_string()
+}
+
+fn stamp(path: &Path) -> io::Result<Stamp> {
+    let metadata = fs::metadata(path)?;
+    let modified = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    Ok((metadata.len(), modified))
+}
+
+fn format_line((name, start): &(String, String), stamp: Stamp, entry: &Entry) -> String {
+    let loudness = |loudness: &Loudness| {
+        let integrated = match loudness.integrated {
+            Some(integrated) => format!("{integrated:.2}"),
+            None => "-".to_string(),
+        };
+        format!(
+            "{integrated}\t{:.2}\t{:.6}",
+            loudness.range, loudness.true_peak
+        )
+    };
+    let (size, modified) = stamp;
+    let (track, album) = (loudness(&entry.track), loudness(&entry.album));
+    format!("{name}\t{start}\t{size}\t{modified}\t{track}\t{album}")
+}
+
+fn parse_line(line: &str) -> Option<((String, String), Stamp, Entry)> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    // Only the header is a comment, as names can start with '#' too
+    if line == HEADER || fields.len() != FIELD_COUNT {
+        return None;
+    }
+    let key = (fields[0].to_string(), fields[1].to_string());
+    let stamp = (fields[2].parse().ok()?, fields[3].parse().ok()?);
+    let loudness = |fields: &[&str]| {
+        let integrated = match fields[0] {
+            "-" => None,
+            integrated => Some(integrated.parse().ok()?),
+        };
+        Some(Loudness {
+            integrated,
+            range: fields[1].parse().ok()?,
+            true_peak: fields[2].parse().ok()?,
+        })
+    };
+    let entry = Entry {
+        track: loudness(&fields[4..7])?,
+        album: loudness(&fields[7..10])?,
+    };
+    Some((key, stamp, entry))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+    use crate::playlist::Section;
+
+    fn entry(track: Option<f64>, album: f64) -> Entry {
+        Entry {
+            track: Loudness {
+                integrated: track,
+                range: 4.5,
+                true_peak: 0.5,
+            },
+            album: Loudness {
+                integrated: Some(album),
+                range: 6.25,
+                true_peak: 0.75,
+            },
+        }
+    }
+
+    #[test]
+    fn measurements_are_saved_for_each_track() {
+        let dir = env::temp_dir().join(format!("afqueue_loudness_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("album.flac").to_str().unwrap().to_string();
+        fs::write(&path, b"audio").unwrap();
+
+        let first = Track::new(path.clone());
+        let second = Track {
+            section: Some(Section {
+                start: 180.5,
+                end: None,
+            }),
+            ..Track::new(path.clone())
+        };
+        save([(&first, entry(Some(-9.0), -10.0))]).unwrap();
+        save([(&second, entry(None, -10.0))]).unwrap();
+        // Saving again replaces what was there
+        save([(&first, entry(Some(-11.0), -10.0))]).unwrap();
+
+        assert_eq!(load(&first), Some(entry(Some(-11.0), -10.0)));
+        assert_eq!(load(&second), Some(entry(None, -10.0)));
+        let text = fs::read_to_string(dir.join(FILE_NAME)).unwrap();
+        assert_eq!(text.lines().count(), 3);
+
+        // Changing the file leaves its measurements behind
+        fs::write(&path, b"different audio").unwrap();
+        assert_eq!(load(&first), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn lines_that_do_not_fit_are_ignored() {
+        assert!(parse_line(HEADER).is_none());
+        assert!(parse_line("a.flac\t0\t5\t10\t-9.00\t4.50").is_none());
+        assert!(parse_line("a.flac\t0\t5\t10\tloud\t4.50\t0.5\t-9\t1\t0.5").is_none());
+
+        let line = format_line(
+            &("a.flac".to_string(), "0".to_string()),
+            (5, 10),
+            &entry(Some(-9.0), -10.0),
+        );
+        assert_eq!(
+            line,
+            "a.flac\t0\t5\t10\t-9.00\t4.50\t0.500000\t-10.00\t6.25\t0.750000"
+        );
+        let (_, stamp, parsed) = parse_line(&line).unwrap();
+        assert_eq!(stamp, (5, 10));
+        assert_eq!(parsed, entry(Some(-9.0), -10.0));
+    }
+
+    #[test]
+    fn names_can_start_with_a_hash() {
+        let key = ("#1 Hit.flac".to_string(), "0".to_string());
+        let line = format_line(&key, (5, 10), &entry(Some(-9.0), -10.0));
+
+        let (parsed, _, _) = parse_line(&line).unwrap();
+        assert_eq!(parsed, key);
+    }
+}
@@ -1,63 +1,326 @@
 //! Afqueue manages playback of a queue of audio files.
 //!
-//! Built on top of the macOS AudioToolbox framework.
+//! Built on top of the macOS AudioToolbox framework, with a silent fallback
+//! output for platforms where it isn't available.
 
 mod ffi {
+    #[cfg(target_os = "macos")]
     pub mod audio_toolbox;
+    #[cfg(target_os = "macos")]
     pub mod core_foundation;
+    #[cfg(target_os = "linux")]
+    pub mod epoll;
     pub mod ioctl;
+    #[cfg(target_os = "macos")]
     pub mod kqueue;
     pub mod termios;
+    pub mod unistd;
+}
+
+mod output {
+    #[cfg(target_os = "macos")]
+    pub mod audio_queue;
+    #[cfg_attr(target_os = "macos", allow(dead_code))]
+    pub mod null;
+    #[cfg(test)]
+    pub mod scripted;
+}
+
+mod source {
+    #[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+    pub mod ape;
+    #[cfg(target_os = "macos")]
+    pub mod audio_converter;
+    #[cfg(target_os = "macos")]
+    pub mod audio_file;
+    pub mod flac;
+    #[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+    pub mod mp3;
+    pub mod wav;
 }
 
 mod boombox;
+mod crossfade;
 mod error;
 mod events;
+mod keymap;
+mod loudness;
 mod player;
+mod playlist;
+mod prompt;
+mod replaygain;
+#[cfg(test)]
+mod temp_dir;
 mod ui;
 
-use boombox::Boombox;
-use error::AfqueueError;
+use boombox::{Boombox, Settings};
+use crossfade::FadeCurve;
+use error::{AfqueueError, ErrorContext, ErrorCtx};
+use keymap::Keymap;
+use loudness::{Loudness, Measurement};
+use playlist::{Format, PlayOrder, Playlist, Repeat, Shuffle, SkippedEntry, SortOrder, Track};
+use replaygain::GainMode;
 
-use std::ops::ControlFlow::Continue;
 use std::{env, process};
 
+/// What to do, as asked for on the command line.
+#[derive(Default)]
+struct Options {
+    settings: Settings,
+    /// Write the queue out to a playlist rather than playing it
+    save_playlist: Option<(String, Format)>,
+    /// Measure the loudness of the queue rather than playing it
+    scan: bool,
+    /// Keep the loudness measured alongside the tracks, for playing back
+    save_loudness: bool,
+    order: PlayOrder,
+}
+
 fn main() {
     let args = env::args();
-    let audio_file_paths = parse_args(args);
+    let (options, audio_file_paths) = parse_args(args);
+
+    let (tracks, skipped) = playlist::load_paths(audio_file_paths, &options.settings.scan)
+        .unwrap_or_else(|err| {
+            println!("{err}");
+            process::exit(1)
+        });
+    if tracks.is_empty() {
+        print_skipped(&skipped);
+        println!("Nothing to play");
+        process::exit(1);
+    }
 
-    play_audio_files(audio_file_paths).unwrap_or_else(|err| {
+    let playlist = Playlist::new(tracks, options.order);
+
+    if let Some((path, format)) = options.save_playlist {
+        print_skipped(&skipped);
+        playlist::save(&path, format, playlist.tracks())
+            .map_err(AfqueueError::from)
+            .with(ErrorCtx::SavingPlaylist(path))
+            .unwrap_or_else(|err| {
+                println!("{err}");
+                process::exit(1)
+            });
+        return;
+    }
+
+    if options.scan {
+        print_skipped(&skipped);
+        scan_loudness(playlist.tracks(), options.save_loudness).unwrap_or_else(|err| {
+            println!("{err}");
+            process::exit(1)
+        });
+        return;
+    }
+
+    let result = play_audio_files(options.settings, playlist);
+    // Leave these until the UI has gone, so they aren't drawn over
+    print_skipped(&skipped);
+    result.unwrap_or_else(|err| {
         println!("{err}");
         process::exit(1)
     });
 }
 
 /// Parse arguments or print help message if supplied invalid input.
-fn parse_args(args: impl IntoIterator<Item = String>) -> impl Iterator<Item = String> {
+fn parse_args(args: impl IntoIterator<Item = String>) -> (Options, impl Iterator<Item = String>) {
     let mut args = args.into_iter().peekable();
     let exec = args.next();
+    let exec = exec.as_deref().unwrap_or("afqueue");
+    let mut options = Options::default();
+
+    // Options come before any files
+    while let Some(option) = args.next_if(|arg| arg.starts_with("--")) {
+        let mut value = || args.next().unwrap_or_else(|| print_usage_and_exit(exec));
+        match option.as_str() {
+            "--seek-step" => match value().parse::<f64>() {
+                Ok(step) if step > 0.0 => options.settings.seek_step = step,
+                _ => print_usage_and_exit(exec),
+            },
+            "--save-playlist" => {
+                let path = value();
+                match Format::of(&path) {
+                    Some(Format::Cue) | None => print_usage_and_exit(exec),
+                    Some(format) => options.save_playlist = Some((path, format)),
+                }
+            }
+            "--extensions" => {
+                let extensions = value().to_ascii_lowercase();
+                let extensions = extensions.split(',').map(|ext| ext.trim_start_matches('.'));
+                options.settings.scan.extensions = extensions.map(str::to_string).collect();
+            }
+            "--scan" => options.scan = true,
+            "--save-loudness" => {
+                options.scan = true;
+                options.save_loudness = true;
+            }
+            "--sniff" => options.settings.scan.sniff = true,
+            "--sort-by-tags" => options.settings.scan.sort = SortOrder::Tags,
+            "--shuffle" => options.order.shuffle = Shuffle::Tracks,
+            "--shuffle-albums" => options.order.shuffle = Shuffle::Albums,
+            "--repeat" => match value().as_str() {
+                "all" => options.order.repeat = Repeat::All,
+                "one" => options.order.repeat = Repeat::One,
+                _ => print_usage_and_exit(exec),
+            },
+            "--seed" => match value().parse() {
+                Ok(seed) => options.order.seed = Some(seed),
+                _ => print_usage_and_exit(exec),
+            },
+            "--crossfade" => match value().parse::<f64>() {
+                Ok(seconds) if seconds > 0.0 => {
+                    options.settings.crossfade.enabled = true;
+                    options.settings.crossfade.seconds = seconds;
+                }
+                _ => print_usage_and_exit(exec),
+            },
+            "--crossfade-curve" => match FadeCurve::from_name(&value()) {
+                Some(curve) => options.settings.crossfade.curve = curve,
+                None => print_usage_and_exit(exec),
+            },
+            "--volume-step" => match value().parse::<f64>() {
+                Ok(step) if step > 0.0 && step.is_finite() => options.settings.volume_step = step,
+                _ => print_usage_and_exit(exec),
+            },
+            "--replay-gain" => match GainMode::from_name(&value()) {
+                Some(mode) => options.settings.replay_gain = mode,
+                None => print_usage_and_exit(exec),
+            },
+            "--fallback-preamp" => match value().parse::<f64>() {
+                Ok(decibels) if decibels.is_finite() => {
+                    options.settings.fallback_preamp = decibels;
+                }
+                _ => print_usage_and_exit(exec),
+            },
+            _ => print_usage_and_exit(exec),
+        }
+    }
+
     if args.peek().is_none() {
-        let exec = exec.as_deref().unwrap_or("afqueue");
-        println!("Usage: {exec} [audio-file ...]");
-        process::exit(1);
+        print_usage_and_exit(exec);
+    }
+    (options, args)
+}
+
+fn print_usage_and_exit(exec: &str) -> ! {
+    println!("Usage: {exec} [options] [audio-file | playlist | directory ...]");
+    println!();
+    println!("Options:");
+    println!("  --seek-step seconds       Time to move by when seeking");
+    println!("  --save-playlist playlist  Write the queue to a playlist instead of playing");
+    println!("  --extensions ext,...      Audio files to pick out of directories");
+    println!("  --sniff                   Pick out audio files by content, not extension");
+    println!("  --sort-by-tags            Order files in directories by disc and track");
+    println!("  --shuffle                 Play tracks in a random order");
+    println!("  --shuffle-albums          Play albums in a random order, each in order");
+    println!("  --repeat all|one          Repeat the whole queue, or the current track");
+    println!("  --seed number             Seed for shuffling, to repeat a shuffle");
+    println!("  --crossfade seconds       Fade each track into the next over this long");
+    println!("  --crossfade-curve curve   One of equal-power, linear or logarithmic");
+    println!("  --volume-step dB          Decibels to turn the volume up or down by");
+    println!("  --replay-gain mode        Normalise loudness: off, track, album or auto");
+    println!("  --fallback-preamp dB      Gain for tracks without ReplayGain tags, which");
+    println!("                            like any gain can't go past full volume");
+    println!("  --scan                    Measure loudness instead of playing");
+    println!("  --save-loudness           Measure loudness, saving it for playback");
+    println!();
+    println!("Playlists can be M3U, M3U8, PLS, XSPF or CUE files, though CUE can't be saved");
+    process::exit(1);
+}
+
+fn print_skipped(skipped: &[SkippedEntry]) {
+    for entry in skipped {
+        println!("Skipped {entry}");
     }
-    args
 }
 
-fn play_audio_files(paths: impl IntoIterator<Item = String>) -> Result<(), AfqueueError> {
-    let mut boombox = Boombox::initialise()?;
+/// Print the loudness of each track, and of each album as a whole where there
+/// are several tracks from one in a row.
+fn scan_loudness(tracks: &[Track], save: bool) -> Result<(), AfqueueError> {
+    println!("{:>8} {:>6} {:>7}  Track", "LUFS", "LU", "dBTP");
+    let mut saving = Vec::new();
+    let mut skipped = 0;
+    for album in tracks.chunk_by(playlist::same_album) {
+        let mut measured = Vec::new();
+        for track in album {
+            match Measurement::of(track) {
+                Ok(measurement) => {
+                    print_loudness(&measurement.loudness(), track.name());
+                    measured.push((track, measurement));
+                }
+                Err(err) => println!(
+                    "Skipped '{}', as it couldn't be measured: {err}",
+                    track.path
+                ),
+            }
+        }
+        let album_loudness = Measurement::combine(measured.iter().map(|(_, m)| m)).loudness();
+        // Skipped tracks are left out of the album as a whole
+        let left_out = album.len() - measured.len();
+        skipped += left_out;
+        let name = match left_out {
+            0 => "Album".to_string(),
+            _ => format!("Album, without the {left_out} skipped"),
+        };
+        if measured.len() > 1 {
+            print_loudness(&album_loudness, &name);
+        }
+        println!();
+
+        saving.extend(measured.iter().map(|(track, measurement)| {
+            let entry = loudness::cache::Entry {
+                track: measurement.loudness(),
+                album: album_loudness,
+            };
+            (*track, entry)
+        }));
+    }
 
-    let mut result = Ok(Continue(()));
-    let mut paths = paths.into_iter();
+    match skipped {
+        0 => {}
+        1 => println!("Skipped 1 of {} tracks", tracks.len()),
+        skipped => println!("Skipped {skipped} of {} tracks", tracks.len()),
+    }
 
-    while let (Some(path), Ok(Continue(()))) = (paths.next(), &result) {
-        result = boombox.play_file(&path);
+    if save {
+        loudness::cache::save(saving)
+            .map_err(AfqueueError::from)
+            .with(ErrorCtx::SavingLoudness)?;
     }
+    Ok(())
+}
+
+fn print_loudness(loudness: &Loudness, name: &str) {
+    let integrated = match loudness.integrated {
+        Some(integrated) => format!("{integrated:.2}"),
+        None => "-".to_string(),
+    };
+    let true_peak = loudness::decibels(loudness.true_peak);
+    println!(
+        "{integrated:>8} {:>6.2} {true_peak:>7.2}  {name}",
+        loudness.range
+    );
+}
+
+fn play_audio_files(settings: Settings, playlist: Playlist) -> Result<(), AfqueueError> {
+    let keymap = load_keymap()?;
+    let mut boombox: Boombox = Boombox::initialise(settings, keymap, playlist)?;
+
+    let result = boombox.play_queue();
 
     // We are much more likely to encouter a playback error than a UI error, so we
     // try and deactive the UI first so playback errors can be printed normally
     boombox.shutdown()?;
 
-    // We only want to return the error, not control flow
-    result.map(|_| ())
+    result
+}
+
+fn load_keymap() -> Result<Keymap, AfqueueError> {
+    let Some(path) = keymap::config_path() else {
+        return Ok(Keymap::default());
+    };
+    Keymap::load(&path)
+        .map_err(AfqueueError::from)
+        .with(ErrorCtx::LoadingKeymap(path.display().to_string()))
 }
@@ -0,0 +1,574 @@
+//! Audio output backed by an AudioToolbox audio queue.
+
+use std::ffi::c_void;
+use std::mem::{self, MaybeUninit};
+use std::ptr;
+use std::slice;
+
+use crate::ffi::audio_toolbox::{
+    self, audio_queue_get_current_time, AudioQueueBufferRef, AudioQueueLevelMeterState,
+    AudioQueueParameterEvent, AudioQueuePropertyID, AudioQueueRef, AudioStreamBasicDescription,
+    AudioTimeStamp,
+};
+use crate::player::{
+    AudioCallbackHandler, AudioOutput, PacketBuffer, PacketDescription, PlaybackResult,
+    StreamFormat, SystemErrorCode, SystemResult,
+};
+
+const BUFFER_COUNT: usize = 3;
+
+const AUDIO_QUEUE_RUN_STATE_STOPPED: u32 = 0;
+
+pub struct AudioQueueOutput {
+    output_queue: AudioQueueRef,
+    handler: *mut AudioCallbackHandler,
+    buffers: Vec<AudioQueueBufferRef>,
+    sample_rate: f64,
+    // Seconds between the queue's timeline and the position in the file,
+    // which drift apart after seeking.
+    time_offset: f64,
+    //TODO: Assert somehow that this is at least > 1
+    meter_state: Box<[AudioQueueLevelMeterState]>,
+}
+
+impl AudioOutput for AudioQueueOutput {
+    unsafe fn new(handler: *mut AudioCallbackHandler) -> PlaybackResult<Self> {
+        let handler_ptr = handler as *mut c_void;
+        let format = AudioStreamBasicDescription::from((*handler).format());
+        let output_queue = output_queue_create(&format, handler_ptr)?;
+
+        let packet_descriptions = (*handler).packet_descriptions_per_buffer();
+        let buffer_size = (*handler).buffer_size();
+        let buffers = create_buffers(output_queue, packet_descriptions, buffer_size)?;
+
+        if let Some(cookie) = (*handler).magic_cookie()? {
+            audio_queue_set_magic_cookie(output_queue, cookie)?
+        }
+
+        audio_queue_listen_to_run_state(output_queue, handler_ptr)?;
+
+        // TODO: Do this on start?
+        // While handle_buffer is usually invoked from the output queues internal
+        // callback thread to refill a buffer, we call it a few times before
+        // starting to pre load the buffers with audio. This means that any
+        // error during pre-buffering is not directly surfaced here, but
+        // reported back via the handler as an error event.
+        // For small files, some buffers might remain unused.
+        for &buffer_ref in &buffers {
+            handle_buffer(handler_ptr, output_queue, buffer_ref);
+        }
+
+        let default_meter = AudioQueueLevelMeterState::default();
+        let meter_count = format.channels_per_frame as usize;
+        let meters = vec![default_meter; meter_count];
+
+        Ok(AudioQueueOutput {
+            output_queue,
+            handler,
+            buffers,
+            sample_rate: format.sample_rate,
+            time_offset: 0.0,
+            meter_state: meters.into_boxed_slice(),
+        })
+    }
+
+    fn start(&mut self) -> PlaybackResult<()> {
+        audio_queue_enable_metering(self.output_queue)?;
+        audio_queue_start(self.output_queue)?;
+        Ok(())
+    }
+
+    fn pause(&mut self) -> PlaybackResult<()> {
+        audio_queue_pause(self.output_queue)?;
+        Ok(())
+    }
+
+    fn resume(&mut self) -> PlaybackResult<()> {
+        audio_queue_start(self.output_queue)?;
+        Ok(())
+    }
+
+    fn stop(&mut self) -> PlaybackResult<()> {
+        // Stop the queue synchronously
+        audio_queue_stop(self.output_queue, true)?;
+        Ok(())
+    }
+
+    fn set_volume(&mut self, gain: f32) -> PlaybackResult<()> {
+        audio_queue_set_volume(self.output_queue, gain)?;
+        Ok(())
+    }
+
+    fn get_meter_level(&mut self) -> PlaybackResult<[f32; 2]> {
+        audio_queue_read_meter_level(self.output_queue, &mut self.meter_state)?;
+
+        // TODO: Panic (or maybe err?) if meter_state.len() is 0
+        let mut levels = self.meter_state.iter().map(|chan| chan.average_power);
+        Ok(match (levels.next(), levels.next()) {
+            (Some(chan_1), None) => [chan_1, chan_1],
+            (Some(chan_1), Some(chan_2)) => [chan_1, chan_2],
+            _ => [0.0, 0.0], // This shouldn't happen
+        })
+    }
+
+    fn get_playback_time(&mut self) -> PlaybackResult<Option<f64>> {
+        let time = audio_queue_read_current_sample_time(self.output_queue)?;
+        let time = time.map(|t| t / self.sample_rate + self.time_offset);
+        Ok(time)
+    }
+
+    fn seek(&mut self, time: f64) -> PlaybackResult<()> {
+        unsafe {
+            // Resetting hands back every enqueued buffer via the callback. These
+            // are left empty while flushing, and refilled from the new position
+            // below.
+            (*self.handler).set_flushing(true);
+            let reset = audio_queue_reset(self.output_queue);
+            (*self.handler).set_flushing(false);
+            reset?;
+
+            // With nothing enqueued, the callback wont fire so the handler is
+            // safe to modify.
+            // NOTE: If the end of the file had already been read, the queue
+            // will have been asked to stop once it runs dry. So seeking in
+            // the last few buffers of a file will just end playback.
+            let position = (*self.handler).seek(time)?;
+
+            // Carry on the playback time from the new position, regardless of
+            // what resetting did to the queue's timeline.
+            let queue_time = audio_queue_read_current_sample_time(self.output_queue)?;
+            self.time_offset = position - queue_time.unwrap_or(0.0) / self.sample_rate;
+
+            let handler_ptr = self.handler as *mut c_void;
+            for &buffer_ref in &self.buffers {
+                handle_buffer(handler_ptr, self.output_queue, buffer_ref);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AudioQueueOutput {
+    fn drop(&mut self) {
+        // Dispose of the queue synchronously
+        audio_queue_dispose(self.output_queue, true).expect("Failed to dispose of audio queue");
+    }
+}
+
+impl From<&StreamFormat> for AudioStreamBasicDescription {
+    fn from(format: &StreamFormat) -> Self {
+        AudioStreamBasicDescription {
+            sample_rate: format.sample_rate,
+            format_id: format.format_id,
+            format_flags: format.format_flags,
+            bytes_per_packet: format.bytes_per_packet,
+            frames_per_packet: format.frames_per_packet,
+            bytes_per_frame: format.bytes_per_frame,
+            channels_per_frame: format.channels_per_frame,
+            bits_per_channel: format.bits_per_channel,
+            reserved: 0,
+        }
+    }
+}
+
+// TODO: Should always we ask for more packets than buffer can hold to ensure
+// the buffer gets fully used?
+//
+// We could calculate the max packets per buffer instead of minimum? I.e
+// optimistic instead of pessamistic.
+//
+// This would possibly take advantage of the properties AudioFileReadPacketData
+// has over AudioFileReadPackets?
+//
+// We would still need an upper limit (and overallocate) the
+// packet_descriptions.
+//
+// Is there somehow we could test this by detecting underutilized buffers?
+// The queue could continue to callback with remaining buffers.
+// Avoid unnecessary attempts to read the file again.
+
+// This handler assumes `buffer` adheres to several invarients:
+// - Is big enough to hold the handlers packets per buffer
+// - Was allocated with packet descriptions (if needed)
+// - Belong to `audio_queue`
+extern "C" fn handle_buffer(
+    user_data: *mut c_void,
+    audio_queue: AudioQueueRef,
+    buffer_ref: AudioQueueBufferRef,
+) {
+    unsafe {
+        let handler = &mut *(user_data as *mut AudioCallbackHandler);
+        let buffer = &mut *buffer_ref;
+
+        // Buffers returned while flushing are refilled once the flush is over
+        if handler.is_finished() || handler.is_flushing() {
+            return;
+        }
+
+        // PacketDescription shares its layout with AudioStreamPacketDescription
+        let packet_descriptions = match buffer.packet_description_capacity {
+            0 => None,
+            capacity => Some(slice::from_raw_parts_mut(
+                buffer.packet_descriptions as *mut PacketDescription,
+                capacity as usize,
+            )),
+        };
+
+        let mut packet_buffer = PacketBuffer {
+            data: slice::from_raw_parts_mut(
+                buffer.audio_data as *mut u8,
+                buffer.audio_data_bytes_capacity as usize,
+            ),
+            byte_size: 0,
+            packet_descriptions,
+            packet_description_count: 0,
+            trim_start: 0,
+            trim_end: 0,
+        };
+
+        let packets_read = handler.read_packets(&mut packet_buffer);
+
+        if packets_read == 0 {
+            // Request an asynchronous stop so that buffered audio can finish playing.
+            // Queue stopping is detected via seperate callback to property listener.
+            //TODO: Handle and report Error
+            audio_queue_stop(audio_queue, false).expect("oh no");
+            return;
+        }
+
+        buffer.audio_data_byte_size = packet_buffer.byte_size;
+        buffer.packet_description_count = packet_buffer.packet_description_count;
+        let trim = (packet_buffer.trim_start, packet_buffer.trim_end);
+        let volume = handler.take_volume_change();
+
+        match audio_queue_enqueue_buffer(audio_queue, buffer_ref, trim, volume) {
+            Ok(()) => {}
+            // Attempting to enqueue during reset can be expected when the user
+            // has stopped the queue before playback has finished.
+            Err(SystemErrorCode(audio_toolbox::AUDIO_QUEUE_ERROR_ENQUEUE_DURING_RESET)) => {
+                handler.finish();
+            }
+            // Anything else is probably a legitimate error condition
+            Err(SystemErrorCode(_code)) => {
+                //TODO: Report error
+                handler.finish();
+            }
+        }
+    }
+}
+
+extern "C" fn handle_running_state_change(
+    user_data: *mut c_void,
+    audio_queue: AudioQueueRef,
+    property: AudioQueuePropertyID,
+) {
+    // The handler should only react to changes to the "is running" property
+    assert!(property == audio_toolbox::AUDIO_QUEUE_PROPERTY_IS_RUNNING);
+
+    unsafe {
+        let handler = &mut *(user_data as *mut AudioCallbackHandler);
+        match audio_queue_read_run_state(audio_queue) {
+            Ok(AUDIO_QUEUE_RUN_STATE_STOPPED) => handler.notify_playback_finished(),
+            // Any other value is the queue starting
+            Ok(_) => handler.notify_playback_started(),
+            //TODO: Feed back error to controller?
+            Err(error) => panic!("booooo!: {error}\r\n"),
+        }
+    }
+}
+
+fn create_buffers(
+    output_queue: AudioQueueRef,
+    packet_descriptions: u32,
+    buffer_size: u32,
+) -> SystemResult<Vec<AudioQueueBufferRef>> {
+    unsafe {
+        vec![MaybeUninit::uninit(); BUFFER_COUNT]
+            .into_iter()
+            //TODO: Can we allocate buffers _without_ packet descriptions if we dont need them?
+            .map(|mut buffer_ref| {
+                let status = audio_toolbox::audio_queue_allocate_buffer_with_packet_descriptions(
+                    output_queue,
+                    buffer_size,
+                    packet_descriptions,
+                    buffer_ref.as_mut_ptr(),
+                );
+
+                if status == 0 {
+                    Ok(buffer_ref.assume_init())
+                } else {
+                    Err(SystemErrorCode(status))
+                }
+            })
+            .collect()
+    }
+}
+
+fn output_queue_create(
+    format: *const AudioStreamBasicDescription,
+    user_data: *mut c_void,
+) -> SystemResult<AudioQueueRef> {
+    unsafe {
+        let mut output_queue = MaybeUninit::uninit();
+        let status = audio_toolbox::audio_queue_new_output(
+            format,
+            handle_buffer,
+            user_data,
+            std::ptr::null(), // Run loop
+            std::ptr::null(), // Run loop mode
+            0,                // flags
+            output_queue.as_mut_ptr(),
+        );
+
+        if status != 0 {
+            return Err(SystemErrorCode(status));
+        }
+        let output_queue = output_queue.assume_init();
+        Ok(output_queue)
+    }
+}
+
+fn audio_queue_set_magic_cookie(queue: AudioQueueRef, cookie: Vec<u8>) -> SystemResult<()> {
+    unsafe {
+        let status = audio_toolbox::audio_queue_set_property(
+            queue,
+            audio_toolbox::AUDIO_QUEUE_PROPERTY_MAGIC_COOKIE_DATA,
+            cookie.as_ptr() as *const c_void,
+            cookie.len() as u32,
+        );
+
+        if status == 0 {
+            Ok(())
+        } else {
+            Err(SystemErrorCode(status))
+        }
+    }
+}
+
+fn audio_queue_start(queue: AudioQueueRef) -> SystemResult<()> {
+    unsafe {
+        let status = audio_toolbox::audio_queue_start(queue, ptr::null());
+
+        if status == 0 {
+            Ok(())
+        } else {
+            Err(SystemErrorCode(status))
+        }
+    }
+}
+
+fn audio_queue_stop(queue: AudioQueueRef, immediate: bool) -> SystemResult<()> {
+    unsafe {
+        let status = audio_toolbox::audio_queue_stop(queue, immediate);
+
+        if status == 0 {
+            Ok(())
+        } else {
+            Err(SystemErrorCode(status))
+        }
+    }
+}
+
+fn audio_queue_reset(queue: AudioQueueRef) -> SystemResult<()> {
+    unsafe {
+        let status = audio_toolbox::audio_queue_reset(queue);
+
+        if status == 0 {
+            Ok(())
+        } else {
+            Err(SystemErrorCode(status))
+        }
+    }
+}
+
+fn audio_queue_dispose(queue: AudioQueueRef, immediate: bool) -> SystemResult<()> {
+    unsafe {
+        let status = audio_toolbox::audio_queue_dispose(queue, immediate);
+
+        if status == 0 {
+            Ok(())
+        } else {
+            Err(SystemErrorCode(status))
+        }
+    }
+}
+
+fn audio_queue_pause(queue: AudioQueueRef) -> SystemResult<()> {
+    unsafe {
+        let status = audio_toolbox::audio_queue_pause(queue);
+
+        if status == 0 {
+            Ok(())
+        } else {
+            Err(SystemErrorCode(status))
+        }
+    }
+}
+
+/// Enqueue `buffer`, skipping over the number of frames given by `trim` at
+/// its start and end, and switching to `volume` as it starts playing.
+fn audio_queue_enqueue_buffer(
+    queue: AudioQueueRef,
+    buffer: AudioQueueBufferRef,
+    (trim_start, trim_end): (u32, u32),
+    volume: Option<f32>,
+) -> SystemResult<()> {
+    let event = volume.map(|value| AudioQueueParameterEvent {
+        id: audio_toolbox::AUDIO_QUEUE_PARAMETER_VOLUME,
+        value,
+    });
+    let events = event.as_slice();
+    unsafe {
+        let status = match (trim_start, trim_end, events.len()) {
+            (0, 0, 0) => audio_toolbox::audio_queue_enqueue_buffer(
+                queue,
+                buffer,
+                // Packet descriptions are supplied via buffer itself
+                0,
+                ptr::null(),
+            ),
+            _ => audio_toolbox::audio_queue_enqueue_buffer_with_parameters(
+                queue,
+                buffer,
+                0,
+                ptr::null(),
+                trim_start,
+                trim_end,
+                events.len() as u32,
+                events.as_ptr(),
+                // No particular time to start
+                ptr::null(),
+                ptr::null_mut(),
+            ),
+        };
+
+        if status == 0 {
+            Ok(())
+        } else {
+            Err(SystemErrorCode(status))
+        }
+    }
+}
+
+fn audio_queue_listen_to_run_state(
+    queue: AudioQueueRef,
+    user_data: *mut c_void,
+) -> SystemResult<()> {
+    unsafe {
+        let status = audio_toolbox::audio_queue_add_property_listener(
+            queue,
+            audio_toolbox::AUDIO_QUEUE_PROPERTY_IS_RUNNING,
+            handle_running_state_change,
+            user_data,
+        );
+        if status == 0 {
+            Ok(())
+        } else {
+            Err(SystemErrorCode(status))
+        }
+    }
+}
+
+fn audio_queue_read_run_state(queue: AudioQueueRef) -> SystemResult<u32> {
+    unsafe {
+        let mut data = MaybeUninit::<u32>::uninit();
+        let mut data_size = mem::size_of::<u32>() as u32;
+
+        let status = audio_toolbox::audio_queue_get_property(
+            queue,
+            audio_toolbox::AUDIO_QUEUE_PROPERTY_IS_RUNNING,
+            data.as_mut_ptr() as *mut c_void,
+            &mut data_size as *mut _,
+        );
+
+        let data = data.assume_init();
+
+        if status != 0 {
+            return Err(SystemErrorCode(status));
+        }
+
+        // audio_queue_get_property outputs the number of bytes written to data_size
+        // Check to see if this is correct
+        assert!(data_size == mem::size_of::<u32>() as u32);
+
+        Ok(data)
+    }
+}
+
+fn audio_queue_set_volume(queue: AudioQueueRef, gain: f32) -> SystemResult<()> {
+    unsafe {
+        let status = audio_toolbox::audio_queue_set_parameter(
+            queue,
+            audio_toolbox::AUDIO_QUEUE_PARAMETER_VOLUME,
+            gain,
+        );
+
+        if status == 0 {
+            Ok(())
+        } else {
+            Err(SystemErrorCode(status))
+        }
+    }
+}
+
+fn audio_queue_enable_metering(queue: AudioQueueRef) -> SystemResult<()> {
+    unsafe {
+        let enabled: u32 = 1;
+
+        let status = audio_toolbox::audio_queue_set_property(
+            queue,
+            audio_toolbox::AUDIO_QUEUE_PROPERTY_ENABLE_LEVEL_METERING,
+            &enabled as *const _ as *const c_void,
+            mem::size_of::<u32>() as u32,
+        );
+
+        if status == 0 {
+            Ok(())
+        } else {
+            Err(SystemErrorCode(status))
+        }
+    }
+}
+
+fn audio_queue_read_meter_level(
+    queue: AudioQueueRef,
+    meter_state: &mut Box<[AudioQueueLevelMeterState]>,
+) -> SystemResult<()> {
+    unsafe {
+        let meter_size = mem::size_of::<AudioQueueLevelMeterState>();
+        let expected_size = (meter_size * meter_state.len()) as u32;
+        let mut data_size = expected_size;
+
+        let status = audio_toolbox::audio_queue_get_property(
+            queue,
+            audio_toolbox::AUDIO_QUEUE_PROPERTY_LEVEL_METER_STATE,
+            meter_state.as_mut_ptr() as *mut c_void,
+            &mut data_size as *mut _,
+        );
+
+        if status != 0 {
+            return Err(SystemErrorCode(status));
+        }
+
+        assert!(data_size == expected_size);
+        Ok(())
+    }
+}
+
+fn audio_queue_read_current_sample_time(queue: AudioQueueRef) -> SystemResult<Option<f64>> {
+    //TODO: Check this isnt problematically large to repetedly zero
+    let mut timestamp = AudioTimeStamp::default();
+    unsafe {
+        let status =
+            audio_queue_get_current_time(queue, ptr::null(), &mut timestamp, ptr::null_mut());
+
+        // If the queue isn't running, it has no playback time
+        if status == audio_toolbox::AUDIO_QUEUE_ERROR_INVALID_RUN_STATE {
+            return Ok(None);
+        }
+        if status != 0 {
+            return Err(SystemErrorCode(status));
+        }
+    }
+    Ok(Some(timestamp.sample_time))
+}
@@ -0,0 +1,470 @@
+//! Audio output that discards audio instead of playing it.
+//!
+//! Packets are consumed from a background thread at the same rate that real
+//! hardware would play them back, so that playback time, metering and
+//! started / finished notifications all behave as they would for a real
+//! device. This allows the rest of afqueue to run on machines without audio
+//! hardware, or without AudioToolbox.
+
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::player::{
+    count_frames, decode_sample, AudioCallbackHandler, AudioOutput, PacketBuffer,
+    PacketDescription, PlaybackResult, StreamFormat,
+};
+
+// Audio is played out in small slices to keep the playback time and meter
+// responsive, rather than jumping a whole buffer at a time.
+const SLICES_PER_SECOND: f64 = 100.0;
+
+pub struct NullOutput {
+    handler: HandlerPtr,
+    format: StreamFormat,
+    buffer_size: u32,
+    packet_descriptions: u32,
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+// The output thread needs to take the raw handler pointer with it.
+struct HandlerPtr(*mut AudioCallbackHandler);
+
+// SAFETY: The handler is only ever accessed from the output thread once it has
+// been spawned, and the thread is always joined before the output is dropped.
+unsafe impl Send for HandlerPtr {}
+
+impl HandlerPtr {
+    // Taking the pointer out via a method ensures closures capture the whole
+    // Send wrapper, rather than just the raw pointer inside it.
+    fn into_inner(self) -> *mut AudioCallbackHandler {
+        self.0
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    running: bool,
+    paused: bool,
+    stop_requested: bool,
+    seek_requested: Option<f64>,
+    gain: f32,
+    frames_played: u64,
+    levels: [f32; 2],
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("null output state poisoned")
+    }
+}
+
+impl AudioOutput for NullOutput {
+    unsafe fn new(handler: *mut AudioCallbackHandler) -> PlaybackResult<Self> {
+        let shared = Shared::default();
+        shared.lock().gain = 1.0;
+
+        Ok(NullOutput {
+            format: *(*handler).format(),
+            buffer_size: (*handler).buffer_size(),
+            packet_descriptions: (*handler).packet_descriptions_per_buffer(),
+            handler: HandlerPtr(handler),
+            shared: Arc::new(shared),
+            thread: None,
+        })
+    }
+
+    fn start(&mut self) -> PlaybackResult<()> {
+        if self.thread.is_some() {
+            return Ok(());
+        }
+
+        let handler = HandlerPtr(self.handler.0);
+        let playout = Playout {
+            format: self.format,
+            data: vec![0; self.buffer_size as usize],
+            descriptions: vec![PacketDescription::default(); self.packet_descriptions as usize],
+            shared: self.shared.clone(),
+        };
+
+        self.shared.lock().running = true;
+        let thread = thread::Builder::new()
+            .name("null-output".to_string())
+            .spawn(move || {
+                // SAFETY: See `AudioOutput::new`
+                playout.run(unsafe { &mut *handler.into_inner() })
+            })?;
+        self.thread = Some(thread);
+        Ok(())
+    }
+
+    fn pause(&mut self) -> PlaybackResult<()> {
+        self.shared.lock().paused = true;
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+
+    fn resume(&mut self) -> PlaybackResult<()> {
+        self.shared.lock().paused = false;
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+
+    fn stop(&mut self) -> PlaybackResult<()> {
+        self.shared.lock().stop_requested = true;
+        self.shared.changed.notify_all();
+        if let Some(thread) = self.thread.take() {
+            thread.join().expect("null output thread panicked");
+        }
+        Ok(())
+    }
+
+    fn set_volume(&mut self, gain: f32) -> PlaybackResult<()> {
+        self.shared.lock().gain = gain;
+        Ok(())
+    }
+
+    fn get_meter_level(&mut self) -> PlaybackResult<[f32; 2]> {
+        Ok(self.shared.lock().levels)
+    }
+
+    fn get_playback_time(&mut self) -> PlaybackResult<Option<f64>> {
+        let state = self.shared.lock();
+        if !state.running {
+            return Ok(None);
+        }
+        Ok(Some(state.frames_played as f64 / self.format.sample_rate))
+    }
+
+    fn seek(&mut self, time: f64) -> PlaybackResult<()> {
+        let mut state = self.shared.lock();
+        if !state.running {
+            return Ok(());
+        }
+        state.seek_requested = Some(time);
+        self.shared.changed.notify_all();
+
+        // Wait for the output thread to pick up the new position, so that the
+        // playback time is correct as soon as this returns.
+        while state.running && state.seek_requested.is_some() {
+            state = self
+                .shared
+                .changed
+                .wait(state)
+                .expect("null output state poisoned");
+        }
+        Ok(())
+    }
+}
+
+impl Drop for NullOutput {
+    fn drop(&mut self) {
+        self.stop().expect("Failed to stop null output");
+    }
+}
+
+/// State owned by the output thread while it plays out a file.
+struct Playout {
+    format: StreamFormat,
+    data: Vec<u8>,
+    descriptions: Vec<PacketDescription>,
+    shared: Arc<Shared>,
+}
+
+impl Playout {
+    fn run(mut self, handler: &mut AudioCallbackHandler) {
+        handler.notify_playback_started();
+
+        let slice_frames = ((self.format.sample_rate / SLICES_PER_SECOND) as usize).max(1);
+
+        'playback: loop {
+            let mut buffer = PacketBuffer {
+                data: &mut self.data,
+                byte_size: 0,
+                packet_descriptions: match self.descriptions.len() {
+                    0 => None,
+                    _ => Some(&mut self.descriptions),
+                },
+                packet_description_count: 0,
+                trim_start: 0,
+                trim_end: 0,
+            };
+
+            let packets = handler.read_packets(&mut buffer);
+            if packets == 0 {
+                break;
+            }
+            if let Some(gain) = handler.take_volume_change() {
+                self.shared.lock().gain = gain;
+            }
+
+            let frames = count_frames(&self.format, packets, &buffer);
+            let trim = buffer.trim_start as usize;
+            let byte_size = buffer.byte_size as usize;
+            let pcm = match self.format.is_linear_pcm() && self.format.bytes_per_frame > 0 {
+                true => Some(&buffer.data[..byte_size]),
+                false => None,
+            };
+
+            let mut frame = 0;
+            while frame < frames {
+                let count = slice_frames.min(frames - frame);
+                let duration = Duration::from_secs_f64(count as f64 / self.format.sample_rate);
+
+                let levels = match pcm {
+                    Some(pcm) => measure_levels(&self.format, pcm, trim + frame, count),
+                    None => [0.0, 0.0],
+                };
+
+                match wait(&self.shared, duration) {
+                    None => {}
+                    Some(Interruption::Stop) => break 'playback,
+                    Some(Interruption::Seek(time)) => {
+                        // Abandon the rest of the buffer, and pick up from the
+                        // new position instead
+                        let position = handler.seek(time);
+                        let mut state = self.shared.lock();
+                        state.seek_requested = None;
+                        self.shared.changed.notify_all();
+                        match position {
+                            Ok(position) => {
+                                let frame = position * self.format.sample_rate;
+                                state.frames_played = frame.round() as u64;
+                                continue 'playback;
+                            }
+                            Err(_error) => {
+                                //TODO: Report error properly
+                                break 'playback;
+                            }
+                        }
+                    }
+                }
+
+                let mut state = self.shared.lock();
+                let gain = state.gain;
+                state.frames_played += count as u64;
+                state.levels = levels.map(|level| level * gain);
+                frame += count;
+            }
+        }
+
+        handler.finish();
+        {
+            let mut state = self.shared.lock();
+            state.running = false;
+            state.levels = [0.0, 0.0];
+        }
+        self.shared.changed.notify_all();
+        handler.notify_playback_finished();
+    }
+}
+
+/// Something that cut short waiting for audio to play out.
+enum Interruption {
+    Stop,
+    Seek(f64),
+}
+
+/// Wait for `duration` worth of unpaused time to pass.
+///
+/// Returns early if a stop or seek was requested while waiting.
+fn wait(shared: &Shared, duration: Duration) -> Option<Interruption> {
+    let mut remaining = duration;
+    let mut state = shared.lock();
+    loop {
+        if state.stop_requested {
+            return Some(Interruption::Stop);
+        }
+        if let Some(time) = state.seek_requested {
+            return Some(Interruption::Seek(time));
+        }
+        if state.paused {
+            state = shared
+                .changed
+                .wait(state)
+                .expect("null output state poisoned");
+            continue;
+        }
+        if remaining.is_zero() {
+            return None;
+        }
+        let started = Instant::now();
+        state = shared
+            .changed
+            .wait_timeout(state, remaining)
+            .expect("null output state poisoned")
+            .0;
+        remaining = remaining.saturating_sub(started.elapsed());
+    }
+}
+
+/// Measure the RMS level of the first two channels of `count` PCM frames,
+/// starting `first` frames into `pcm`.
+fn measure_levels(format: &StreamFormat, pcm: &[u8], first: usize, count: usize) -> [f32; 2] {
+    let channels = format.channels_per_frame as usize;
+    let bytes_per_frame = format.bytes_per_frame as usize;
+    let bytes_per_sample = bytes_per_frame / channels.max(1);
+
+    let mut sums = [0f64; 2];
+    let frames = pcm.chunks_exact(bytes_per_frame).skip(first).take(count);
+    for frame in frames {
+        for (channel, sum) in sums.iter_mut().enumerate().take(channels) {
+            let offset = channel * bytes_per_sample;
+            let sample = decode_sample(format, &frame[offset..offset + bytes_per_sample]);
+            *sum += (sample * sample) as f64;
+        }
+    }
+
+    let rms = sums.map(|sum| (sum / count.max(1) as f64).sqrt() as f32);
+    match channels {
+        1 => [rms[0], rms[0]],
+        _ => rms,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::fd::AsRawFd;
+    use std::os::unix::net::UnixStream;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::events::{build_event_queue, Event, EventSource};
+    use crate::keymap::Keymap;
+    use crate::player::{
+        AudioFilePlayer, PlaybackContext, FORMAT_FLAG_IS_SIGNED_INTEGER, FORMAT_LINEAR_PCM,
+    };
+    use crate::source::wav::encode::Wav;
+
+    const RATE: u32 = 8000;
+
+    fn stereo_format() -> StreamFormat {
+        StreamFormat {
+            sample_rate: RATE as f64,
+            format_id: FORMAT_LINEAR_PCM,
+            format_flags: FORMAT_FLAG_IS_SIGNED_INTEGER,
+            bytes_per_packet: 4,
+            frames_per_packet: 1,
+            bytes_per_frame: 4,
+            channels_per_frame: 2,
+            bits_per_channel: 16,
+        }
+    }
+
+    /// Frames of 16 bit stereo PCM, each with the same pair of samples.
+    fn frames(count: usize, left: i16, right: i16) -> Vec<u8> {
+        [left.to_le_bytes(), right.to_le_bytes()]
+            .concat()
+            .repeat(count)
+    }
+
+    #[test]
+    fn levels_are_measured_from_the_first_frame_asked_for() {
+        let format = stereo_format();
+        // Loud frames ahead of the ones measured, as left by trimming
+        let mut pcm = frames(10, i16::MAX, i16::MAX);
+        pcm.extend(frames(20, 16384, -8192));
+
+        let [left, right] = measure_levels(&format, &pcm, 10, 20);
+        assert!((left - 0.5).abs() < 0.001, "left was {left}");
+        assert!((right - 0.25).abs() < 0.001, "right was {right}");
+    }
+
+    #[test]
+    fn mono_levels_are_shown_on_both_channels() {
+        let format = StreamFormat {
+            bytes_per_packet: 2,
+            bytes_per_frame: 2,
+            channels_per_frame: 1,
+            ..stereo_format()
+        };
+        let pcm = 16384i16.to_le_bytes().repeat(8);
+        let [left, right] = measure_levels(&format, &pcm, 0, 8);
+        assert!((left - 0.5).abs() < 0.001, "left was {left}");
+        assert_eq!(left, right);
+    }
+
+    /// Write a file of a steady tone at half of full scale.
+    fn tone_file(test: &str, seconds: f64) -> PathBuf {
+        let name = format!("afqueue-{}-{test}.wav", std::process::id());
+        let path = std::env::temp_dir().join(name);
+        let pcm = frames((seconds * RATE as f64) as usize, 16384, 16384);
+        let wav = Wav {
+            sample_rate: RATE,
+            ..Wav::pcm(2, 16, pcm)
+        };
+        fs::write(&path, wav.build()).unwrap();
+        path
+    }
+
+    #[test]
+    fn short_buffer_plays_out_in_real_time() {
+        let seconds = 0.25;
+        let path = tone_file("null_plays_out", seconds);
+
+        let (_input, input_reader) = UnixStream::pair().unwrap();
+        let mut events = build_event_queue(Keymap::default(), input_reader.as_raw_fd()).unwrap();
+        let context = PlaybackContext::new(path.to_str().unwrap()).unwrap();
+        let mut handler = context.into_audio_callback_handler(events.create_callback_notifier());
+        let mut player = AudioFilePlayer::<NullOutput>::new(&mut handler).unwrap();
+
+        assert_eq!(player.get_playback_time().unwrap(), None);
+        let started = Instant::now();
+        player.start_playback().unwrap();
+        assert!(matches!(events.next_event(), Event::PlaybackStarted));
+
+        thread::sleep(Duration::from_millis(100));
+        let time = player
+            .get_playback_time()
+            .unwrap()
+            .expect("should be playing");
+        assert!(time > 0.0 && time < seconds, "time was {time}");
+        let [left, _] = player.get_meter_level().unwrap();
+        assert!((left - 0.5).abs() < 0.001, "level was {left}");
+
+        assert!(matches!(events.next_event(), Event::PlaybackFinished));
+        assert!(started.elapsed().as_secs_f64() >= seconds);
+        assert_eq!(player.get_playback_time().unwrap(), None);
+        assert_eq!(player.get_meter_level().unwrap(), [0.0, 0.0]);
+
+        drop(player);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn seeking_moves_the_playback_time_straight_away() {
+        let path = tone_file("null_seeking", 2.0);
+
+        let (_input, input_reader) = UnixStream::pair().unwrap();
+        let mut events = build_event_queue(Keymap::default(), input_reader.as_raw_fd()).unwrap();
+        let context = PlaybackContext::new(path.to_str().unwrap()).unwrap();
+        let mut handler = context.into_audio_callback_handler(events.create_callback_notifier());
+        let mut player = AudioFilePlayer::<NullOutput>::new(&mut handler).unwrap();
+
+        let started = Instant::now();
+        player.start_playback().unwrap();
+        assert!(matches!(events.next_event(), Event::PlaybackStarted));
+
+        player.seek(1.75).unwrap();
+        let time = player
+            .get_playback_time()
+            .unwrap()
+            .expect("should be playing");
+        assert!((1.75..1.8).contains(&time), "time was {time}");
+
+        // Only what was left after the seek is played out
+        assert!(matches!(events.next_event(), Event::PlaybackFinished));
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        drop(player);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,303 @@
+//! Audio output for tests, which plays out audio against a virtual clock.
+//!
+//! Rather than waiting on real time to pass, packets are only consumed as a
+//! `VirtualClock` is moved forward, so tests can say exactly when things
+//! happen. Started and finished notifications still go through the
+//! `CallbackNotifier`, just like a real output. Everything the output is asked
+//! to do is recorded in a `ScriptedBackend`, for tests to check afterwards.
+//!
+//! Outputs are created by the player rather than by tests, so each picks up
+//! the backend installed on the thread it is created on.
+
+use std::cell::{Cell, RefCell};
+use std::io;
+use std::rc::Rc;
+
+use crate::player::{
+    count_frames, AudioCallbackHandler, AudioOutput, PacketBuffer, PacketDescription,
+    PlaybackResult, StreamFormat,
+};
+
+thread_local! {
+    static BACKEND: RefCell<Option<ScriptedBackend>> = const { RefCell::new(None) };
+}
+
+/// Time as seen by scripted outputs, which only moves when told to.
+#[derive(Clone, Default)]
+pub struct VirtualClock(Rc<Cell<f64>>);
+
+impl VirtualClock {
+    /// Seconds since the clock was created.
+    pub fn now(&self) -> f64 {
+        self.0.get()
+    }
+
+    pub fn advance(&self, seconds: f64) {
+        self.0.set(self.0.get() + seconds);
+    }
+}
+
+/// Something a scripted output was asked to do.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    Start,
+    Pause,
+    Resume,
+    Stop,
+    SetVolume(f32),
+    Seek(f64),
+}
+
+struct Backend {
+    clock: VirtualClock,
+    levels: [f32; 2],
+    commands: Vec<Command>,
+    /// Whether outputs fail when asked to seek
+    failing_seeks: bool,
+}
+
+/// State shared between a test and the outputs created while it runs.
+#[derive(Clone)]
+pub struct ScriptedBackend(Rc<RefCell<Backend>>);
+
+impl ScriptedBackend {
+    /// Set up a backend for outputs created on this thread to use, replacing
+    /// any installed before.
+    pub fn install(clock: VirtualClock) -> Self {
+        let backend = ScriptedBackend(Rc::new(RefCell::new(Backend {
+            clock,
+            levels: [0.0, 0.0],
+            commands: Vec::new(),
+            failing_seeks: false,
+        })));
+        BACKEND.with(|installed| *installed.borrow_mut() = Some(backend.clone()));
+        backend
+    }
+
+    /// Clock that outputs using this backend play out against.
+    pub fn clock(&self) -> VirtualClock {
+        self.0.borrow().clock.clone()
+    }
+
+    /// Levels for outputs to report while playing, before volume is applied.
+    pub fn set_levels(&self, levels: [f32; 2]) {
+        self.0.borrow_mut().levels = levels;
+    }
+
+    /// Make outputs fail whenever they are asked to seek from now on.
+    pub fn fail_seeks(&self) {
+        self.0.borrow_mut().failing_seeks = true;
+    }
+
+    /// Everything asked of outputs so far, oldest first.
+    pub fn commands(&self) -> Vec<Command> {
+        self.0.borrow().commands.clone()
+    }
+
+    fn record(&self, command: Command) {
+        self.0.borrow_mut().commands.push(command);
+    }
+
+    fn failing_seeks(&self) -> bool {
+        self.0.borrow().failing_seeks
+    }
+
+    fn levels(&self) -> [f32; 2] {
+        self.0.borrow().levels
+    }
+
+    fn now(&self) -> f64 {
+        self.0.borrow().clock.now()
+    }
+}
+
+pub struct ScriptedOutput {
+    handler: *mut AudioCallbackHandler,
+    backend: ScriptedBackend,
+    format: StreamFormat,
+    data: Vec<u8>,
+    descriptions: Vec<PacketDescription>,
+    running: bool,
+    paused: bool,
+    gain: f32,
+    /// Clock time that playback has been caught up to
+    caught_up_to: f64,
+    frames_played: u64,
+    /// Frames read from the handler that are yet to be played
+    frames_buffered: u64,
+}
+
+impl ScriptedOutput {
+    fn handler(&mut self) -> &mut AudioCallbackHandler {
+        // SAFETY: See `AudioOutput::new`. Scripted outputs never leave the
+        // thread they were created on, so there is no other access to race.
+        unsafe { &mut *self.handler }
+    }
+
+    /// Play out whatever audio would have been played since the last time the
+    /// clock was checked, finishing if the handler runs out.
+    fn catch_up(&mut self) {
+        let now = self.backend.now();
+        let rate = self.format.sample_rate;
+        let mut due = ((now * rate).round() - (self.caught_up_to * rate).round()) as u64;
+        self.caught_up_to = now;
+        if !self.running || self.paused {
+            return;
+        }
+
+        loop {
+            let played = due.min(self.frames_buffered);
+            self.frames_played += played;
+            self.frames_buffered -= played;
+            due -= played;
+
+            if self.frames_buffered > 0 {
+                return;
+            }
+            // Keep the next buffer ready, so the end of the audio is noticed
+            // as soon as it is reached
+            if !self.read_buffer() {
+                self.finish();
+                return;
+            }
+            if due == 0 {
+                return;
+            }
+        }
+    }
+
+    /// Fetch the next buffer of audio, returning false if there was none.
+    fn read_buffer(&mut self) -> bool {
+        let mut data = std::mem::take(&mut self.data);
+        let mut descriptions = std::mem::take(&mut self.descriptions);
+        let mut buffer = PacketBuffer {
+            data: &mut data,
+            byte_size: 0,
+            packet_descriptions: match descriptions.len() {
+                0 => None,
+                _ => Some(&mut descriptions),
+            },
+            packet_description_count: 0,
+            trim_start: 0,
+            trim_end: 0,
+        };
+        let packets = self.handler().read_packets(&mut buffer);
+        let frames = count_frames(&self.format, packets, &buffer);
+        self.data = data;
+        self.descriptions = descriptions;
+
+        // Buffers are only read once the last has played out, so this one
+        // starts playing straight away
+        let volume_change = self.handler().take_volume_change();
+        if let Some(gain) = volume_change.filter(|&gain| gain != self.gain) {
+            self.set_volume(gain).expect("scripted volume can't fail");
+        }
+
+        self.frames_buffered += frames as u64;
+        packets > 0
+    }
+
+    fn finish(&mut self) {
+        self.running = false;
+        self.frames_buffered = 0;
+        self.handler().finish();
+        self.handler().notify_playback_finished();
+    }
+}
+
+impl AudioOutput for ScriptedOutput {
+    unsafe fn new(handler: *mut AudioCallbackHandler) -> PlaybackResult<Self> {
+        let backend = BACKEND
+            .with(|installed| installed.borrow().clone())
+            .expect("no scripted backend installed on this thread");
+        let now = backend.now();
+
+        Ok(ScriptedOutput {
+            format: *(*handler).format(),
+            data: vec![0; (*handler).buffer_size() as usize],
+            descriptions: vec![
+                PacketDescription::default();
+                (*handler).packet_descriptions_per_buffer() as usize
+            ],
+            handler,
+            backend,
+            running: false,
+            paused: false,
+            gain: 1.0,
+            caught_up_to: now,
+            frames_played: 0,
+            frames_buffered: 0,
+        })
+    }
+
+    fn start(&mut self) -> PlaybackResult<()> {
+        self.backend.record(Command::Start);
+        self.catch_up();
+        self.running = true;
+        self.handler().notify_playback_started();
+        self.catch_up();
+        Ok(())
+    }
+
+    fn pause(&mut self) -> PlaybackResult<()> {
+        self.backend.record(Command::Pause);
+        self.catch_up();
+        self.paused = true;
+        Ok(())
+    }
+
+    fn resume(&mut self) -> PlaybackResult<()> {
+        self.backend.record(Command::Resume);
+        self.catch_up();
+        self.paused = false;
+        Ok(())
+    }
+
+    fn stop(&mut self) -> PlaybackResult<()> {
+        self.backend.record(Command::Stop);
+        self.catch_up();
+        if self.running {
+            self.finish();
+        }
+        Ok(())
+    }
+
+    fn set_volume(&mut self, gain: f32) -> PlaybackResult<()> {
+        self.backend.record(Command::SetVolume(gain));
+        self.gain = gain;
+        Ok(())
+    }
+
+    fn get_meter_level(&mut self) -> PlaybackResult<[f32; 2]> {
+        self.catch_up();
+        if !self.running || self.paused {
+            return Ok([0.0, 0.0]);
+        }
+        let levels = self.backend.levels();
+        Ok(levels.map(|level| level * self.gain))
+    }
+
+    fn get_playback_time(&mut self) -> PlaybackResult<Option<f64>> {
+        self.catch_up();
+        if !self.running {
+            return Ok(None);
+        }
+        Ok(Some(self.frames_played as f64 / self.format.sample_rate))
+    }
+
+    fn seek(&mut self, time: f64) -> PlaybackResult<()> {
+        self.backend.record(Command::Seek(time));
+        if self.backend.failing_seeks() {
+            return Err(io::Error::other("seek failed").into());
+        }
+        self.catch_up();
+        if !self.running {
+            return Ok(());
+        }
+        let position = self.handler().seek(time)?;
+        self.frames_played = (position * self.format.sample_rate).round() as u64;
+        self.frames_buffered = 0;
+        self.catch_up();
+        Ok(())
+    }
+}
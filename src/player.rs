@@ -1,22 +1,25 @@
 use std::cmp;
 use std::error::Error;
-use std::ffi::{c_void, CStr, CString, NulError};
+use std::ffi::NulError;
 use std::fmt;
-use std::io;
+use std::fs::File;
+use std::io::{self, Read};
 use std::marker::PhantomData;
-use std::mem::{self, MaybeUninit};
-use std::ptr;
+use std::mem::{self, ManuallyDrop};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
 
 //TODO: Check for lots of inner loop allocs (i.e Vec::new or vec!)
 
-use crate::ffi::audio_toolbox::{
-    self, audio_queue_get_current_time, AudioFileID, AudioQueueBufferRef,
-    AudioQueueLevelMeterState, AudioQueuePropertyID, AudioQueueRef, AudioStreamBasicDescription,
-    AudioTimeStamp,
-};
-
 use crate::events::CallbackNotifier;
-use crate::ffi::core_foundation;
+use crate::source::flac::{self, FlacSource};
+use crate::source::wav::{self, WavSource};
+
+#[cfg(target_os = "macos")]
+use crate::source::audio_converter::AudioConverterDecoder;
+#[cfg(target_os = "macos")]
+use crate::source::audio_file::AudioFileSource;
 
 pub type PlaybackResult<T> = Result<T, PlaybackError>;
 
@@ -25,6 +28,8 @@ pub enum PlaybackError {
     Path(PathError),
     System(SystemErrorCode),
     IO(io::Error),
+    Decode(DecodeError),
+    UnsupportedFormat,
 }
 
 impl From<PathError> for PlaybackError {
@@ -46,6 +51,12 @@ impl From<io::Error> for PlaybackError {
     }
 }
 
+impl From<DecodeError> for PlaybackError {
+    fn from(err: DecodeError) -> PlaybackError {
+        PlaybackError::Decode(err)
+    }
+}
+
 impl fmt::Display for PlaybackError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -58,6 +69,12 @@ impl fmt::Display for PlaybackError {
             PlaybackError::IO(err) => {
                 write!(f, "encountered IO error '{err}'")
             }
+            PlaybackError::Decode(err) => {
+                write!(f, "could not decode file: {err}")
+            }
+            PlaybackError::UnsupportedFormat => {
+                write!(f, "file is not in a supported audio format")
+            }
         }
     }
 }
@@ -68,6 +85,8 @@ impl Error for PlaybackError {
             PlaybackError::Path(err) => Some(err),
             PlaybackError::System(err) => Some(err),
             PlaybackError::IO(err) => Some(err),
+            PlaybackError::Decode(err) => Some(err),
+            PlaybackError::UnsupportedFormat => None,
         }
     }
 }
@@ -106,10 +125,30 @@ impl Error for PathError {
     }
 }
 
-type SystemResult<T> = Result<T, SystemErrorCode>;
+#[derive(Debug)]
+pub enum DecodeError {
+    Truncated,
+    Missing(&'static str),
+    Invalid(&'static str),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Truncated => write!(f, "file ended unexpectedly"),
+            DecodeError::Missing(part) => write!(f, "file has no {part}"),
+            DecodeError::Invalid(part) => write!(f, "file has an invalid {part}"),
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+#[cfg(target_os = "macos")]
+pub type SystemResult<T> = Result<T, SystemErrorCode>;
 
 #[derive(Debug)]
-pub struct SystemErrorCode(i32);
+pub struct SystemErrorCode(pub i32);
 
 impl fmt::Display for SystemErrorCode {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -120,22 +159,383 @@ impl fmt::Display for SystemErrorCode {
 
 impl Error for SystemErrorCode {}
 
-type PacketPosition = i64;
-type PacketCount = u32;
+pub type PacketPosition = i64;
+pub type PacketCount = u32;
 
 const LOWER_BUFFER_SIZE_HINT: u32 = 0x4000;
 const UPPER_BUFFER_SIZE_HINT: u32 = 0x50000;
 const BUFFER_SECONDS_HINT: f64 = 0.5;
-const BUFFER_COUNT: usize = 3;
+const HEADER_SNIFF_SIZE: usize = 16;
+
+/// How far each press of volume up or down moves it, in decibels.
+pub const DEFAULT_VOLUME_STEP_DB: f64 = 3.0;
+
+// Below this the volume goes straight to silence, as there's little left to
+// hear by then
+const MIN_VOLUME_DB: f64 = -60.0;
+
+// Leeway for steps that should land exactly on the floor
+const VOLUME_TOLERANCE: f64 = 1e-9;
+
+/// Format identifier for linear PCM audio.
+pub const FORMAT_LINEAR_PCM: u32 = u32::from_be_bytes(*b"lpcm");
+
+/// Linear PCM flag indicating samples are floating point.
+pub const FORMAT_FLAG_IS_FLOAT: u32 = 0x1;
+
+/// Linear PCM flag indicating samples are big endian.
+pub const FORMAT_FLAG_IS_BIG_ENDIAN: u32 = 0x2;
+
+/// Linear PCM flag indicating integer samples are signed.
+pub const FORMAT_FLAG_IS_SIGNED_INTEGER: u32 = 0x4;
+
+/// Linear PCM flag indicating samples use all the bits available in each
+/// channel.
+pub const FORMAT_FLAG_IS_PACKED: u32 = 0x8;
+
+/// Specifies the format of an audio stream.
+///
+/// This is a platform independent equivalent of AudioToolbox's
+/// `AudioStreamBasicDescription`, which it mirrors field for field. As with
+/// its counterpart, a field value of 0 indicates that the value is either
+/// unknown or not applicable to the format.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct StreamFormat {
+    /// Number of frames per second of uncompressed (or decompressed) audio.
+    pub sample_rate: f64,
+    /// General kind of data in the stream.
+    pub format_id: u32,
+    /// Flags for the format indicated by format_id.
+    pub format_flags: u32,
+    /// Number of bytes in each packet.
+    pub bytes_per_packet: u32,
+    /// Number of sample frames in each packet.
+    pub frames_per_packet: u32,
+    /// Number of bytes in a sample frame.
+    pub bytes_per_frame: u32,
+    /// Number of channels in each frame of data.
+    pub channels_per_frame: u32,
+    /// Number of bits of sample data for each channel.
+    pub bits_per_channel: u32,
+}
+
+impl StreamFormat {
+    pub fn is_linear_pcm(&self) -> bool {
+        self.format_id == FORMAT_LINEAR_PCM
+    }
+}
+
+/// Describes a single packet within a buffer of variable sized packets.
+///
+/// Laid out identically to AudioToolbox's `AudioStreamPacketDescription` so
+/// that descriptions can be written directly into audio queue buffers.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct PacketDescription {
+    /// The number of bytes from the start of the buffer to the packet
+    pub start_offset: i64,
+    /// The number of samples frames in the packet.
+    /// This is 0 for formats with a constant number of frames per packet.
+    pub variable_frames_in_packet: u32,
+    /// The number of bytes in the packet.
+    pub data_byte_size: u32,
+}
+
+/// A buffer that a `PacketSource` can read packets of audio data into.
+pub struct PacketBuffer<'a> {
+    /// Storage for packet data, the length of which is the buffer capacity.
+    pub data: &'a mut [u8],
+    /// The number of bytes of packet data held in `data`.
+    pub byte_size: u32,
+    /// Storage for packet descriptions, present only for formats that need
+    /// them.
+    pub packet_descriptions: Option<&'a mut [PacketDescription]>,
+    /// The number of packet descriptions held in `packet_descriptions`.
+    pub packet_description_count: u32,
+    /// Frames at the start of the buffer that aren't to be played, such as
+    /// encoder delay.
+    pub trim_start: u32,
+    /// Frames at the end of the buffer that aren't to be played, such as
+    /// padding added by an encoder.
+    pub trim_end: u32,
+}
+
+/// Number of frames to play from the `packets` that were just read into
+/// `buffer`, leaving out any trimmed from either end.
+pub fn count_frames(format: &StreamFormat, packets: u32, buffer: &PacketBuffer) -> usize {
+    let frames = if format.frames_per_packet != 0 {
+        packets as usize * format.frames_per_packet as usize
+    } else {
+        let count = buffer.packet_description_count as usize;
+        match buffer.packet_descriptions.as_deref() {
+            Some(descriptions) => descriptions[..count]
+                .iter()
+                .map(|desc| desc.variable_frames_in_packet as usize)
+                .sum(),
+            None => 0,
+        }
+    };
+    frames.saturating_sub((buffer.trim_start + buffer.trim_end) as usize)
+}
+
+/// Decode a single PCM sample to a value between -1 and 1.
+pub fn decode_sample(format: &StreamFormat, bytes: &[u8]) -> f32 {
+    let big_endian = format.format_flags & FORMAT_FLAG_IS_BIG_ENDIAN != 0;
+    let mut raw = [0u8; 8];
+    let raw = &mut raw[..bytes.len().min(8)];
+    raw.copy_from_slice(&bytes[..raw.len()]);
+    if big_endian {
+        raw.reverse();
+    }
+
+    if format.format_flags & FORMAT_FLAG_IS_FLOAT != 0 {
+        return match raw.len() {
+            4 => f32::from_le_bytes(raw.try_into().unwrap()),
+            8 => f64::from_le_bytes(raw.try_into().unwrap()) as f32,
+            _ => 0.0,
+        };
+    }
+
+    // Widen to a 64 bit integer, with the sample occupying the top bits
+    let mut widened = [0u8; 8];
+    widened[8 - raw.len()..].copy_from_slice(raw);
+    let value = i64::from_le_bytes(widened);
+
+    let value = if format.format_flags & FORMAT_FLAG_IS_SIGNED_INTEGER != 0 {
+        value
+    } else {
+        value.wrapping_sub(i64::MIN)
+    };
+
+    (value as f64 / i64::MAX as f64) as f32
+}
+
+/// Where the audio itself lies among the frames that a source decodes to,
+/// leaving out the delay and padding that encoders add either side.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ValidFrames {
+    /// Frames before the audio starts
+    pub priming: u64,
+    /// Frames of audio, after which the rest is padding
+    pub count: u64,
+}
+
+/// A source of audio packets, such as an audio file.
+pub trait PacketSource {
+    /// Describe the format of the packets provided by this source.
+    fn format(&self) -> &StreamFormat;
+
+    /// The theoretical maximum size of a single packet, in bytes.
+    fn packet_size_upper_bound(&self) -> u32;
+
+    /// Any format specific data that must be supplied to a decoder before
+    /// playback.
+    fn magic_cookie(&self) -> PlaybackResult<Option<Vec<u8>>>;
+
+    /// Descriptive key value pairs such as artist or title.
+    fn metadata(&self) -> PlaybackResult<Vec<(String, String)>>;
+
+    /// Duration of the audio in seconds.
+    fn estimated_duration(&self) -> PlaybackResult<f64>;
+
+    /// Where the audio lies, for formats that encoders pad out. `None` if
+    /// every frame is to be played.
+    fn valid_frames(&self) -> PlaybackResult<Option<ValidFrames>> {
+        Ok(None)
+    }
+
+    /// Find the packet containing `frame`, returning its index along with the
+    /// position of the first frame within it.
+    ///
+    /// Formats with a constant number of frames per packet can work this out
+    /// directly, others need to consult a packet table.
+    fn packet_for_frame(&self, frame: u64) -> PlaybackResult<(PacketPosition, u64)> {
+        let frames_per_packet = self.format().frames_per_packet as u64;
+        if frames_per_packet == 0 {
+            return Err(DecodeError::Missing("packet table").into());
+        }
+        let packet = frame / frames_per_packet;
+        Ok((packet as PacketPosition, packet * frames_per_packet))
+    }
+
+    /// Read up to `packets` packets, starting at `from_packet`, into `buffer`.
+    ///
+    /// Fewer packets may be read than requested if `buffer` is too small, or
+    /// the end of the source is reached. Returns the number of packets
+    /// actually read, which will be 0 once all packets have been read.
+    fn read_packets(
+        &mut self,
+        from_packet: PacketPosition,
+        packets: PacketCount,
+        buffer: &mut PacketBuffer,
+    ) -> PlaybackResult<PacketCount>;
+}
+
+/// A backend able to play back the audio supplied by an `AudioCallbackHandler`.
+pub trait AudioOutput: Sized {
+    /// Create a new output that pulls audio from `handler`.
+    ///
+    /// # Safety
+    ///
+    /// The output retains `handler` and will mutate it, potentially from
+    /// another thread, until the output is dropped. The caller must ensure
+    /// `handler` remains valid and is not otherwise accessed for that time.
+    unsafe fn new(handler: *mut AudioCallbackHandler) -> PlaybackResult<Self>;
+
+    fn start(&mut self) -> PlaybackResult<()>;
+
+    fn pause(&mut self) -> PlaybackResult<()>;
+
+    fn resume(&mut self) -> PlaybackResult<()>;
+
+    /// Stop playback synchronously, discarding any audio yet to be played.
+    fn stop(&mut self) -> PlaybackResult<()>;
+
+    /// Set the gain applied to the output, from 0.0 (silent) to 1.0 (unity).
+    fn set_volume(&mut self, gain: f32) -> PlaybackResult<()>;
+
+    /// Level of the left and right channels, normalised between 0 and 1.
+    fn get_meter_level(&mut self) -> PlaybackResult<[f32; 2]>;
+
+    /// Seconds of audio played so far, or `None` if the output isn't running.
+    fn get_playback_time(&mut self) -> PlaybackResult<Option<f64>>;
+
+    /// Continue playback from `time` seconds into the audio, discarding any
+    /// audio already queued up.
+    ///
+    /// Playback time should pick up from the new position.
+    fn seek(&mut self, time: f64) -> PlaybackResult<()>;
+}
+
+#[cfg(target_os = "macos")]
+pub type DefaultOutput = crate::output::audio_queue::AudioQueueOutput;
+
+#[cfg(not(target_os = "macos"))]
+pub type DefaultOutput = crate::output::null::NullOutput;
+
+/// Where a section of a file starts or ends.
+#[derive(Debug, Clone, Copy, Default)]
+struct Boundary {
+    packet: PacketPosition,
+    /// Seconds into the audio that the section starts or ends at
+    time: f64,
+    /// Frames of the packet outside of the section, those before it at the
+    /// start and those after it at the end
+    trim: u32,
+}
+
+/// A file, or section of one, being read from a packet at a time.
+struct Segment {
+    source: Box<dyn PacketSource>,
+    current_packet: PacketPosition,
+    /// Frame of the file that times are measured from, after any encoder delay
+    first_frame: u64,
+    start: Boundary,
+    end: Option<Boundary>,
+}
+
+impl Segment {
+    fn new(source: Box<dyn PacketSource>) -> PlaybackResult<Self> {
+        let format = *source.format();
+        let mut segment = Segment {
+            source,
+            current_packet: 0,
+            first_frame: 0,
+            start: Boundary::default(),
+            end: None,
+        };
+
+        // Trimming down to the frame needs packets of a known length
+        let valid = segment.source.valid_frames()?;
+        if let Some(valid) = valid.filter(|_| format.frames_per_packet != 0) {
+            let frames_per_packet = format.frames_per_packet as u64;
+            let end_frame = valid.priming + valid.count;
+            let end_packet = end_frame.div_ceil(frames_per_packet);
+            segment.first_frame = valid.priming;
+            segment.start = Boundary {
+                packet: (valid.priming / frames_per_packet) as PacketPosition,
+                time: 0.0,
+                trim: (valid.priming % frames_per_packet) as u32,
+            };
+            segment.end = Some(Boundary {
+                packet: end_packet as PacketPosition,
+                time: valid.count as f64 / format.sample_rate,
+                trim: (end_packet * frames_per_packet - end_frame) as u32,
+            });
+            segment.current_packet = segment.start.packet;
+        }
+        Ok(segment)
+    }
+
+    /// The start of the packet containing the frame `time` seconds in.
+    fn boundary(&self, time: f64) -> PlaybackResult<Boundary> {
+        let sample_rate = self.source.format().sample_rate;
+        let frame = (time.max(0.0) * sample_rate) as u64 + self.first_frame;
+        let (packet, first_frame) = self.source.packet_for_frame(frame)?;
+        Ok(Boundary {
+            packet,
+            time: (first_frame as f64 - self.first_frame as f64) / sample_rate,
+            trim: 0,
+        })
+    }
+
+    fn estimated_duration(&self) -> PlaybackResult<f64> {
+        let end = match self.end {
+            Some(end) => end.time,
+            None => self.source.estimated_duration()?,
+        };
+        Ok((end - self.start.time).max(0.0))
+    }
+
+    /// Read the next run of packets, up to the end of the segment.
+    fn read(
+        &mut self,
+        packets: PacketCount,
+        buffer: &mut PacketBuffer,
+    ) -> PlaybackResult<PacketCount> {
+        // Don't read past the end of the section being played
+        let packets = match self.end {
+            Some(end) => {
+                let remaining = (end.packet - self.current_packet).max(0);
+                remaining.min(packets as PacketPosition) as PacketCount
+            }
+            None => packets,
+        };
+        if packets == 0 {
+            return Ok(0);
+        }
+
+        let from_packet = self.current_packet;
+        let packets_read = self.source.read_packets(from_packet, packets, buffer)?;
+        self.current_packet += packets_read as PacketPosition;
 
-const AUDIO_QUEUE_RUN_STATE_STOPPED: u32 = 0;
+        // Leave out any part of the packets at either end outside the segment
+        buffer.trim_start = match from_packet == self.start.packet {
+            true => self.start.trim,
+            false => 0,
+        };
+        buffer.trim_end = match self.end {
+            Some(end) if packets_read > 0 && self.current_packet == end.packet => end.trim,
+            _ => 0,
+        };
+        Ok(packets_read)
+    }
 
-const MAX_VOLUME: usize = 16;
-const VOLUME_STEP: usize = 1;
+    /// Move to the packet containing the frame `time` seconds in, returning
+    /// the time that the packet actually starts at.
+    fn seek(&mut self, time: f64) -> PlaybackResult<f64> {
+        let sample_rate = self.source.format().sample_rate;
+        let frame = (time.max(0.0) + self.start.time) * sample_rate + self.first_frame as f64;
+        let (packet, first_frame) = self.source.packet_for_frame(frame.max(0.0) as u64)?;
+
+        self.current_packet = packet.max(self.start.packet);
+        let packet_time = (first_frame as f64 - self.first_frame as f64) / sample_rate;
+        Ok((packet_time - self.start.time).max(0.0))
+    }
+}
 
 pub struct PlaybackContext {
-    playback_file: AudioFileID,
-    format: AudioStreamBasicDescription,
+    segment: Segment,
     buffer_size: u32,
     is_vbr: bool,
     packets_per_buffer: PacketCount,
@@ -143,8 +543,17 @@ pub struct PlaybackContext {
 
 impl PlaybackContext {
     pub fn new(path: &str) -> PlaybackResult<Self> {
-        let path = cstring_path(path)?;
-        let audio_file = audio_file_open(&path)?;
+        if path.is_empty() {
+            return Err(PathError::PathIsEmpty.into());
+        }
+
+        let source = open_packet_source(path)?;
+        Ok(PlaybackContext::sized_for(Segment::new(source)?))
+    }
+
+    /// Size buffers to suit what `segment` is read from.
+    fn sized_for(segment: Segment) -> Self {
+        let source = &segment.source;
 
         // Use
         //  - the theoretical max size of a packet of this format
@@ -154,8 +563,8 @@ impl PlaybackContext {
         // - how big each buffer needs to be
         // - how many packet to read each time we fill a buffer
 
-        let format = audio_file_read_basic_description(audio_file)?;
-        let max_packet_size = audio_file_read_packet_size_upper_bound(audio_file)?;
+        let format = source.format();
+        let max_packet_size = source.packet_size_upper_bound();
 
         let buffer_size = if format.frames_per_packet != 0 {
             // If frames per packet are known, tailor the buffer size.
@@ -172,817 +581,884 @@ impl PlaybackContext {
         let is_vbr = format.bytes_per_packet == 0 || format.frames_per_packet == 0;
         let packets_per_buffer = buffer_size / max_packet_size;
 
-        Ok(PlaybackContext {
-            playback_file: audio_file,
+        PlaybackContext {
+            segment,
             packets_per_buffer,
-            format,
             buffer_size,
             is_vbr,
-        })
+        }
+    }
+
+    /// Only play the part of the file from `start` seconds in, up until `end`
+    /// if given. Times during playback are then relative to `start`.
+    ///
+    /// Both ends are moved to the start of the packet they fall in, so that a
+    /// section ending where another starts doesn't share any audio with it.
+    /// Ends left open stay clear of any encoder delay or padding.
+    pub fn select_section(&mut self, start: f64, end: Option<f64>) -> PlaybackResult<()> {
+        let segment = &mut self.segment;
+        if start > 0.0 {
+            segment.start = segment.boundary(start)?;
+            segment.current_packet = segment.start.packet;
+        }
+        if let Some(end) = end {
+            segment.end = Some(segment.boundary(end)?);
+        }
+        Ok(())
     }
 
     pub fn file_metadata(&self) -> PlaybackResult<Vec<(String, String)>> {
-        audio_file_read_metadata(self.playback_file).map_err(|e| e.into())
+        self.segment.source.metadata()
     }
 
     pub fn estimated_duration(&self) -> PlaybackResult<f64> {
-        audio_file_read_estimated_duration(self.playback_file).map_err(|e| e.into())
+        self.segment.estimated_duration()
     }
 
-    pub fn new_audio_callback_handler(&self, notifier: CallbackNotifier) -> AudioCallbackHandler {
-        AudioCallbackHandler {
-            playback_file: self.playback_file,
-            is_vbr: self.is_vbr,
-            notifier,
-            packets_per_buffer: self.packets_per_buffer,
-            current_packet: 0,
-            finished: false,
-        }
+    pub fn format(&self) -> &StreamFormat {
+        self.segment.source.format()
     }
 
-    pub fn new_audio_player(
-        &self,
-        handler: &mut AudioCallbackHandler,
-    ) -> PlaybackResult<AudioFilePlayer> {
-        let handler_ptr = handler as *mut _ as *mut c_void;
-        let output_queue = output_queue_create(&self.format, handler_ptr)?;
+    /// Read through the rest of the audio, passing each buffer's worth to
+    /// `receive` as interleaved samples between -1 and 1.
+    ///
+    /// Linear PCM is read directly. Anything else is left to the OS to decode,
+    /// so can only be read on macOS.
+    pub fn read_samples(&mut self, receive: impl FnMut(&[f32])) -> PlaybackResult<()> {
+        if self.format().is_linear_pcm() {
+            return self.read_pcm_samples(receive);
+        }
 
-        let packet_descriptions = match self.is_vbr {
-            true => self.packets_per_buffer,
-            false => 0,
-        };
+        #[cfg(target_os = "macos")]
+        return self.read_decoded_samples(receive);
 
-        let buffers = create_buffers(output_queue, packet_descriptions, self.buffer_size)?;
+        #[cfg(not(target_os = "macos"))]
+        Err(PlaybackError::UnsupportedFormat)
+    }
 
-        if let Some(cookie) = audio_file_read_magic_cookie(self.playback_file)? {
-            audio_queue_set_magic_cookie(output_queue, cookie)?
+    fn read_pcm_samples(&mut self, mut receive: impl FnMut(&[f32])) -> PlaybackResult<()> {
+        let format = *self.format();
+        let channels = format.channels_per_frame as usize;
+        let bytes_per_frame = format.bytes_per_frame as usize;
+        if channels == 0 || bytes_per_frame == 0 {
+            return Err(PlaybackError::UnsupportedFormat);
         }
+        let bytes_per_sample = bytes_per_frame / channels;
+
+        let mut data = vec![0u8; self.buffer_size as usize];
+        let mut samples = Vec::new();
+        loop {
+            let mut buffer = PacketBuffer {
+                data: &mut data,
+                byte_size: 0,
+                packet_descriptions: None,
+                packet_description_count: 0,
+                trim_start: 0,
+                trim_end: 0,
+            };
+            let packets = self.segment.read(self.packets_per_buffer, &mut buffer)?;
+            if packets == 0 {
+                return Ok(());
+            }
 
-        audio_queue_listen_to_run_state(output_queue, handler_ptr)?;
+            let frames = count_frames(&format, packets, &buffer);
+            let pcm = &buffer.data[..buffer.byte_size as usize];
+            let frames = pcm
+                .chunks_exact(bytes_per_frame)
+                .skip(buffer.trim_start as usize)
+                .take(frames);
+            samples.clear();
+            for frame in frames {
+                let channel_samples = frame.chunks_exact(bytes_per_sample);
+                samples.extend(channel_samples.map(|bytes| decode_sample(&format, bytes)));
+            }
+            receive(&samples);
+        }
+    }
 
-        // TODO: Do this on start?
-        // While handle_buffer is usually invoked from the output queues internal
-        // callback thread to refill a buffer, we call it a few times before
-        // starting to pre load the buffers with audio. This means that any
-        // error during pre-buffering is not directly surfaced here, but
-        // reported back via the handler as an error event.
-        // For small files, some buffers might remain unused.
-        for buffer_ref in buffers {
-            handle_buffer(handler_ptr, output_queue, buffer_ref);
+    #[cfg(target_os = "macos")]
+    fn read_decoded_samples(&mut self, mut receive: impl FnMut(&[f32])) -> PlaybackResult<()> {
+        let format = *self.format();
+        let channels = format.channels_per_frame as usize;
+        if channels == 0 {
+            return Err(PlaybackError::UnsupportedFormat);
         }
+        let magic_cookie = self.segment.source.magic_cookie()?;
+        let mut decoder = AudioConverterDecoder::new(&format, magic_cookie.as_deref())?;
+
+        // The decoder may hold audio back, so rather than trimming each
+        // buffer, frames are skipped and kept as they come out of it
+        let (mut skip, mut keep) = (0, 0);
+        let mut pass_on = |samples: &mut Vec<f32>, skip: &mut usize, keep: &mut usize| {
+            let frames = samples.len() / channels;
+            let skipped = frames.min(*skip);
+            let kept = (frames - skipped).min(*keep);
+            *skip -= skipped;
+            *keep -= kept;
+            if kept > 0 {
+                receive(&samples[skipped * channels..(skipped + kept) * channels]);
+            }
+            samples.clear();
+        };
 
-        let default_meter = AudioQueueLevelMeterState::default();
-        let meter_count = self.format.channels_per_frame as usize;
-        let meters = vec![default_meter; meter_count];
+        let mut data = vec![0u8; self.buffer_size as usize];
+        let mut descriptions = vec![PacketDescription::default(); self.packets_per_buffer as usize];
+        let mut samples = Vec::new();
+        loop {
+            let mut buffer = PacketBuffer {
+                data: &mut data,
+                byte_size: 0,
+                packet_descriptions: match self.is_vbr {
+                    true => Some(&mut descriptions),
+                    false => None,
+                },
+                packet_description_count: 0,
+                trim_start: 0,
+                trim_end: 0,
+            };
+            let packets = self.segment.read(self.packets_per_buffer, &mut buffer)?;
+            if packets == 0 {
+                break;
+            }
+            skip += buffer.trim_start as usize;
+            keep += count_frames(&format, packets, &buffer);
+            decoder.decode(packets, &mut buffer, &mut samples)?;
+            pass_on(&mut samples, &mut skip, &mut keep);
+        }
+        decoder.finish(&mut samples)?;
+        pass_on(&mut samples, &mut skip, &mut keep);
+        Ok(())
+    }
 
-        Ok(AudioFilePlayer {
-            output_queue,
-            handler: PhantomData,
-            sample_rate: self.format.sample_rate,
-            meter_state: meters.into_boxed_slice(),
-        })
+    pub fn into_audio_callback_handler(self, notifier: CallbackNotifier) -> AudioCallbackHandler {
+        AudioCallbackHandler {
+            segment: self.segment,
+            is_vbr: self.is_vbr,
+            notifier,
+            buffer_size: self.buffer_size,
+            packets_per_buffer: self.packets_per_buffer,
+            finished: false,
+            flushing: AtomicBool::new(false),
+            quiet: AtomicBool::new(false),
+            supplied: 0.0,
+            volume_change: None,
+            handover: Mutex::default(),
+        }
     }
 }
 
-impl Drop for PlaybackContext {
-    fn drop(&mut self) {
-        audio_file_close(self.playback_file).expect("Failed to close audio file");
-    }
+/// Passes the next track over to a handler as it reads the last of the
+/// current one, so that playback carries straight on into it.
+#[derive(Default)]
+struct Handover {
+    /// Opened ahead of time, ready to be read from next
+    next: Option<Segment>,
+    /// Where the handler has moved on from, kept while its audio is still
+    /// playing out in case of seeking back into it
+    previous: Option<Segment>,
+    /// Playback time at which the segment handed over to starts
+    starts_at: Option<f64>,
+    /// Volume for the output to switch to as the segment lined up starts
+    /// playing
+    volume: f32,
 }
 
+fn lock(handover: &Mutex<Handover>) -> MutexGuard<'_, Handover> {
+    handover.lock().expect("handover poisoned")
+}
+
+/// Supplies audio to an output as it works its way through a file.
+///
+/// Outputs call back into the handler each time they need a buffer refilling,
+/// and to report that playback has started or finished.
 pub struct AudioCallbackHandler {
-    playback_file: AudioFileID,
+    segment: Segment,
     is_vbr: bool,
     notifier: CallbackNotifier,
+    buffer_size: u32,
     packets_per_buffer: PacketCount,
-    current_packet: PacketPosition,
     finished: bool,
+    flushing: AtomicBool,
+    /// Set once nothing is waiting to hear about playback starting or finishing
+    quiet: AtomicBool,
+    /// Seconds of audio read since playback started or last moved
+    supplied: f64,
+    /// Volume to play the last buffer read at, having handed over with it
+    volume_change: Option<f32>,
+    handover: Mutex<Handover>,
 }
 
 impl AudioCallbackHandler {
-    fn handle_buffer(&mut self, audio_queue: AudioQueueRef, buffer: AudioQueueBufferRef) {
+    pub fn format(&self) -> &StreamFormat {
+        self.segment.source.format()
+    }
+
+    pub fn buffer_size(&self) -> u32 {
+        self.buffer_size
+    }
+
+    /// Number of packet descriptions each buffer needs space for, which is 0
+    /// for formats that don't need them.
+    pub fn packet_descriptions_per_buffer(&self) -> u32 {
+        match self.is_vbr {
+            true => self.packets_per_buffer,
+            false => 0,
+        }
+    }
+
+    pub fn magic_cookie(&self) -> PlaybackResult<Option<Vec<u8>>> {
+        self.segment.source.magic_cookie()
+    }
+
+    #[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Mark that no more audio should be read, e.g because the output can no
+    /// longer accept it.
+    pub fn finish(&mut self) {
+        self.finished = true;
+    }
+
+    /// Fill `buffer` with the next run of packets from the file, carrying on
+    /// into the next one if it has been handed over.
+    ///
+    /// Returns the number of packets read, or 0 once there is nothing more to
+    /// play. At which point the handler is finished.
+    pub fn read_packets(&mut self, buffer: &mut PacketBuffer) -> PacketCount {
         if self.finished {
-            return;
+            return 0;
         }
 
-        let read_result = audio_file_read_packet_data(
-            self.playback_file,
-            self.current_packet,
-            self.packets_per_buffer,
-            buffer,
-            self.is_vbr,
-        );
-
-        let packets_read = match read_result {
-            //TODO: Report error properly
-            Ok(packets_read) => packets_read,
-            Err(error) => {
-                //TODO: Report error properly
-                self.finished = true;
-                return;
+        loop {
+            match self.segment.read(self.packets_per_buffer, buffer) {
+                Ok(0) => {
+                    if !self.hand_over() {
+                        self.finished = true;
+                        return 0;
+                    }
+                }
+                Ok(packets_read) => {
+                    let format = self.segment.source.format();
+                    let frames = count_frames(format, packets_read, buffer);
+                    self.supplied += frames as f64 / format.sample_rate;
+                    return packets_read;
+                }
+                Err(_error) => {
+                    //TODO: Report error properly
+                    self.finished = true;
+                    return 0;
+                }
             }
+        }
+    }
+
+    /// Move on to the segment lined up next, if there is one.
+    fn hand_over(&mut self) -> bool {
+        let mut handover = lock(&self.handover);
+        let Some(next) = handover.next.take() else {
+            return false;
         };
+        handover.previous = Some(mem::replace(&mut self.segment, next));
+        handover.starts_at = Some(self.supplied);
+        self.volume_change = Some(handover.volume);
+        true
+    }
 
-        if packets_read == 0 {
-            self.finished = true;
-            // Request an asynchronous stop so that buffered audio can finish playing.
-            // Queue stopping is detected via seperate callback to property listener.
-            //TODO: Handle and report Error
-            audio_queue_stop(audio_queue, false).expect("oh no");
-            return;
-        }
+    /// Take the volume that the buffer last read should be played at, if it
+    /// is the first read after handing over.
+    ///
+    /// Outputs switch to it as that buffer starts playing, so that the track
+    /// handed over to is heard at its own volume from the very start.
+    pub fn take_volume_change(&mut self) -> Option<f32> {
+        self.volume_change.take()
+    }
 
-        match audio_queue_enqueue_buffer(audio_queue, buffer) {
-            Ok(()) => {
-                self.current_packet += packets_read as i64;
-            }
-            // Attempting to enqueue during reset can be expected when the user
-            // has stopped the queue before playback has finished.
-            Err(SystemErrorCode(audio_toolbox::AUDIO_QUEUE_ERROR_ENQUEUE_DURING_RESET)) => {
-                self.finished = true;
-            }
-            // Anything else is probably a legitimate error condition
-            Err(SystemErrorCode(code)) => {
-                //TODO: Report error
-                self.finished = true;
+    /// Move playback to the packet containing the frame `time` seconds in.
+    ///
+    /// Returns the time that the packet actually starts at, which may be a
+    /// little before the time requested.
+    pub fn seek(&mut self, time: f64) -> PlaybackResult<f64> {
+        {
+            // The segment handed over from is still the one playing, so seek
+            // within that, ready to hand over again once it is read through
+            let mut handover = lock(&self.handover);
+            if let Some(previous) = handover.previous.take() {
+                let mut next = mem::replace(&mut self.segment, previous);
+                next.current_packet = next.start.packet;
+                handover.next = Some(next);
+                handover.starts_at = None;
             }
         }
+
+        let position = self.segment.seek(time)?;
+        self.finished = false;
+        self.supplied = position;
+        self.volume_change = None;
+        Ok(position)
     }
 
-    fn handle_running_state_change(&mut self, audio_queue: AudioQueueRef) {
-        match audio_queue_read_run_state(audio_queue) {
-            Ok(AUDIO_QUEUE_RUN_STATE_STOPPED) => {
-                self.notifier
-                    .trigger_playback_finished_event()
-                    .expect("failed to trigger playback event");
-            }
-            Ok(_) => {
-                // Any other value is the queue starting
-                self.notifier
-                    .trigger_playback_started_event()
-                    .expect("failed to trigger playback event");
-            }
-            //TODO: Feed back error to controller?
-            Err(error) => panic!("booooo!: {error}\r\n"),
+    /// Mark that any buffers handed back by the output are being discarded,
+    /// rather than needing to be refilled.
+    ///
+    /// Unlike the rest of the handler, this is safe to call while the output
+    /// is running.
+    #[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+    pub fn set_flushing(&self, flushing: bool) {
+        self.flushing.store(flushing, Ordering::SeqCst);
+    }
+
+    #[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+    pub fn is_flushing(&self) -> bool {
+        self.flushing.load(Ordering::SeqCst)
+    }
+
+    pub fn notify_playback_started(&mut self) {
+        if self.quiet.load(Ordering::SeqCst) {
+            return;
         }
+        self.notifier
+            .trigger_playback_started_event()
+            .expect("failed to trigger playback event");
+    }
+
+    pub fn notify_playback_finished(&mut self) {
+        if self.quiet.load(Ordering::SeqCst) {
+            return;
+        }
+        self.notifier
+            .trigger_playback_finished_event()
+            .expect("failed to trigger playback event");
     }
 }
 
 // SAFETY: Although not immediately apparent from the fields in the
-// AudioFilePlayer struct, the output queue will internally hold a raw pointer
-// to `handler`. The output queue will use this pointer to mutate the handler as
-// it advances through the audio file a buffer at a time. Therefore the
+// AudioFilePlayer struct, the output will internally hold a raw pointer to
+// `handler`. The output will use this pointer to mutate the handler as it
+// advances through the audio file a buffer at a time. Therefore the
 // PhantomData marker is used to enforce ownership of the handler for the
-// lifetime of the AudioFilePlayer. After which the output queue will have been
+// lifetime of the AudioFilePlayer. After which the output will have been
 // disposed of and handler _should_ be safe to access again.
-pub struct AudioFilePlayer<'a> {
-    output_queue: AudioQueueRef,
+//
+// The player keeps a pointer of its own, only ever used to reach the
+// handover, which like the flushing flag is safe to use while the output is
+// running.
+pub struct AudioFilePlayer<'a, O: AudioOutput> {
+    output: O,
+    handover: *const Mutex<Handover>,
+    /// What a track must match to follow on through the same output
+    format: StreamFormat,
+    buffer_size: u32,
+    magic_cookie: Option<Vec<u8>>,
+    /// Playback time that the track now playing started at
+    offset: f64,
+    /// Gain asked for by the volume, before any fade is applied
+    gain: f32,
+    /// Scale applied along with the volume, such as for ReplayGain
+    pre_gain: f32,
+    /// Scale for the track lined up to follow on
+    following_pre_gain: f32,
+    /// How far faded in, from 0.0 (silent) to 1.0 (not faded at all)
+    fade: f32,
     handler: PhantomData<&'a mut AudioCallbackHandler>,
-    sample_rate: f64,
-    //TODO: Assert somehow that this is at least > 1
-    meter_state: Box<[AudioQueueLevelMeterState]>,
 }
 
-impl<'a> AudioFilePlayer<'a> {
+impl<'a, O: AudioOutput> AudioFilePlayer<'a, O> {
+    pub fn new(handler: &'a mut AudioCallbackHandler) -> PlaybackResult<Self> {
+        let format = *handler.format();
+        let buffer_size = handler.buffer_size();
+        let magic_cookie = handler.magic_cookie()?;
+        let handover = &handler.handover as *const _;
+        // SAFETY: The handler is mutably borrowed for as long as the player,
+        // and so the output, exists.
+        let output = unsafe { O::new(handler)? };
+        Ok(AudioFilePlayer {
+            output,
+            handover,
+            format,
+            buffer_size,
+            magic_cookie,
+            offset: 0.0,
+            gain: 1.0,
+            pre_gain: 1.0,
+            following_pre_gain: 1.0,
+            fade: 1.0,
+            handler: PhantomData,
+        })
+    }
+
+    fn handover(&self) -> &'a Mutex<Handover> {
+        // SAFETY: See above
+        unsafe { &*self.handover }
+    }
+
     pub fn start_playback(&mut self) -> PlaybackResult<()> {
-        audio_queue_enable_metering(self.output_queue)?;
-        audio_queue_start(self.output_queue)?;
-        Ok(())
+        self.output.start()
     }
 
     pub fn pause(&mut self) -> PlaybackResult<()> {
-        audio_queue_pause(self.output_queue)?;
-        Ok(())
+        self.output.pause()
     }
 
     pub fn resume(&mut self) -> PlaybackResult<()> {
-        audio_queue_start(self.output_queue)?;
-        Ok(())
+        self.output.resume()
     }
 
     pub fn stop(&mut self) -> PlaybackResult<()> {
-        // Stop the queue synchronously
-        audio_queue_stop(self.output_queue, true)?;
-        Ok(())
+        self.output.stop()
     }
 
     pub fn set_volume(&mut self, volume: &PlaybackVolume) -> PlaybackResult<()> {
         let gain = volume.gain();
         assert!(gain >= 0.0f32);
         assert!(gain <= 1.0f32);
-        audio_queue_set_volume(self.output_queue, gain)?;
-        Ok(())
+        self.gain = gain;
+        self.apply_gain()
     }
 
-    pub fn get_meter_level(&mut self) -> PlaybackResult<[f32; 2]> {
-        audio_queue_read_meter_level(self.output_queue, &mut self.meter_state)?;
-
-        // TODO: Panic (or maybe err?) if meter_state.len() is 0
-        let mut levels = self.meter_state.iter().map(|chan| chan.average_power);
-        Ok(match (levels.next(), levels.next()) {
-            (Some(chan_1), None) => [chan_1, chan_1],
-            (Some(chan_1), Some(chan_2)) => [chan_1, chan_2],
-            _ => [0.0, 0.0], // This shouldn't happen
-        })
+    /// Scale the audio by `scale` along with the volume, which can't go past
+    /// full volume however far it is turned up.
+    pub fn set_pre_gain(&mut self, scale: f32) -> PlaybackResult<()> {
+        assert!(scale >= 0.0f32);
+        if scale == self.pre_gain {
+            return Ok(());
+        }
+        self.pre_gain = scale;
+        self.apply_gain()
     }
 
-    pub fn get_playback_time(&mut self) -> PlaybackResult<Option<f64>> {
-        let time = audio_queue_read_current_sample_time(self.output_queue)?;
-        let time = time.map(|t| t / self.sample_rate);
-        Ok(time)
+    /// Fade the volume down to `level`, between 0.0 (silent) and 1.0 (not
+    /// faded at all).
+    pub fn set_fade(&mut self, level: f32) -> PlaybackResult<()> {
+        assert!((0.0f32..=1.0f32).contains(&level));
+        if level == self.fade {
+            return Ok(());
+        }
+        self.fade = level;
+        self.apply_gain()
     }
-}
 
-impl<'a> Drop for AudioFilePlayer<'a> {
-    fn drop(&mut self) {
-        // Dispose of the queue synchronously
-        audio_queue_dispose(self.output_queue, true).expect("Failed to dispose of audio queue");
+    fn apply_gain(&mut self) -> PlaybackResult<()> {
+        {
+            // Once handed over, the volume has been passed on to the output
+            // already, and is put right on moving on if need be
+            let mut handover = lock(self.handover());
+            if handover.starts_at.is_none() {
+                handover.volume = self.volume(self.following_pre_gain);
+            }
+        }
+        self.output.set_volume(self.volume(self.pre_gain))
     }
-}
-
-pub struct PlaybackVolume {
-    volume: usize,
-}
 
-impl PlaybackVolume {
-    pub fn new() -> Self {
-        PlaybackVolume { volume: MAX_VOLUME }
+    /// Output volume for audio scaled by `pre_gain`.
+    fn volume(&self, pre_gain: f32) -> f32 {
+        // Outputs can only turn audio down, so boosts stop at full volume
+        (self.gain * pre_gain).min(1.0) * self.fade
     }
 
-    pub fn increment(&mut self) {
-        self.volume = cmp::min(self.volume + VOLUME_STEP, MAX_VOLUME);
+    pub fn get_meter_level(&mut self) -> PlaybackResult<[f32; 2]> {
+        self.output.get_meter_level()
     }
 
-    pub fn decrement(&mut self) {
-        self.volume = cmp::max(self.volume.saturating_sub(VOLUME_STEP), 0);
+    /// Seconds into the track now playing.
+    pub fn get_playback_time(&mut self) -> PlaybackResult<Option<f64>> {
+        let time = self.output.get_playback_time()?;
+        Ok(time.map(|time| time - self.offset))
     }
 
-    pub fn gain(&self) -> f32 {
-        self.volume as f32 / MAX_VOLUME as f32
+    pub fn seek(&mut self, time: f64) -> PlaybackResult<()> {
+        assert!(time >= 0.0);
+        // Seeking back before the track handed over to, which the output may
+        // have already switched the volume for
+        let handing_over = self.is_handing_over();
+        self.output.seek(time)?;
+        self.offset = 0.0;
+        if handing_over {
+            self.apply_gain()?;
+        }
+        Ok(())
     }
-}
 
-// TODO: Should always we ask for more packets than buffer can hold to ensure
-// the buffer gets fully used?
-//
-// We could calculate the max packets per buffer instead of minimum? I.e
-// optimistic instead of pessamistic.
-//
-// This would possibly take advantage of the properties AudioFileReadPacketData
-// has over AudioFileReadPackets?
-//
-// We would still need an upper limit (and overallocate) the
-// packet_descriptions.
-//
-// Is there somehow we could test this by detecting underutilized buffers?
-// The queue could continue to callback with remaining buffers.
-// Avoid unnecessary attempts to read the file again.
-
-// This handler assumes `buffer` adheres to several invarients:
-// - Is at least `packets_per_buffer` big
-// - Was allocated with packet descriptions (if needed)
-// - Belong to `audio_queue`
-extern "C" fn handle_buffer(
-    user_data: *mut c_void,
-    audio_queue: AudioQueueRef,
-    buffer: AudioQueueBufferRef,
-) {
-    unsafe {
-        let handler = &mut *(user_data as *mut AudioCallbackHandler);
-        handler.handle_buffer(audio_queue, buffer);
-    }
-}
-
-extern "C" fn handle_running_state_change(
-    user_data: *mut c_void,
-    audio_queue: AudioQueueRef,
-    property: AudioQueuePropertyID,
-) {
-    // The handler should only react to changes to the "is running" property
-    assert!(property == audio_toolbox::AUDIO_QUEUE_PROPERTY_IS_RUNNING);
-
-    unsafe {
-        let handler = &mut *(user_data as *mut AudioCallbackHandler);
-        handler.handle_running_state_change(audio_queue);
-    }
-}
-
-fn create_buffers(
-    output_queue: AudioQueueRef,
-    packet_descriptions: u32,
-    buffer_size: u32,
-) -> SystemResult<Vec<AudioQueueBufferRef>> {
-    unsafe {
-        vec![MaybeUninit::uninit(); BUFFER_COUNT]
-            .into_iter()
-            //TODO: Can we allocate buffers _without_ packet descriptions if we dont need them?
-            .map(|mut buffer_ref| {
-                let status = audio_toolbox::audio_queue_allocate_buffer_with_packet_descriptions(
-                    output_queue,
-                    buffer_size,
-                    packet_descriptions,
-                    buffer_ref.as_mut_ptr(),
-                );
+    /// Line up `next` to be played straight after the current track, in place
+    /// of anything lined up before, scaled by `pre_gain` from the moment it
+    /// starts. Returns `next` back if it can't go through the same output, in
+    /// which case nothing is lined up.
+    pub fn follow_with(
+        &mut self,
+        next: Option<PlaybackContext>,
+        pre_gain: f32,
+    ) -> PlaybackResult<Option<PlaybackContext>> {
+        assert!(pre_gain >= 0.0f32);
+        let (lined_up, unused) = match next {
+            Some(next) if self.can_follow(&next)? => (Some(next.segment), None),
+            next => (None, next),
+        };
+        self.following_pre_gain = pre_gain;
+        let mut handover = lock(self.handover());
+        handover.next = lined_up;
+        handover.volume = self.volume(pre_gain);
+        Ok(unused)
+    }
 
-                if status == 0 {
-                    Ok(buffer_ref.assume_init())
-                } else {
-                    Err(SystemErrorCode(status))
-                }
-            })
-            .collect()
+    /// Take back the track lined up to follow on, so long as it hasn't started
+    /// being read, leaving nothing lined up.
+    pub fn take_following(&mut self) -> Option<PlaybackContext> {
+        let mut handover = lock(self.handover());
+        if handover.starts_at.is_some() {
+            return None;
+        }
+        handover.next.take().map(PlaybackContext::sized_for)
     }
-}
 
-fn cstring_path(path: &str) -> Result<CString, PathError> {
-    if path.is_empty() {
-        return Err(PathError::PathIsEmpty);
+    fn can_follow(&self, next: &PlaybackContext) -> PlaybackResult<bool> {
+        let source = &next.segment.source;
+        Ok(*source.format() == self.format
+            && source.packet_size_upper_bound() <= self.buffer_size
+            && source.magic_cookie()? == self.magic_cookie)
     }
 
-    Ok(CString::new(path)?)
-}
+    /// Whether the track lined up has already started being read, making it
+    /// too late to line up anything else.
+    pub fn is_handing_over(&self) -> bool {
+        lock(self.handover()).starts_at.is_some()
+    }
 
-fn audio_file_read_packet_data(
-    file: AudioFileID,
-    from_packet: PacketPosition,
-    packets: PacketCount,
-    buffer: AudioQueueBufferRef,
-    is_vbr: bool,
-) -> SystemResult<PacketCount> {
-    unsafe {
-        let buffer = &mut *buffer;
-        let mut num_bytes = buffer.audio_data_bytes_capacity;
-        let mut num_packets = packets;
-
-        let packet_descs_ptr = if is_vbr {
-            buffer.packet_descriptions
-        } else {
-            ptr::null_mut()
+    /// Check if playback has reached the track lined up to follow on, which
+    /// then becomes the track playing.
+    pub fn moved_on(&mut self) -> PlaybackResult<bool> {
+        // Outputs may read more audio while answering, so ask before locking
+        let time = self.output.get_playback_time()?;
+        let mut handover = lock(self.handover());
+        let Some(starts_at) = handover.starts_at else {
+            return Ok(false);
         };
-
-        let status = audio_toolbox::audio_file_read_packet_data(
-            file,
-            false, // dont use caching
-            &mut num_bytes,
-            packet_descs_ptr,
-            from_packet,
-            &mut num_packets,
-            buffer.audio_data,
-        );
-
-        if status != 0 {
-            return Err(SystemErrorCode(status));
+        // Once the output has stopped, everything read has been played
+        if time.is_some_and(|time| time < starts_at) {
+            return Ok(false);
         }
-
-        buffer.audio_data_byte_size = num_bytes;
-        buffer.packet_description_count = if is_vbr { num_packets } else { 0 };
-
-        Ok(num_packets)
+        handover.starts_at = None;
+        handover.previous = None;
+        self.offset = starts_at;
+
+        self.pre_gain = self.following_pre_gain;
+        // Should the volume have changed since handing over, the output will
+        // have switched to one that is out of date
+        let volume = self.volume(self.pre_gain);
+        if volume != handover.volume {
+            drop(handover);
+            self.output.set_volume(volume)?;
+        }
+        Ok(true)
     }
 }
 
-fn audio_file_open(path: &CStr) -> SystemResult<AudioFileID> {
-    let path = path.to_bytes();
-
-    unsafe {
-        // Create URL
-        let url_ref = core_foundation::cfurl_create_from_filesystem_representation(
-            ptr::null(), // Use default allocator
-            path.as_ptr(),
-            path.len() as isize,
-            false, // Not a directory
-        );
-
-        // Open file
-        let mut file_id = MaybeUninit::uninit();
-        let status = audio_toolbox::audio_file_open_url(
-            url_ref,
-            audio_toolbox::AUDIO_FILE_READ_PERMISSION,
-            0, // No file hints
-            file_id.as_mut_ptr(),
-        );
-
-        // Dont need the CFURL anymore
-        core_foundation::cf_release(url_ref as *const c_void);
-
-        if status != 0 {
-            return Err(SystemErrorCode(status));
-        }
-
-        let file_id = file_id.assume_init();
-        Ok(file_id)
-    }
+/// A player that owns the handler it plays from, so that it can be passed on
+/// while playing. Such as when the next track fades in over the end of the
+/// current one, and carries on playing after it.
+pub struct Deck<O: AudioOutput> {
+    player: ManuallyDrop<AudioFilePlayer<'static, O>>,
+    handler: *mut AudioCallbackHandler,
 }
 
-fn audio_file_close(file: AudioFileID) -> SystemResult<()> {
-    unsafe {
-        let status = audio_toolbox::audio_file_close(file);
-        if status != 0 {
-            return Err(SystemErrorCode(status));
+impl<O: AudioOutput> Deck<O> {
+    pub fn new(context: PlaybackContext, notifier: CallbackNotifier) -> PlaybackResult<Self> {
+        let handler = context.into_audio_callback_handler(notifier);
+        let handler = Box::into_raw(Box::new(handler));
+        // SAFETY: The handler is only freed once the player is dropped
+        match AudioFilePlayer::new(unsafe { &mut *handler }) {
+            Ok(player) => Ok(Deck {
+                player: ManuallyDrop::new(player),
+                handler,
+            }),
+            Err(err) => {
+                drop(unsafe { Box::from_raw(handler) });
+                Err(err)
+            }
         }
     }
-    Ok(())
-}
-
-fn audio_file_read_metadata(file: AudioFileID) -> SystemResult<Vec<(String, String)>> {
-    unsafe {
-        let info_dict =
-            audio_file_get_property(file, audio_toolbox::AUDIO_FILE_PROPERTY_INFO_DICTIONARY)?;
-
-        // Extract keys and values
-        let count = core_foundation::cfdictionary_get_count(info_dict);
-        let mut keys = vec![0 as core_foundation::CFStringRef; count as usize];
-        let mut values = vec![0 as core_foundation::CFStringRef; count as usize];
-
-        core_foundation::cfdictionary_get_keys_and_values(
-            info_dict,
-            keys.as_mut_ptr() as *mut *const c_void,
-            values.as_mut_ptr() as *mut *const c_void,
-        );
 
-        // Filter out non CFString values and convert to Rust strings
-        // Note: We eagerly collect to force conversation before the dictionary is
-        // released
-
-        let cfstring_type_id = core_foundation::cfstring_get_type_id();
-
-        let properties = keys
-            .into_iter()
-            .zip(values.into_iter())
-            .filter(|(_, v)| {
-                core_foundation::cf_get_type_id(*v as *const c_void) == cfstring_type_id
-            })
-            .map(|(k, v)| (cfstring_to_string(k), cfstring_to_string(v)))
-            .collect();
-
-        core_foundation::cf_release(info_dict as *const c_void);
-
-        Ok(properties)
+    /// Stop playback without notifying anyone, for when playback ending isn't
+    /// something being waited on.
+    pub fn discard(mut self) -> PlaybackResult<()> {
+        // SAFETY: Like the flushing flag, this is safe to set while the
+        // output is running
+        unsafe { (*self.handler).quiet.store(true, Ordering::SeqCst) };
+        self.player.stop()
     }
 }
 
-fn audio_file_read_basic_description(
-    file: AudioFileID,
-) -> SystemResult<AudioStreamBasicDescription> {
-    audio_file_get_property(file, audio_toolbox::AUDIO_FILE_PROPERTY_DATA_FORMAT)
-}
+impl<O: AudioOutput> Deref for Deck<O> {
+    type Target = AudioFilePlayer<'static, O>;
 
-fn audio_file_read_packet_size_upper_bound(file: AudioFileID) -> SystemResult<u32> {
-    audio_file_get_property(
-        file,
-        audio_toolbox::AUDIO_FILE_PROPERTY_PACKET_SIZE_UPPER_BOUND,
-    )
-}
-
-fn audio_file_read_estimated_duration(file: AudioFileID) -> SystemResult<f64> {
-    audio_file_get_property(file, audio_toolbox::AUDIO_FILE_PROPERTY_ESTIMATED_DURATION)
+    fn deref(&self) -> &Self::Target {
+        &self.player
+    }
 }
 
-// This only works with sized types
-fn audio_file_get_property<T>(
-    file_id: audio_toolbox::AudioFileID,
-    property: audio_toolbox::AudioFilePropertyID,
-) -> SystemResult<T> {
-    unsafe {
-        let mut data = MaybeUninit::<T>::uninit();
-        let mut data_size = mem::size_of::<T>() as u32;
-
-        let status = audio_toolbox::audio_file_get_property(
-            file_id,
-            property,
-            &mut data_size as *mut _,
-            data.as_mut_ptr() as *mut c_void,
-        );
-        let data = data.assume_init();
-
-        if status != 0 {
-            return Err(SystemErrorCode(status));
-        }
-
-        // audio_file_get_property outputs the number of bytes written to data_size
-        // Check to see if this is correct for the given type
-        assert!(data_size == mem::size_of::<T>() as u32);
-
-        Ok(data)
+impl<O: AudioOutput> DerefMut for Deck<O> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.player
     }
 }
 
-fn audio_file_read_magic_cookie(file: AudioFileID) -> SystemResult<Option<Vec<u8>>> {
-    unsafe {
-        // Check to see if there is a cookie, and if so how large it is.
-        let mut cookie_size: u32 = 0;
-        let mut is_writable: u32 = 0;
-        let status = audio_toolbox::audio_file_get_property_info(
-            file,
-            audio_toolbox::AUDIO_FILE_PROPERTY_MAGIC_COOKIE_DATA,
-            &mut cookie_size as *mut _,
-            &mut is_writable as *mut _,
-        );
-
-        // No magic cookie data
-        if status == audio_toolbox::AUDIO_FILE_ERROR_UNSUPPORTED_PROPERTY || cookie_size == 0 {
-            return Ok(None);
-        }
-
-        // Some other status is probably an error
-        if status != 0 {
-            return Err(SystemErrorCode(status));
-        }
-
-        // Read the cookie
-        let mut cookie_data: Vec<u8> = vec![0; cookie_size as usize];
-        let mut data_size = cookie_size;
-
-        let status = audio_toolbox::audio_file_get_property(
-            file,
-            audio_toolbox::AUDIO_FILE_PROPERTY_MAGIC_COOKIE_DATA,
-            &mut data_size as *mut _,
-            cookie_data.as_mut_ptr() as *mut c_void,
-        );
-
-        if status != 0 {
-            return Err(SystemErrorCode(status));
+impl<O: AudioOutput> Drop for Deck<O> {
+    fn drop(&mut self) {
+        // SAFETY: The output goes first, so nothing is left using the handler
+        unsafe {
+            ManuallyDrop::drop(&mut self.player);
+            drop(Box::from_raw(self.handler));
         }
-
-        assert!(data_size == cookie_size);
-        Ok(Some(cookie_data))
     }
 }
 
-fn output_queue_create(
-    format: *const AudioStreamBasicDescription,
-    user_data: *mut c_void,
-) -> SystemResult<AudioQueueRef> {
-    unsafe {
-        let mut output_queue = MaybeUninit::uninit();
-        let status = audio_toolbox::audio_queue_new_output(
-            format,
-            handle_buffer,
-            user_data,
-            std::ptr::null(), // Run loop
-            std::ptr::null(), // Run loop mode
-            0,                // flags
-            output_queue.as_mut_ptr(),
-        );
+/// Volume in decibels below full, which steps evenly in loudness rather than
+/// in gain. Going down past the lowest step above the floor turns it off
+/// altogether.
+pub struct PlaybackVolume {
+    /// Steps below full volume, or `None` once turned all the way down
+    steps_down: Option<u32>,
+    step: f64,
+    /// Silenced, while keeping the level to go back to
+    muted: bool,
+}
 
-        if status != 0 {
-            return Err(SystemErrorCode(status));
+impl PlaybackVolume {
+    /// Full volume, moving by `step` decibels at a time.
+    pub fn new(step: f64) -> Self {
+        assert!(step > 0.0);
+        PlaybackVolume {
+            steps_down: Some(0),
+            step,
+            muted: false,
         }
-        let output_queue = output_queue.assume_init();
-        Ok(output_queue)
     }
-}
 
-fn audio_queue_set_magic_cookie(queue: AudioQueueRef, cookie: Vec<u8>) -> SystemResult<()> {
-    unsafe {
-        let status = audio_toolbox::audio_queue_set_property(
-            queue,
-            audio_toolbox::AUDIO_QUEUE_PROPERTY_MAGIC_COOKIE_DATA,
-            cookie.as_ptr() as *const c_void,
-            cookie.len() as u32,
-        );
+    /// Number of steps down to the quietest volume short of silence.
+    fn lowest(&self) -> u32 {
+        (-MIN_VOLUME_DB / self.step + VOLUME_TOLERANCE).floor() as u32
+    }
 
-        if status == 0 {
-            Ok(())
-        } else {
-            Err(SystemErrorCode(status))
-        }
+    /// Turn up a step, or from silence to the quietest volume. Unmutes
+    /// first.
+    pub fn increment(&mut self) {
+        self.muted = false;
+        self.steps_down = match self.steps_down {
+            Some(steps) => Some(steps.saturating_sub(1)),
+            None => Some(self.lowest()),
+        };
     }
-}
 
-fn audio_queue_start(queue: AudioQueueRef) -> SystemResult<()> {
-    unsafe {
-        let status = audio_toolbox::audio_queue_start(queue, ptr::null());
+    /// Turn down a step, or to silence from the quietest volume. Unmutes
+    /// first.
+    pub fn decrement(&mut self) {
+        self.muted = false;
+        self.steps_down = match self.steps_down {
+            Some(steps) if steps < self.lowest() => Some(steps + 1),
+            _ => None,
+        };
+    }
 
-        if status == 0 {
-            Ok(())
-        } else {
-            Err(SystemErrorCode(status))
-        }
+    pub fn toggle_mute(&mut self) {
+        self.muted = !self.muted;
     }
-}
 
-fn audio_queue_stop(queue: AudioQueueRef, immediate: bool) -> SystemResult<()> {
-    unsafe {
-        let status = audio_toolbox::audio_queue_stop(queue, immediate);
+    pub fn is_muted(&self) -> bool {
+        self.muted
+    }
 
-        if status == 0 {
-            Ok(())
-        } else {
-            Err(SystemErrorCode(status))
+    pub fn gain(&self) -> f32 {
+        match (self.muted, self.steps_down) {
+            (false, Some(steps)) => decibels_to_gain(-(steps as f64) * self.step),
+            _ => 0.0,
         }
     }
 }
 
-fn audio_queue_dispose(queue: AudioQueueRef, immediate: bool) -> SystemResult<()> {
-    unsafe {
-        let status = audio_toolbox::audio_queue_dispose(queue, immediate);
-
-        if status == 0 {
-            Ok(())
-        } else {
-            Err(SystemErrorCode(status))
-        }
-    }
+/// Convert a level in decibels to a gain between 0 and 1.
+pub fn decibels_to_gain(decibels: f64) -> f32 {
+    10f64.powf(decibels.min(0.0) / 20.0) as f32
 }
 
-fn audio_queue_pause(queue: AudioQueueRef) -> SystemResult<()> {
-    unsafe {
-        let status = audio_toolbox::audio_queue_pause(queue);
+/// Convert a gain between 0 and 1 to decibels, which is minus infinity for
+/// silence.
+pub fn gain_to_decibels(gain: f32) -> f64 {
+    20.0 * (gain as f64).log10()
+}
 
-        if status == 0 {
-            Ok(())
-        } else {
-            Err(SystemErrorCode(status))
-        }
+fn open_packet_source(path: &str) -> PlaybackResult<Box<dyn PacketSource>> {
+    // Prefer the built in decoders where they recognise the file
+    let header = read_header(path)?;
+    if wav::is_wav(&header) {
+        return Ok(Box::new(WavSource::open(path)?));
+    }
+    if flac::is_flac(&header) {
+        return Ok(Box::new(FlacSource::open(path)?));
     }
-}
 
-fn audio_queue_enqueue_buffer(
-    queue: AudioQueueRef,
-    buffer: AudioQueueBufferRef,
-) -> SystemResult<()> {
-    unsafe {
-        let status = audio_toolbox::audio_queue_enqueue_buffer(
-            queue,
-            buffer,
-            // Packet descriptions are supplied via buffer itself
-            0,
-            ptr::null(),
-        );
+    #[cfg(target_os = "macos")]
+    return Ok(Box::new(AudioFileSource::open(path)?));
 
-        if status == 0 {
-            Ok(())
-        } else {
-            Err(SystemErrorCode(status))
-        }
-    }
+    #[cfg(not(target_os = "macos"))]
+    Err(PlaybackError::UnsupportedFormat)
 }
 
-fn audio_queue_listen_to_run_state(
-    queue: AudioQueueRef,
-    user_data: *mut c_void,
-) -> SystemResult<()> {
-    unsafe {
-        let status = audio_toolbox::audio_queue_add_property_listener(
-            queue,
-            audio_toolbox::AUDIO_QUEUE_PROPERTY_IS_RUNNING,
-            handle_running_state_change,
-            user_data,
-        );
-        if status == 0 {
-            Ok(())
-        } else {
-            Err(SystemErrorCode(status))
-        }
+/// Extensions of the audio files that can be played on this platform.
+#[cfg(target_os = "macos")]
+pub const AUDIO_EXTENSIONS: &[&str] = &[
+    "wav", "wave", "flac", "aif", "aiff", "aifc", "caf", "mp3", "m4a", "m4b", "aac", "mp4",
+];
+
+#[cfg(not(target_os = "macos"))]
+pub const AUDIO_EXTENSIONS: &[&str] = &["wav", "wave", "flac"];
+
+/// Check if a file looks like audio that can be played, going by its first
+/// few bytes rather than its name.
+pub fn is_audio_file(path: &str) -> io::Result<bool> {
+    let header = read_header(path)?;
+    if wav::is_wav(&header) || flac::is_flac(&header) {
+        return Ok(true);
     }
-}
 
-fn audio_queue_read_run_state(queue: AudioQueueRef) -> SystemResult<u32> {
-    unsafe {
-        let mut data = MaybeUninit::<u32>::uninit();
-        let mut data_size = mem::size_of::<u32>() as u32;
+    #[cfg(target_os = "macos")]
+    return Ok(is_audio_toolbox_file(&header));
 
-        let status = audio_toolbox::audio_queue_get_property(
-            queue,
-            audio_toolbox::AUDIO_QUEUE_PROPERTY_IS_RUNNING,
-            data.as_mut_ptr() as *mut c_void,
-            &mut data_size as *mut _,
-        );
+    #[cfg(not(target_os = "macos"))]
+    Ok(false)
+}
 
-        let data = data.assume_init();
+/// Check for the more common formats AudioToolbox can play.
+#[cfg(target_os = "macos")]
+fn is_audio_toolbox_file(header: &[u8]) -> bool {
+    let starts_with = |offset: usize, marker: &[u8]| {
+        header
+            .get(offset..offset + marker.len())
+            .is_some_and(|bytes| bytes == marker)
+    };
+    // MPEG audio, either behind an ID3 tag or starting with a frame sync
+    let is_mpeg =
+        starts_with(0, b"ID3") || matches!(header, [0xff, second, ..] if second & 0xe0 == 0xe0);
+    let is_aiff = starts_with(0, b"FORM") && (starts_with(8, b"AIFF") || starts_with(8, b"AIFC"));
+    // MPEG-4 files, such as M4A
+    let is_mp4 = starts_with(4, b"ftyp");
+    is_mpeg || is_aiff || is_mp4 || starts_with(0, b"caff")
+}
 
-        if status != 0 {
-            return Err(SystemErrorCode(status));
-        }
+/// Read the first few bytes of a file, used to sniff out its format.
+fn read_header(path: &str) -> io::Result<Vec<u8>> {
+    let mut header = Vec::with_capacity(HEADER_SNIFF_SIZE);
+    File::open(path)?
+        .take(HEADER_SNIFF_SIZE as u64)
+        .read_to_end(&mut header)?;
+    Ok(header)
+}
 
-        // audio_queue_get_property outputs the number of bytes written to data_size
-        // Check to see if this is correct
-        assert!(data_size == mem::size_of::<u32>() as u32);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        Ok(data)
+    /// Steps in decibels to check, from small to large.
+    fn steps() -> impl Iterator<Item = f64> {
+        (1..=40).map(|step| step as f64 * 0.25)
     }
-}
 
-fn audio_queue_set_volume(queue: AudioQueueRef, gain: f32) -> SystemResult<()> {
-    unsafe {
-        let status = audio_toolbox::audio_queue_set_parameter(
-            queue,
-            audio_toolbox::AUDIO_QUEUE_PARAMETER_VOLUME,
-            gain,
-        );
+    /// An endless run of presses, `true` for up and `false` for down, from a
+    /// simple linear congruential generator.
+    fn presses(seed: u64) -> impl Iterator<Item = bool> {
+        let mut state = seed;
+        std::iter::repeat_with(move || {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            state >> 63 == 1
+        })
+    }
 
-        if status == 0 {
-            Ok(())
-        } else {
-            Err(SystemErrorCode(status))
+    #[test]
+    fn volume_moves_the_way_it_is_turned() {
+        for (seed, step) in steps().enumerate() {
+            let mut volume = PlaybackVolume::new(step);
+            for up in presses(seed as u64).take(500) {
+                let before = volume.gain();
+                match up {
+                    true => volume.increment(),
+                    false => volume.decrement(),
+                }
+                let after = volume.gain();
+                assert!((0.0..=1.0).contains(&after), "step {step}");
+                match up {
+                    true => assert!(after >= before, "step {step}"),
+                    false => assert!(after <= before, "step {step}"),
+                }
+                // Only stays put at either end
+                assert!(
+                    after != before || after == 0.0 || after == 1.0,
+                    "step {step}"
+                );
+            }
         }
     }
-}
-
-fn audio_queue_enable_metering(queue: AudioQueueRef) -> SystemResult<()> {
-    unsafe {
-        let enabled: u32 = 1;
 
-        let status = audio_toolbox::audio_queue_set_property(
-            queue,
-            audio_toolbox::AUDIO_QUEUE_PROPERTY_ENABLE_LEVEL_METERING,
-            &enabled as *const _ as *const c_void,
-            mem::size_of::<u32>() as u32,
-        );
-
-        if status == 0 {
-            Ok(())
-        } else {
-            Err(SystemErrorCode(status))
+    #[test]
+    fn steps_retrace_their_way_back() {
+        for step in steps() {
+            let mut volume = PlaybackVolume::new(step);
+            let mut descended = vec![volume.gain()];
+            while volume.gain() > 0.0 {
+                volume.decrement();
+                descended.push(volume.gain());
+            }
+            // The quietest step is as close to the floor as it can be
+            let quietest = gain_to_decibels(descended[descended.len() - 2]);
+            assert!(quietest > MIN_VOLUME_DB - 1e-6, "step {step}");
+            assert!(quietest - step < MIN_VOLUME_DB, "step {step}");
+
+            let mut climbed = vec![volume.gain()];
+            while volume.gain() < 1.0 {
+                volume.increment();
+                climbed.push(volume.gain());
+            }
+            climbed.reverse();
+            assert_eq!(climbed, descended, "step {step}");
         }
     }
-}
-
-fn audio_queue_read_meter_level(
-    queue: AudioQueueRef,
-    meter_state: &mut Box<[AudioQueueLevelMeterState]>,
-) -> SystemResult<()> {
-    unsafe {
-        let meter_size = mem::size_of::<AudioQueueLevelMeterState>();
-        let expected_size = (meter_size * meter_state.len()) as u32;
-        let mut data_size = expected_size;
-
-        let status = audio_toolbox::audio_queue_get_property(
-            queue,
-            audio_toolbox::AUDIO_QUEUE_PROPERTY_LEVEL_METER_STATE,
-            meter_state.as_mut_ptr() as *mut c_void,
-            &mut data_size as *mut _,
-        );
 
-        if status != 0 {
-            return Err(SystemErrorCode(status));
+    #[test]
+    fn decibels_and_gain_convert_back_and_forth() {
+        for tenth in 0..=600 {
+            let decibels = -(tenth as f64) / 10.0;
+            let gain = decibels_to_gain(decibels);
+            assert!(
+                (gain_to_decibels(gain) - decibels).abs() < 1e-4,
+                "{decibels}"
+            );
         }
-
-        assert!(data_size == expected_size);
-        Ok(())
+        for hundredth in 1..=100 {
+            let gain = hundredth as f32 / 100.0;
+            let decibels = gain_to_decibels(gain);
+            assert!((decibels_to_gain(decibels) - gain).abs() < 1e-6, "{gain}");
+        }
+        assert_eq!(gain_to_decibels(0.0), f64::NEG_INFINITY);
     }
-}
 
-fn audio_queue_read_current_sample_time(queue: AudioQueueRef) -> SystemResult<Option<f64>> {
-    //TODO: Check this isnt problematically large to repetedly zero
-    let mut timestamp = AudioTimeStamp::default();
-    unsafe {
-        let status =
-            audio_queue_get_current_time(queue, ptr::null(), &mut timestamp, ptr::null_mut());
-
-        // If the queue isn't running, it has no playback time
-        if status == audio_toolbox::AUDIO_QUEUE_ERROR_INVALID_RUN_STATE {
-            return Ok(None);
-        }
-        if status != 0 {
-            return Err(SystemErrorCode(status));
-        }
-    }
-    Ok(Some(timestamp.sample_time))
-}
-
-unsafe fn cfstring_to_string(cfstring: core_foundation::CFStringRef) -> String {
-    assert!(!cfstring.is_null());
-
-    let string_len = core_foundation::cfstring_get_length(cfstring);
-
-    // This is effectively asking how big a buffer we are going to need
-    let mut bytes_required = 0;
-    core_foundation::cfstring_get_bytes(
-        cfstring,
-        core_foundation::CFRange {
-            location: 0,
-            length: string_len,
-        },
-        core_foundation::CFSTRING_ENCODING_UTF8,
-        0,               // no loss byte
-        false,           // no byte order marker
-        ptr::null_mut(), // dont actually capture any bytes
-        0,               // buffer size of 0 as no buffer supplied
-        &mut bytes_required,
-    );
-
-    // Now actually copy out the bytes
-    let mut buffer = vec![b'\x00'; bytes_required as usize];
-    let mut bytes_written = 0;
-
-    let chars_converted = core_foundation::cfstring_get_bytes(
-        cfstring,
-        core_foundation::CFRange {
-            location: 0,
-            length: string_len,
-        },
-        core_foundation::CFSTRING_ENCODING_UTF8,
-        0,     // no loss byte
-        false, // no byte order marker
-        buffer.as_mut_ptr(),
-        buffer.len() as core_foundation::CFIndex,
-        &mut bytes_written,
-    );
-
-    assert!(chars_converted == string_len);
-    assert!(bytes_written as usize == buffer.len());
-
-    String::from_utf8_unchecked(buffer)
+    #[test]
+    fn muting_keeps_the_level_to_return_to() {
+        let mut volume = PlaybackVolume::new(DEFAULT_VOLUME_STEP_DB);
+        volume.decrement();
+        volume.decrement();
+        let level = volume.gain();
+        assert!((level - 0.501).abs() < 1e-3);
+
+        volume.toggle_mute();
+        assert!(volume.is_muted());
+        assert_eq!(volume.gain(), 0.0);
+        volume.toggle_mute();
+        assert_eq!(volume.gain(), level);
+
+        // Turning up while muted carries on from where it was
+        volume.toggle_mute();
+        volume.increment();
+        assert!(!volume.is_muted());
+        assert!((gain_to_decibels(volume.gain()) + 3.0).abs() < 1e-6);
+    }
 }
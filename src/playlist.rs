@@ -0,0 +1,668 @@
+//! The list of files to play, and where we are up to in it.
+//!
+//! Tracks can come straight from the command line, be read out of playlist
+//! files, or be found by searching through directories. The queue can also be
+//! saved back out as a playlist.
+
+use std::fmt::{self, Write};
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+
+mod cue;
+mod directory;
+mod m3u;
+mod order;
+mod pls;
+mod xspf;
+
+pub use self::directory::{scan_directory, ScanOptions, SortOrder};
+pub use self::order::{same_album, PlayOrder, Repeat, Shuffle};
+
+use self::order::Random;
+use crate::error::{AfqueueError, ErrorContext, ErrorCtx};
+
+/// A file to play, along with anything a playlist file had to say about it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Track {
+    pub path: String,
+    pub title: Option<String>,
+    /// Length in seconds
+    pub duration: Option<f64>,
+    /// Part of the file to play, when it holds more than one track
+    pub section: Option<Section>,
+    /// Properties to show alongside, or instead of, those in the file
+    pub metadata: Vec<(String, String)>,
+}
+
+/// A span of a file, in seconds from its start.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Section {
+    pub start: f64,
+    /// `None` to carry on to the end of the file
+    pub end: Option<f64>,
+}
+
+impl Track {
+    pub fn new(path: String) -> Self {
+        Track {
+            path,
+            title: None,
+            duration: None,
+            section: None,
+            metadata: Vec::new(),
+        }
+    }
+
+    /// What to call the track, which is its title if known, otherwise the
+    /// name of its file.
+    pub fn name(&self) -> &str {
+        if let Some(title) = &self.title {
+            return title;
+        }
+        Path::new(&self.path)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(&self.path)
+    }
+}
+
+/// Something found in a playlist file or directory that had to be left out.
+#[derive(Debug)]
+pub struct SkippedEntry {
+    /// The playlist it was listed in, or its own path if found in a directory
+    pub source: String,
+    /// Line of the playlist it was listed on
+    pub line: Option<usize>,
+    pub reason: String,
+}
+
+impl fmt::Display for SkippedEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let SkippedEntry {
+            source,
+            line,
+            reason,
+        } = self;
+        match line {
+            Some(line) => write!(f, "line {line} of '{source}': {reason}"),
+            None => write!(f, "'{source}': {reason}"),
+        }
+    }
+}
+
+/// The kinds of playlist file that can be read and written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    M3u,
+    Pls,
+    Xspf,
+    /// CUE sheets can only be read
+    Cue,
+}
+
+impl Format {
+    /// Work out the format of a playlist file from its extension, or `None`
+    /// if it doesn't look like a playlist.
+    pub fn of(path: &str) -> Option<Format> {
+        let extension = Path::new(path).extension()?.to_str()?;
+        match extension.to_ascii_lowercase().as_str() {
+            "m3u" | "m3u8" => Some(Format::M3u),
+            "pls" => Some(Format::Pls),
+            "xspf" => Some(Format::Xspf),
+            "cue" => Some(Format::Cue),
+            _ => None,
+        }
+    }
+}
+
+/// Expand any playlist files and directories amongst `paths` into the tracks
+/// they hold.
+pub fn load_paths(
+    paths: impl IntoIterator<Item = String>,
+    scan: &ScanOptions,
+) -> Result<(Vec<Track>, Vec<SkippedEntry>), AfqueueError> {
+    let mut tracks = Vec::new();
+    let mut skipped = Vec::new();
+    for path in paths {
+        if Path::new(&path).is_dir() {
+            let (found, unreadable) = scan_directory(&path, scan)
+                .map_err(AfqueueError::from)
+                .with(ErrorCtx::ScanningDirectory(path))?;
+            tracks.extend(found);
+            skipped.extend(unreadable);
+            continue;
+        }
+        let Some(format) = Format::of(&path) else {
+            tracks.push(Track::new(path));
+            continue;
+        };
+        let (listed, unplayable) = load(&path, format)
+            .map_err(AfqueueError::from)
+            .with(ErrorCtx::LoadingPlaylist(path))?;
+        tracks.extend(listed);
+        skipped.extend(unplayable);
+    }
+    Ok((tracks, skipped))
+}
+
+/// Read the tracks listed in a playlist file. Entries that can't be played are
+/// left out, rather than failing the whole playlist.
+pub fn load(path: &str, format: Format) -> io::Result<(Vec<Track>, Vec<SkippedEntry>)> {
+    let text = decode(fs::read(path)?);
+    let base = Path::new(path).parent().unwrap_or(Path::new(""));
+    let entries = match format {
+        Format::M3u => m3u::parse(&text, base),
+        Format::Pls => pls::parse(&text, base),
+        Format::Xspf => xspf::parse(&text, base),
+        Format::Cue => cue::parse(&text, base),
+    };
+
+    let mut tracks = Vec::new();
+    let mut skipped = Vec::new();
+    for (line, entry) in entries {
+        let readable = entry.and_then(|track| match File::open(&track.path) {
+            Ok(_) => Ok(track),
+            Err(err) => Err(format!("could not read '{}': {err}", track.path)),
+        });
+        match readable {
+            Ok(track) => tracks.push(track),
+            Err(reason) => skipped.push(SkippedEntry {
+                source: path.to_string(),
+                line: Some(line),
+                reason,
+            }),
+        }
+    }
+    Ok((tracks, skipped))
+}
+
+/// Write `tracks` out to a playlist file. Paths are made absolute, so that
+/// they don't depend on where the playlist is kept.
+///
+/// Only the file each track comes from is written, so tracks taken from a CUE
+/// sheet play their whole file.
+pub fn save(path: &str, format: Format, tracks: &[Track]) -> io::Result<()> {
+    let tracks = tracks
+        .iter()
+        .map(|track| {
+            let path = std::path::absolute(&track.path)?;
+            Ok(Track {
+                path: path.to_string_lossy().into_owned(),
+                ..track.clone()
+            })
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    let text = match format {
+        Format::M3u => m3u::write(&tracks),
+        Format::Pls => pls::write(&tracks),
+        Format::Xspf => xspf::write(&tracks),
+        Format::Cue => {
+            let message = "CUE sheets can't be written";
+            return Err(io::Error::new(io::ErrorKind::Unsupported, message));
+        }
+    };
+    fs::write(path, text)
+}
+
+/// Interpret the contents of a playlist file as text. This should be UTF-8,
+/// but older files tend to be Latin-1, so fall back on that.
+fn decode(bytes: Vec<u8>) -> String {
+    let text = match String::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(err) => err.into_bytes().iter().map(|&byte| byte as char).collect(),
+    };
+    match text.strip_prefix('\u{feff}') {
+        Some(text) => text.to_string(),
+        None => text,
+    }
+}
+
+/// Turn a playlist entry into a path, relative paths being relative to the
+/// directory holding the playlist.
+fn resolve(entry: &str, base: &Path) -> Result<String, String> {
+    let path = match entry.split_once("://") {
+        Some(("file", path)) => percent_decode(path),
+        Some(_) => return Err(format!("'{entry}' is a stream, which can't be played")),
+        None => entry.to_string(),
+    };
+    let path = base.join(path);
+    Ok(path.to_string_lossy().into_owned())
+}
+
+/// Decode escaped bytes in a URL, such as `%20` for a space.
+fn percent_decode(text: &str) -> String {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let escaped = tail
+            .get(..2)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (byte, escaped) {
+            (b'%', Some(escaped)) => {
+                bytes.push(escaped);
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Escape bytes that can't appear in the path of a URL as is.
+fn percent_encode(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for &byte in text.as_bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => write!(encoded, "%{byte:02X}").unwrap(),
+        }
+    }
+    encoded
+}
+
+/// A list of tracks, along with a cursor pointing at the one to play.
+///
+/// Unlike an iterator, the cursor can move backwards as well as forwards, so
+/// tracks that have already been played can be returned to.
+pub struct Playlist {
+    tracks: Vec<Track>,
+    /// Where each track was given in the queue, to return to once unshuffled
+    original: Vec<usize>,
+    position: usize,
+    shuffle: Shuffle,
+    repeat: Repeat,
+    random: Random,
+}
+
+impl Playlist {
+    pub fn new(tracks: impl IntoIterator<Item = Track>, order: PlayOrder) -> Self {
+        let tracks: Vec<Track> = tracks.into_iter().collect();
+        let mut playlist = Playlist {
+            original: (0..tracks.len()).collect(),
+            tracks,
+            position: 0,
+            shuffle: order.shuffle,
+            repeat: order.repeat,
+            random: Random::new(order.seed.unwrap_or_else(order::random_seed)),
+        };
+        // Nothing has played yet, so the first track is up for grabs too
+        playlist.arrange(0);
+        playlist
+    }
+
+    /// The track under the cursor, or `None` once the end has been passed.
+    pub fn current(&self) -> Option<&Track> {
+        self.tracks.get(self.position)
+    }
+
+    pub fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    /// Index of the track under the cursor.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn shuffle(&self) -> Shuffle {
+        self.shuffle
+    }
+
+    pub fn repeat(&self) -> Repeat {
+        self.repeat
+    }
+
+    /// Rearrange the tracks after the current one. Turning shuffle off puts
+    /// every track back where it was, keeping the cursor on the current one.
+    pub fn set_shuffle(&mut self, shuffle: Shuffle) {
+        self.shuffle = shuffle;
+        if shuffle == Shuffle::Off {
+            let mut order: Vec<usize> = (0..self.tracks.len()).collect();
+            order.sort_by_key(|&index| self.original[index]);
+            self.rearrange(&order);
+        } else {
+            self.arrange(self.position + 1);
+        }
+    }
+
+    pub fn set_repeat(&mut self, repeat: Repeat) {
+        self.repeat = repeat;
+    }
+
+    /// Move on to the following track.
+    pub fn next(&mut self) {
+        let next = self.position + 1;
+        self.position = match self.repeat {
+            Repeat::All if next >= self.tracks.len() => 0,
+            _ => next.min(self.tracks.len()),
+        };
+    }
+
+    /// Move on from a track that played through to its end, which is where
+    /// repeating a single track comes in.
+    pub fn finished(&mut self) {
+        if self.repeat != Repeat::One {
+            self.next();
+        }
+    }
+
+    /// The track that `finished` would move on to, if any.
+    pub fn upcoming(&self) -> Option<&Track> {
+        self.tracks.get(self.upcoming_position()?)
+    }
+
+    /// Index of the track that `finished` would move on to, if any.
+    pub fn upcoming_position(&self) -> Option<usize> {
+        let next = match self.repeat {
+            Repeat::One => self.position,
+            Repeat::All if self.position + 1 >= self.tracks.len() => 0,
+            _ => self.position + 1,
+        };
+        (next < self.tracks.len()).then_some(next)
+    }
+
+    /// Whether the track at `index` is played alongside others from its
+    /// album, going by the tracks either side of it.
+    pub fn in_album(&self, index: usize) -> bool {
+        let Some(track) = self.tracks.get(index) else {
+            return false;
+        };
+        let before = index
+            .checked_sub(1)
+            .and_then(|before| self.tracks.get(before));
+        let after = self.tracks.get(index + 1);
+        [before, after]
+            .into_iter()
+            .flatten()
+            .any(|other| order::same_album(track, other))
+    }
+
+    /// Move back to the preceding track, or stay on the first if already there.
+    pub fn previous(&mut self) {
+        self.position = self.position.saturating_sub(1);
+    }
+
+    /// Add tracks to the end of the queue.
+    pub fn append(&mut self, tracks: impl IntoIterator<Item = Track>) {
+        let next = self.original.iter().max().map_or(0, |last| last + 1);
+        for (offset, track) in tracks.into_iter().enumerate() {
+            self.tracks.push(track);
+            self.original.push(next + offset);
+        }
+    }
+
+    /// Take the track at `index` out of the queue. Removing the current track
+    /// leaves the cursor on the one that followed it.
+    pub fn remove(&mut self, index: usize) -> Option<Track> {
+        if index >= self.tracks.len() {
+            return None;
+        }
+        self.original.remove(index);
+        let track = self.tracks.remove(index);
+        if index < self.position {
+            self.position -= 1;
+        }
+        self.keep_order();
+        Some(track)
+    }
+
+    /// Move the track at `from` so that it ends up at `to`, with the cursor
+    /// staying on the same track.
+    pub fn move_track(&mut self, from: usize, to: usize) {
+        if from >= self.tracks.len() || to >= self.tracks.len() {
+            return;
+        }
+        let track = self.tracks.remove(from);
+        let original = self.original.remove(from);
+        self.tracks.insert(to, track);
+        self.original.insert(to, original);
+
+        if self.position == from {
+            self.position = to;
+        } else {
+            if from < self.position {
+                self.position -= 1;
+            }
+            if to <= self.position {
+                self.position += 1;
+            }
+        }
+        self.keep_order();
+    }
+
+    /// Move the track at `index` to straight after the current one, returning
+    /// where it ends up.
+    pub fn play_next(&mut self, index: usize) -> usize {
+        if index == self.position {
+            return index;
+        }
+        // Taking out an earlier track brings the current one back a place
+        let to = match index < self.position {
+            true => self.position,
+            false => self.position + 1,
+        };
+        self.move_track(index, to);
+        to
+    }
+
+    /// Without shuffling, edits to the queue become its order to return to.
+    fn keep_order(&mut self) {
+        if self.shuffle == Shuffle::Off {
+            self.original = (0..self.tracks.len()).collect();
+        }
+    }
+
+    /// Shuffle the tracks from `from` onwards, if shuffling at all.
+    fn arrange(&mut self, from: usize) {
+        let from = from.min(self.tracks.len());
+        let mut rest: Vec<usize> = (from..self.tracks.len()).collect();
+        match self.shuffle {
+            Shuffle::Off => return,
+            Shuffle::Tracks => self.random.shuffle(&mut rest),
+            Shuffle::Albums => {
+                rest = order::shuffle_albums(&self.tracks, &self.original, from, &mut self.random)
+            }
+        }
+        let order: Vec<usize> = (0..from).chain(rest).collect();
+        self.rearrange(&order);
+    }
+
+    /// Put the tracks in `order`, given as their current indices.
+    fn rearrange(&mut self, order: &[usize]) {
+        let mut tracks: Vec<Option<Track>> = self.tracks.drain(..).map(Some).collect();
+        self.tracks = order
+            .iter()
+            .map(|&index| tracks[index].take().expect("each track is used once"))
+            .collect();
+        self.original = order.iter().map(|&index| self.original[index]).collect();
+        if let Some(position) = order.iter().position(|&index| index == self.position) {
+            self.position = position;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn playlist(count: usize, order: PlayOrder) -> Playlist {
+        let tracks = (1..=count).map(|number| Track::new(format!("/music/{number}.flac")));
+        Playlist::new(tracks, order)
+    }
+
+    fn paths(playlist: &Playlist) -> Vec<&str> {
+        playlist
+            .tracks()
+            .iter()
+            .map(|track| track.path.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn cursor_moves_both_ways_and_stops_at_the_ends() {
+        let mut playlist = playlist(2, PlayOrder::default());
+        playlist.previous();
+        assert_eq!(playlist.position(), 0);
+
+        playlist.next();
+        playlist.next();
+        assert!(playlist.current().is_none());
+        playlist.next();
+        assert_eq!(playlist.position(), 2);
+
+        // Going back from past the end returns to the last track
+        playlist.previous();
+        assert_eq!(playlist.current().unwrap().path, "/music/2.flac");
+        playlist.previous();
+        assert_eq!(playlist.current().unwrap().path, "/music/1.flac");
+    }
+
+    #[test]
+    fn repeating_all_goes_back_to_the_start() {
+        let order = PlayOrder {
+            repeat: Repeat::All,
+            ..PlayOrder::default()
+        };
+        let mut playlist = playlist(2, order);
+        playlist.next();
+        playlist.finished();
+        assert_eq!(playlist.position(), 0);
+
+        playlist.set_repeat(Repeat::Off);
+        playlist.next();
+        playlist.next();
+        assert!(playlist.current().is_none());
+    }
+
+    #[test]
+    fn repeating_one_is_only_when_a_track_finishes() {
+        let order = PlayOrder {
+            repeat: Repeat::One,
+            ..PlayOrder::default()
+        };
+        let mut playlist = playlist(3, order);
+        playlist.finished();
+        assert_eq!(playlist.position(), 0);
+        playlist.next();
+        assert_eq!(playlist.position(), 1);
+    }
+
+    #[test]
+    fn upcoming_is_where_finishing_moves_on_to() {
+        for repeat in [Repeat::Off, Repeat::All, Repeat::One] {
+            let mut playlist = playlist(3, PlayOrder::default());
+            playlist.set_repeat(repeat);
+            for _ in 0..3 {
+                let upcoming = playlist.upcoming().cloned();
+                playlist.finished();
+                assert_eq!(upcoming.as_ref(), playlist.current(), "{repeat:?}");
+                playlist.next();
+            }
+        }
+    }
+
+    #[test]
+    fn albums_are_told_apart_by_neighbouring_tracks() {
+        let paths = ["/a/1.flac", "/a/2.flac", "/b/1.flac", "/c/1.flac"];
+        let tracks = paths.map(|path| Track::new(path.to_string()));
+        let playlist = Playlist::new(tracks, PlayOrder::default());
+        let in_album: Vec<bool> = (0..5).map(|index| playlist.in_album(index)).collect();
+        assert_eq!(in_album, [true, true, false, false, false]);
+    }
+
+    #[test]
+    fn shuffling_leaves_what_has_played() {
+        let order = PlayOrder {
+            seed: Some(5),
+            ..PlayOrder::default()
+        };
+        let mut playlist = playlist(10, order);
+        playlist.next();
+        playlist.next();
+        playlist.set_shuffle(Shuffle::Tracks);
+        assert_eq!(
+            paths(&playlist)[..3],
+            ["/music/1.flac", "/music/2.flac", "/music/3.flac"]
+        );
+        assert_ne!(
+            paths(&playlist),
+            paths(&self::playlist(10, PlayOrder::default()))
+        );
+
+        // Back in order, still on the same track
+        playlist.next();
+        let current = playlist.current().unwrap().clone();
+        playlist.set_shuffle(Shuffle::Off);
+        assert_eq!(
+            paths(&playlist),
+            paths(&self::playlist(10, PlayOrder::default()))
+        );
+        assert_eq!(playlist.current(), Some(&current));
+    }
+
+    #[test]
+    fn editing_keeps_the_cursor_on_the_current_track() {
+        let mut playlist = playlist(5, PlayOrder::default());
+        playlist.next();
+        playlist.next();
+
+        playlist.move_track(0, 4);
+        assert_eq!(playlist.position(), 1);
+        assert_eq!(playlist.play_next(4), 2);
+        playlist.remove(0);
+        assert_eq!(
+            paths(&playlist),
+            [
+                "/music/3.flac",
+                "/music/1.flac",
+                "/music/4.flac",
+                "/music/5.flac"
+            ]
+        );
+        assert_eq!(playlist.current().unwrap().path, "/music/3.flac");
+
+        // Taking out the current track moves on to the next
+        playlist.remove(0);
+        assert_eq!(playlist.current().unwrap().path, "/music/1.flac");
+        playlist.append([Track::new("/music/6.flac".to_string())]);
+        assert_eq!(paths(&playlist).last(), Some(&"/music/6.flac"));
+    }
+
+    #[test]
+    fn edits_made_while_shuffled_are_kept() {
+        let order = PlayOrder {
+            shuffle: Shuffle::Tracks,
+            seed: Some(3),
+            ..PlayOrder::default()
+        };
+        let mut playlist = playlist(4, order);
+        let removed = playlist.remove(1).unwrap();
+        playlist.append([Track::new("/music/5.flac".to_string())]);
+
+        playlist.set_shuffle(Shuffle::Off);
+        let expected: Vec<String> = (1..=5)
+            .map(|number| format!("/music/{number}.flac"))
+            .filter(|path| *path != removed.path)
+            .collect();
+        assert_eq!(paths(&playlist), expected);
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_queue() {
+        let order = || PlayOrder {
+            shuffle: Shuffle::Tracks,
+            seed: Some(1234),
+            ..PlayOrder::default()
+        };
+        let first = playlist(10, order());
+        assert_eq!(paths(&first), paths(&playlist(10, order())));
+        assert_ne!(paths(&first), paths(&playlist(10, PlayOrder::default())));
+    }
+}
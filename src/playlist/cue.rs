@@ -0,0 +1,370 @@
+//! Parsing of CUE sheets, which split a single file into tracks.
+//!
+//! Albums are often ripped as one big file, with a CUE sheet alongside saying
+//! where each track starts:
+//!
+//! ```text
+//! PERFORMER "Someone"
+//! TITLE "An Album"
+//! REM REPLAYGAIN_ALBUM_GAIN -7.89 dB
+//! FILE "album.flac" WAVE
+//!   TRACK 01 AUDIO
+//!     TITLE "Something"
+//!     INDEX 01 00:00:00
+//!   TRACK 02 AUDIO
+//!     TITLE "Something Else"
+//!     REM REPLAYGAIN_TRACK_GAIN -6.54 dB
+//!     INDEX 00 03:58:20
+//!     INDEX 01 04:00:00
+//! ```
+//!
+//! Times are minutes, seconds and frames, with 75 frames to a second. Each
+//! track plays from its `INDEX 01` up until that of the next track in the same
+//! file, so any pregap marked by `INDEX 00` is heard at the end of the track
+//! before. Commands other than those needed to find and describe tracks are
+//! ignored.
+
+use std::path::Path;
+
+use super::{resolve, Section, Track};
+
+const FRAMES_PER_SECOND: f64 = 75.0;
+
+// Comments that have an equivalent AudioToolbox info dictionary key. Any
+// others are passed through with lower case names.
+const REMARK_KEYS: [(&str, &str); 3] = [
+    ("DATE", "year"),
+    ("GENRE", "genre"),
+    ("COMMENT", "comments"),
+];
+
+/// A track as found in the sheet, before its end is known.
+struct CueTrack {
+    line: usize,
+    number: String,
+    is_audio: bool,
+    /// The file in use when the track started
+    file: Option<Result<String, String>>,
+    /// Seconds into the file of `INDEX 01`
+    start: Option<f64>,
+    title: Option<String>,
+    performer: Option<String>,
+    metadata: Vec<(String, String)>,
+}
+
+/// Pick out the tracks listed in `text`, with relative file names resolved
+/// against `base`. Each entry comes with the line its track starts on, and is
+/// an error if it isn't something that can be played.
+pub fn parse(text: &str, base: &Path) -> Vec<(usize, Result<Track, String>)> {
+    let mut album_performer = None;
+    let mut album_metadata = Vec::new();
+    let mut file = None;
+    let mut tracks: Vec<CueTrack> = Vec::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let (command, arguments) = split_command(line);
+        let mut arguments = arguments.into_iter();
+        // Until the first track, everything describes the album as a whole
+        let track = tracks.last_mut();
+
+        match (command.as_str(), track) {
+            ("FILE", _) => {
+                let name = arguments.next().unwrap_or_default();
+                file = Some(resolve(&name, base));
+            }
+            ("TRACK", _) => tracks.push(CueTrack {
+                line: index + 1,
+                number: arguments.next().unwrap_or_default(),
+                is_audio: arguments.next().as_deref() == Some("AUDIO"),
+                file: file.clone(),
+                start: None,
+                title: None,
+                performer: None,
+                metadata: Vec::new(),
+            }),
+            ("INDEX", Some(track)) => {
+                let number = arguments.next();
+                let time = arguments.next().and_then(|time| parse_time(&time));
+                if number.as_deref() == Some("01") {
+                    // The track may have started in a previous file, with its
+                    // pregap, but it is the file holding this index that counts
+                    track.file = file.clone();
+                    track.start = time;
+                }
+            }
+            ("TITLE", Some(track)) => track.title = arguments.next(),
+            ("TITLE", None) => {
+                let title = arguments.next().unwrap_or_default();
+                set(&mut album_metadata, "album", title);
+            }
+            ("PERFORMER", Some(track)) => track.performer = arguments.next(),
+            ("PERFORMER", None) => album_performer = arguments.next(),
+            ("REM", track) => {
+                let Some(name) = arguments.next() else {
+                    continue;
+                };
+                let value = arguments.collect::<Vec<_>>().join(" ");
+                let metadata = match track {
+                    Some(track) => &mut track.metadata,
+                    None => &mut album_metadata,
+                };
+                set(metadata, &remark_key(&name), value);
+            }
+            _ => {}
+        }
+    }
+
+    let mut entries = Vec::new();
+    for (index, track) in tracks.iter().enumerate() {
+        // Data tracks on mixed mode discs aren't worth mentioning
+        if !track.is_audio {
+            continue;
+        }
+        // Tracks run until the next one starts, unless that is in another file
+        let end = tracks[index + 1..]
+            .iter()
+            .find(|next| next.is_audio)
+            .filter(|next| next.file == track.file)
+            .and_then(|next| next.start);
+        let entry = describe(track, end, album_performer.as_deref(), &album_metadata);
+        entries.push((track.line, entry));
+    }
+    entries
+}
+
+fn describe(
+    track: &CueTrack,
+    end: Option<f64>,
+    album_performer: Option<&str>,
+    album_metadata: &[(String, String)],
+) -> Result<Track, String> {
+    let number = &track.number;
+    let path = match &track.file {
+        Some(file) => file.clone()?,
+        None => return Err(format!("track {number} isn't in a file")),
+    };
+    let Some(start) = track.start else {
+        return Err(format!("track {number} has no INDEX 01"));
+    };
+
+    let performer = track.performer.as_deref().or(album_performer);
+    let title = match (performer, &track.title) {
+        (Some(performer), Some(title)) => format!("{performer} - {title}"),
+        (None, Some(title)) => title.clone(),
+        (_, None) => format!("Track {number}"),
+    };
+
+    let mut metadata = album_metadata.to_vec();
+    set(&mut metadata, "track number", number.clone());
+    if let Some(title) = &track.title {
+        set(&mut metadata, "title", title.clone());
+    }
+    if let Some(performer) = performer {
+        set(&mut metadata, "artist", performer.to_string());
+    }
+    for (key, value) in &track.metadata {
+        set(&mut metadata, key, value.clone());
+    }
+
+    Ok(Track {
+        title: Some(title),
+        duration: end.map(|end| end - start),
+        section: Some(Section { start, end }),
+        metadata,
+        ..Track::new(path)
+    })
+}
+
+/// Split a line into its command, in upper case, and arguments. Arguments are
+/// separated by spaces, unless in quotes.
+fn split_command(line: &str) -> (String, Vec<String>) {
+    let mut words: Vec<String> = Vec::new();
+    let mut chars = line.trim().chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let word = match c {
+            '"' => {
+                chars.next();
+                chars.by_ref().take_while(|&c| c != '"').collect()
+            }
+            _ => chars.by_ref().take_while(|c| !c.is_whitespace()).collect(),
+        };
+        words.push(word);
+    }
+
+    let mut words = words.into_iter();
+    let command = words.next().unwrap_or_default().to_ascii_uppercase();
+    (command, words.collect())
+}
+
+/// Convert a time given as `mm:ss:ff` into seconds.
+fn parse_time(time: &str) -> Option<f64> {
+    let mut parts = time.split(':').map(|part| part.parse::<u32>().ok());
+    let (minutes, seconds, frames) = (parts.next()??, parts.next()??, parts.next()??);
+    if parts.next().is_some() || seconds >= 60 || frames >= FRAMES_PER_SECOND as u32 {
+        return None;
+    }
+    Some(minutes as f64 * 60.0 + seconds as f64 + frames as f64 / FRAMES_PER_SECOND)
+}
+
+fn remark_key(name: &str) -> String {
+    let name = name.to_ascii_uppercase();
+    match REMARK_KEYS.iter().find(|(remark, _)| *remark == name) {
+        Some((_, key)) => key.to_string(),
+        None => name.to_ascii_lowercase(),
+    }
+}
+
+/// Set a property, replacing any already there.
+fn set(metadata: &mut Vec<(String, String)>, key: &str, value: String) {
+    match metadata.iter_mut().find(|(existing, _)| existing == key) {
+        Some((_, existing)) => *existing = value,
+        None => metadata.push((key.to_string(), value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALBUM: &str = r#"REM GENRE Ambient
+REM REPLAYGAIN_ALBUM_GAIN -7.89 dB
+PERFORMER "Someone"
+TITLE "An Album"
+FILE "album.flac" WAVE
+  TRACK 01 AUDIO
+    TITLE "Something"
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE "Something Else"
+    PERFORMER "Someone Else"
+    REM REPLAYGAIN_TRACK_GAIN -6.54 dB
+    INDEX 00 03:58:20
+    INDEX 01 04:00:37
+  TRACK 03 AUDIO
+    INDEX 01 07:30:00
+"#;
+
+    fn parse_ok(text: &str) -> Vec<(usize, Track)> {
+        parse(text, Path::new("/music"))
+            .into_iter()
+            .map(|(line, entry)| (line, entry.unwrap()))
+            .collect()
+    }
+
+    fn metadata(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|&(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn tracks_run_until_the_next_one_starts() {
+        let tracks = parse_ok(ALBUM);
+        let lines: Vec<usize> = tracks.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, [6, 9, 15]);
+
+        let sections: Vec<Section> = tracks
+            .iter()
+            .map(|(_, track)| track.section.unwrap())
+            .collect();
+        let second_start = 240.0 + 37.0 / 75.0;
+        assert_eq!(
+            sections,
+            [
+                Section {
+                    start: 0.0,
+                    end: Some(second_start)
+                },
+                Section {
+                    start: second_start,
+                    end: Some(450.0)
+                },
+                Section {
+                    start: 450.0,
+                    end: None
+                },
+            ]
+        );
+        assert_eq!(tracks[1].1.duration, Some(450.0 - second_start));
+        assert_eq!(tracks[2].1.duration, None);
+        assert!(tracks
+            .iter()
+            .all(|(_, track)| track.path == "/music/album.flac"));
+    }
+
+    #[test]
+    fn tracks_are_described_by_the_album_and_themselves() {
+        let tracks = parse_ok(ALBUM);
+        let titles: Vec<&str> = tracks.iter().map(|(_, track)| track.name()).collect();
+        assert_eq!(
+            titles,
+            [
+                "Someone - Something",
+                "Someone Else - Something Else",
+                "Track 03"
+            ]
+        );
+        assert_eq!(
+            tracks[1].1.metadata,
+            metadata(&[
+                ("genre", "Ambient"),
+                ("replaygain_album_gain", "-7.89 dB"),
+                ("album", "An Album"),
+                ("track number", "02"),
+                ("title", "Something Else"),
+                ("artist", "Someone Else"),
+                ("replaygain_track_gain", "-6.54 dB"),
+            ])
+        );
+    }
+
+    #[test]
+    fn tracks_in_separate_files() {
+        let text = r#"FILE "01.wav" WAVE
+  TRACK 01 AUDIO
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    INDEX 00 03:00:00
+FILE "02.wav" WAVE
+    INDEX 01 00:00:00
+"#;
+        let tracks = parse_ok(text);
+        assert_eq!(tracks[0].1.path, "/music/01.wav");
+        assert_eq!(tracks[0].1.section.unwrap().end, None);
+        assert_eq!(tracks[1].1.path, "/music/02.wav");
+        assert_eq!(tracks[1].1.section.unwrap().start, 0.0);
+    }
+
+    #[test]
+    fn unplayable_tracks() {
+        let text = r#"  TRACK 01 AUDIO
+    INDEX 01 00:00:00
+FILE "disc.bin" BINARY
+  TRACK 02 MODE1/2352
+    INDEX 01 00:00:00
+  TRACK 03 AUDIO
+    INDEX 01 00:99:00
+"#;
+        let entries = parse(text, Path::new("/music"));
+        let lines: Vec<usize> = entries.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, [1, 6]);
+        assert!(entries.iter().all(|(_, entry)| entry.is_err()));
+    }
+
+    #[test]
+    fn commands_with_quotes() {
+        assert_eq!(
+            split_command(r#"  file "My Album.flac"  WAVE"#),
+            (
+                "FILE".to_string(),
+                vec!["My Album.flac".to_string(), "WAVE".to_string()]
+            )
+        );
+        assert_eq!(split_command(""), (String::new(), vec![]));
+    }
+}
@@ -0,0 +1,340 @@
+//! Finding the audio files within a directory, and all of those below it.
+//!
+//! Files are picked out either by their extension, or by sniffing their first
+//! few bytes. Within each directory, files come first in natural order, where
+//! runs of digits are compared by value so that "2 - x" comes before
+//! "10 - y". Subdirectories follow on in the same order. Files can instead be
+//! ordered by their disc and track number tags.
+//!
+//! Hidden files are left out, as are symlinks leading back to a directory that
+//! is already being searched.
+
+use std::cmp::Ordering;
+use std::fs;
+use std::io;
+use std::iter;
+use std::path::{Path, PathBuf};
+
+use super::{SkippedEntry, Track};
+use crate::player::{self, PlaybackContext, AUDIO_EXTENSIONS};
+
+/// How to order the files within each directory.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortOrder {
+    Name,
+    /// Disc then track number, with untagged files going last
+    Tags,
+}
+
+pub struct ScanOptions {
+    /// Extensions of the files to pick out, in lower case
+    pub extensions: Vec<String>,
+    /// Pick out audio files by their contents rather than their extension
+    pub sniff: bool,
+    pub sort: SortOrder,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions {
+            extensions: AUDIO_EXTENSIONS.iter().map(|ext| ext.to_string()).collect(),
+            sniff: false,
+            sort: SortOrder::Name,
+        }
+    }
+}
+
+struct Scan<'a> {
+    options: &'a ScanOptions,
+    tracks: Vec<Track>,
+    skipped: Vec<SkippedEntry>,
+    /// Canonical paths of the directories being searched, outermost first
+    ancestors: Vec<PathBuf>,
+}
+
+/// Find the audio files in `path` and its subdirectories. Anything below
+/// `path` that can't be read is skipped, rather than failing the whole search.
+pub fn scan_directory(
+    path: &str,
+    options: &ScanOptions,
+) -> io::Result<(Vec<Track>, Vec<SkippedEntry>)> {
+    let mut scan = Scan {
+        options,
+        tracks: Vec::new(),
+        skipped: Vec::new(),
+        ancestors: Vec::new(),
+    };
+    scan.directory(Path::new(path))?;
+    Ok((scan.tracks, scan.skipped))
+}
+
+impl Scan<'_> {
+    fn directory(&mut self, dir: &Path) -> io::Result<()> {
+        let canonical = fs::canonicalize(dir)?;
+        if self.ancestors.contains(&canonical) {
+            let reason = format!("loops back to '{}'", canonical.display());
+            self.skip(dir, reason);
+            return Ok(());
+        }
+
+        let mut files = Vec::new();
+        let mut directories = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if file_name(&path).starts_with('.') {
+                continue;
+            }
+            // Following symlinks to whatever they point at
+            match fs::metadata(&path) {
+                Ok(metadata) if metadata.is_dir() => directories.push(path),
+                Ok(_) if self.is_audio(&path) => files.push(path),
+                Ok(_) => {}
+                Err(err) => self.skip(&path, err.to_string()),
+            }
+        }
+
+        self.sort_files(&mut files);
+        directories.sort_by(|a, b| natural_cmp(file_name(a), file_name(b)));
+        let files = files.into_iter().map(|path| Track::new(path_string(&path)));
+        self.tracks.extend(files);
+
+        self.ancestors.push(canonical);
+        for directory in directories {
+            if let Err(err) = self.directory(&directory) {
+                self.skip(&directory, err.to_string());
+            }
+        }
+        self.ancestors.pop();
+        Ok(())
+    }
+
+    fn is_audio(&mut self, path: &Path) -> bool {
+        if !self.options.sniff {
+            let extension = path.extension().and_then(|ext| ext.to_str());
+            let extension = extension.map(str::to_ascii_lowercase);
+            return extension.is_some_and(|ext| self.options.extensions.contains(&ext));
+        }
+        match player::is_audio_file(&path_string(path)) {
+            Ok(is_audio) => is_audio,
+            Err(err) => {
+                self.skip(path, err.to_string());
+                false
+            }
+        }
+    }
+
+    fn sort_files(&self, files: &mut Vec<PathBuf>) {
+        let by_name = |a: &PathBuf, b: &PathBuf| natural_cmp(file_name(a), file_name(b));
+        match self.options.sort {
+            SortOrder::Name => files.sort_by(by_name),
+            SortOrder::Tags => {
+                let mut tagged: Vec<_> = files.drain(..).map(|f| (position(&f), f)).collect();
+                tagged.sort_by(|(a, a_path), (b, b_path)| {
+                    let ordering = match (a, b) {
+                        (Some(a), Some(b)) => a.cmp(b),
+                        (Some(_), None) => Ordering::Less,
+                        (None, Some(_)) => Ordering::Greater,
+                        (None, None) => Ordering::Equal,
+                    };
+                    ordering.then_with(|| by_name(a_path, b_path))
+                });
+                files.extend(tagged.into_iter().map(|(_, path)| path));
+            }
+        }
+    }
+
+    fn skip(&mut self, path: &Path, reason: String) {
+        self.skipped.push(SkippedEntry {
+            source: path_string(path),
+            line: None,
+            reason,
+        });
+    }
+}
+
+/// Disc and track number of a file, or `None` if it has neither tag.
+fn position(path: &Path) -> Option<(u32, u32)> {
+    let context = PlaybackContext::new(&path_string(path)).ok()?;
+    let metadata = context.file_metadata().ok()?;
+    let number = |key: &str| {
+        let (_, value) = metadata.iter().find(|(existing, _)| existing == key)?;
+        // Numbers can be given out of a total, like "3/12"
+        let digits: String = value
+            .trim()
+            .chars()
+            .take_while(char::is_ascii_digit)
+            .collect();
+        digits.parse().ok()
+    };
+    match (number("disc number"), number("track number")) {
+        (None, None) => None,
+        (disc, track) => Some((disc.unwrap_or(1), track.unwrap_or(0))),
+    }
+}
+
+/// Compare names so that runs of digits are ordered by their value, and
+/// everything else without regard to case.
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a_chunks = chunks(a);
+    let mut b_chunks = chunks(b);
+    loop {
+        let (a_chunk, b_chunk) = match (a_chunks.next(), b_chunks.next()) {
+            // Names that only differ by case or leading zeros still need an order
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(a_chunk), Some(b_chunk)) => (a_chunk, b_chunk),
+        };
+        let is_number = |chunk: &str| chunk.starts_with(|c: char| c.is_ascii_digit());
+        let ordering = match (is_number(a_chunk), is_number(b_chunk)) {
+            (true, true) => {
+                let a_digits = a_chunk.trim_start_matches('0');
+                let b_digits = b_chunk.trim_start_matches('0');
+                // Longer numbers are bigger, and otherwise digits are in order
+                a_digits
+                    .len()
+                    .cmp(&b_digits.len())
+                    .then_with(|| a_digits.cmp(b_digits))
+            }
+            _ => a_chunk.to_lowercase().cmp(&b_chunk.to_lowercase()),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+}
+
+/// Split text into alternating runs of digits and everything else.
+fn chunks(text: &str) -> impl Iterator<Item = &str> {
+    let mut rest = text;
+    iter::from_fn(move || {
+        let is_digit = rest.chars().next()?.is_ascii_digit();
+        let end = rest
+            .find(|c: char| c.is_ascii_digit() != is_digit)
+            .unwrap_or(rest.len());
+        let (chunk, tail) = rest.split_at(end);
+        rest = tail;
+        Some(chunk)
+    })
+}
+
+fn file_name(path: &Path) -> &str {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default()
+}
+
+fn path_string(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    /// Enough of a header to pass for a WAV file.
+    const WAV_HEADER: &[u8] = b"RIFF\0\0\0\0WAVE";
+
+    /// Paths of the tracks found in `dir`, relative to it.
+    fn scan(dir: &TempDir, options: &ScanOptions) -> (Vec<String>, Vec<SkippedEntry>) {
+        let (tracks, skipped) = scan_directory(dir.path(), options).unwrap();
+        let prefix = format!("{}/", dir.path());
+        let paths = tracks
+            .into_iter()
+            .map(|track| track.path.strip_prefix(&prefix).unwrap().to_string())
+            .collect();
+        (paths, skipped)
+    }
+
+    #[test]
+    fn numbers_are_compared_by_value() {
+        let mut names = [
+            "10 - y.flac",
+            "2 - x.flac",
+            "Disc 1",
+            "disc 10",
+            "Disc 2",
+            "02 - z.flac",
+            "b.flac",
+            "a.flac",
+        ];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(
+            names,
+            [
+                "2 - x.flac",
+                "02 - z.flac",
+                "10 - y.flac",
+                "a.flac",
+                "b.flac",
+                "Disc 1",
+                "Disc 2",
+                "disc 10",
+            ]
+        );
+    }
+
+    #[test]
+    fn files_come_before_subdirectories() {
+        let dir = TempDir::new(
+            "files_before_subdirectories",
+            &[
+                "10 - y.wav",
+                "2 - x.WAV",
+                "cover.jpg",
+                ".hidden.wav",
+                "Disc 10/1.wav",
+                "Disc 2/1.flac",
+                "Disc 2/notes.txt",
+            ],
+            WAV_HEADER,
+        );
+        let (paths, skipped) = scan(&dir, &ScanOptions::default());
+        assert_eq!(
+            paths,
+            ["2 - x.WAV", "10 - y.wav", "Disc 2/1.flac", "Disc 10/1.wav"]
+        );
+        assert!(skipped.is_empty());
+    }
+
+    #[test]
+    fn extensions_can_be_chosen() {
+        let dir = TempDir::new("extensions_can_be_chosen", &["a.wav", "b.flac"], WAV_HEADER);
+        let options = ScanOptions {
+            extensions: vec!["flac".to_string()],
+            ..ScanOptions::default()
+        };
+        assert_eq!(scan(&dir, &options).0, ["b.flac"]);
+    }
+
+    #[test]
+    fn files_can_be_sniffed() {
+        let dir = TempDir::new("files_can_be_sniffed", &["a.wav", "recording"], WAV_HEADER);
+        fs::write(dir.join("b.wav"), "not really").unwrap();
+        let options = ScanOptions {
+            sniff: true,
+            ..ScanOptions::default()
+        };
+        assert_eq!(scan(&dir, &options).0, ["a.wav", "recording"]);
+    }
+
+    #[test]
+    fn symlink_loops_are_skipped() {
+        let dir = TempDir::new("symlink_loops", &["a/1.wav", "b/2.wav"], WAV_HEADER);
+        symlink(dir.join("b"), dir.join("a/to-b")).unwrap();
+        symlink(dir.path(), dir.join("b/to-top")).unwrap();
+        symlink("self", dir.join("self")).unwrap();
+
+        let (paths, skipped) = scan(&dir, &ScanOptions::default());
+        assert_eq!(paths, ["a/1.wav", "a/to-b/2.wav", "b/2.wav"]);
+        let mut sources: Vec<_> = skipped
+            .iter()
+            .map(|entry| entry.source.strip_prefix(dir.path()).unwrap())
+            .collect();
+        sources.sort();
+        assert_eq!(sources, ["/a/to-b/to-top", "/b/to-top", "/self"]);
+    }
+}
@@ -0,0 +1,191 @@
+//! Parsing of M3U playlists, including the extended M3U8 flavour.
+//!
+//! Plain M3U files are just a list of paths, one per line. Extended files
+//! start with an `#EXTM3U` header, and can precede each path with an `#EXTINF`
+//! line giving its duration and title. Any other line starting with a hash is
+//! a comment. Plenty of files in the wild leave out the header, so track info
+//! is taken wherever it is found.
+
+use std::fmt::Write;
+use std::path::Path;
+
+use super::{resolve, Track};
+
+const HEADER: &str = "#EXTM3U";
+const TRACK_INFO: &str = "#EXTINF:";
+
+/// Pick out the tracks listed in `text`, with relative paths resolved against
+/// `base`. Each entry comes with the line it was found on, and is an error
+/// if it isn't something that can be played.
+pub fn parse(text: &str, base: &Path) -> Vec<(usize, Result<Track, String>)> {
+    let mut entries = Vec::new();
+    // Info applies to the next path to come along
+    let mut info = None;
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(rest) = line.strip_prefix(TRACK_INFO) {
+            info = Some(parse_track_info(rest));
+            continue;
+        }
+        // Including the header
+        if line.starts_with('#') {
+            continue;
+        }
+
+        let (duration, title) = info.take().unwrap_or_default();
+        let entry = resolve(line, base).map(|path| Track {
+            title,
+            duration,
+            ..Track::new(path)
+        });
+        entries.push((index + 1, entry));
+    }
+    entries
+}
+
+/// Split out the duration and title from what follows `#EXTINF:`, which looks
+/// like `123 key="value",Artist - Title`.
+fn parse_track_info(info: &str) -> (Option<f64>, Option<String>) {
+    let (details, title) = info.split_once(',').unwrap_or((info, ""));
+    // Any attributes follow the duration, separated by spaces
+    let duration = details
+        .split_whitespace()
+        .next()
+        .and_then(|duration| duration.parse::<f64>().ok())
+        // Unknown durations are given as -1
+        .filter(|&duration| duration > 0.0);
+    let title = Some(title.trim())
+        .filter(|title| !title.is_empty())
+        .map(str::to_string);
+    (duration, title)
+}
+
+/// Write out `tracks` as the contents of an extended M3U file.
+pub fn write(tracks: &[Track]) -> String {
+    let mut text = format!("{HEADER}\n");
+    for track in tracks {
+        if track.title.is_some() || track.duration.is_some() {
+            let duration = track.duration.map_or(-1.0, f64::round);
+            let title = track.title.as_deref().unwrap_or_default();
+            writeln!(text, "{TRACK_INFO}{duration},{title}").unwrap();
+        }
+        writeln!(text, "{}", track.path).unwrap();
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_ok(text: &str) -> Vec<(usize, Track)> {
+        parse(text, Path::new("/music/mixes"))
+            .into_iter()
+            .map(|(line, entry)| (line, entry.unwrap()))
+            .collect()
+    }
+
+    fn track(path: &str, title: Option<&str>, duration: Option<f64>) -> Track {
+        Track {
+            title: title.map(str::to_string),
+            duration,
+            ..Track::new(path.to_string())
+        }
+    }
+
+    #[test]
+    fn plain_paths_are_resolved_against_the_playlist() {
+        let entries = parse_ok("one.flac\n../albums/two.flac\n/elsewhere/three.flac\n");
+        assert_eq!(
+            entries,
+            [
+                (1, track("/music/mixes/one.flac", None, None)),
+                (2, track("/music/mixes/../albums/two.flac", None, None)),
+                (3, track("/elsewhere/three.flac", None, None)),
+            ]
+        );
+    }
+
+    #[test]
+    fn extended_info_applies_to_the_following_path() {
+        let text = "#EXTM3U\n\
+                    #EXTINF:185,Someone - Something\n\
+                    one.flac\n\
+                    \n\
+                    # A comment\n\
+                    two.flac\n";
+        assert_eq!(
+            parse_ok(text),
+            [
+                (
+                    3,
+                    track(
+                        "/music/mixes/one.flac",
+                        Some("Someone - Something"),
+                        Some(185.0)
+                    )
+                ),
+                (6, track("/music/mixes/two.flac", None, None)),
+            ]
+        );
+    }
+
+    #[test]
+    fn track_info_with_attributes_or_unknown_duration() {
+        assert_eq!(
+            parse_track_info(r#"42.5 tvg-id="x",Title, with comma"#),
+            (Some(42.5), Some("Title, with comma".to_string()))
+        );
+        assert_eq!(parse_track_info("-1,"), (None, None));
+        assert_eq!(parse_track_info("junk"), (None, None));
+    }
+
+    #[test]
+    fn file_urls_are_decoded() {
+        let entries = parse_ok("file:///music/My%20Album/one.flac\r\n");
+        assert_eq!(
+            entries,
+            [(1, track("/music/My Album/one.flac", None, None))]
+        );
+    }
+
+    #[test]
+    fn written_playlists_read_back_the_same() {
+        let tracks = [
+            track("/music/one.flac", Some("Someone - Something"), Some(185.0)),
+            track("/music/two.flac", None, Some(42.0)),
+            track("/music/three.flac", None, None),
+        ];
+        let text = write(&tracks);
+        assert_eq!(
+            text,
+            "#EXTM3U\n\
+             #EXTINF:185,Someone - Something\n\
+             /music/one.flac\n\
+             #EXTINF:42,\n\
+             /music/two.flac\n\
+             /music/three.flac\n"
+        );
+        let read: Vec<_> = parse_ok(&text)
+            .into_iter()
+            .map(|(_, track)| track)
+            .collect();
+        assert_eq!(read, tracks);
+    }
+
+    #[test]
+    fn streams_are_errors() {
+        let entries = parse(
+            "one.flac\nhttp://radio.example/stream\n",
+            Path::new("/music"),
+        );
+        assert!(entries[0].1.is_ok());
+        assert_eq!(entries[1].0, 2);
+        assert!(entries[1].1.is_err());
+    }
+}
@@ -0,0 +1,222 @@
+//! Working through the queue in something other than the order given.
+//!
+//! Shuffles come from a seeded generator, so that passing the same seed again
+//! gives the same order. Albums are told apart by their album property where
+//! there is one, and otherwise by the directory their files are in.
+
+use std::iter;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::Track;
+
+/// How the tracks still to come are arranged.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Shuffle {
+    #[default]
+    Off,
+    Tracks,
+    /// Albums in a random order, each played through in order
+    Albums,
+}
+
+impl Shuffle {
+    /// The mode that follows on when toggling through them.
+    pub fn cycle(self) -> Self {
+        match self {
+            Shuffle::Off => Shuffle::Tracks,
+            Shuffle::Tracks => Shuffle::Albums,
+            Shuffle::Albums => Shuffle::Off,
+        }
+    }
+}
+
+/// What happens once a track or the queue comes to an end.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Repeat {
+    #[default]
+    Off,
+    /// Go back to the start of the queue after the last track
+    All,
+    /// Play the current track again, unless skipped
+    One,
+}
+
+impl Repeat {
+    /// The mode that follows on when toggling through them.
+    pub fn cycle(self) -> Self {
+        match self {
+            Repeat::Off => Repeat::All,
+            Repeat::All => Repeat::One,
+            Repeat::One => Repeat::Off,
+        }
+    }
+}
+
+/// How to work through the queue to start with.
+#[derive(Debug, Default)]
+pub struct PlayOrder {
+    pub shuffle: Shuffle,
+    pub repeat: Repeat,
+    /// Seed for shuffling, or `None` to pick one at random
+    pub seed: Option<u64>,
+}
+
+/// SplitMix64, which is plenty random enough for picking songs.
+pub struct Random {
+    state: u64,
+}
+
+impl Random {
+    pub fn new(seed: u64) -> Self {
+        Random { state: seed }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number from 0 up to, but not including, `bound`.
+    fn below(&mut self, bound: usize) -> usize {
+        // Scaling rather than taking the remainder, with a bias too small to
+        // matter for a queue of any realistic length
+        ((self.next_u64() as u128 * bound as u128) >> 64) as usize
+    }
+
+    /// Fisher–Yates shuffle `items` in place.
+    pub fn shuffle<T>(&mut self, items: &mut [T]) {
+        for index in (1..items.len()).rev() {
+            let other = self.below(index + 1);
+            items.swap(index, other);
+        }
+    }
+}
+
+/// A seed that will differ from one run to the next.
+pub fn random_seed() -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+    nanos ^ ((std::process::id() as u64) << 32)
+}
+
+/// Indices of `tracks` from `from` onwards, arranged so that albums come in a
+/// random order with each one in its original order, as given by `original`.
+/// Whatever is left of the album of the track before `from` comes first.
+pub fn shuffle_albums(
+    tracks: &[Track],
+    original: &[usize],
+    from: usize,
+    random: &mut Random,
+) -> Vec<usize> {
+    let playing = from
+        .checked_sub(1)
+        .map(|index| (album(&tracks[index]), original[index]));
+
+    let mut albums: Vec<(Album, Vec<usize>)> = Vec::new();
+    let mut rest_of_playing = Vec::new();
+    for index in from..tracks.len() {
+        let key = album(&tracks[index]);
+        match playing {
+            Some((playing, after)) if key == playing && original[index] > after => {
+                rest_of_playing.push(index);
+            }
+            _ => match albums.iter_mut().find(|(existing, _)| *existing == key) {
+                Some((_, indices)) => indices.push(index),
+                None => albums.push((key, vec![index])),
+            },
+        }
+    }
+
+    random.shuffle(&mut albums);
+    let mut order = Vec::with_capacity(tracks.len() - from);
+    for mut indices in iter::once(rest_of_playing).chain(albums.into_iter().map(|(_, i)| i)) {
+        indices.sort_by_key(|&index| original[index]);
+        order.extend(indices);
+    }
+    order
+}
+
+/// What makes tracks part of the same album.
+type Album<'a> = (Option<&'a str>, Option<&'a Path>);
+
+/// Whether two tracks are from the same album.
+pub fn same_album(first: &Track, second: &Track) -> bool {
+    album(first) == album(second)
+}
+
+fn album(track: &Track) -> Album<'_> {
+    let tag = track
+        .metadata
+        .iter()
+        .find(|(key, _)| key == "album")
+        .map(|(_, value)| value.as_str());
+    (tag, Path::new(&track.path).parent())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracks(paths: &[&str]) -> Vec<Track> {
+        paths
+            .iter()
+            .map(|path| Track::new(path.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn shuffles_can_be_repeated_with_the_same_seed() {
+        let shuffled = |seed| {
+            let mut items: Vec<u32> = (0..20).collect();
+            Random::new(seed).shuffle(&mut items);
+            items
+        };
+        let first = shuffled(42);
+        assert_eq!(first, shuffled(42));
+        assert_ne!(first, shuffled(43));
+
+        let mut sorted = first.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..20).collect::<Vec<_>>());
+        assert_ne!(first, sorted);
+    }
+
+    #[test]
+    fn albums_are_kept_in_order() {
+        let tracks = tracks(&[
+            "/a/1.flac",
+            "/a/2.flac",
+            "/a/3.flac",
+            "/b/1.flac",
+            "/b/2.flac",
+            "/c/1.flac",
+        ]);
+        let original: Vec<usize> = (0..tracks.len()).collect();
+        for seed in 0..10 {
+            let order = shuffle_albums(&tracks, &original, 0, &mut Random::new(seed));
+            let paths: Vec<&str> = order.iter().map(|&i| tracks[i].path.as_str()).collect();
+            let mut albums: Vec<&str> = paths.iter().map(|path| &path[..2]).collect();
+            albums.dedup();
+            assert_eq!(albums.len(), 3, "albums split up in {paths:?}");
+            for pair in paths.windows(2) {
+                assert!(pair[0][..2] != pair[1][..2] || pair[0] < pair[1]);
+            }
+        }
+    }
+
+    #[test]
+    fn the_album_playing_carries_on_first() {
+        let mut tracks = tracks(&["/x/1.flac", "/x/2.flac", "/y/1.flac", "/x/3.flac"]);
+        // A tag sets a track apart from the rest of its directory
+        tracks[3].metadata = vec![("album".to_string(), "Other".to_string())];
+        let original = [0, 1, 2, 3];
+        let order = shuffle_albums(&tracks, &original, 1, &mut Random::new(7));
+        assert_eq!(order[0], 1);
+        assert_eq!(order.len(), 3);
+    }
+}
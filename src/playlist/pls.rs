@@ -0,0 +1,189 @@
+//! Parsing and writing of PLS playlists.
+//!
+//! These are INI style files with a single `[playlist]` section, where each
+//! entry is spread over numbered keys:
+//!
+//! ```text
+//! [playlist]
+//! File1=one.flac
+//! Title1=Someone - Something
+//! Length1=185
+//! NumberOfEntries=1
+//! Version=2
+//! ```
+//!
+//! Only `File` is required, and a length of -1 means it isn't known.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::Path;
+
+use super::{resolve, Track};
+
+const HEADER: &str = "[playlist]";
+
+#[derive(Default)]
+struct Entry {
+    /// Line that `File` was found on
+    line: Option<usize>,
+    file: Option<String>,
+    title: Option<String>,
+    duration: Option<f64>,
+}
+
+/// Pick out the tracks listed in `text`, with relative paths resolved against
+/// `base`. Each entry comes with the line it was found on, and is an error
+/// if it isn't something that can be played.
+pub fn parse(text: &str, base: &Path) -> Vec<(usize, Result<Track, String>)> {
+    // Keys can come in any order, so gather them up by number first
+    let mut entries: BTreeMap<u32, Entry> = BTreeMap::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        // Not bothering with sections, as there should only be the one
+        if line.is_empty() || line.starts_with([';', '#', '[']) {
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let (key, value) = (key.trim(), value.trim());
+
+        let Some(split) = key.find(|c: char| c.is_ascii_digit()) else {
+            // Things like `NumberOfEntries`, which aren't needed
+            continue;
+        };
+        let (name, number) = key.split_at(split);
+        let Ok(number) = number.parse() else {
+            continue;
+        };
+        let entry = entries.entry(number).or_default();
+        match name.to_ascii_lowercase().as_str() {
+            "file" => {
+                entry.line = Some(index + 1);
+                entry.file = Some(value.to_string());
+            }
+            "title" if !value.is_empty() => entry.title = Some(value.to_string()),
+            "length" => {
+                entry.duration = value.parse().ok().filter(|&duration| duration > 0.0);
+            }
+            _ => {}
+        }
+    }
+
+    entries
+        .into_values()
+        .filter_map(|entry| {
+            let (line, file) = (entry.line?, entry.file?);
+            let track = resolve(&file, base).map(|path| Track {
+                title: entry.title,
+                duration: entry.duration,
+                ..Track::new(path)
+            });
+            Some((line, track))
+        })
+        .collect()
+}
+
+/// Write out `tracks` as the contents of a PLS file.
+pub fn write(tracks: &[Track]) -> String {
+    let mut text = format!("{HEADER}\n");
+    for (index, track) in tracks.iter().enumerate() {
+        let number = index + 1;
+        let Track {
+            path,
+            title,
+            duration,
+            ..
+        } = track;
+        writeln!(text, "File{number}={path}").unwrap();
+        if let Some(title) = title {
+            writeln!(text, "Title{number}={title}").unwrap();
+        }
+        match duration {
+            Some(duration) => writeln!(text, "Length{number}={duration:.0}").unwrap(),
+            None => writeln!(text, "Length{number}=-1").unwrap(),
+        }
+    }
+    writeln!(text, "NumberOfEntries={}", tracks.len()).unwrap();
+    writeln!(text, "Version=2").unwrap();
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(path: &str, title: Option<&str>, duration: Option<f64>) -> Track {
+        Track {
+            title: title.map(str::to_string),
+            duration,
+            ..Track::new(path.to_string())
+        }
+    }
+
+    #[test]
+    fn entries_are_gathered_by_number() {
+        let text = "[playlist]\n\
+                    File2=two.flac\n\
+                    Title1=Someone - Something\n\
+                    File1=/elsewhere/one.flac\n\
+                    Length1=185\n\
+                    Length2=-1\n\
+                    NumberOfEntries=2\n\
+                    Version=2\n";
+        let entries: Vec<_> = parse(text, Path::new("/music"))
+            .into_iter()
+            .map(|(line, entry)| (line, entry.unwrap()))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                (
+                    4,
+                    track(
+                        "/elsewhere/one.flac",
+                        Some("Someone - Something"),
+                        Some(185.0)
+                    )
+                ),
+                (2, track("/music/two.flac", None, None)),
+            ]
+        );
+    }
+
+    #[test]
+    fn entries_without_a_file_are_ignored() {
+        let entries = parse(
+            "[playlist]\nTitle1=Nothing\nFile2=two.flac\n",
+            Path::new("/"),
+        );
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].0, 3);
+    }
+
+    #[test]
+    fn written_playlists_read_back_the_same() {
+        let tracks = [
+            track("/music/one.flac", Some("Someone - Something"), Some(185.0)),
+            track("/music/two words.flac", None, None),
+        ];
+        let text = write(&tracks);
+        assert_eq!(
+            text,
+            "[playlist]\n\
+             File1=/music/one.flac\n\
+             Title1=Someone - Something\n\
+             Length1=185\n\
+             File2=/music/two words.flac\n\
+             Length2=-1\n\
+             NumberOfEntries=2\n\
+             Version=2\n"
+        );
+        let read: Vec<_> = parse(&text, Path::new("/"))
+            .into_iter()
+            .map(|(_, entry)| entry.unwrap())
+            .collect();
+        assert_eq!(read, tracks);
+    }
+}
@@ -0,0 +1,283 @@
+//! Parsing and writing of XSPF playlists.
+//!
+//! XSPF is XML, with the tracks found in a `trackList`:
+//!
+//! ```text
+//! <?xml version="1.0" encoding="UTF-8"?>
+//! <playlist version="1" xmlns="http://xspf.org/ns/0/">
+//!   <trackList>
+//!     <track>
+//!       <location>file:///music/one.flac</location>
+//!       <creator>Someone</creator>
+//!       <title>Something</title>
+//!       <duration>185000</duration>
+//!     </track>
+//!   </trackList>
+//! </playlist>
+//! ```
+//!
+//! Rather than a full XML parser, this only understands enough to pick out the
+//! elements of each track. Locations are URIs, and durations milliseconds.
+
+use std::fmt::Write;
+use std::path::Path;
+
+use super::{percent_decode, percent_encode, resolve, Track};
+
+const TRACK_START: &str = "<track>";
+const TRACK_END: &str = "</track>";
+
+/// Pick out the tracks listed in `text`, with relative locations resolved
+/// against `base`. Each entry comes with the line its track starts on, and is
+/// an error if it isn't something that can be played.
+pub fn parse(text: &str, base: &Path) -> Vec<(usize, Result<Track, String>)> {
+    let text = strip_comments(text);
+    let mut entries = Vec::new();
+    let mut rest = text.as_str();
+
+    while let Some(start) = rest.find(TRACK_START) {
+        let offset = text.len() - rest.len() + start;
+        let line = text[..offset].matches('\n').count() + 1;
+        rest = &rest[start + TRACK_START.len()..];
+        let end = rest.find(TRACK_END).unwrap_or(rest.len());
+        let (track, after) = rest.split_at(end);
+        rest = after;
+
+        let entry = match element(track, "location") {
+            Some(location) => parse_track(track, &location, base),
+            None => Err("track has no location".to_string()),
+        };
+        entries.push((line, entry));
+    }
+    entries
+}
+
+fn parse_track(track: &str, location: &str, base: &Path) -> Result<Track, String> {
+    // Relative locations are still URIs, so need decoding
+    let path = match location.contains("://") {
+        true => resolve(location, base)?,
+        false => resolve(&percent_decode(location), base)?,
+    };
+    let title = match (element(track, "creator"), element(track, "title")) {
+        (Some(creator), Some(title)) => Some(format!("{creator} - {title}")),
+        (None, title) => title,
+        (creator, None) => creator,
+    };
+    let duration = element(track, "duration")
+        .and_then(|millis| millis.parse::<f64>().ok())
+        .filter(|&millis| millis > 0.0)
+        .map(|millis| millis / 1000.0);
+    Ok(Track {
+        title,
+        duration,
+        ..Track::new(path)
+    })
+}
+
+/// The text of the first element called `name`, or `None` if there isn't one
+/// or it is empty.
+fn element(xml: &str, name: &str) -> Option<String> {
+    let open = format!("<{name}>");
+    let close = format!("</{name}>");
+    let start = xml.find(&open)? + open.len();
+    let end = start + xml[start..].find(&close)?;
+    Some(unescape(xml[start..end].trim())).filter(|text| !text.is_empty())
+}
+
+fn strip_comments(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("<!--") {
+        stripped.push_str(&rest[..start]);
+        let comment = &rest[start..];
+        let end = comment.find("-->").map_or(comment.len(), |end| end + 3);
+        // Keep line numbers the same
+        stripped.extend(comment[..end].matches('\n'));
+        rest = &comment[end..];
+    }
+    stripped.push_str(rest);
+    stripped
+}
+
+/// Replace character and entity references with what they stand for.
+fn unescape(text: &str) -> String {
+    if let Some(cdata) = text
+        .strip_prefix("<![CDATA[")
+        .and_then(|text| text.strip_suffix("]]>"))
+    {
+        return cdata.to_string();
+    }
+
+    let mut unescaped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        unescaped.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let reference = rest
+            .find(';')
+            .and_then(|end| Some((decode_reference(&rest[1..end])?, end)));
+        match reference {
+            Some((c, end)) => {
+                unescaped.push(c);
+                rest = &rest[end + 1..];
+            }
+            // Leave anything unrecognised as it is
+            None => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+fn decode_reference(name: &str) -> Option<char> {
+    let code = match name {
+        "amp" => return Some('&'),
+        "lt" => return Some('<'),
+        "gt" => return Some('>'),
+        "quot" => return Some('"'),
+        "apos" => return Some('\''),
+        _ => match name.strip_prefix("#x").or_else(|| name.strip_prefix("#X")) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+            None => name.strip_prefix('#')?.parse().ok()?,
+        },
+    };
+    char::from_u32(code)
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Write out `tracks` as the contents of an XSPF file. Paths are expected to
+/// be absolute, as locations are written as `file://` URIs.
+pub fn write(tracks: &[Track]) -> String {
+    let mut text = String::new();
+    writeln!(text, r#"<?xml version="1.0" encoding="UTF-8"?>"#).unwrap();
+    writeln!(
+        text,
+        r#"<playlist version="1" xmlns="http://xspf.org/ns/0/">"#
+    )
+    .unwrap();
+    writeln!(text, "  <trackList>").unwrap();
+    for track in tracks {
+        let location = format!("file://{}", percent_encode(&track.path));
+        writeln!(text, "    <track>").unwrap();
+        writeln!(text, "      <location>{}</location>", escape(&location)).unwrap();
+        if let Some(title) = &track.title {
+            writeln!(text, "      <title>{}</title>", escape(title)).unwrap();
+        }
+        if let Some(duration) = track.duration {
+            let millis = (duration * 1000.0).round();
+            writeln!(text, "      <duration>{millis}</duration>").unwrap();
+        }
+        writeln!(text, "    </track>").unwrap();
+    }
+    writeln!(text, "  </trackList>").unwrap();
+    writeln!(text, "</playlist>").unwrap();
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(path: &str, title: Option<&str>, duration: Option<f64>) -> Track {
+        Track {
+            title: title.map(str::to_string),
+            duration,
+            ..Track::new(path.to_string())
+        }
+    }
+
+    fn parse_ok(text: &str) -> Vec<(usize, Track)> {
+        parse(text, Path::new("/music"))
+            .into_iter()
+            .map(|(line, entry)| (line, entry.unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn tracks_are_read_from_the_track_list() {
+        let text = r#"<?xml version="1.0" encoding="UTF-8"?>
+<playlist version="1" xmlns="http://xspf.org/ns/0/">
+  <title>A mix</title>
+  <trackList>
+    <!-- <track><location>commented.flac</location></track> -->
+    <track>
+      <location>file:///music/My%20Album/one.flac</location>
+      <creator>Someone &amp; Friends</creator>
+      <title>Something</title>
+      <duration>185000</duration>
+    </track>
+    <track><location>albums/two%20words.flac</location></track>
+  </trackList>
+</playlist>
+"#;
+        assert_eq!(
+            parse_ok(text),
+            [
+                (
+                    6,
+                    track(
+                        "/music/My Album/one.flac",
+                        Some("Someone & Friends - Something"),
+                        Some(185.0)
+                    )
+                ),
+                (12, track("/music/albums/two words.flac", None, None)),
+            ]
+        );
+    }
+
+    #[test]
+    fn tracks_without_a_location_are_errors() {
+        let entries = parse(
+            "<trackList><track><title>Lost</title></track></trackList>",
+            Path::new("/"),
+        );
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0].1.is_err());
+    }
+
+    #[test]
+    fn references_are_unescaped() {
+        assert_eq!(
+            unescape("a &lt;b&gt; &#233;&#xE9; &bogus; &"),
+            "a <b> éé &bogus; &"
+        );
+        assert_eq!(unescape("<![CDATA[a & b]]>"), "a & b");
+    }
+
+    #[test]
+    fn written_playlists_read_back_the_same() {
+        let tracks = [
+            track(
+                "/music/Rock & Roll/one.flac",
+                Some("Someone - <Something>"),
+                Some(185.25),
+            ),
+            track("/music/日本語.flac", None, None),
+        ];
+        let text = write(&tracks);
+        assert!(text.contains("<location>file:///music/Rock%20%26%20Roll/one.flac</location>"));
+        let read: Vec<_> = parse(&text, Path::new("/"))
+            .into_iter()
+            .map(|(_, entry)| entry.unwrap())
+            .collect();
+        assert_eq!(read, tracks);
+    }
+}
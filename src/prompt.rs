@@ -0,0 +1,190 @@
+//! Typing in a path, with tab completion much like a shell's.
+//!
+//! Tab completes as much of the last part of the path as all matching entries
+//! have in common, finishing off directories with a slash once there is only
+//! one match. A leading `~` stands for the home directory.
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use crate::events::{Key, KeyPress};
+
+/// Where entering a path is up to after a key press.
+#[derive(Debug, PartialEq)]
+pub enum Entry {
+    Editing,
+    /// The path was accepted, with any `~` expanded
+    Done(String),
+    Cancelled,
+}
+
+#[derive(Debug, Default)]
+pub struct PathPrompt {
+    text: String,
+}
+
+impl PathPrompt {
+    pub fn new() -> Self {
+        PathPrompt::default()
+    }
+
+    /// What has been typed so far.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn handle(&mut self, press: KeyPress) -> Entry {
+        let modifiers = press.modifiers;
+        match press.key {
+            Key::Char('u') if modifiers.ctrl => self.text.clear(),
+            Key::Char('c') if modifiers.ctrl => return Entry::Cancelled,
+            Key::Char(c) if !modifiers.ctrl && !modifiers.alt => self.text.push(c),
+            Key::Backspace => {
+                self.text.pop();
+            }
+            Key::Tab => {
+                if let Some(completed) = complete(&self.text) {
+                    self.text = completed;
+                }
+            }
+            Key::Enter if !self.text.is_empty() => return Entry::Done(expand_home(&self.text)),
+            Key::Escape => return Entry::Cancelled,
+            _ => {}
+        }
+        Entry::Editing
+    }
+}
+
+/// Fill in as much of the last part of `text` as can be worked out from the
+/// entries of its directory, or `None` if nothing matches.
+fn complete(text: &str) -> Option<String> {
+    let (dir, partial) = match text.rfind('/') {
+        Some(slash) => text.split_at(slash + 1),
+        None => ("", text),
+    };
+    let search = match dir {
+        "" => ".".to_string(),
+        dir => expand_home(dir),
+    };
+
+    let mut matches = Vec::new();
+    for entry in fs::read_dir(search).ok()? {
+        let Ok(entry) = entry else {
+            continue;
+        };
+        let name = entry.file_name().to_string_lossy().into_owned();
+        // Hidden files only when asked for
+        if !name.starts_with(partial) || (name.starts_with('.') && !partial.starts_with('.')) {
+            continue;
+        }
+        // Following symlinks to whatever they point at
+        let is_dir = fs::metadata(entry.path()).is_ok_and(|metadata| metadata.is_dir());
+        matches.push((name, is_dir));
+    }
+
+    let (first, is_dir) = matches.first()?;
+    let common = matches.iter().fold(first.as_str(), |common, (name, _)| {
+        let length = common
+            .char_indices()
+            .zip(name.chars())
+            .find(|((_, a), b)| a != b)
+            .map_or(common.len().min(name.len()), |((index, _), _)| index);
+        &common[..length]
+    });
+
+    let mut completed = format!("{dir}{common}");
+    if matches.len() == 1 && *is_dir {
+        completed.push('/');
+    }
+    Some(completed)
+}
+
+/// Replace a leading `~` with the home directory.
+pub fn expand_home(path: &str) -> String {
+    let home = env::var_os("HOME");
+    match (path.strip_prefix('~'), home) {
+        (Some(rest), Some(home)) if rest.is_empty() || rest.starts_with('/') => {
+            format!("{}{rest}", Path::new(&home).display())
+        }
+        _ => path.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::Modifiers;
+    use crate::temp_dir::TempDir;
+
+    /// Complete `text` as typed after the directory.
+    fn complete_in(dir: &TempDir, text: &str) -> Option<String> {
+        let prefix = format!("{}/", dir.path());
+        let completed = complete(&format!("{prefix}{text}"))?;
+        Some(completed.strip_prefix(&prefix).unwrap().to_string())
+    }
+
+    fn type_in(prompt: &mut PathPrompt, text: &str) {
+        for c in text.chars() {
+            prompt.handle(KeyPress::plain(Key::Char(c)));
+        }
+    }
+
+    #[test]
+    fn completes_what_matches_have_in_common() {
+        let dir = TempDir::new(
+            "completes_in_common",
+            &[
+                "Album One/1.flac",
+                "Album Two/1.flac",
+                "cover.jpg",
+                ".hidden",
+            ],
+            b"",
+        );
+        assert_eq!(complete_in(&dir, "Al").as_deref(), Some("Album "));
+        assert_eq!(complete_in(&dir, "Album O").as_deref(), Some("Album One/"));
+        assert_eq!(complete_in(&dir, "c").as_deref(), Some("cover.jpg"));
+        assert_eq!(
+            complete_in(&dir, "Album One/").as_deref(),
+            Some("Album One/1.flac")
+        );
+        assert_eq!(complete_in(&dir, ".").as_deref(), Some(".hidden"));
+        assert_eq!(complete_in(&dir, "x"), None);
+    }
+
+    #[test]
+    fn keys_edit_the_path() {
+        let mut prompt = PathPrompt::new();
+        type_in(&mut prompt, "/music/ab");
+        prompt.handle(KeyPress::plain(Key::Backspace));
+        assert_eq!(prompt.text(), "/music/a");
+        assert_eq!(prompt.handle(KeyPress::plain(Key::Left)), Entry::Editing);
+        assert_eq!(
+            prompt.handle(KeyPress::plain(Key::Enter)),
+            Entry::Done("/music/a".to_string())
+        );
+
+        let ctrl = Modifiers {
+            ctrl: true,
+            ..Modifiers::NONE
+        };
+        prompt.handle(KeyPress::new(Key::Char('u'), ctrl));
+        assert_eq!(prompt.text(), "");
+        // Nothing to accept yet
+        assert_eq!(prompt.handle(KeyPress::plain(Key::Enter)), Entry::Editing);
+        assert_eq!(
+            prompt.handle(KeyPress::plain(Key::Escape)),
+            Entry::Cancelled
+        );
+    }
+
+    #[test]
+    fn tilde_is_the_home_directory() {
+        let home = env::var("HOME").unwrap();
+        assert_eq!(expand_home("~/Music"), format!("{home}/Music"));
+        assert_eq!(expand_home("~"), home);
+        assert_eq!(expand_home("~someone/Music"), "~someone/Music");
+        assert_eq!(expand_home("/music/~"), "/music/~");
+    }
+}
@@ -0,0 +1,245 @@
+//! Evening out loudness from one track to the next with ReplayGain.
+//!
+//! ReplayGain tags say how far to turn a track up or down to reach a common
+//! loudness, along with the loudest sample in it so that turning up doesn't
+//! clip. Each is given for the track on its own, and for the album it is from
+//! so that quiet tracks stay quieter than loud ones on the same album. Tags are
+//! read from Vorbis comments, ID3 `TXXX` frames, APE tags and CUE sheet `REM`
+//! comments, all of which end up among a track's properties. Tracks without
+//! tags can instead use loudness measured by afqueue itself.
+
+use crate::loudness::cache::Entry;
+
+const TRACK_GAIN: &str = "replaygain_track_gain";
+const TRACK_PEAK: &str = "replaygain_track_peak";
+const ALBUM_GAIN: &str = "replaygain_album_gain";
+const ALBUM_PEAK: &str = "replaygain_album_peak";
+
+// ReplayGain 2.0 brings tracks to this loudness, in LUFS
+const REFERENCE_LOUDNESS: f64 = -18.0;
+
+/// Which of a track's gains to play it at.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum GainMode {
+    /// Play tracks as they are
+    #[default]
+    Off,
+    Track,
+    Album,
+    /// Album gain for tracks played alongside others from their album, and
+    /// track gain otherwise
+    Auto,
+}
+
+impl GainMode {
+    /// The mode after this one, for stepping through them all in turn.
+    pub fn cycle(self) -> Self {
+        match self {
+            GainMode::Off => GainMode::Track,
+            GainMode::Track => GainMode::Album,
+            GainMode::Album => GainMode::Auto,
+            GainMode::Auto => GainMode::Off,
+        }
+    }
+
+    /// Look up a mode by the name given on the command line.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "off" => Some(GainMode::Off),
+            "track" => Some(GainMode::Track),
+            "album" => Some(GainMode::Album),
+            "auto" => Some(GainMode::Auto),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            GainMode::Off => "off",
+            GainMode::Track => "track",
+            GainMode::Album => "album",
+            GainMode::Auto => "auto",
+        }
+    }
+}
+
+/// The ReplayGain tags of a track, with gains in decibels and peaks as a
+/// fraction of full scale.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ReplayGain {
+    pub track_gain: Option<f64>,
+    pub track_peak: Option<f64>,
+    pub album_gain: Option<f64>,
+    pub album_peak: Option<f64>,
+}
+
+impl ReplayGain {
+    /// Pick out the tags from a track's properties, which may be named in
+    /// any case.
+    pub fn from_metadata(metadata: &[(String, String)]) -> Self {
+        let find = |name: &str, parse: fn(&str) -> Option<f64>| {
+            metadata
+                .iter()
+                .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+                .find_map(|(_, value)| parse(value))
+        };
+        ReplayGain {
+            track_gain: find(TRACK_GAIN, parse_gain),
+            track_peak: find(TRACK_PEAK, parse_peak),
+            album_gain: find(ALBUM_GAIN, parse_gain),
+            album_peak: find(ALBUM_PEAK, parse_peak),
+        }
+    }
+
+    /// Gains that bring measured loudness up or down to the reference, as
+    /// tags would.
+    pub fn from_measured(measured: &Entry) -> Self {
+        let gain = |integrated: Option<f64>| integrated.map(|lufs| REFERENCE_LOUDNESS - lufs);
+        ReplayGain {
+            track_gain: gain(measured.track.integrated),
+            track_peak: Some(measured.track.true_peak),
+            album_gain: gain(measured.album.integrated),
+            album_peak: Some(measured.album.true_peak),
+        }
+    }
+
+    /// Whether there is any gain to go by.
+    pub fn is_empty(&self) -> bool {
+        self.track_gain.is_none() && self.album_gain.is_none()
+    }
+
+    /// Factor to scale the track's audio by, using the album's gain and peak
+    /// if `album` and the track's otherwise, either standing in for the other
+    /// where missing. Gain is held back so as not to push the peak past full
+    /// scale. Untagged tracks are scaled by `fallback` decibels instead.
+    pub fn scale(&self, album: bool, fallback: f64) -> f32 {
+        let (gain, peak) = match album {
+            true => (
+                self.album_gain.or(self.track_gain),
+                self.album_peak.or(self.track_peak),
+            ),
+            false => (
+                self.track_gain.or(self.album_gain),
+                self.track_peak.or(self.album_peak),
+            ),
+        };
+        let Some(gain) = gain else {
+            return decibels_to_scale(fallback) as f32;
+        };
+        let scale = decibels_to_scale(gain);
+        let scale = match peak {
+            Some(peak) if peak > 0.0 => scale.min(1.0 / peak),
+            _ => scale,
+        };
+        scale as f32
+    }
+}
+
+fn decibels_to_scale(decibels: f64) -> f64 {
+    10f64.powf(decibels / 20.0)
+}
+
+/// Parse a gain such as `-6.54 dB`, with or without the unit.
+fn parse_gain(value: &str) -> Option<f64> {
+    let value = value.trim().to_ascii_lowercase();
+    let number = value.strip_suffix("db").unwrap_or(&value);
+    number
+        .trim()
+        .parse()
+        .ok()
+        .filter(|gain: &f64| gain.is_finite())
+}
+
+fn parse_peak(value: &str) -> Option<f64> {
+    let peak: f64 = value.trim().parse().ok()?;
+    (peak.is_finite() && peak >= 0.0).then_some(peak)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loudness::Loudness;
+
+    fn tags(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn tags_are_read_however_they_are_written() {
+        let gain = ReplayGain::from_metadata(&tags(&[
+            ("replaygain_track_gain", "-6.54 dB"),
+            ("REPLAYGAIN_TRACK_PEAK", "0.988"),
+            ("replaygain_album_gain", "+1.5dB"),
+            ("replaygain_album_peak", "loud"),
+        ]));
+        assert_eq!(
+            gain,
+            ReplayGain {
+                track_gain: Some(-6.54),
+                track_peak: Some(0.988),
+                album_gain: Some(1.5),
+                album_peak: None,
+            }
+        );
+    }
+
+    #[test]
+    fn album_and_track_stand_in_for_each_other() {
+        let track_only = ReplayGain {
+            track_gain: Some(-20.0),
+            ..ReplayGain::default()
+        };
+        assert!((track_only.scale(true, 0.0) - 0.1).abs() < 1e-6);
+
+        let both = ReplayGain {
+            track_gain: Some(-20.0),
+            album_gain: Some(-6.0206),
+            ..ReplayGain::default()
+        };
+        assert!((both.scale(false, 0.0) - 0.1).abs() < 1e-6);
+        assert!((both.scale(true, 0.0) - 0.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn peaks_hold_back_gain_that_would_clip() {
+        let gain = ReplayGain {
+            track_gain: Some(6.0206),
+            track_peak: Some(0.8),
+            ..ReplayGain::default()
+        };
+        assert_eq!(gain.scale(false, 0.0), 1.25);
+
+        let quiet = ReplayGain {
+            track_peak: Some(0.25),
+            ..gain
+        };
+        assert!((quiet.scale(false, 0.0) - 2.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn measured_loudness_is_brought_to_the_reference() {
+        let loudness = |integrated, true_peak| Loudness {
+            integrated,
+            range: 5.0,
+            true_peak,
+        };
+        let measured = ReplayGain::from_measured(&Entry {
+            track: loudness(Some(-12.0), 0.9),
+            album: loudness(None, 0.0),
+        });
+        assert_eq!(measured.track_gain, Some(-6.0));
+        assert_eq!(measured.album_gain, None);
+        assert!(!measured.is_empty());
+        assert!((measured.scale(true, 0.0) - 0.5012).abs() < 1e-4);
+    }
+
+    #[test]
+    fn untagged_tracks_use_the_fallback() {
+        let untagged = ReplayGain::from_metadata(&tags(&[("album", "Somewhere")]));
+        assert_eq!(untagged.scale(false, 0.0), 1.0);
+        assert!((untagged.scale(true, -6.0206) - 0.5).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,163 @@
+//! Reading of APEv2 tags, which some taggers add to the end of MP3 files.
+//!
+//! The tag ends with a 32 byte footer, found either at the very end of the
+//! file or just before an ID3v1 tag:
+//!
+//! ```text
+//! "APETAGEX" | version | size | item count | flags | reserved
+//! ```
+//!
+//! All numbers are little endian, and the size covers the items along with
+//! the footer, but not any header in front of them. Each item is its value's
+//! length, its flags, a null terminated key and then the value itself.
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+const FOOTER_SIZE: u64 = 32;
+const FOOTER_MARKER: &[u8] = b"APETAGEX";
+const ID3V1_SIZE: u64 = 128;
+const ID3V1_MARKER: &[u8] = b"TAG";
+
+// Bits of an item's flags giving the type of its value
+const ITEM_TYPE_MASK: u32 = 0x6;
+const ITEM_TYPE_TEXT: u32 = 0x0;
+
+// More than enough for any tag that is only text
+const MAX_TAG_SIZE: u64 = 0x100000;
+
+/// Read the text items of the APE tag at the end of a file, keyed by their
+/// names in lower case. Files without a tag have no items.
+pub fn read_items(reader: &mut (impl Read + Seek)) -> io::Result<Vec<(String, String)>> {
+    let length = reader.seek(SeekFrom::End(0))?;
+
+    // Skip back over any ID3v1 tag
+    let mut end = length;
+    if length >= ID3V1_SIZE {
+        reader.seek(SeekFrom::Start(length - ID3V1_SIZE))?;
+        let mut marker = [0; 3];
+        reader.read_exact(&mut marker)?;
+        if marker == ID3V1_MARKER {
+            end -= ID3V1_SIZE;
+        }
+    }
+
+    let Some(footer_start) = end.checked_sub(FOOTER_SIZE) else {
+        return Ok(Vec::new());
+    };
+    reader.seek(SeekFrom::Start(footer_start))?;
+    let mut footer = [0; FOOTER_SIZE as usize];
+    reader.read_exact(&mut footer)?;
+    let Some((size, count)) = parse_footer(&footer) else {
+        return Ok(Vec::new());
+    };
+
+    let items_size = size.saturating_sub(FOOTER_SIZE);
+    if items_size > MAX_TAG_SIZE || items_size > footer_start {
+        return Ok(Vec::new());
+    }
+    reader.seek(SeekFrom::Start(footer_start - items_size))?;
+    let mut items = vec![0; items_size as usize];
+    reader.read_exact(&mut items)?;
+    Ok(parse_items(&items, count))
+}
+
+/// The size and item count given by a footer.
+fn parse_footer(footer: &[u8; FOOTER_SIZE as usize]) -> Option<(u64, u32)> {
+    if !footer.starts_with(FOOTER_MARKER) {
+        return None;
+    }
+    let size = read_u32_le(footer, 12)? as u64;
+    let count = read_u32_le(footer, 16)?;
+    Some((size, count))
+}
+
+fn parse_items(mut items: &[u8], count: u32) -> Vec<(String, String)> {
+    let mut parsed = Vec::new();
+    for _ in 0..count {
+        let (Some(length), Some(flags)) = (read_u32_le(items, 0), read_u32_le(items, 4)) else {
+            break;
+        };
+        let rest = &items[8..];
+        let Some(key_end) = rest.iter().position(|&byte| byte == 0) else {
+            break;
+        };
+        let value_start = key_end + 1;
+        let Some(value) = rest.get(value_start..value_start + length as usize) else {
+            break;
+        };
+
+        // Binary values and links to external files are of no use here
+        if flags & ITEM_TYPE_MASK == ITEM_TYPE_TEXT {
+            let key = String::from_utf8_lossy(&rest[..key_end]).to_ascii_lowercase();
+            // Lists of values are separated by nulls
+            let value = String::from_utf8_lossy(value).replace('\0', "; ");
+            parsed.push((key, value));
+        }
+        items = &rest[value_start + length as usize..];
+    }
+    parsed
+}
+
+fn read_u32_le(bytes: &[u8], offset: usize) -> Option<u32> {
+    let bytes = bytes.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes(bytes.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// An APE tag holding each item, with the given flags.
+    fn ape_tag(items: &[(&str, &str, u32)]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (key, value, flags) in items {
+            body.extend((value.len() as u32).to_le_bytes());
+            body.extend(flags.to_le_bytes());
+            body.extend(key.as_bytes());
+            body.push(0);
+            body.extend(value.as_bytes());
+        }
+        let mut tag = body.clone();
+        tag.extend(FOOTER_MARKER);
+        tag.extend(2000u32.to_le_bytes());
+        tag.extend((body.len() as u32 + FOOTER_SIZE as u32).to_le_bytes());
+        tag.extend((items.len() as u32).to_le_bytes());
+        tag.extend([0; 12]);
+        tag
+    }
+
+    #[test]
+    fn reads_text_items_from_the_end() {
+        let mut file = vec![0xff; 1000];
+        file.extend(ape_tag(&[
+            ("REPLAYGAIN_TRACK_GAIN", "-3.21 dB", 0),
+            ("Cover Art (Front)", "\u{1}\u{2}", 0x2),
+            ("Artist", "Someone\0Someone Else", 0),
+        ]));
+        let items = read_items(&mut Cursor::new(file.clone())).unwrap();
+        let expected = [
+            ("replaygain_track_gain", "-3.21 dB"),
+            ("artist", "Someone; Someone Else"),
+        ]
+        .map(|(key, value)| (key.to_string(), value.to_string()));
+        assert_eq!(items, expected);
+
+        // Still found with an ID3v1 tag after it
+        let mut id3v1 = b"TAG".to_vec();
+        id3v1.resize(ID3V1_SIZE as usize, 0);
+        file.extend(id3v1);
+        assert_eq!(read_items(&mut Cursor::new(file)).unwrap(), expected);
+    }
+
+    #[test]
+    fn files_without_a_tag_have_no_items() {
+        assert!(read_items(&mut Cursor::new(vec![0; 10]))
+            .unwrap()
+            .is_empty());
+        assert!(read_items(&mut Cursor::new(vec![0; 500]))
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,217 @@
+//! Decoding of compressed packets to samples, backed by an AudioToolbox audio
+//! converter.
+//!
+//! Playback leaves decoding to the audio queue, so this is only needed where
+//! the samples themselves are wanted, such as when measuring loudness.
+
+use std::ffi::c_void;
+use std::mem::MaybeUninit;
+use std::ptr;
+
+use crate::ffi::audio_toolbox::{
+    self, AudioBuffer, AudioBufferList, AudioConverterRef, AudioStreamBasicDescription,
+    AudioStreamPacketDescription, OSStatus,
+};
+use crate::player::{
+    PacketBuffer, PacketCount, PlaybackResult, StreamFormat, SystemErrorCode, SystemResult,
+    FORMAT_FLAG_IS_FLOAT, FORMAT_FLAG_IS_PACKED, FORMAT_LINEAR_PCM,
+};
+
+const BYTES_PER_SAMPLE: u32 = 4;
+
+/// Frames to decode at a time.
+const OUTPUT_FRAMES: u32 = 0x2000;
+
+/// Status to stop the converter with once it has been given all of the
+/// packets read so far, as opposed to there being none left at all.
+const NEEDS_MORE_INPUT: OSStatus = i32::from_be_bytes(*b"more");
+
+/// Decodes packets to interleaved 32 bit float samples.
+pub struct AudioConverterDecoder {
+    converter: AudioConverterRef,
+    channels: u32,
+}
+
+/// Packets waiting to be handed to the converter.
+struct Input {
+    data: *const u8,
+    byte_size: u32,
+    packets: PacketCount,
+    descriptions: *mut AudioStreamPacketDescription,
+    channels: u32,
+    /// Set once the packets have been handed over, or there are none to come
+    supplied: bool,
+    /// Set once there are no more packets to come
+    finished: bool,
+}
+
+impl AudioConverterDecoder {
+    pub fn new(format: &StreamFormat, magic_cookie: Option<&[u8]>) -> PlaybackResult<Self> {
+        let channels = format.channels_per_frame;
+        let source = AudioStreamBasicDescription::from(format);
+        let destination = AudioStreamBasicDescription {
+            sample_rate: format.sample_rate,
+            format_id: FORMAT_LINEAR_PCM,
+            format_flags: FORMAT_FLAG_IS_FLOAT | FORMAT_FLAG_IS_PACKED,
+            bytes_per_packet: BYTES_PER_SAMPLE * channels,
+            frames_per_packet: 1,
+            bytes_per_frame: BYTES_PER_SAMPLE * channels,
+            channels_per_frame: channels,
+            bits_per_channel: BYTES_PER_SAMPLE * 8,
+            reserved: 0,
+        };
+        let decoder = AudioConverterDecoder {
+            converter: audio_converter_create(&source, &destination)?,
+            channels,
+        };
+        if let Some(cookie) = magic_cookie {
+            audio_converter_set_magic_cookie(decoder.converter, cookie)?;
+        }
+        Ok(decoder)
+    }
+
+    /// Decode the `packets` just read into `buffer`, adding the samples to
+    /// `samples`. Some audio may be held back until more packets are given,
+    /// or `finish` is called.
+    pub fn decode(
+        &mut self,
+        packets: PacketCount,
+        buffer: &mut PacketBuffer,
+        samples: &mut Vec<f32>,
+    ) -> PlaybackResult<()> {
+        // PacketDescription shares its layout with AudioStreamPacketDescription
+        let descriptions = match buffer.packet_descriptions.as_mut() {
+            Some(descriptions) => descriptions.as_mut_ptr() as *mut AudioStreamPacketDescription,
+            None => ptr::null_mut(),
+        };
+        let mut input = Input {
+            data: buffer.data.as_ptr(),
+            byte_size: buffer.byte_size,
+            packets,
+            descriptions,
+            channels: self.channels,
+            supplied: false,
+            finished: false,
+        };
+        self.convert(&mut input, samples)
+    }
+
+    /// Add whatever audio was held back to `samples`, as there are no more
+    /// packets to come.
+    pub fn finish(&mut self, samples: &mut Vec<f32>) -> PlaybackResult<()> {
+        let mut input = Input {
+            data: ptr::null(),
+            byte_size: 0,
+            packets: 0,
+            descriptions: ptr::null_mut(),
+            channels: self.channels,
+            supplied: true,
+            finished: true,
+        };
+        self.convert(&mut input, samples)
+    }
+
+    fn convert(&mut self, input: &mut Input, samples: &mut Vec<f32>) -> PlaybackResult<()> {
+        let channels = self.channels as usize;
+        let mut output = vec![0f32; OUTPUT_FRAMES as usize * channels];
+        loop {
+            let mut buffers = AudioBufferList {
+                number_buffers: 1,
+                buffers: [AudioBuffer {
+                    number_channels: self.channels,
+                    data_byte_size: (output.len() * BYTES_PER_SAMPLE as usize) as u32,
+                    data: output.as_mut_ptr() as *mut c_void,
+                }],
+            };
+            let mut frames = OUTPUT_FRAMES;
+            let status = unsafe {
+                audio_toolbox::audio_converter_fill_complex_buffer(
+                    self.converter,
+                    supply_input,
+                    input as *mut Input as *mut c_void,
+                    &mut frames,
+                    &mut buffers,
+                    ptr::null_mut(),
+                )
+            };
+            samples.extend(&output[..frames as usize * channels]);
+
+            match status {
+                // Only stops short of filling the output once the input is done
+                0 if frames == OUTPUT_FRAMES => continue,
+                0 | NEEDS_MORE_INPUT => return Ok(()),
+                status => return Err(SystemErrorCode(status).into()),
+            }
+        }
+    }
+}
+
+impl Drop for AudioConverterDecoder {
+    fn drop(&mut self) {
+        unsafe {
+            audio_toolbox::audio_converter_dispose(self.converter);
+        }
+    }
+}
+
+/// Hand the converter whatever packets are waiting, at most once.
+unsafe extern "C" fn supply_input(
+    _converter: AudioConverterRef,
+    io_number_data_packets: *mut u32,
+    io_data: *mut AudioBufferList,
+    out_data_packet_description: *mut *mut AudioStreamPacketDescription,
+    in_user_data: *mut c_void,
+) -> OSStatus {
+    let input = &mut *(in_user_data as *mut Input);
+    if input.supplied {
+        *io_number_data_packets = 0;
+        return match input.finished {
+            true => 0,
+            false => NEEDS_MORE_INPUT,
+        };
+    }
+    input.supplied = true;
+
+    let buffer = &mut (*io_data).buffers[0];
+    buffer.number_channels = input.channels;
+    buffer.data_byte_size = input.byte_size;
+    buffer.data = input.data as *mut c_void;
+    *io_number_data_packets = input.packets;
+    if !out_data_packet_description.is_null() {
+        *out_data_packet_description = input.descriptions;
+    }
+    0
+}
+
+fn audio_converter_create(
+    source: &AudioStreamBasicDescription,
+    destination: &AudioStreamBasicDescription,
+) -> SystemResult<AudioConverterRef> {
+    unsafe {
+        let mut converter = MaybeUninit::uninit();
+        let status =
+            audio_toolbox::audio_converter_new(source, destination, converter.as_mut_ptr());
+        if status != 0 {
+            return Err(SystemErrorCode(status));
+        }
+        Ok(converter.assume_init())
+    }
+}
+
+fn audio_converter_set_magic_cookie(
+    converter: AudioConverterRef,
+    cookie: &[u8],
+) -> SystemResult<()> {
+    unsafe {
+        let status = audio_toolbox::audio_converter_set_property(
+            converter,
+            audio_toolbox::AUDIO_CONVERTER_DECOMPRESSION_MAGIC_COOKIE,
+            cookie.len() as u32,
+            cookie.as_ptr() as *const c_void,
+        );
+        if status != 0 {
+            return Err(SystemErrorCode(status));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,452 @@
+//! Packet source backed by the AudioToolbox audio file API.
+//!
+//! Able to read any container format supported by the OS.
+
+use std::ffi::{c_void, CStr, CString};
+use std::fs::File;
+use std::mem::{self, MaybeUninit};
+use std::ptr;
+
+use crate::ffi::audio_toolbox::{
+    self, AudioFileID, AudioFilePacketTableInfo, AudioFramePacketTranslation,
+    AudioStreamBasicDescription,
+};
+use crate::ffi::core_foundation;
+use crate::player::{
+    PacketBuffer, PacketCount, PacketPosition, PacketSource, PathError, PlaybackResult,
+    StreamFormat, SystemErrorCode, SystemResult, ValidFrames,
+};
+use crate::source::{ape, mp3};
+
+/// Format identifier for MPEG-1/2 layer III audio.
+const FORMAT_MPEG_LAYER_3: u32 = u32::from_be_bytes(*b".mp3");
+
+pub struct AudioFileSource {
+    playback_file: AudioFileID,
+    format: StreamFormat,
+    max_packet_size: u32,
+    valid_frames: Option<ValidFrames>,
+    /// Properties from tags that AudioToolbox doesn't read
+    replay_gain: Vec<(String, String)>,
+}
+
+impl AudioFileSource {
+    pub fn open(path: &str) -> PlaybackResult<Self> {
+        let cpath = cstring_path(path)?;
+        let audio_file = audio_file_open(&cpath)?;
+        let format = audio_file_read_basic_description(audio_file)?.into();
+        let max_packet_size = audio_file_read_packet_size_upper_bound(audio_file)?;
+
+        let mut source = AudioFileSource {
+            playback_file: audio_file,
+            format,
+            max_packet_size,
+            valid_frames: None,
+            replay_gain: Vec::new(),
+        };
+        source.valid_frames = source.find_valid_frames(path)?;
+        source.replay_gain = source.read_replay_gain(path)?;
+        Ok(source)
+    }
+
+    /// Where the audio lies, going by the file's packet table. Failing that,
+    /// MP3 files are checked for what their encoder left in them.
+    fn find_valid_frames(&self, path: &str) -> PlaybackResult<Option<ValidFrames>> {
+        match audio_file_read_packet_table_info(self.playback_file) {
+            Ok(info) if info.number_valid_frames > 0 => {
+                return Ok(Some(ValidFrames {
+                    priming: info.priming_frames.max(0) as u64,
+                    count: info.number_valid_frames as u64,
+                }));
+            }
+            Ok(_) => {}
+            Err(SystemErrorCode(audio_toolbox::AUDIO_FILE_ERROR_UNSUPPORTED_PROPERTY)) => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        if self.format.format_id != FORMAT_MPEG_LAYER_3 {
+            return Ok(None);
+        }
+        Ok(mp3::read_valid_frames(&mut File::open(path)?)?)
+    }
+
+    /// ReplayGain values from the ID3 `TXXX` frames and APE tags of MP3
+    /// files, with those in the ID3 tag coming first.
+    fn read_replay_gain(&self, path: &str) -> PlaybackResult<Vec<(String, String)>> {
+        if self.format.format_id != FORMAT_MPEG_LAYER_3 {
+            return Ok(Vec::new());
+        }
+        let mut file = File::open(path)?;
+        let mut tags = mp3::read_user_text(&mut file)?;
+        tags.extend(ape::read_items(&mut file)?);
+        tags.retain(|(key, _)| key.starts_with("replaygain_"));
+        Ok(tags)
+    }
+}
+
+impl PacketSource for AudioFileSource {
+    fn format(&self) -> &StreamFormat {
+        &self.format
+    }
+
+    fn packet_size_upper_bound(&self) -> u32 {
+        self.max_packet_size
+    }
+
+    fn magic_cookie(&self) -> PlaybackResult<Option<Vec<u8>>> {
+        audio_file_read_magic_cookie(self.playback_file).map_err(|e| e.into())
+    }
+
+    fn metadata(&self) -> PlaybackResult<Vec<(String, String)>> {
+        let mut metadata = audio_file_read_metadata(self.playback_file)?;
+        metadata.extend(self.replay_gain.iter().cloned());
+        Ok(metadata)
+    }
+
+    fn estimated_duration(&self) -> PlaybackResult<f64> {
+        audio_file_read_estimated_duration(self.playback_file).map_err(|e| e.into())
+    }
+
+    fn valid_frames(&self) -> PlaybackResult<Option<ValidFrames>> {
+        Ok(self.valid_frames)
+    }
+
+    fn packet_for_frame(&self, frame: u64) -> PlaybackResult<(PacketPosition, u64)> {
+        let frames_per_packet = self.format.frames_per_packet as u64;
+        if frames_per_packet != 0 {
+            let packet = frame / frames_per_packet;
+            return Ok((packet as PacketPosition, packet * frames_per_packet));
+        }
+
+        // The number of frames in each packet varies, so ask the file
+        let translation = audio_file_frame_to_packet(self.playback_file, frame as i64)?;
+        let first_frame = translation.frame - translation.frame_offset_in_packet as i64;
+        Ok((translation.packet, first_frame.max(0) as u64))
+    }
+
+    fn read_packets(
+        &mut self,
+        from_packet: PacketPosition,
+        packets: PacketCount,
+        buffer: &mut PacketBuffer,
+    ) -> PlaybackResult<PacketCount> {
+        audio_file_read_packet_data(self.playback_file, from_packet, packets, buffer)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Drop for AudioFileSource {
+    fn drop(&mut self) {
+        audio_file_close(self.playback_file).expect("Failed to close audio file");
+    }
+}
+
+impl From<AudioStreamBasicDescription> for StreamFormat {
+    fn from(asbd: AudioStreamBasicDescription) -> Self {
+        StreamFormat {
+            sample_rate: asbd.sample_rate,
+            format_id: asbd.format_id,
+            format_flags: asbd.format_flags,
+            bytes_per_packet: asbd.bytes_per_packet,
+            frames_per_packet: asbd.frames_per_packet,
+            bytes_per_frame: asbd.bytes_per_frame,
+            channels_per_frame: asbd.channels_per_frame,
+            bits_per_channel: asbd.bits_per_channel,
+        }
+    }
+}
+
+fn cstring_path(path: &str) -> Result<CString, PathError> {
+    if path.is_empty() {
+        return Err(PathError::PathIsEmpty);
+    }
+
+    Ok(CString::new(path)?)
+}
+
+fn audio_file_read_packet_data(
+    file: AudioFileID,
+    from_packet: PacketPosition,
+    packets: PacketCount,
+    buffer: &mut PacketBuffer,
+) -> SystemResult<PacketCount> {
+    unsafe {
+        let mut num_bytes = buffer.data.len() as u32;
+        let mut num_packets = packets;
+
+        // PacketDescription shares its layout with AudioStreamPacketDescription
+        let packet_descs_ptr = match buffer.packet_descriptions.as_mut() {
+            Some(descriptions) => {
+                num_packets = num_packets.min(descriptions.len() as u32);
+                descriptions.as_mut_ptr() as *mut audio_toolbox::AudioStreamPacketDescription
+            }
+            None => ptr::null_mut(),
+        };
+
+        let status = audio_toolbox::audio_file_read_packet_data(
+            file,
+            false, // dont use caching
+            &mut num_bytes,
+            packet_descs_ptr,
+            from_packet,
+            &mut num_packets,
+            buffer.data.as_mut_ptr() as *mut c_void,
+        );
+
+        if status != 0 {
+            return Err(SystemErrorCode(status));
+        }
+
+        buffer.byte_size = num_bytes;
+        buffer.packet_description_count = if packet_descs_ptr.is_null() {
+            0
+        } else {
+            num_packets
+        };
+
+        Ok(num_packets)
+    }
+}
+
+fn audio_file_open(path: &CStr) -> SystemResult<AudioFileID> {
+    let path = path.to_bytes();
+
+    unsafe {
+        // Create URL
+        let url_ref = core_foundation::cfurl_create_from_filesystem_representation(
+            ptr::null(), // Use default allocator
+            path.as_ptr(),
+            path.len() as isize,
+            false, // Not a directory
+        );
+
+        // Open file
+        let mut file_id = MaybeUninit::uninit();
+        let status = audio_toolbox::audio_file_open_url(
+            url_ref,
+            audio_toolbox::AUDIO_FILE_READ_PERMISSION,
+            0, // No file hints
+            file_id.as_mut_ptr(),
+        );
+
+        // Dont need the CFURL anymore
+        core_foundation::cf_release(url_ref as *const c_void);
+
+        if status != 0 {
+            return Err(SystemErrorCode(status));
+        }
+
+        let file_id = file_id.assume_init();
+        Ok(file_id)
+    }
+}
+
+fn audio_file_close(file: AudioFileID) -> SystemResult<()> {
+    unsafe {
+        let status = audio_toolbox::audio_file_close(file);
+        if status != 0 {
+            return Err(SystemErrorCode(status));
+        }
+    }
+    Ok(())
+}
+
+fn audio_file_read_metadata(file: AudioFileID) -> SystemResult<Vec<(String, String)>> {
+    unsafe {
+        let info_dict =
+            audio_file_get_property(file, audio_toolbox::AUDIO_FILE_PROPERTY_INFO_DICTIONARY)?;
+
+        // Extract keys and values
+        let count = core_foundation::cfdictionary_get_count(info_dict);
+        let mut keys = vec![0 as core_foundation::CFStringRef; count as usize];
+        let mut values = vec![0 as core_foundation::CFStringRef; count as usize];
+
+        core_foundation::cfdictionary_get_keys_and_values(
+            info_dict,
+            keys.as_mut_ptr() as *mut *const c_void,
+            values.as_mut_ptr() as *mut *const c_void,
+        );
+
+        // Filter out non CFString values and convert to Rust strings
+        // Note: We eagerly collect to force conversation before the dictionary is
+        // released
+
+        let cfstring_type_id = core_foundation::cfstring_get_type_id();
+
+        let properties = keys
+            .into_iter()
+            .zip(values.into_iter())
+            .filter(|(_, v)| {
+                core_foundation::cf_get_type_id(*v as *const c_void) == cfstring_type_id
+            })
+            .map(|(k, v)| (cfstring_to_string(k), cfstring_to_string(v)))
+            .collect();
+
+        core_foundation::cf_release(info_dict as *const c_void);
+
+        Ok(properties)
+    }
+}
+
+fn audio_file_read_basic_description(
+    file: AudioFileID,
+) -> SystemResult<AudioStreamBasicDescription> {
+    audio_file_get_property(file, audio_toolbox::AUDIO_FILE_PROPERTY_DATA_FORMAT)
+}
+
+fn audio_file_read_packet_size_upper_bound(file: AudioFileID) -> SystemResult<u32> {
+    audio_file_get_property(
+        file,
+        audio_toolbox::AUDIO_FILE_PROPERTY_PACKET_SIZE_UPPER_BOUND,
+    )
+}
+
+fn audio_file_read_estimated_duration(file: AudioFileID) -> SystemResult<f64> {
+    audio_file_get_property(file, audio_toolbox::AUDIO_FILE_PROPERTY_ESTIMATED_DURATION)
+}
+
+fn audio_file_read_packet_table_info(file: AudioFileID) -> SystemResult<AudioFilePacketTableInfo> {
+    audio_file_get_property(file, audio_toolbox::AUDIO_FILE_PROPERTY_PACKET_TABLE_INFO)
+}
+
+fn audio_file_frame_to_packet(
+    file: AudioFileID,
+    frame: i64,
+) -> SystemResult<AudioFramePacketTranslation> {
+    unsafe {
+        // The frame is passed in via the same struct that holds the result
+        let mut translation = AudioFramePacketTranslation {
+            frame,
+            ..Default::default()
+        };
+        let mut data_size = mem::size_of::<AudioFramePacketTranslation>() as u32;
+
+        let status = audio_toolbox::audio_file_get_property(
+            file,
+            audio_toolbox::AUDIO_FILE_PROPERTY_FRAME_TO_PACKET,
+            &mut data_size as *mut _,
+            &mut translation as *mut _ as *mut c_void,
+        );
+
+        if status != 0 {
+            return Err(SystemErrorCode(status));
+        }
+        Ok(translation)
+    }
+}
+
+// This only works with sized types
+fn audio_file_get_property<T>(
+    file_id: audio_toolbox::AudioFileID,
+    property: audio_toolbox::AudioFilePropertyID,
+) -> SystemResult<T> {
+    unsafe {
+        let mut data = MaybeUninit::<T>::uninit();
+        let mut data_size = mem::size_of::<T>() as u32;
+
+        let status = audio_toolbox::audio_file_get_property(
+            file_id,
+            property,
+            &mut data_size as *mut _,
+            data.as_mut_ptr() as *mut c_void,
+        );
+        let data = data.assume_init();
+
+        if status != 0 {
+            return Err(SystemErrorCode(status));
+        }
+
+        // audio_file_get_property outputs the number of bytes written to data_size
+        // Check to see if this is correct for the given type
+        assert!(data_size == mem::size_of::<T>() as u32);
+
+        Ok(data)
+    }
+}
+
+fn audio_file_read_magic_cookie(file: AudioFileID) -> SystemResult<Option<Vec<u8>>> {
+    unsafe {
+        // Check to see if there is a cookie, and if so how large it is.
+        let mut cookie_size: u32 = 0;
+        let mut is_writable: u32 = 0;
+        let status = audio_toolbox::audio_file_get_property_info(
+            file,
+            audio_toolbox::AUDIO_FILE_PROPERTY_MAGIC_COOKIE_DATA,
+            &mut cookie_size as *mut _,
+            &mut is_writable as *mut _,
+        );
+
+        // No magic cookie data
+        if status == audio_toolbox::AUDIO_FILE_ERROR_UNSUPPORTED_PROPERTY || cookie_size == 0 {
+            return Ok(None);
+        }
+
+        // Some other status is probably an error
+        if status != 0 {
+            return Err(SystemErrorCode(status));
+        }
+
+        // Read the cookie
+        let mut cookie_data: Vec<u8> = vec![0; cookie_size as usize];
+        let mut data_size = cookie_size;
+
+        let status = audio_toolbox::audio_file_get_property(
+            file,
+            audio_toolbox::AUDIO_FILE_PROPERTY_MAGIC_COOKIE_DATA,
+            &mut data_size as *mut _,
+            cookie_data.as_mut_ptr() as *mut c_void,
+        );
+
+        if status != 0 {
+            return Err(SystemErrorCode(status));
+        }
+
+        assert!(data_size == cookie_size);
+        Ok(Some(cookie_data))
+    }
+}
+
+unsafe fn cfstring_to_string(cfstring: core_foundation::CFStringRef) -> String {
+    assert!(!cfstring.is_null());
+
+    let string_len = core_foundation::cfstring_get_length(cfstring);
+
+    // This is effectively asking how big a buffer we are going to need
+    let mut bytes_required = 0;
+    core_foundation::cfstring_get_bytes(
+        cfstring,
+        core_foundation::CFRange {
+            location: 0,
+            length: string_len,
+        },
+        core_foundation::CFSTRING_ENCODING_UTF8,
+        0,               // no loss byte
+        false,           // no byte order marker
+        ptr::null_mut(), // dont actually capture any bytes
+        0,               // buffer size of 0 as no buffer supplied
+        &mut bytes_required,
+    );
+
+    // Now actually copy out the bytes
+    let mut buffer = vec![b'\x00'; bytes_required as usize];
+    let mut bytes_written = 0;
+
+    let chars_converted = core_foundation::cfstring_get_bytes(
+        cfstring,
+        core_foundation::CFRange {
+            location: 0,
+            length: string_len,
+        },
+        core_foundation::CFSTRING_ENCODING_UTF8,
+        0,     // no loss byte
+        false, // no byte order marker
+        buffer.as_mut_ptr(),
+        buffer.len() as core_foundation::CFIndex,
+        &mut bytes_written,
+    );
+
+    assert!(chars_converted == string_len);
+    assert!(bytes_written as usize == buffer.len());
+
+    String::from_utf8_unchecked(buffer)
+}
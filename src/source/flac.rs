@@ -0,0 +1,794 @@
+//! Platform independent packet source for FLAC files.
+//!
+//! Frames are decoded to linear PCM as they are read, so packets supplied to
+//! the output are single PCM frames. Samples are widened to fill whole bytes,
+//! e.g 20 bit audio is supplied as 24 bit.
+//!
+//! The SEEKTABLE, if present, is used to avoid decoding from the start of the
+//! file when reading from an arbitrary position. When a file is played through
+//! from the start, the decoded audio is checked against the MD5 signature held
+//! in STREAMINFO.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use crate::player::{
+    DecodeError, PacketBuffer, PacketCount, PacketPosition, PacketSource, PlaybackResult,
+    StreamFormat, FORMAT_FLAG_IS_PACKED, FORMAT_FLAG_IS_SIGNED_INTEGER, FORMAT_LINEAR_PCM,
+};
+
+mod bits;
+#[cfg(test)]
+mod encode;
+mod frame;
+mod md5;
+
+use self::frame::Block;
+use self::md5::Md5;
+
+const FLAC_MARKER: &[u8; 4] = b"fLaC";
+
+const BLOCK_TYPE_STREAMINFO: u8 = 0;
+const BLOCK_TYPE_SEEKTABLE: u8 = 3;
+const BLOCK_TYPE_VORBIS_COMMENT: u8 = 4;
+
+const STREAMINFO_SIZE: usize = 34;
+const SEEK_POINT_SIZE: usize = 18;
+const PLACEHOLDER_SEEK_POINT: u64 = u64::MAX;
+
+const READ_CHUNK_SIZE: usize = 0x10000;
+
+// Vorbis comment field names, and the equivalent AudioToolbox info dictionary
+// keys. Any other fields are passed through with lower case names.
+const COMMENT_KEYS: [(&str, &str); 11] = [
+    ("TITLE", "title"),
+    ("ARTIST", "artist"),
+    ("ALBUM", "album"),
+    ("DATE", "year"),
+    ("GENRE", "genre"),
+    ("COMMENT", "comments"),
+    ("DESCRIPTION", "comments"),
+    ("TRACKNUMBER", "track number"),
+    ("DISCNUMBER", "disc number"),
+    ("COMPOSER", "composer"),
+    ("COPYRIGHT", "copyright"),
+];
+
+/// Check if the start of a file looks like a FLAC file.
+pub fn is_flac(header: &[u8]) -> bool {
+    header.starts_with(FLAC_MARKER)
+}
+
+/// Properties of the stream as a whole, taken from the STREAMINFO block.
+pub struct StreamInfo {
+    pub max_block_size: u16,
+    pub sample_rate: u32,
+    pub channels: u32,
+    pub bits_per_sample: u32,
+    pub total_samples: u64,
+    pub md5_signature: [u8; 16],
+}
+
+/// Entry in the seek table, giving the position of the frame starting with
+/// a particular sample.
+struct SeekPoint {
+    sample: u64,
+    /// Offset in bytes from the first frame.
+    offset: u64,
+}
+
+pub struct FlacSource<R> {
+    frames: FrameReader<R>,
+    info: StreamInfo,
+    format: StreamFormat,
+    seek_points: Vec<SeekPoint>,
+    first_frame_offset: u64,
+    metadata: Vec<(String, String)>,
+    block: Block,
+    next_sample: u64,
+    // Only present while the stream is being decoded from start to finish
+    md5: Option<Md5>,
+}
+
+impl FlacSource<File> {
+    pub fn open(path: &str) -> PlaybackResult<Self> {
+        FlacSource::new(File::open(path)?)
+    }
+}
+
+impl<R: Read + Seek> FlacSource<R> {
+    pub fn new(mut reader: R) -> PlaybackResult<Self> {
+        let mut marker = [0u8; 4];
+        read_exact(&mut reader, &mut marker)?;
+        if !is_flac(&marker) {
+            return Err(DecodeError::Missing("FLAC marker").into());
+        }
+
+        let mut info = None;
+        let mut seek_points = Vec::new();
+        let mut metadata = Vec::new();
+
+        // Metadata blocks follow the marker, with the last one flagged as such
+        loop {
+            let mut header = [0u8; 4];
+            read_exact(&mut reader, &mut header)?;
+            let is_last = header[0] & 0x80 != 0;
+            let block_type = header[0] & 0x7F;
+            let size = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+
+            let mut body = vec![0u8; size];
+            read_exact(&mut reader, &mut body)?;
+
+            match block_type {
+                BLOCK_TYPE_STREAMINFO => info = Some(parse_stream_info(&body)?),
+                BLOCK_TYPE_SEEKTABLE => seek_points = parse_seek_table(&body),
+                BLOCK_TYPE_VORBIS_COMMENT => metadata = parse_vorbis_comment(&body)?,
+                _ => {}
+            }
+
+            if is_last {
+                break;
+            }
+        }
+
+        let info = info.ok_or(DecodeError::Missing("STREAMINFO block"))?;
+        let first_frame_offset = reader.stream_position()?;
+
+        // Samples are supplied in whole bytes, little endian
+        let bytes_per_sample = info.bits_per_sample.div_ceil(8);
+        let bytes_per_frame = bytes_per_sample * info.channels;
+        let format = StreamFormat {
+            sample_rate: info.sample_rate as f64,
+            format_id: FORMAT_LINEAR_PCM,
+            format_flags: FORMAT_FLAG_IS_SIGNED_INTEGER | FORMAT_FLAG_IS_PACKED,
+            bytes_per_packet: bytes_per_frame,
+            frames_per_packet: 1,
+            bytes_per_frame,
+            channels_per_frame: info.channels,
+            bits_per_channel: bytes_per_sample * 8,
+        };
+
+        Ok(FlacSource {
+            frames: FrameReader::new(reader),
+            info,
+            format,
+            seek_points,
+            first_frame_offset,
+            metadata,
+            block: Block::default(),
+            next_sample: 0,
+            md5: Some(Md5::new()),
+        })
+    }
+
+    /// Decode the next frame into the current block, returning false at the
+    /// end of the stream.
+    fn decode_next_block(&mut self) -> PlaybackResult<bool> {
+        if !self.frames.decode_next(&self.info, &mut self.block)? {
+            return Ok(false);
+        }
+
+        if let Some(md5) = &mut self.md5 {
+            // The signature covers samples at their original size, rather than
+            // widened to whole bytes.
+            let bytes_per_sample = self.info.bits_per_sample.div_ceil(8) as usize;
+            let mut frame = Vec::with_capacity(bytes_per_sample * self.block.channels.len());
+            for index in 0..self.block.frames {
+                frame.clear();
+                for channel in &self.block.channels {
+                    let bytes = channel[index].to_le_bytes();
+                    frame.extend_from_slice(&bytes[..bytes_per_sample]);
+                }
+                md5.update(&frame);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Reposition the stream so that `sample` is the next one to be read.
+    fn seek(&mut self, sample: u64) -> PlaybackResult<()> {
+        // Use the closest seek point before the sample, if there is one.
+        let seek_point = self
+            .seek_points
+            .iter()
+            .take_while(|point| point.sample <= sample)
+            .last()
+            .map(|point| (point.sample, point.offset))
+            .unwrap_or((0, 0));
+
+        // Carry on decoding from where we are if that will get there sooner
+        let (point_sample, point_offset) = seek_point;
+        let can_continue =
+            sample >= self.block.first_sample && point_sample < self.block.end_sample();
+
+        if !can_continue {
+            self.frames
+                .seek_to(self.first_frame_offset + point_offset)?;
+            self.block = Block::default();
+            self.block.first_sample = point_sample;
+
+            // The signature covers every frame, so can only be checked if
+            // decoding starts over from the first.
+            self.md5 = match point_offset {
+                0 => Some(Md5::new()),
+                _ => None,
+            };
+        }
+
+        while !self.block.contains(sample) {
+            if !self.decode_next_block()? {
+                break;
+            }
+        }
+
+        self.next_sample = sample;
+        Ok(())
+    }
+
+    /// Compare the decoded audio against the signature, once the whole stream
+    /// has been decoded.
+    fn verify_signature(&mut self) -> PlaybackResult<()> {
+        let Some(md5) = self.md5.take() else {
+            return Ok(());
+        };
+
+        // An all zero signature means the encoder didn't calculate one
+        let expected = self.info.md5_signature;
+        if expected != [0; 16] && md5.finish() != expected {
+            return Err(DecodeError::Invalid("MD5 signature").into());
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> PacketSource for FlacSource<R> {
+    fn format(&self) -> &StreamFormat {
+        &self.format
+    }
+
+    fn packet_size_upper_bound(&self) -> u32 {
+        self.format.bytes_per_packet
+    }
+
+    fn magic_cookie(&self) -> PlaybackResult<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    fn metadata(&self) -> PlaybackResult<Vec<(String, String)>> {
+        Ok(self.metadata.clone())
+    }
+
+    fn estimated_duration(&self) -> PlaybackResult<f64> {
+        Ok(self.info.total_samples as f64 / self.format.sample_rate)
+    }
+
+    fn read_packets(
+        &mut self,
+        from_packet: PacketPosition,
+        packets: PacketCount,
+        buffer: &mut PacketBuffer,
+    ) -> PlaybackResult<PacketCount> {
+        let from_packet = from_packet.max(0) as u64;
+        if from_packet != self.next_sample {
+            self.seek(from_packet)?;
+        }
+
+        let bytes_per_frame = self.format.bytes_per_frame as usize;
+        let bytes_per_sample = bytes_per_frame / self.format.channels_per_frame as usize;
+        let capacity = (buffer.data.len() / bytes_per_frame).min(packets as usize);
+
+        // Widen samples to fill the most significant bits of each sample
+        let shift = bytes_per_sample as u32 * 8 - self.info.bits_per_sample;
+
+        let mut frames_read = 0;
+        while frames_read < capacity {
+            if !self.block.contains(self.next_sample) {
+                if !self.decode_next_block()? {
+                    break;
+                }
+                continue;
+            }
+
+            let index = (self.next_sample - self.block.first_sample) as usize;
+            let count = (self.block.frames - index).min(capacity - frames_read);
+
+            let output = &mut buffer.data[frames_read * bytes_per_frame..];
+            let frames = output.chunks_exact_mut(bytes_per_frame).take(count);
+            for (offset, frame) in frames.enumerate() {
+                let samples = frame.chunks_exact_mut(bytes_per_sample);
+                for (sample, channel) in samples.zip(&self.block.channels) {
+                    let value = channel[index + offset] << shift;
+                    sample.copy_from_slice(&value.to_le_bytes()[..bytes_per_sample]);
+                }
+            }
+
+            frames_read += count;
+            self.next_sample += count as u64;
+        }
+
+        if frames_read == 0 {
+            self.verify_signature()?;
+        }
+
+        buffer.byte_size = (frames_read * bytes_per_frame) as u32;
+        buffer.packet_description_count = 0;
+        Ok(frames_read as PacketCount)
+    }
+}
+
+/// Buffers the compressed frame data read from the file.
+struct FrameReader<R> {
+    reader: R,
+    buffer: Vec<u8>,
+    start: usize,
+    end: usize,
+    at_end_of_file: bool,
+}
+
+impl<R: Read + Seek> FrameReader<R> {
+    fn new(reader: R) -> Self {
+        FrameReader {
+            reader,
+            buffer: vec![0; READ_CHUNK_SIZE],
+            start: 0,
+            end: 0,
+            at_end_of_file: false,
+        }
+    }
+
+    fn seek_to(&mut self, offset: u64) -> io::Result<()> {
+        self.reader.seek(SeekFrom::Start(offset))?;
+        self.start = 0;
+        self.end = 0;
+        self.at_end_of_file = false;
+        Ok(())
+    }
+
+    /// Decode the next frame into `block`, returning false if there are no
+    /// frames left.
+    fn decode_next(&mut self, info: &StreamInfo, block: &mut Block) -> PlaybackResult<bool> {
+        loop {
+            let available = &self.buffer[self.start..self.end];
+            if available.is_empty() && self.at_end_of_file {
+                return Ok(false);
+            }
+
+            match frame::decode_frame(available, info, block) {
+                Ok(size) => {
+                    self.start += size;
+                    return Ok(true);
+                }
+                Err(DecodeError::Truncated) if !self.at_end_of_file => self.fill()?,
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    /// Read more of the file into the buffer, growing it if already full.
+    fn fill(&mut self) -> io::Result<()> {
+        self.buffer.copy_within(self.start..self.end, 0);
+        self.end -= self.start;
+        self.start = 0;
+
+        if self.end == self.buffer.len() {
+            self.buffer.resize(self.buffer.len() * 2, 0);
+        }
+
+        let read = loop {
+            match self.reader.read(&mut self.buffer[self.end..]) {
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                result => break result?,
+            }
+        };
+        self.end += read;
+        self.at_end_of_file = read == 0;
+        Ok(())
+    }
+}
+
+fn parse_stream_info(body: &[u8]) -> PlaybackResult<StreamInfo> {
+    if body.len() < STREAMINFO_SIZE {
+        return Err(DecodeError::Invalid("STREAMINFO block").into());
+    }
+
+    // Sample rate, channels, sample size and total samples are packed
+    // together into 64 bits
+    let packed = u64::from_be_bytes(body[10..18].try_into().unwrap());
+    let info = StreamInfo {
+        max_block_size: u16::from_be_bytes([body[2], body[3]]),
+        sample_rate: (packed >> 44) as u32,
+        channels: ((packed >> 41) & 0x7) as u32 + 1,
+        bits_per_sample: ((packed >> 36) & 0x1F) as u32 + 1,
+        total_samples: packed & 0xF_FFFF_FFFF,
+        md5_signature: body[18..34].try_into().unwrap(),
+    };
+
+    if info.sample_rate == 0 || info.max_block_size < 16 || info.bits_per_sample < 4 {
+        return Err(DecodeError::Invalid("STREAMINFO block").into());
+    }
+    Ok(info)
+}
+
+fn parse_seek_table(body: &[u8]) -> Vec<SeekPoint> {
+    body.chunks_exact(SEEK_POINT_SIZE)
+        .map(|point| SeekPoint {
+            sample: u64::from_be_bytes(point[0..8].try_into().unwrap()),
+            offset: u64::from_be_bytes(point[8..16].try_into().unwrap()),
+        })
+        .filter(|point| point.sample != PLACEHOLDER_SEEK_POINT)
+        .collect()
+}
+
+fn parse_vorbis_comment(body: &[u8]) -> PlaybackResult<Vec<(String, String)>> {
+    let mut remaining = body;
+
+    // Unlike the rest of FLAC, lengths here are little endian
+    let vendor_length = take_u32_le(&mut remaining)? as usize;
+    take_bytes(&mut remaining, vendor_length)?;
+    let count = take_u32_le(&mut remaining)?;
+
+    let mut metadata = Vec::new();
+    for _ in 0..count {
+        let length = take_u32_le(&mut remaining)? as usize;
+        let comment = String::from_utf8_lossy(take_bytes(&mut remaining, length)?);
+        let Some((name, value)) = comment.split_once('=') else {
+            continue;
+        };
+
+        // Field names are case insensitive
+        let name = name.to_ascii_uppercase();
+        let key = COMMENT_KEYS
+            .iter()
+            .find(|(field, _)| *field == name)
+            .map(|(_, key)| key.to_string())
+            .unwrap_or_else(|| name.to_ascii_lowercase());
+        metadata.push((key, value.to_string()));
+    }
+    Ok(metadata)
+}
+
+fn take_bytes<'a>(data: &mut &'a [u8], count: usize) -> PlaybackResult<&'a [u8]> {
+    if data.len() < count {
+        return Err(DecodeError::Invalid("VORBIS_COMMENT block").into());
+    }
+    let (taken, rest) = data.split_at(count);
+    *data = rest;
+    Ok(taken)
+}
+
+fn take_u32_le(data: &mut &[u8]) -> PlaybackResult<u32> {
+    let bytes = take_bytes(data, 4)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_exact(reader: &mut impl Read, buffer: &mut [u8]) -> PlaybackResult<()> {
+    reader.read_exact(buffer).map_err(|err| match err.kind() {
+        io::ErrorKind::UnexpectedEof => DecodeError::Truncated.into(),
+        _ => err.into(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    use self::encode::Subframe;
+    use crate::player::PlaybackError;
+
+    const BLOCK_SIZE: usize = 64;
+
+    struct Fixture {
+        bits: u32,
+        channels: Vec<Vec<i64>>,
+        /// Add a seek point at the start of every so many frames
+        seek_every: Option<usize>,
+        comments: Vec<&'static str>,
+        /// In place of the signature of the samples
+        signature: Option<[u8; 16]>,
+    }
+
+    impl Fixture {
+        /// A stereo stream a few frames long, with a short frame at the end.
+        fn stereo(bits: u32) -> Self {
+            let amplitude = (1 << (bits - 1)) as f64 - 1.0;
+            let channel = |phase: f64| -> Vec<i64> {
+                (0..250)
+                    .map(|index| ((phase + index as f64 * 0.1).sin() * amplitude) as i64)
+                    .collect()
+            };
+            Fixture {
+                bits,
+                channels: vec![channel(0.0), channel(1.0)],
+                seek_every: None,
+                comments: Vec::new(),
+                signature: None,
+            }
+        }
+
+        fn mono(bits: u32) -> Self {
+            let mut fixture = Fixture::stereo(bits);
+            fixture.channels.truncate(1);
+            fixture
+        }
+
+        fn frames(&self) -> Vec<Vec<u8>> {
+            let subframes = [
+                Subframe::Fixed(2),
+                Subframe::Lpc {
+                    precision: 14,
+                    shift: 12,
+                    coefficients: &[7800, -3700],
+                },
+                Subframe::Verbatim,
+                Subframe::Fixed(1),
+            ];
+            let frame_count = self.channels[0].len().div_ceil(BLOCK_SIZE);
+            (0..frame_count)
+                .map(|number| {
+                    let start = number * BLOCK_SIZE;
+                    let end = (start + BLOCK_SIZE).min(self.channels[0].len());
+                    let subframe = subframes[number % subframes.len()];
+                    let (channel_code, coded) = match &self.channels[..] {
+                        [left, right] => {
+                            // Try out each way of coding a pair of channels
+                            let channel_code = [1, 8, 9, 10][number % 4];
+                            let (first, second) = encode::correlate(
+                                channel_code,
+                                &left[start..end],
+                                &right[start..end],
+                            );
+                            (channel_code, vec![first, second])
+                        }
+                        channels => (
+                            channels.len() as u64 - 1,
+                            channels.iter().map(|c| c[start..end].to_vec()).collect(),
+                        ),
+                    };
+                    let coded: Vec<_> = coded.iter().map(|c| (subframe, &c[..])).collect();
+                    encode::frame(number as u64, channel_code, self.bits, &coded)
+                })
+                .collect()
+        }
+
+        fn signature(&self) -> [u8; 16] {
+            let bytes_per_sample = self.bits.div_ceil(8) as usize;
+            let mut md5 = Md5::new();
+            for index in 0..self.channels[0].len() {
+                for channel in &self.channels {
+                    md5.update(&channel[index].to_le_bytes()[..bytes_per_sample]);
+                }
+            }
+            md5.finish()
+        }
+
+        fn build(&self) -> Vec<u8> {
+            let frames = self.frames();
+            let mut blocks = vec![encode::stream_info(
+                BLOCK_SIZE as u16,
+                44100,
+                self.channels.len() as u32,
+                self.bits,
+                self.channels[0].len() as u64,
+                self.signature.unwrap_or_else(|| self.signature()),
+            )];
+
+            if let Some(every) = self.seek_every {
+                let mut points = Vec::new();
+                let mut offset = 0;
+                for (number, frame) in frames.iter().enumerate() {
+                    if number % every == 0 {
+                        points.push(((number * BLOCK_SIZE) as u64, offset));
+                    }
+                    offset += frame.len() as u64;
+                }
+                points.push((PLACEHOLDER_SEEK_POINT, 0));
+                blocks.push(encode::seek_table(&points));
+            }
+
+            if !self.comments.is_empty() {
+                blocks.push(encode::vorbis_comment("afqueue tests", &self.comments));
+            }
+            encode::stream(blocks, &frames)
+        }
+
+        fn open(&self) -> FlacSource<Cursor<Vec<u8>>> {
+            FlacSource::new(Cursor::new(self.build())).expect("fixture should open")
+        }
+
+        /// PCM expected for a run of frames, widened to whole bytes.
+        fn pcm(&self, frames: std::ops::Range<usize>) -> Vec<u8> {
+            let bytes_per_sample = self.bits.div_ceil(8);
+            let shift = bytes_per_sample * 8 - self.bits;
+            let mut pcm = Vec::new();
+            for index in frames {
+                for channel in &self.channels {
+                    let value = channel[index] << shift;
+                    pcm.extend(&value.to_le_bytes()[..bytes_per_sample as usize]);
+                }
+            }
+            pcm
+        }
+    }
+
+    fn read(
+        source: &mut impl PacketSource,
+        from_packet: PacketPosition,
+        packets: PacketCount,
+    ) -> PlaybackResult<Vec<u8>> {
+        let mut storage = vec![0u8; 4096];
+        let mut buffer = PacketBuffer {
+            data: &mut storage,
+            byte_size: 0,
+            packet_descriptions: None,
+            packet_description_count: 0,
+            trim_start: 0,
+            trim_end: 0,
+        };
+        source.read_packets(from_packet, packets, &mut buffer)?;
+        Ok(buffer.data[..buffer.byte_size as usize].to_vec())
+    }
+
+    fn read_all(source: &mut impl PacketSource, packets_per_read: u32) -> PlaybackResult<Vec<u8>> {
+        let mut output = Vec::new();
+        loop {
+            let packets = read(source, output.len() as i64 / 4, packets_per_read)?;
+            if packets.is_empty() {
+                return Ok(output);
+            }
+            output.extend(packets);
+        }
+    }
+
+    #[test]
+    fn stream_info_gives_the_format() {
+        let source = Fixture::stereo(16).open();
+        let format = *source.format();
+        assert_eq!(format.format_id, FORMAT_LINEAR_PCM);
+        assert_eq!(
+            format.format_flags,
+            FORMAT_FLAG_IS_SIGNED_INTEGER | FORMAT_FLAG_IS_PACKED
+        );
+        assert_eq!(format.sample_rate, 44100.0);
+        assert_eq!(format.channels_per_frame, 2);
+        assert_eq!(format.bits_per_channel, 16);
+        assert_eq!(format.bytes_per_frame, 4);
+        assert_eq!(format.frames_per_packet, 1);
+        assert_eq!(source.estimated_duration().unwrap(), 250.0 / 44100.0);
+    }
+
+    #[test]
+    fn decodes_each_way_of_coding_channels() {
+        // Frames cycle through the channel assignments and subframe types
+        let fixture = Fixture::stereo(16);
+        let mut source = fixture.open();
+        assert_eq!(read_all(&mut source, 37).unwrap(), fixture.pcm(0..250));
+
+        let fixture = Fixture::mono(16);
+        let mut source = fixture.open();
+        let pcm = fixture.pcm(0..250);
+        let mut output = Vec::new();
+        while output.len() < pcm.len() {
+            output.extend(read(&mut source, output.len() as i64 / 2, 100).unwrap());
+        }
+        assert_eq!(output, pcm);
+    }
+
+    #[test]
+    fn samples_are_widened_to_whole_bytes() {
+        let fixture = Fixture::stereo(12);
+        let mut source = fixture.open();
+        assert_eq!(source.format().bits_per_channel, 16);
+        let pcm = read_all(&mut source, 250).unwrap();
+        assert_eq!(pcm, fixture.pcm(0..250));
+        // The low bits are left clear
+        assert!(pcm.chunks(2).all(|sample| sample[0] & 0x0F == 0));
+    }
+
+    #[test]
+    fn wrong_signature_is_reported_once_played_through() {
+        let fixture = Fixture {
+            signature: Some([1; 16]),
+            ..Fixture::stereo(16)
+        };
+        let result = read_all(&mut fixture.open(), 64);
+        assert!(matches!(
+            result,
+            Err(PlaybackError::Decode(DecodeError::Invalid("MD5 signature")))
+        ));
+
+        // Unless the encoder didn't work one out
+        let fixture = Fixture {
+            signature: Some([0; 16]),
+            ..Fixture::stereo(16)
+        };
+        assert!(read_all(&mut fixture.open(), 64).is_ok());
+    }
+
+    #[test]
+    fn seeks_through_the_seek_table_and_back() {
+        let fixture = Fixture {
+            seek_every: Some(2),
+            ..Fixture::stereo(16)
+        };
+        let mut source = fixture.open();
+        assert_eq!(source.seek_points.len(), 2);
+
+        // Jumping forward starts decoding from the seek point at frame 2
+        assert_eq!(read(&mut source, 150, 20).unwrap(), fixture.pcm(150..170));
+        assert_eq!(source.block.first_sample, 128);
+        assert!(source.md5.is_none());
+
+        // Going back has to start again from the first frame
+        assert_eq!(read(&mut source, 10, 20).unwrap(), fixture.pcm(10..30));
+        assert_eq!(source.block.first_sample, 0);
+
+        // Carrying on from the same frame doesn't need to seek at all
+        assert_eq!(read(&mut source, 40, 30).unwrap(), fixture.pcm(40..70));
+        assert_eq!(read(&mut source, 245, 20).unwrap(), fixture.pcm(245..250));
+        assert_eq!(read(&mut source, 250, 20).unwrap(), []);
+    }
+
+    #[test]
+    fn seeks_without_a_seek_table() {
+        let fixture = Fixture::stereo(16);
+        let mut source = fixture.open();
+        assert_eq!(read(&mut source, 200, 10).unwrap(), fixture.pcm(200..210));
+        assert_eq!(read(&mut source, 3, 10).unwrap(), fixture.pcm(3..13));
+        // Starting over from the beginning means the signature can be checked
+        assert!(source.md5.is_some());
+    }
+
+    #[test]
+    fn reads_vorbis_comments() {
+        let fixture = Fixture {
+            comments: vec![
+                "TITLE=Song",
+                "artist=Band",
+                "TrackNumber=3",
+                "DESCRIPTION=a=b",
+                "not a field",
+                "REPLAYGAIN_TRACK_GAIN=-6.02 dB",
+            ],
+            ..Fixture::mono(16)
+        };
+        let metadata = fixture.open().metadata().unwrap();
+        let expected = [
+            ("title", "Song"),
+            ("artist", "Band"),
+            ("track number", "3"),
+            ("comments", "a=b"),
+            ("replaygain_track_gain", "-6.02 dB"),
+        ];
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        assert_eq!(metadata, expected);
+    }
+
+    #[test]
+    fn rejects_damaged_metadata() {
+        let comment = encode::vorbis_comment("vendor", &["TITLE=Song"]);
+        let damaged = parse_vorbis_comment(&comment[4..comment.len() - 1]);
+        assert!(matches!(
+            damaged,
+            Err(PlaybackError::Decode(DecodeError::Invalid(
+                "VORBIS_COMMENT block"
+            )))
+        ));
+
+        let mut bytes = Fixture::mono(16).build();
+        bytes[0] = b'x';
+        assert!(FlacSource::new(Cursor::new(bytes)).is_err());
+
+        // A stream with only a comment block has no STREAMINFO
+        let bytes = encode::stream(vec![comment], &[]);
+        assert!(matches!(
+            FlacSource::new(Cursor::new(bytes)),
+            Err(PlaybackError::Decode(DecodeError::Missing(
+                "STREAMINFO block"
+            )))
+        ));
+    }
+}
@@ -0,0 +1,272 @@
+//! Bit level reading and checksums used when decoding FLAC frames.
+
+use crate::player::DecodeError;
+
+pub type BitResult<T> = Result<T, DecodeError>;
+
+/// Reads big endian, most significant bit first, values out of a byte slice.
+///
+/// Running past the end of the slice results in `DecodeError::Truncated`, so
+/// callers can fetch more data and try again.
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    bit_position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        BitReader {
+            data,
+            bit_position: 0,
+        }
+    }
+
+    /// Number of whole bytes read so far, including any partially read byte.
+    pub fn byte_position(&self) -> usize {
+        self.bit_position.div_ceil(8)
+    }
+
+    /// Skip to the start of the next byte, unless already at one.
+    pub fn align_to_byte(&mut self) {
+        self.bit_position = self.byte_position() * 8;
+    }
+
+    /// Read an unsigned value of up to 64 bits.
+    pub fn read_bits(&mut self, count: u32) -> BitResult<u64> {
+        debug_assert!(count <= 64);
+        if self.bit_position + count as usize > self.data.len() * 8 {
+            return Err(DecodeError::Truncated);
+        }
+
+        let mut value = 0u64;
+        let mut remaining = count;
+        while remaining > 0 {
+            let byte = self.data[self.bit_position / 8];
+            let offset = (self.bit_position % 8) as u32;
+            let available = 8 - offset;
+            let take = available.min(remaining);
+
+            let bits = (byte << offset) >> (8 - take);
+            value = (value << take) | bits as u64;
+
+            remaining -= take;
+            self.bit_position += take as usize;
+        }
+        Ok(value)
+    }
+
+    pub fn read_bit(&mut self) -> BitResult<bool> {
+        Ok(self.read_bits(1)? == 1)
+    }
+
+    /// Read a two's complement signed value of up to 64 bits.
+    pub fn read_signed(&mut self, count: u32) -> BitResult<i64> {
+        if count == 0 {
+            return Ok(0);
+        }
+        let value = self.read_bits(count)?;
+        let shift = 64 - count;
+        Ok(((value << shift) as i64) >> shift)
+    }
+
+    /// Read a unary coded value, i.e the number of 0 bits before a 1 bit.
+    pub fn read_unary(&mut self) -> BitResult<u32> {
+        let mut count = 0;
+        loop {
+            let byte_index = self.bit_position / 8;
+            let Some(byte) = self.data.get(byte_index) else {
+                return Err(DecodeError::Truncated);
+            };
+
+            // Look at all the remaining bits of the current byte at once
+            let offset = (self.bit_position % 8) as u32;
+            let zeros = (byte << offset).leading_zeros().min(8 - offset);
+
+            if zeros < 8 - offset {
+                self.bit_position += zeros as usize + 1;
+                return Ok(count + zeros);
+            }
+            count += zeros;
+            self.bit_position += zeros as usize;
+        }
+    }
+
+    /// Read a Rice coded value with the given parameter, as used for residuals.
+    pub fn read_rice(&mut self, parameter: u32) -> BitResult<i64> {
+        let quotient = self.read_unary()? as u64;
+        let remainder = self.read_bits(parameter)?;
+        let folded = (quotient << parameter) | remainder;
+
+        // Values are zigzag encoded, so that small magnitudes have small codes
+        Ok((folded >> 1) as i64 ^ -((folded & 1) as i64))
+    }
+
+    /// Read a UTF-8 style variable length coded number of up to 36 bits, as
+    /// used for frame and sample numbers.
+    pub fn read_coded_number(&mut self) -> BitResult<u64> {
+        let first = self.read_bits(8)?;
+        let extra_bytes = match (first as u8).leading_ones() {
+            0 => return Ok(first),
+            count @ 2..=7 => count - 1,
+            _ => return Err(DecodeError::Invalid("coded number")),
+        };
+
+        let mut value = first & (0x7F >> (extra_bytes + 1));
+        for _ in 0..extra_bytes {
+            let byte = self.read_bits(8)?;
+            if byte & 0xC0 != 0x80 {
+                return Err(DecodeError::Invalid("coded number"));
+            }
+            value = (value << 6) | (byte & 0x3F);
+        }
+        Ok(value)
+    }
+}
+
+const CRC8_TABLE: [u8; 256] = crc8_table();
+const CRC16_TABLE: [u16; 256] = crc16_table();
+
+/// CRC-8 with polynomial x^8 + x^2 + x + 1, used to protect frame headers.
+pub fn crc8(data: &[u8]) -> u8 {
+    data.iter()
+        .fold(0, |crc, byte| CRC8_TABLE[(crc ^ byte) as usize])
+}
+
+/// CRC-16 with polynomial x^16 + x^15 + x^2 + 1, used to protect whole frames.
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, byte| {
+        (crc << 8) ^ CRC16_TABLE[((crc >> 8) as u8 ^ byte) as usize]
+    })
+}
+
+const fn crc8_table() -> [u8; 256] {
+    let mut table = [0u8; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut crc = index as u8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 0x80 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x07,
+            };
+            bit += 1;
+        }
+        table[index] = crc;
+        index += 1;
+    }
+    table
+}
+
+const fn crc16_table() -> [u16; 256] {
+    let mut table = [0u16; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut crc = (index as u16) << 8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 0x8000 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x8005,
+            };
+            bit += 1;
+        }
+        table[index] = crc;
+        index += 1;
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::flac::encode::BitWriter;
+
+    #[test]
+    fn values_are_read_across_byte_boundaries() {
+        let mut reader = BitReader::new(&[0b1011_0011, 0b1100_0101, 0xFF]);
+        assert_eq!(reader.read_bits(3).unwrap(), 0b101);
+        assert_eq!(reader.read_bits(7).unwrap(), 0b100_1111);
+        assert!(!reader.read_bit().unwrap());
+        assert_eq!(reader.byte_position(), 2);
+
+        reader.align_to_byte();
+        assert_eq!(reader.byte_position(), 2);
+        assert_eq!(reader.read_bits(8).unwrap(), 0xFF);
+        assert_eq!(reader.read_bits(0).unwrap(), 0);
+        assert!(matches!(reader.read_bit(), Err(DecodeError::Truncated)));
+    }
+
+    #[test]
+    fn signed_values_are_sign_extended() {
+        let mut reader = BitReader::new(&[0b1110_0111, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(reader.read_signed(4).unwrap(), -2);
+        assert_eq!(reader.read_signed(4).unwrap(), 7);
+        assert_eq!(reader.read_signed(0).unwrap(), 0);
+        assert_eq!(reader.read_signed(24).unwrap(), -1);
+    }
+
+    #[test]
+    fn unary_values_count_zeros_up_to_a_one() {
+        let mut reader = BitReader::new(&[0b0001_1000, 0b0000_0000, 0b0100_0000, 0]);
+        assert_eq!(reader.read_unary().unwrap(), 3);
+        assert_eq!(reader.read_unary().unwrap(), 0);
+        // Runs of zeros can span whole bytes
+        assert_eq!(reader.read_unary().unwrap(), 12);
+        assert!(matches!(reader.read_unary(), Err(DecodeError::Truncated)));
+    }
+
+    #[test]
+    fn rice_codes_fold_negative_values_between_positive_ones() {
+        // Quotient 0 remainder 11 for the 6th value, which is -3
+        let mut reader = BitReader::new(&[0b1101_0000]);
+        assert_eq!(reader.read_rice(3).unwrap(), -3);
+
+        let values = [0, -1, 1, -2, 2, 100, -100, 12345, -65536];
+        for parameter in [0, 1, 4, 14] {
+            let mut writer = BitWriter::default();
+            for value in values {
+                writer.write_rice(value, parameter);
+            }
+            let mut reader = BitReader::new(writer.bytes());
+            for value in values {
+                assert_eq!(reader.read_rice(parameter).unwrap(), value);
+            }
+        }
+    }
+
+    #[test]
+    fn coded_numbers_take_up_to_seven_bytes() {
+        let mut reader = BitReader::new(&[0x7F, 0xC2, 0xA9, 0xE2, 0x82, 0xAC]);
+        assert_eq!(reader.read_coded_number().unwrap(), 0x7F);
+        assert_eq!(reader.read_coded_number().unwrap(), 0xA9);
+        assert_eq!(reader.read_coded_number().unwrap(), 0x20AC);
+
+        let largest = (1 << 36) - 1;
+        let mut writer = BitWriter::default();
+        writer.write_coded_number(largest);
+        assert_eq!(writer.bytes().len(), 7);
+        let mut reader = BitReader::new(writer.bytes());
+        assert_eq!(reader.read_coded_number().unwrap(), largest);
+
+        // Continuation bytes can't start a number, or be left out
+        let mut reader = BitReader::new(&[0x80]);
+        assert!(matches!(
+            reader.read_coded_number(),
+            Err(DecodeError::Invalid(_))
+        ));
+        let mut reader = BitReader::new(&[0xC2, 0x29]);
+        assert!(matches!(
+            reader.read_coded_number(),
+            Err(DecodeError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn checksums_match_their_check_values() {
+        assert_eq!(crc8(b""), 0);
+        assert_eq!(crc8(b"123456789"), 0xF4);
+        assert_eq!(crc16(b""), 0);
+        assert_eq!(crc16(b"123456789"), 0xFEE8);
+    }
+}
@@ -0,0 +1,311 @@
+//! Just enough of a FLAC encoder to build streams for tests.
+//!
+//! Nothing is done to find a good way of coding the audio. Each subframe is
+//! coded however the test asks for, and Rice parameters are picked by trying
+//! each in turn.
+
+use super::bits::{crc16, crc8};
+use super::{BLOCK_TYPE_SEEKTABLE, BLOCK_TYPE_STREAMINFO, BLOCK_TYPE_VORBIS_COMMENT, FLAC_MARKER};
+
+const MAX_RICE_PARAMETER: u32 = 14;
+
+/// Writes big endian, most significant bit first, values into bytes.
+#[derive(Default)]
+pub struct BitWriter {
+    bytes: Vec<u8>,
+    bit_position: usize,
+}
+
+impl BitWriter {
+    pub fn write_bits(&mut self, value: u64, count: u32) {
+        for bit in (0..count).rev() {
+            if self.bit_position.is_multiple_of(8) {
+                self.bytes.push(0);
+            }
+            let set = ((value >> bit) & 1) as u8;
+            let last = self.bytes.last_mut().unwrap();
+            *last |= set << (7 - self.bit_position % 8);
+            self.bit_position += 1;
+        }
+    }
+
+    pub fn write_bit(&mut self, bit: bool) {
+        self.write_bits(bit as u64, 1);
+    }
+
+    pub fn write_signed(&mut self, value: i64, count: u32) {
+        self.write_bits(value as u64 & mask(count), count);
+    }
+
+    pub fn write_unary(&mut self, value: u32) {
+        for _ in 0..value {
+            self.write_bit(false);
+        }
+        self.write_bit(true);
+    }
+
+    pub fn write_rice(&mut self, value: i64, parameter: u32) {
+        let folded = zigzag(value);
+        self.write_unary((folded >> parameter) as u32);
+        self.write_bits(folded & mask(parameter), parameter);
+    }
+
+    pub fn write_coded_number(&mut self, value: u64) {
+        if value < 0x80 {
+            self.write_bits(value, 8);
+            return;
+        }
+        // Each extra byte holds 6 bits, and takes one away from the first
+        let extra_bytes = (1..=6).find(|extra| value >> (6 + 5 * extra) == 0).unwrap();
+        let prefix = (0xFF00 >> (extra_bytes + 1)) & 0xFF;
+        self.write_bits(prefix | (value >> (6 * extra_bytes)), 8);
+        for byte in (0..extra_bytes).rev() {
+            self.write_bits(0x80 | ((value >> (6 * byte)) & 0x3F), 8);
+        }
+    }
+
+    /// Pad with zeros up to the start of the next byte.
+    pub fn align_to_byte(&mut self) {
+        self.bit_position = self.bytes.len() * 8;
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+fn mask(count: u32) -> u64 {
+    match count {
+        64 => u64::MAX,
+        _ => (1 << count) - 1,
+    }
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+/// How to code the samples of a channel.
+#[derive(Debug, Clone, Copy)]
+pub enum Subframe<'a> {
+    Constant,
+    Verbatim,
+    Fixed(usize),
+    Lpc {
+        precision: u32,
+        shift: u32,
+        coefficients: &'a [i64],
+    },
+}
+
+/// Code a channel of samples, which must all fit in `bits_per_sample`.
+pub fn write_subframe(
+    writer: &mut BitWriter,
+    subframe: Subframe,
+    bits_per_sample: u32,
+    samples: &[i64],
+) {
+    let subframe_type = match subframe {
+        Subframe::Constant => 0,
+        Subframe::Verbatim => 1,
+        Subframe::Fixed(order) => 8 + order as u64,
+        Subframe::Lpc { coefficients, .. } => 31 + coefficients.len() as u64,
+    };
+    writer.write_bit(false);
+    writer.write_bits(subframe_type, 6);
+    // No wasted bits
+    writer.write_bit(false);
+
+    match subframe {
+        Subframe::Constant => writer.write_signed(samples[0], bits_per_sample),
+        Subframe::Verbatim => {
+            for &sample in samples {
+                writer.write_signed(sample, bits_per_sample);
+            }
+        }
+        Subframe::Fixed(order) => {
+            for &sample in &samples[..order] {
+                writer.write_signed(sample, bits_per_sample);
+            }
+            let residual = (order..samples.len()).map(|index| {
+                let prediction = match order {
+                    0 => 0,
+                    1 => samples[index - 1],
+                    2 => 2 * samples[index - 1] - samples[index - 2],
+                    3 => 3 * samples[index - 1] - 3 * samples[index - 2] + samples[index - 3],
+                    _ => {
+                        4 * samples[index - 1] - 6 * samples[index - 2] + 4 * samples[index - 3]
+                            - samples[index - 4]
+                    }
+                };
+                samples[index] - prediction
+            });
+            write_residual(writer, order, &residual.collect::<Vec<_>>());
+        }
+        Subframe::Lpc {
+            precision,
+            shift,
+            coefficients,
+        } => {
+            let order = coefficients.len();
+            for &sample in &samples[..order] {
+                writer.write_signed(sample, bits_per_sample);
+            }
+            writer.write_bits(precision as u64 - 1, 4);
+            writer.write_signed(shift as i64, 5);
+            for &coefficient in coefficients {
+                writer.write_signed(coefficient, precision);
+            }
+            let residual = (order..samples.len()).map(|index| {
+                let history = samples[index - order..index].iter().rev();
+                let prediction: i64 = coefficients.iter().zip(history).map(|(c, s)| c * s).sum();
+                samples[index] - (prediction >> shift)
+            });
+            write_residual(writer, order, &residual.collect::<Vec<_>>());
+        }
+    }
+}
+
+/// Rice code the residual of a predictor of the given order, split into four
+/// partitions where the block allows it.
+fn write_residual(writer: &mut BitWriter, order: usize, residual: &[i64]) {
+    let block_size = residual.len() + order;
+    let partition_order = match block_size.is_multiple_of(4) && block_size / 4 >= order {
+        true => 2,
+        false => 0,
+    };
+    writer.write_bits(0, 2);
+    writer.write_bits(partition_order, 4);
+
+    let partition_size = block_size >> partition_order;
+    let mut start = 0;
+    for partition in 0..1 << partition_order {
+        // The first partition is short by the warm up samples
+        let end = (partition + 1) * partition_size - order;
+        let values = &residual[start..end];
+        start = end;
+
+        let cost = |parameter: u32| -> u64 {
+            let bits = values.iter().map(|&value| (zigzag(value) >> parameter) + 1);
+            bits.sum::<u64>() + (values.len() as u64 * parameter as u64)
+        };
+        let parameter = (0..=MAX_RICE_PARAMETER).min_by_key(|&p| cost(p)).unwrap();
+        writer.write_bits(parameter as u64, 4);
+        for &value in values {
+            writer.write_rice(value, parameter);
+        }
+    }
+}
+
+/// Code a frame of a fixed block size stream, using samples already coded to
+/// suit `channel_code`.
+pub fn frame(
+    number: u64,
+    channel_code: u64,
+    bits_per_sample: u32,
+    subframes: &[(Subframe, &[i64])],
+) -> Vec<u8> {
+    let block_size = subframes[0].1.len() as u64;
+    let mut writer = BitWriter::default();
+    writer.write_bits(0b11111111111110, 14);
+    writer.write_bit(false);
+    // Fixed block size
+    writer.write_bit(false);
+    // Block size given after the coded number, sample rate and size as in
+    // STREAMINFO
+    writer.write_bits(7, 4);
+    writer.write_bits(0, 4);
+    writer.write_bits(channel_code, 4);
+    writer.write_bits(0, 3);
+    writer.write_bit(false);
+    writer.write_coded_number(number);
+    writer.write_bits(block_size - 1, 16);
+    writer.write_bits(crc8(writer.bytes()) as u64, 8);
+
+    for (channel, (subframe, samples)) in subframes.iter().enumerate() {
+        let is_side = matches!((channel_code, channel), (8, 1) | (9, 0) | (10, 1));
+        let bits = bits_per_sample + is_side as u32;
+        write_subframe(&mut writer, *subframe, bits, samples);
+    }
+
+    writer.align_to_byte();
+    writer.write_bits(crc16(writer.bytes()) as u64, 16);
+    writer.bytes().to_vec()
+}
+
+/// Code a pair of left and right channels for the given channel assignment.
+pub fn correlate(channel_code: u64, left: &[i64], right: &[i64]) -> (Vec<i64>, Vec<i64>) {
+    let pairs = left.iter().zip(right);
+    match channel_code {
+        8 => (left.to_vec(), pairs.map(|(l, r)| l - r).collect()),
+        9 => (pairs.map(|(l, r)| l - r).collect(), right.to_vec()),
+        10 => pairs.map(|(l, r)| ((l + r) >> 1, l - r)).unzip(),
+        _ => (left.to_vec(), right.to_vec()),
+    }
+}
+
+/// A metadata block, with its header.
+pub fn metadata_block(block_type: u8, is_last: bool, body: &[u8]) -> Vec<u8> {
+    let mut block = vec![block_type | ((is_last as u8) << 7)];
+    block.extend(&(body.len() as u32).to_be_bytes()[1..]);
+    block.extend(body);
+    block
+}
+
+pub fn stream_info(
+    block_size: u16,
+    sample_rate: u32,
+    channels: u32,
+    bits_per_sample: u32,
+    total_samples: u64,
+    md5_signature: [u8; 16],
+) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend(block_size.to_be_bytes());
+    body.extend(block_size.to_be_bytes());
+    // Minimum and maximum frame sizes aren't known
+    body.extend([0; 6]);
+    let packed = (sample_rate as u64) << 44
+        | ((channels as u64 - 1) << 41)
+        | ((bits_per_sample as u64 - 1) << 36)
+        | total_samples;
+    body.extend(packed.to_be_bytes());
+    body.extend(md5_signature);
+    metadata_block(BLOCK_TYPE_STREAMINFO, false, &body)
+}
+
+/// A seek table of (sample, offset) points.
+pub fn seek_table(points: &[(u64, u64)]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for &(sample, offset) in points {
+        body.extend(sample.to_be_bytes());
+        body.extend(offset.to_be_bytes());
+        // Samples in the frame, which isn't needed
+        body.extend(0u16.to_be_bytes());
+    }
+    metadata_block(BLOCK_TYPE_SEEKTABLE, false, &body)
+}
+
+pub fn vorbis_comment(vendor: &str, comments: &[&str]) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend((vendor.len() as u32).to_le_bytes());
+    body.extend(vendor.as_bytes());
+    body.extend((comments.len() as u32).to_le_bytes());
+    for comment in comments {
+        body.extend((comment.len() as u32).to_le_bytes());
+        body.extend(comment.as_bytes());
+    }
+    metadata_block(BLOCK_TYPE_VORBIS_COMMENT, false, &body)
+}
+
+/// Put together a stream from its metadata blocks and frames, marking the
+/// last block as such.
+pub fn stream(mut blocks: Vec<Vec<u8>>, frames: &[Vec<u8>]) -> Vec<u8> {
+    if let Some(last) = blocks.last_mut() {
+        last[0] |= 0x80;
+    }
+    let mut stream = FLAC_MARKER.to_vec();
+    stream.extend(blocks.concat());
+    stream.extend(frames.concat());
+    stream
+}
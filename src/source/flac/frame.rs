@@ -0,0 +1,523 @@
+//! Decoding of individual FLAC frames into blocks of samples.
+//!
+//! A frame holds a header, one subframe per channel and a CRC-16 footer. Each
+//! subframe encodes a channel either as a constant, verbatim samples, or as
+//! the residual of a fixed or LPC predictor, which is Rice coded.
+
+use super::bits::{self, BitReader, BitResult};
+use super::StreamInfo;
+use crate::player::DecodeError;
+
+const FRAME_SYNC_CODE: u64 = 0b11111111111110;
+
+const MAX_LPC_ORDER: usize = 32;
+
+/// How the channels of a frame relate to each other.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ChannelAssignment {
+    /// Each channel is coded separately.
+    Independent(usize),
+    /// Left channel, then the difference between left and right.
+    LeftSide,
+    /// Difference between left and right, then the right channel.
+    SideRight,
+    /// Average of left and right, then the difference between them.
+    MidSide,
+}
+
+impl ChannelAssignment {
+    fn channels(&self) -> usize {
+        match self {
+            ChannelAssignment::Independent(channels) => *channels,
+            _ => 2,
+        }
+    }
+
+    /// Whether the given channel holds a difference, which needs an extra bit.
+    fn is_side_channel(&self, channel: usize) -> bool {
+        matches!(
+            (self, channel),
+            (ChannelAssignment::LeftSide, 1)
+                | (ChannelAssignment::SideRight, 0)
+                | (ChannelAssignment::MidSide, 1)
+        )
+    }
+}
+
+struct FrameHeader {
+    block_size: usize,
+    channels: ChannelAssignment,
+    bits_per_sample: u32,
+    first_sample: u64,
+}
+
+/// A run of decoded samples, one buffer per channel.
+///
+/// Blocks are reused from frame to frame to avoid allocating as we go.
+#[derive(Default)]
+pub struct Block {
+    pub first_sample: u64,
+    pub frames: usize,
+    pub channels: Vec<Vec<i64>>,
+}
+
+impl Block {
+    /// Position just beyond the last sample of the block.
+    pub fn end_sample(&self) -> u64 {
+        self.first_sample + self.frames as u64
+    }
+
+    pub fn contains(&self, sample: u64) -> bool {
+        self.first_sample <= sample && sample < self.end_sample()
+    }
+}
+
+/// Decode the frame at the start of `data` into `block`, returning the number
+/// of bytes the frame took up.
+///
+/// Fails with `DecodeError::Truncated` if `data` doesn't hold the whole frame.
+pub fn decode_frame(data: &[u8], info: &StreamInfo, block: &mut Block) -> BitResult<usize> {
+    let mut reader = BitReader::new(data);
+    let header = read_header(&mut reader, info)?;
+
+    let header_size = reader.byte_position();
+    let expected_crc = reader.read_bits(8)? as u8;
+    if bits::crc8(&data[..header_size]) != expected_crc {
+        return Err(DecodeError::Invalid("frame header checksum"));
+    }
+
+    let channel_count = header.channels.channels();
+    if channel_count != info.channels as usize {
+        return Err(DecodeError::Invalid("frame channel count"));
+    }
+
+    block.first_sample = header.first_sample;
+    block.frames = header.block_size;
+    block.channels.resize_with(channel_count, Vec::new);
+
+    for (channel, samples) in block.channels.iter_mut().enumerate() {
+        let bits_per_sample = match header.channels.is_side_channel(channel) {
+            true => header.bits_per_sample + 1,
+            false => header.bits_per_sample,
+        };
+        samples.clear();
+        samples.resize(header.block_size, 0);
+        read_subframe(&mut reader, bits_per_sample, samples)?;
+    }
+
+    reader.align_to_byte();
+    let frame_size = reader.byte_position();
+    let expected_crc = reader.read_bits(16)? as u16;
+    if bits::crc16(&data[..frame_size]) != expected_crc {
+        return Err(DecodeError::Invalid("frame checksum"));
+    }
+
+    decorrelate(header.channels, &mut block.channels);
+    Ok(frame_size + 2)
+}
+
+fn read_header(reader: &mut BitReader, info: &StreamInfo) -> BitResult<FrameHeader> {
+    if reader.read_bits(14)? != FRAME_SYNC_CODE {
+        return Err(DecodeError::Invalid("frame sync code"));
+    }
+    if reader.read_bit()? {
+        return Err(DecodeError::Invalid("frame header"));
+    }
+    let variable_block_size = reader.read_bit()?;
+
+    let block_size_code = reader.read_bits(4)?;
+    let sample_rate_code = reader.read_bits(4)?;
+    let channel_code = reader.read_bits(4)?;
+    let sample_size_code = reader.read_bits(3)?;
+    if reader.read_bit()? {
+        return Err(DecodeError::Invalid("frame header"));
+    }
+
+    // Fixed block size streams number frames, variable ones number samples
+    let coded_number = reader.read_coded_number()?;
+    let first_sample = match variable_block_size {
+        true => coded_number,
+        false => coded_number * info.max_block_size as u64,
+    };
+
+    let block_size = match block_size_code {
+        0 => return Err(DecodeError::Invalid("frame block size")),
+        1 => 192,
+        code @ 2..=5 => 576 << (code - 2),
+        6 => reader.read_bits(8)? as usize + 1,
+        7 => reader.read_bits(16)? as usize + 1,
+        code => 256 << (code - 8),
+    };
+
+    // The sample rate of each frame must match the stream, so any explicitly
+    // coded rate is only read to skip over it.
+    match sample_rate_code {
+        12 => {
+            reader.read_bits(8)?;
+        }
+        13 | 14 => {
+            reader.read_bits(16)?;
+        }
+        15 => return Err(DecodeError::Invalid("frame sample rate")),
+        _ => {}
+    }
+
+    let channels = match channel_code {
+        code @ 0..=7 => ChannelAssignment::Independent(code as usize + 1),
+        8 => ChannelAssignment::LeftSide,
+        9 => ChannelAssignment::SideRight,
+        10 => ChannelAssignment::MidSide,
+        _ => return Err(DecodeError::Invalid("frame channel assignment")),
+    };
+
+    let bits_per_sample = match sample_size_code {
+        0 => info.bits_per_sample,
+        1 => 8,
+        2 => 12,
+        4 => 16,
+        5 => 20,
+        6 => 24,
+        7 => 32,
+        _ => return Err(DecodeError::Invalid("frame sample size")),
+    };
+    if bits_per_sample != info.bits_per_sample {
+        return Err(DecodeError::Invalid("frame sample size"));
+    }
+
+    Ok(FrameHeader {
+        block_size,
+        channels,
+        bits_per_sample,
+        first_sample,
+    })
+}
+
+fn read_subframe(
+    reader: &mut BitReader,
+    bits_per_sample: u32,
+    samples: &mut [i64],
+) -> BitResult<()> {
+    if reader.read_bit()? {
+        return Err(DecodeError::Invalid("subframe header"));
+    }
+    let subframe_type = reader.read_bits(6)? as usize;
+
+    // Samples may have some number of low zero bits, which aren't coded
+    let wasted_bits = match reader.read_bit()? {
+        true => reader.read_unary()? + 1,
+        false => 0,
+    };
+    if wasted_bits >= bits_per_sample {
+        return Err(DecodeError::Invalid("subframe header"));
+    }
+    let bits_per_sample = bits_per_sample - wasted_bits;
+
+    match subframe_type {
+        0 => {
+            let value = reader.read_signed(bits_per_sample)?;
+            samples.fill(value);
+        }
+        1 => {
+            for sample in samples.iter_mut() {
+                *sample = reader.read_signed(bits_per_sample)?;
+            }
+        }
+        order @ 8..=12 => {
+            let order = order - 8;
+            read_warm_up(reader, bits_per_sample, order, samples)?;
+            read_residual(reader, order, samples)?;
+            predict_fixed(order, samples);
+        }
+        order @ 32..=63 => {
+            let order = order - 31;
+            read_warm_up(reader, bits_per_sample, order, samples)?;
+
+            let precision = reader.read_bits(4)? as u32 + 1;
+            if precision == 16 {
+                return Err(DecodeError::Invalid("LPC precision"));
+            }
+            let shift = reader.read_signed(5)?;
+            if shift < 0 {
+                return Err(DecodeError::Invalid("LPC shift"));
+            }
+
+            let mut coefficients = [0i64; MAX_LPC_ORDER];
+            for coefficient in &mut coefficients[..order] {
+                *coefficient = reader.read_signed(precision)?;
+            }
+
+            read_residual(reader, order, samples)?;
+            predict_lpc(&coefficients[..order], shift as u32, samples);
+        }
+        _ => return Err(DecodeError::Invalid("subframe type")),
+    }
+
+    if wasted_bits > 0 {
+        for sample in samples.iter_mut() {
+            *sample <<= wasted_bits;
+        }
+    }
+    Ok(())
+}
+
+/// Read the unencoded samples a predictor needs to get started.
+fn read_warm_up(
+    reader: &mut BitReader,
+    bits_per_sample: u32,
+    order: usize,
+    samples: &mut [i64],
+) -> BitResult<()> {
+    if order > samples.len() {
+        return Err(DecodeError::Invalid("predictor order"));
+    }
+    for sample in &mut samples[..order] {
+        *sample = reader.read_signed(bits_per_sample)?;
+    }
+    Ok(())
+}
+
+/// Read the Rice coded residual into all but the warm up samples.
+fn read_residual(reader: &mut BitReader, order: usize, samples: &mut [i64]) -> BitResult<()> {
+    let (parameter_bits, escape_code) = match reader.read_bits(2)? {
+        0 => (4, 0b1111),
+        1 => (5, 0b11111),
+        _ => return Err(DecodeError::Invalid("residual coding method")),
+    };
+
+    let partition_order = reader.read_bits(4)?;
+    let partitions = 1usize << partition_order;
+    let partition_size = samples.len() >> partition_order;
+    if partition_size * partitions != samples.len() || partition_size < order {
+        return Err(DecodeError::Invalid("residual partition order"));
+    }
+
+    let mut position = order;
+    for partition in 0..partitions {
+        // The first partition excludes the warm up samples
+        let end = (partition + 1) * partition_size;
+        let partition_samples = &mut samples[position..end];
+        position = end;
+
+        let parameter = reader.read_bits(parameter_bits)? as u32;
+        if parameter == escape_code {
+            // Escaped partitions hold samples unencoded, at a given size
+            let bits = reader.read_bits(5)? as u32;
+            for sample in partition_samples.iter_mut() {
+                *sample = reader.read_signed(bits)?;
+            }
+        } else {
+            for sample in partition_samples.iter_mut() {
+                *sample = reader.read_rice(parameter)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Rebuild samples from the residual of one of the fixed polynomial predictors.
+fn predict_fixed(order: usize, samples: &mut [i64]) {
+    for index in order..samples.len() {
+        let prediction = match order {
+            0 => 0,
+            1 => samples[index - 1],
+            2 => 2 * samples[index - 1] - samples[index - 2],
+            3 => 3 * samples[index - 1] - 3 * samples[index - 2] + samples[index - 3],
+            _ => {
+                4 * samples[index - 1] - 6 * samples[index - 2] + 4 * samples[index - 3]
+                    - samples[index - 4]
+            }
+        };
+        samples[index] = samples[index].wrapping_add(prediction);
+    }
+}
+
+/// Rebuild samples from the residual of a linear predictor.
+fn predict_lpc(coefficients: &[i64], shift: u32, samples: &mut [i64]) {
+    let order = coefficients.len();
+    for index in order..samples.len() {
+        let history = &samples[index - order..index];
+        // Coefficients are stored most recent sample first
+        let prediction: i64 = coefficients
+            .iter()
+            .zip(history.iter().rev())
+            .fold(0i64, |sum, (coefficient, sample)| {
+                sum.wrapping_add(coefficient.wrapping_mul(*sample))
+            });
+        samples[index] = samples[index].wrapping_add(prediction >> shift);
+    }
+}
+
+/// Convert stereo channels coded relative to each other back to left / right.
+fn decorrelate(assignment: ChannelAssignment, channels: &mut [Vec<i64>]) {
+    let [first, second] = channels else {
+        return;
+    };
+    let pairs = first.iter_mut().zip(second.iter_mut());
+    match assignment {
+        ChannelAssignment::Independent(_) => {}
+        ChannelAssignment::LeftSide => {
+            for (left, side) in pairs {
+                *side = *left - *side;
+            }
+        }
+        ChannelAssignment::SideRight => {
+            for (side, right) in pairs {
+                *side += *right;
+            }
+        }
+        ChannelAssignment::MidSide => {
+            for (mid, side) in pairs {
+                // The low bit of the sum is lost when averaging, but is the
+                // same as the low bit of the difference.
+                let sum = (*mid << 1) | (*side & 1);
+                let difference = *side;
+                *mid = (sum + difference) >> 1;
+                *side = (sum - difference) >> 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::flac::encode::{self, Subframe};
+
+    const BLOCK_SIZE: usize = 64;
+
+    fn info(channels: u32) -> StreamInfo {
+        StreamInfo {
+            max_block_size: BLOCK_SIZE as u16,
+            sample_rate: 44100,
+            channels,
+            bits_per_sample: 16,
+            total_samples: 0,
+            md5_signature: [0; 16],
+        }
+    }
+
+    /// A smooth wave with a little noise on top, so that predictors have a
+    /// residual to code.
+    fn wave(phase: f64, amplitude: f64) -> Vec<i64> {
+        (0..BLOCK_SIZE)
+            .map(|index| {
+                let angle = phase + index as f64 * 0.3;
+                (angle.sin() * amplitude) as i64 + (index * 7 % 5) as i64 - 2
+            })
+            .collect()
+    }
+
+    fn decode(data: &[u8], info: &StreamInfo) -> BitResult<Block> {
+        let mut block = Block::default();
+        let size = decode_frame(data, info, &mut block)?;
+        assert_eq!(size, data.len());
+        Ok(block)
+    }
+
+    #[test]
+    fn each_subframe_type_decodes() {
+        let samples = wave(0.0, 20000.0);
+        let subframes = [
+            Subframe::Verbatim,
+            Subframe::Fixed(0),
+            Subframe::Fixed(1),
+            Subframe::Fixed(2),
+            Subframe::Fixed(3),
+            Subframe::Fixed(4),
+            Subframe::Lpc {
+                precision: 12,
+                shift: 10,
+                coefficients: &[1843, -819],
+            },
+            Subframe::Lpc {
+                precision: 15,
+                shift: 13,
+                coefficients: &[12000, -4000, 1500, -600, 300, -100, 50, -20],
+            },
+        ];
+        for subframe in subframes {
+            let data = encode::frame(3, 0, 16, &[(subframe, &samples)]);
+            let block = decode(&data, &info(1)).unwrap();
+            assert_eq!(block.first_sample, 3 * BLOCK_SIZE as u64);
+            assert_eq!(block.frames, BLOCK_SIZE);
+            assert_eq!(block.channels, [&samples[..]], "{subframe:?}");
+        }
+
+        let constant = vec![-1234; BLOCK_SIZE];
+        let data = encode::frame(0, 0, 16, &[(Subframe::Constant, &constant)]);
+        assert_eq!(decode(&data, &info(1)).unwrap().channels, [constant]);
+    }
+
+    #[test]
+    fn stereo_channels_are_decorrelated() {
+        // Differences between channels need the extra bit given to the side
+        // channel, and odd sums check the bit lost when averaging
+        let mut left = wave(0.0, 32000.0);
+        let mut right = wave(2.0, 32000.0);
+        left[0] = i16::MAX as i64;
+        right[0] = i16::MIN as i64;
+        left[1] = 3;
+        right[1] = -4;
+
+        for channel_code in [1, 8, 9, 10] {
+            let (first, second) = encode::correlate(channel_code, &left, &right);
+            let subframes = [
+                (Subframe::Fixed(2), &first[..]),
+                (Subframe::Fixed(2), &second[..]),
+            ];
+            let data = encode::frame(0, channel_code, 16, &subframes);
+
+            let block = decode(&data, &info(2)).unwrap();
+            assert_eq!(
+                block.channels,
+                [left.clone(), right.clone()],
+                "{channel_code}"
+            );
+        }
+    }
+
+    #[test]
+    fn blocks_are_reused_between_frames() {
+        let mut block = Block::default();
+        let samples = wave(0.0, 1000.0);
+        let data = encode::frame(0, 0, 16, &[(Subframe::Verbatim, &samples[..])]);
+        decode_frame(&data, &info(1), &mut block).unwrap();
+
+        let short = &samples[..20];
+        let data = encode::frame(1, 0, 16, &[(Subframe::Verbatim, short)]);
+        decode_frame(&data, &info(1), &mut block).unwrap();
+        assert_eq!(block.frames, 20);
+        assert_eq!(block.channels, [short.to_vec()]);
+        assert!(block.contains(BLOCK_SIZE as u64 + 19));
+        assert!(!block.contains(BLOCK_SIZE as u64 + 20));
+    }
+
+    #[test]
+    fn damaged_frames_are_rejected() {
+        let samples = wave(0.0, 1000.0);
+        let data = encode::frame(0, 0, 16, &[(Subframe::Fixed(2), &samples)]);
+
+        let mut damaged = data.clone();
+        damaged[data.len() / 2] ^= 0x10;
+        assert!(matches!(
+            decode(&damaged, &info(1)),
+            Err(DecodeError::Invalid("frame checksum"))
+        ));
+
+        let mut damaged = data.clone();
+        damaged[4] ^= 0x01;
+        assert!(matches!(
+            decode(&damaged, &info(1)),
+            Err(DecodeError::Invalid("frame header checksum"))
+        ));
+
+        assert!(matches!(
+            decode(&data[..data.len() - 1], &info(1)),
+            Err(DecodeError::Truncated)
+        ));
+        assert!(matches!(
+            decode(&data, &info(2)),
+            Err(DecodeError::Invalid("frame channel count"))
+        ));
+    }
+}
@@ -0,0 +1,161 @@
+//! Minimal MD5 implementation, used to verify decoded audio against the
+//! signature stored in a FLAC file's STREAMINFO block.
+//!
+//! MD5 is no longer suitable for anything security related, but is fine for
+//! detecting corruption.
+
+const SHIFTS: [u32; 64] = [
+    7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, //
+    5, 9, 14, 20, 5, 9, 14, 20, 5, 9, 14, 20, 5, 9, 14, 20, //
+    4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, //
+    6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21,
+];
+
+const CONSTANTS: [u32; 64] = [
+    0xd76aa478, 0xe8c7b756, 0x242070db, 0xc1bdceee, 0xf57c0faf, 0x4787c62a, 0xa8304613, 0xfd469501,
+    0x698098d8, 0x8b44f7af, 0xffff5bb1, 0x895cd7be, 0x6b901122, 0xfd987193, 0xa679438e, 0x49b40821,
+    0xf61e2562, 0xc040b340, 0x265e5a51, 0xe9b6c7aa, 0xd62f105d, 0x02441453, 0xd8a1e681, 0xe7d3fbc8,
+    0x21e1cde6, 0xc33707d6, 0xf4d50d87, 0x455a14ed, 0xa9e3e905, 0xfcefa3f8, 0x676f02d9, 0x8d2a4c8a,
+    0xfffa3942, 0x8771f681, 0x6d9d6122, 0xfde5380c, 0xa4beea44, 0x4bdecfa9, 0xf6bb4b60, 0xbebfbc70,
+    0x289b7ec6, 0xeaa127fa, 0xd4ef3085, 0x04881d05, 0xd9d4d039, 0xe6db99e5, 0x1fa27cf8, 0xc4ac5665,
+    0xf4292244, 0x432aff97, 0xab9423a7, 0xfc93a039, 0x655b59c3, 0x8f0ccc92, 0xffeff47d, 0x85845dd1,
+    0x6fa87e4f, 0xfe2ce6e0, 0xa3014314, 0x4e0811a1, 0xf7537e82, 0xbd3af235, 0x2ad7d2bb, 0xeb86d391,
+];
+
+const BLOCK_SIZE: usize = 64;
+
+pub struct Md5 {
+    state: [u32; 4],
+    block: [u8; BLOCK_SIZE],
+    block_len: usize,
+    total_len: u64,
+}
+
+impl Md5 {
+    pub fn new() -> Self {
+        Md5 {
+            state: [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476],
+            block: [0; BLOCK_SIZE],
+            block_len: 0,
+            total_len: 0,
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.total_len += data.len() as u64;
+
+        while !data.is_empty() {
+            let take = (BLOCK_SIZE - self.block_len).min(data.len());
+            self.block[self.block_len..self.block_len + take].copy_from_slice(&data[..take]);
+            self.block_len += take;
+            data = &data[take..];
+
+            if self.block_len == BLOCK_SIZE {
+                self.process_block();
+                self.block_len = 0;
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> [u8; 16] {
+        let bit_len = self.total_len.wrapping_mul(8);
+
+        // Pad with a single 1 bit, then zeros up until the length
+        self.update(&[0x80]);
+        while self.block_len != BLOCK_SIZE - 8 {
+            self.update(&[0]);
+        }
+        self.update(&bit_len.to_le_bytes());
+
+        let mut digest = [0u8; 16];
+        for (chunk, word) in digest.chunks_exact_mut(4).zip(self.state) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        digest
+    }
+
+    fn process_block(&mut self) {
+        let mut words = [0u32; 16];
+        for (word, bytes) in words.iter_mut().zip(self.block.chunks_exact(4)) {
+            *word = u32::from_le_bytes(bytes.try_into().unwrap());
+        }
+
+        let [mut a, mut b, mut c, mut d] = self.state;
+        for round in 0..64 {
+            let (f, index) = match round / 16 {
+                0 => ((b & c) | (!b & d), round),
+                1 => ((d & b) | (!d & c), (5 * round + 1) % 16),
+                2 => (b ^ c ^ d, (3 * round + 5) % 16),
+                _ => (c ^ (b | !d), (7 * round) % 16),
+            };
+            let rotated = a
+                .wrapping_add(f)
+                .wrapping_add(CONSTANTS[round])
+                .wrapping_add(words[index])
+                .rotate_left(SHIFTS[round]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(rotated);
+        }
+
+        for (state, value) in self.state.iter_mut().zip([a, b, c, d]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex_digest(data: &[u8]) -> String {
+        let mut md5 = Md5::new();
+        md5.update(data);
+        md5.finish()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    #[test]
+    fn digests_match_rfc_1321_test_suite() {
+        let suite: [(&[u8], &str); 7] = [
+            (b"", "d41d8cd98f00b204e9800998ecf8427e"),
+            (b"a", "0cc175b9c0f1b6a831c399e269772661"),
+            (b"abc", "900150983cd24fb0d6963f7d28e17f72"),
+            (b"message digest", "f96b697d7cb7938d525a2f31aaf161d0"),
+            (
+                b"abcdefghijklmnopqrstuvwxyz",
+                "c3fcd3d76192e4007dfb496cca67e13b",
+            ),
+            (
+                b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789",
+                "d174ab98d277d9f5a5611c2c9f419d9f",
+            ),
+            (
+                b"12345678901234567890123456789012345678901234567890123456789012345678901234567890",
+                "57edf4a22be3c955ac49da2e2107b67a",
+            ),
+        ];
+        for (data, digest) in suite {
+            assert_eq!(hex_digest(data), digest);
+        }
+    }
+
+    #[test]
+    fn data_can_be_added_in_pieces() {
+        let data = [b'x'; 200];
+        let whole = {
+            let mut md5 = Md5::new();
+            md5.update(&data);
+            md5.finish()
+        };
+        // Pieces that straddle block boundaries, and one that is empty
+        let mut md5 = Md5::new();
+        for piece in [&data[..1], &data[1..63], &data[63..63], &data[63..200]] {
+            md5.update(piece);
+        }
+        assert_eq!(md5.finish(), whole);
+    }
+}
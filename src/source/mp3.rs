@@ -0,0 +1,448 @@
+//! Gapless playback figures and tags that AudioToolbox leaves out of MP3 files.
+//!
+//! AudioToolbox doesn't always know how much encoder delay and padding an MP3
+//! file has, so these are looked for directly. iTunes records them in an
+//! `iTunSMPB` comment within the ID3 tag, while LAME and FFmpeg add them to the
+//! Xing header in the first frame. Nor does it pass on user defined `TXXX`
+//! text frames, where taggers put values such as ReplayGain.
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+use crate::player::ValidFrames;
+
+const ID3_HEADER_SIZE: usize = 10;
+const ID3_FLAG_EXTENDED_HEADER: u8 = 0x40;
+const ID3_FLAG_FOOTER: u8 = 0x10;
+const ITUNES_COMMENT: &str = "iTunSMPB";
+
+// Enough of the first frame to hold both the Xing and LAME headers
+const FIRST_FRAME_SNIFF_SIZE: u64 = 512;
+
+const XING_FLAG_FRAMES: u32 = 0x1;
+const XING_FLAG_BYTES: u32 = 0x2;
+const XING_FLAG_TOC: u32 = 0x4;
+const XING_FLAG_QUALITY: u32 = 0x8;
+// Offset of the delay and padding within the LAME header
+const LAME_DELAY_OFFSET: usize = 21;
+
+/// Frames that MP3 decoders lag behind by, on top of the encoder's delay.
+const DECODER_DELAY: u64 = 529;
+
+/// An ID3v2 tag from the start of a file.
+struct Id3Tag {
+    version: u8,
+    flags: u8,
+    /// Everything after the header, up to any footer
+    body: Vec<u8>,
+}
+
+impl Id3Tag {
+    /// Bytes taken up by the tag, and so where the first frame of audio is.
+    fn length(&self) -> usize {
+        let footer = match self.flags & ID3_FLAG_FOOTER != 0 {
+            true => ID3_HEADER_SIZE,
+            false => 0,
+        };
+        ID3_HEADER_SIZE + self.body.len() + footer
+    }
+
+    /// The ID and body of each frame in the tag.
+    fn frames(&self) -> Vec<(&[u8], &[u8])> {
+        let (tag, version) = (&self.body[..], self.version);
+        // Version 2.2 has shorter frame IDs and sizes
+        let (id_size, header_size) = match version {
+            2 => (3, 6),
+            _ => (4, 10),
+        };
+
+        let mut position = 0;
+        if self.flags & ID3_FLAG_EXTENDED_HEADER != 0 && version > 2 {
+            let Some(size) = tag.get(..4) else {
+                return Vec::new();
+            };
+            let size = [size[0], size[1], size[2], size[3]];
+            position = match version {
+                3 => u32::from_be_bytes(size) as usize + 4,
+                _ => syncsafe(size) as usize,
+            };
+        }
+
+        let mut frames = Vec::new();
+        while let Some(header) = tag.get(position..position + header_size) {
+            let (id, size) = header.split_at(id_size);
+            // Padding fills out whatever is left after the last frame
+            if id[0] == 0 {
+                break;
+            }
+            let size = match (version, size) {
+                (2, &[a, b, c]) => u32::from_be_bytes([0, a, b, c]),
+                (4, &[a, b, c, d]) => syncsafe([a, b, c, d]),
+                (_, &[a, b, c, d, ..]) => u32::from_be_bytes([a, b, c, d]),
+                _ => break,
+            } as usize;
+
+            let body_start = position + header_size;
+            let Some(body) = tag.get(body_start..body_start + size) else {
+                break;
+            };
+            frames.push((id, body));
+            position = body_start + size;
+        }
+        frames
+    }
+}
+
+/// Find out where the audio lies in an MP3 file, if it says.
+pub fn read_valid_frames(reader: &mut (impl Read + Seek)) -> io::Result<Option<ValidFrames>> {
+    let mut first_frame = 0;
+    if let Some(tag) = read_id3_tag(reader)? {
+        // What iTunes says goes, as it knows about its own encoder
+        if let Some(valid) = find_itunes_comment(&tag) {
+            return Ok(Some(valid));
+        }
+        first_frame = tag.length();
+    }
+
+    reader.seek(SeekFrom::Start(first_frame as u64))?;
+    let mut frame = Vec::new();
+    reader
+        .take(FIRST_FRAME_SNIFF_SIZE)
+        .read_to_end(&mut frame)?;
+    Ok(parse_lame_header(&frame))
+}
+
+/// The user defined text frames of an MP3 file's ID3 tag, named by their
+/// descriptions in lower case.
+pub fn read_user_text(reader: &mut (impl Read + Seek)) -> io::Result<Vec<(String, String)>> {
+    let Some(tag) = read_id3_tag(reader)? else {
+        return Ok(Vec::new());
+    };
+    let text = tag
+        .frames()
+        .into_iter()
+        .filter(|(id, _)| *id == b"TXXX" || *id == b"TXX")
+        .filter_map(|(_, body)| parse_described_text(body, false))
+        .map(|(description, text)| (description.to_ascii_lowercase(), text))
+        .collect();
+    Ok(text)
+}
+
+/// Read the ID3v2 tag at the start of a file, if there is one.
+fn read_id3_tag(reader: &mut (impl Read + Seek)) -> io::Result<Option<Id3Tag>> {
+    reader.seek(SeekFrom::Start(0))?;
+    let mut header = Vec::with_capacity(ID3_HEADER_SIZE);
+    reader
+        .by_ref()
+        .take(ID3_HEADER_SIZE as u64)
+        .read_to_end(&mut header)?;
+
+    let Some((version, flags, size)) = parse_id3_header(&header) else {
+        return Ok(None);
+    };
+    let mut body = Vec::with_capacity(size);
+    reader.by_ref().take(size as u64).read_to_end(&mut body)?;
+    Ok(Some(Id3Tag {
+        version,
+        flags,
+        body,
+    }))
+}
+
+/// The version, flags and size of an ID3v2 tag, from its header.
+fn parse_id3_header(header: &[u8]) -> Option<(u8, u8, usize)> {
+    match *header {
+        [b'I', b'D', b'3', version, _, flags, a, b, c, d] => {
+            Some((version, flags, syncsafe([a, b, c, d]) as usize))
+        }
+        _ => None,
+    }
+}
+
+/// Look through the frames of an ID3v2 tag for the comment left by iTunes.
+fn find_itunes_comment(tag: &Id3Tag) -> Option<ValidFrames> {
+    tag.frames()
+        .into_iter()
+        .filter(|(id, _)| *id == b"COMM" || *id == b"COM")
+        .filter_map(|(_, body)| parse_described_text(body, true))
+        .find(|(description, _)| description == ITUNES_COMMENT)
+        .and_then(|(_, text)| parse_itunes_comment(&text))
+}
+
+/// The description and text of a comment or user defined text frame, the
+/// first of which also gives a language.
+fn parse_described_text(body: &[u8], has_language: bool) -> Option<(String, String)> {
+    let (&encoding, rest) = body.split_first()?;
+    let rest = match has_language {
+        true => rest.get(3..)?,
+        false => rest,
+    };
+
+    // Strings are terminated by a null the width of a character
+    let width = match encoding {
+        1 | 2 => 2,
+        _ => 1,
+    };
+    let end = rest
+        .chunks_exact(width)
+        .position(|character| character.iter().all(|&byte| byte == 0))?
+        * width;
+    let description = decode_text(encoding, &rest[..end]);
+    let text = decode_text(encoding, &rest[end + width..]);
+    Some((description, text))
+}
+
+/// Decode a string in one of the encodings allowed by ID3v2.
+fn decode_text(encoding: u8, bytes: &[u8]) -> String {
+    let text: String = match encoding {
+        1 | 2 => {
+            // UTF-16, big endian unless a byte order mark says otherwise
+            let (big_endian, bytes) = match bytes {
+                [0xff, 0xfe, rest @ ..] => (false, rest),
+                [0xfe, 0xff, rest @ ..] => (true, rest),
+                _ => (encoding == 2, bytes),
+            };
+            let units = bytes.chunks_exact(2).map(|pair| match big_endian {
+                true => u16::from_be_bytes([pair[0], pair[1]]),
+                false => u16::from_le_bytes([pair[0], pair[1]]),
+            });
+            char::decode_utf16(units)
+                .map(|character| character.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .collect()
+        }
+        3 => String::from_utf8_lossy(bytes).into_owned(),
+        // ISO-8859-1 maps directly on to the first 256 code points
+        _ => bytes.iter().map(|&byte| byte as char).collect(),
+    };
+    text.trim_end_matches('\0').to_string()
+}
+
+/// Parse the hexadecimal fields iTunes writes, the second of which is the
+/// encoder delay and the fourth the number of frames of audio.
+fn parse_itunes_comment(text: &str) -> Option<ValidFrames> {
+    let fields: Vec<u64> = text
+        .split_whitespace()
+        .take(4)
+        .map(|field| u64::from_str_radix(field, 16).ok())
+        .collect::<Option<_>>()?;
+    match fields[..] {
+        [_, priming, _, count] if count > 0 => Some(ValidFrames { priming, count }),
+        _ => None,
+    }
+}
+
+/// Parse the delay and padding from the LAME header following the Xing
+/// header, which takes the place of audio in the first frame.
+fn parse_lame_header(frame: &[u8]) -> Option<ValidFrames> {
+    let [0xff, second, _, fourth, ..] = *frame else {
+        return None;
+    };
+    let version = (second >> 3) & 0x3;
+    let layer = (second >> 1) & 0x3;
+    // Only layer III, and not the reserved version
+    if second & 0xe0 != 0xe0 || version == 1 || layer != 1 {
+        return None;
+    }
+
+    // The Xing header comes after the side information, which is smaller
+    // for single channel audio and the lower sample rates of MPEG 2 and 2.5
+    let is_mpeg_1 = version == 3;
+    let is_mono = fourth >> 6 == 3;
+    let side_info_size = match (is_mpeg_1, is_mono) {
+        (true, false) => 32,
+        (true, true) | (false, false) => 17,
+        (false, true) => 9,
+    };
+    let frames_per_packet: u64 = match is_mpeg_1 {
+        true => 1152,
+        false => 576,
+    };
+
+    let mut offset = 4 + side_info_size;
+    let marker = frame.get(offset..offset + 4)?;
+    if marker != b"Xing" && marker != b"Info" {
+        return None;
+    }
+    let flags = read_u32_be(frame, offset + 4)?;
+    offset += 8;
+
+    // Without a count of frames there is no telling where the padding starts
+    if flags & XING_FLAG_FRAMES == 0 {
+        return None;
+    }
+    let packets = read_u32_be(frame, offset)? as u64;
+    offset += 4;
+    for (flag, size) in [
+        (XING_FLAG_BYTES, 4),
+        (XING_FLAG_TOC, 100),
+        (XING_FLAG_QUALITY, 4),
+    ] {
+        if flags & flag != 0 {
+            offset += size;
+        }
+    }
+
+    let encoder = frame.get(offset..offset + 4)?;
+    if encoder != b"LAME" && encoder != b"Lavf" && encoder != b"Lavc" {
+        return None;
+    }
+    let start = offset + LAME_DELAY_OFFSET;
+    let [a, b, c] = *frame.get(start..start + 3)? else {
+        return None;
+    };
+    // Two 12 bit values, packed together
+    let delay = ((a as u64) << 4) | (b as u64 >> 4);
+    let padding = (((b & 0xf) as u64) << 8) | c as u64;
+
+    let count = (packets * frames_per_packet).checked_sub(delay + padding)?;
+    Some(ValidFrames {
+        priming: delay + DECODER_DELAY,
+        count,
+    })
+}
+
+fn read_u32_be(bytes: &[u8], offset: usize) -> Option<u32> {
+    let bytes = bytes.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes(bytes.try_into().ok()?))
+}
+
+/// Decode an integer stored 7 bits to a byte, so as to never look like a
+/// frame sync.
+fn syncsafe(bytes: [u8; 4]) -> u32 {
+    bytes
+        .iter()
+        .fold(0, |value, &byte| (value << 7) | (byte & 0x7f) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// The start of an MPEG 1 layer III stereo frame, with Xing and LAME
+    /// headers giving the delay and padding.
+    fn lame_frame(packets: u32, delay: u16, padding: u16) -> Vec<u8> {
+        let mut frame = vec![0xff, 0xfb, 0x90, 0x00];
+        frame.resize(4 + 32, 0);
+        frame.extend(b"Info");
+        frame.extend((XING_FLAG_FRAMES | XING_FLAG_BYTES).to_be_bytes());
+        frame.extend(packets.to_be_bytes());
+        frame.extend(123456u32.to_be_bytes());
+        frame.extend(b"LAME3.100");
+        frame.resize(frame.len() + LAME_DELAY_OFFSET - 9, 0);
+        frame.push((delay >> 4) as u8);
+        frame.push(((delay & 0xf) << 4) as u8 | (padding >> 8) as u8);
+        frame.push(padding as u8);
+        frame.resize(417, 0);
+        frame
+    }
+
+    /// An ID3v2.3 tag holding a single comment frame.
+    fn id3_comment(encoding: u8, description: &[u8], text: &[u8]) -> Vec<u8> {
+        let mut body = vec![encoding];
+        body.extend(b"eng");
+        body.extend(description);
+        body.extend(text);
+        id3_tag(&[(b"COMM", body)])
+    }
+
+    /// An ID3v2.3 tag holding each frame.
+    fn id3_tag(frames: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+        let mut tag = Vec::new();
+        for (id, body) in frames {
+            tag.extend(*id);
+            tag.extend((body.len() as u32).to_be_bytes());
+            tag.extend([0, 0]);
+            tag.extend(body);
+        }
+        // Padding follows on from the frames
+        tag.resize(tag.len() + 20, 0);
+
+        let size = tag.len() as u32;
+        let mut header = b"ID3\x03\x00\x00".to_vec();
+        header.extend([21, 14, 7, 0].map(|shift| (size >> shift) as u8 & 0x7f));
+        header.extend(tag);
+        header
+    }
+
+    #[test]
+    fn reads_lame_delay_and_padding() {
+        let file = lame_frame(100, 576, 1200);
+        let valid = read_valid_frames(&mut Cursor::new(file)).unwrap();
+        assert_eq!(
+            valid,
+            Some(ValidFrames {
+                priming: 576 + DECODER_DELAY,
+                count: 100 * 1152 - 576 - 1200,
+            })
+        );
+
+        // Anything that doesn't look like a LAME header says nothing
+        let mut file = lame_frame(100, 576, 1200);
+        file[36..40].copy_from_slice(b"VBRI");
+        assert_eq!(read_valid_frames(&mut Cursor::new(file)).unwrap(), None);
+    }
+
+    #[test]
+    fn itunes_comment_takes_precedence() {
+        let text = " 00000000 00000840 000001C0 00000000000AC440 00000000";
+        let mut file = id3_comment(0, b"iTunSMPB\0", text.as_bytes());
+        file.extend(lame_frame(100, 576, 1200));
+        let valid = read_valid_frames(&mut Cursor::new(file)).unwrap();
+        assert_eq!(
+            valid,
+            Some(ValidFrames {
+                priming: 0x840,
+                count: 0xAC440,
+            })
+        );
+    }
+
+    #[test]
+    fn reads_user_defined_text() {
+        let text = |description: &str, value: &str| {
+            let mut body = vec![0];
+            body.extend(description.as_bytes());
+            body.push(0);
+            body.extend(value.as_bytes());
+            body
+        };
+        let mut file = id3_tag(&[
+            (b"TIT2", b"\0A Title".to_vec()),
+            (b"TXXX", text("REPLAYGAIN_TRACK_GAIN", "-8.12 dB")),
+            (b"TXXX", text("replaygain_track_peak", "0.95")),
+        ]);
+        file.extend(lame_frame(100, 576, 1200));
+
+        let text = read_user_text(&mut Cursor::new(file)).unwrap();
+        assert_eq!(
+            text,
+            [
+                ("replaygain_track_gain", "-8.12 dB"),
+                ("replaygain_track_peak", "0.95"),
+            ]
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+        );
+    }
+
+    #[test]
+    fn decodes_utf16_comments() {
+        let utf16 = |text: &str| -> Vec<u8> {
+            let mut bytes = vec![0xff, 0xfe];
+            bytes.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+            bytes.extend([0, 0]);
+            bytes
+        };
+        let text = " 00000000 00000210 00000000 0000000000001000";
+        let mut file = id3_comment(1, &utf16("iTunSMPB"), &utf16(text));
+        file.extend(lame_frame(100, 576, 1200));
+        let valid = read_valid_frames(&mut Cursor::new(file)).unwrap();
+        assert_eq!(
+            valid,
+            Some(ValidFrames {
+                priming: 0x210,
+                count: 0x1000,
+            })
+        );
+    }
+}
@@ -0,0 +1,450 @@
+//! Platform independent packet source for RIFF/WAVE files.
+//!
+//! Supports integer PCM (8, 16, 24 and 32 bit), floating point PCM (32 and 64
+//! bit), either directly or wrapped up as `WAVE_FORMAT_EXTENSIBLE`. Metadata
+//! is read from any `LIST/INFO` chunks.
+//!
+//! As with any PCM format, each frame makes up one packet.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use crate::player::{
+    DecodeError, PacketBuffer, PacketCount, PacketPosition, PacketSource, PlaybackError,
+    PlaybackResult, StreamFormat, FORMAT_FLAG_IS_FLOAT, FORMAT_FLAG_IS_PACKED,
+    FORMAT_FLAG_IS_SIGNED_INTEGER, FORMAT_LINEAR_PCM,
+};
+
+#[cfg(test)]
+pub mod encode;
+
+const WAVE_FORMAT_PCM: u16 = 0x0001;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 0x0003;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+const FMT_CHUNK_MIN_SIZE: u32 = 16;
+const FMT_CHUNK_EXTENSIBLE_SIZE: u32 = 40;
+
+// The length used by streaming encoders that didn't know the data size upfront
+const UNKNOWN_CHUNK_SIZE: u32 = u32::MAX;
+
+// INFO chunk identifiers, and the equivalent AudioToolbox info dictionary keys
+const INFO_KEYS: [(&[u8; 4], &str); 12] = [
+    (b"INAM", "title"),
+    (b"IART", "artist"),
+    (b"IPRD", "album"),
+    (b"ICRD", "year"),
+    (b"IGNR", "genre"),
+    (b"ICMT", "comments"),
+    (b"ITRK", "track number"),
+    (b"IPRT", "track number"),
+    (b"ICMP", "composer"),
+    (b"ICOP", "copyright"),
+    (b"IENG", "engineer"),
+    (b"ISFT", "encoding application"),
+];
+
+/// Check if the start of a file looks like a RIFF/WAVE file.
+pub fn is_wav(header: &[u8]) -> bool {
+    header.len() >= 12 && &header[0..4] == b"RIFF" && &header[8..12] == b"WAVE"
+}
+
+pub struct WavSource<R> {
+    reader: R,
+    format: StreamFormat,
+    data_offset: u64,
+    data_size: u64,
+    metadata: Vec<(String, String)>,
+}
+
+impl WavSource<File> {
+    pub fn open(path: &str) -> PlaybackResult<Self> {
+        WavSource::new(File::open(path)?)
+    }
+}
+
+impl<R: Read + Seek> WavSource<R> {
+    pub fn new(mut reader: R) -> PlaybackResult<Self> {
+        let file_size = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(0))?;
+
+        let mut riff_header = [0u8; 12];
+        read_exact(&mut reader, &mut riff_header)?;
+        if !is_wav(&riff_header) {
+            return Err(DecodeError::Missing("RIFF/WAVE header").into());
+        }
+
+        let mut format = None;
+        let mut data = None;
+        let mut metadata = Vec::new();
+        let mut position = riff_header.len() as u64;
+
+        // Walk the chunks that make up the file, picking out the ones of interest
+        while position + 8 <= file_size {
+            let mut chunk_header = [0u8; 8];
+            read_exact(&mut reader, &mut chunk_header)?;
+            let id = &chunk_header[0..4];
+            let size = u32::from_le_bytes(chunk_header[4..8].try_into().unwrap());
+            let body_offset = position + 8;
+
+            // Clamp sizes that overrun the file, such as those left by an encoder
+            // that was interrupted or streaming
+            let remaining = file_size - body_offset;
+            let body_size = match size {
+                UNKNOWN_CHUNK_SIZE => remaining,
+                size => (size as u64).min(remaining),
+            };
+
+            match id {
+                b"fmt " => {
+                    let mut body = vec![0u8; body_size as usize];
+                    read_exact(&mut reader, &mut body)?;
+                    format = Some(parse_format_chunk(&body)?);
+                }
+                b"data" => {
+                    data = Some((body_offset, body_size));
+                }
+                b"LIST" => {
+                    let mut body = vec![0u8; body_size as usize];
+                    read_exact(&mut reader, &mut body)?;
+                    if body.starts_with(b"INFO") {
+                        metadata.extend(parse_info_chunk(&body[4..]));
+                    }
+                }
+                _ => {}
+            }
+
+            // Chunks are padded to an even number of bytes
+            position = body_offset + body_size + (body_size & 1);
+            reader.seek(SeekFrom::Start(position))?;
+        }
+
+        let format = format.ok_or(DecodeError::Missing("format chunk"))?;
+        let (data_offset, data_size) = data.ok_or(DecodeError::Missing("data chunk"))?;
+
+        Ok(WavSource {
+            reader,
+            format,
+            data_offset,
+            // Ignore any trailing partial frame
+            data_size: data_size - data_size % format.bytes_per_frame as u64,
+            metadata,
+        })
+    }
+}
+
+impl<R: Read + Seek> PacketSource for WavSource<R> {
+    fn format(&self) -> &StreamFormat {
+        &self.format
+    }
+
+    fn packet_size_upper_bound(&self) -> u32 {
+        self.format.bytes_per_packet
+    }
+
+    fn magic_cookie(&self) -> PlaybackResult<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    fn metadata(&self) -> PlaybackResult<Vec<(String, String)>> {
+        Ok(self.metadata.clone())
+    }
+
+    fn estimated_duration(&self) -> PlaybackResult<f64> {
+        let frames = self.data_size / self.format.bytes_per_frame as u64;
+        Ok(frames as f64 / self.format.sample_rate)
+    }
+
+    fn read_packets(
+        &mut self,
+        from_packet: PacketPosition,
+        packets: PacketCount,
+        buffer: &mut PacketBuffer,
+    ) -> PlaybackResult<PacketCount> {
+        let packet_size = self.format.bytes_per_packet as u64;
+        let total_packets = self.data_size / packet_size;
+        let from_packet = from_packet.max(0) as u64;
+
+        let packets = (packets as u64)
+            .min(buffer.data.len() as u64 / packet_size)
+            .min(total_packets.saturating_sub(from_packet));
+
+        let byte_size = (packets * packet_size) as usize;
+        self.reader.seek(SeekFrom::Start(
+            self.data_offset + from_packet * packet_size,
+        ))?;
+        read_exact(&mut self.reader, &mut buffer.data[..byte_size])?;
+
+        buffer.byte_size = byte_size as u32;
+        buffer.packet_description_count = 0;
+        Ok(packets as PacketCount)
+    }
+}
+
+fn parse_format_chunk(body: &[u8]) -> PlaybackResult<StreamFormat> {
+    if body.len() < FMT_CHUNK_MIN_SIZE as usize {
+        return Err(DecodeError::Invalid("format chunk").into());
+    }
+
+    let read_u16 = |offset: usize| u16::from_le_bytes([body[offset], body[offset + 1]]);
+    let read_u32 = |offset: usize| u32::from_le_bytes(body[offset..offset + 4].try_into().unwrap());
+
+    let format_tag = match read_u16(0) {
+        // The actual format is given by the first two bytes of the sub format GUID
+        WAVE_FORMAT_EXTENSIBLE if body.len() >= FMT_CHUNK_EXTENSIBLE_SIZE as usize => read_u16(24),
+        WAVE_FORMAT_EXTENSIBLE => return Err(DecodeError::Invalid("format chunk").into()),
+        format_tag => format_tag,
+    };
+
+    let channels = read_u16(2) as u32;
+    let sample_rate = read_u32(4);
+    let block_align = read_u16(12) as u32;
+
+    if channels == 0
+        || sample_rate == 0
+        || block_align == 0
+        || !block_align.is_multiple_of(channels)
+    {
+        return Err(DecodeError::Invalid("format chunk").into());
+    }
+
+    // Samples narrower than their container (e.g 20 bits in 24) are stored in
+    // the most significant bits, so can be treated as full width samples.
+    let bits_per_channel = (block_align / channels) * 8;
+
+    let sample_flags = match (format_tag, bits_per_channel) {
+        // 8 bit PCM is unsigned, anything wider is signed
+        (WAVE_FORMAT_PCM, 8) => 0,
+        (WAVE_FORMAT_PCM, 16 | 24 | 32) => FORMAT_FLAG_IS_SIGNED_INTEGER,
+        (WAVE_FORMAT_IEEE_FLOAT, 32 | 64) => FORMAT_FLAG_IS_FLOAT,
+        _ => return Err(PlaybackError::UnsupportedFormat),
+    };
+
+    Ok(StreamFormat {
+        sample_rate: sample_rate as f64,
+        format_id: FORMAT_LINEAR_PCM,
+        format_flags: sample_flags | FORMAT_FLAG_IS_PACKED,
+        bytes_per_packet: block_align,
+        frames_per_packet: 1,
+        bytes_per_frame: block_align,
+        channels_per_frame: channels,
+        bits_per_channel,
+    })
+}
+
+fn parse_info_chunk(mut body: &[u8]) -> Vec<(String, String)> {
+    let mut metadata = Vec::new();
+
+    while body.len() >= 8 {
+        let id = &body[0..4];
+        let size = u32::from_le_bytes(body[4..8].try_into().unwrap()) as usize;
+        let value = &body[8..body.len().min(8 + size)];
+
+        // Values are null terminated, and possibly null padded
+        let value = value.split(|byte| *byte == 0).next().unwrap_or_default();
+        let value = String::from_utf8_lossy(value).trim().to_string();
+
+        let key = INFO_KEYS.iter().find(|(info_id, _)| *info_id == id);
+        if let (Some((_, key)), false) = (key, value.is_empty()) {
+            metadata.push((key.to_string(), value));
+        }
+
+        let padded_size = size + (size & 1);
+        body = &body[body.len().min(8 + padded_size)..];
+    }
+
+    metadata
+}
+
+fn read_exact(reader: &mut impl Read, buffer: &mut [u8]) -> PlaybackResult<()> {
+    reader.read_exact(buffer).map_err(|err| match err.kind() {
+        io::ErrorKind::UnexpectedEof => DecodeError::Truncated.into(),
+        _ => err.into(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::encode::{chunk, Wav};
+    use super::*;
+    use std::io::Cursor;
+
+    fn open(wav: &Wav) -> WavSource<Cursor<Vec<u8>>> {
+        WavSource::new(Cursor::new(wav.build())).expect("fixture should open")
+    }
+
+    fn read_all(source: &mut impl PacketSource, packets_per_read: u32) -> Vec<u8> {
+        let mut output = Vec::new();
+        let mut storage = vec![0u8; 4096];
+        let mut position = 0;
+        loop {
+            let mut buffer = PacketBuffer {
+                data: &mut storage,
+                byte_size: 0,
+                packet_descriptions: None,
+                packet_description_count: 0,
+                trim_start: 0,
+                trim_end: 0,
+            };
+            let packets = source
+                .read_packets(position, packets_per_read, &mut buffer)
+                .unwrap();
+            if packets == 0 {
+                return output;
+            }
+            output.extend(&buffer.data[..buffer.byte_size as usize]);
+            position += packets as i64;
+        }
+    }
+
+    #[test]
+    fn reads_16_bit_stereo_pcm() {
+        let data: Vec<u8> = (0..=255).collect();
+        let mut source = open(&Wav::pcm(2, 16, data.clone()));
+
+        let format = *source.format();
+        assert_eq!(format.format_id, FORMAT_LINEAR_PCM);
+        assert_eq!(
+            format.format_flags,
+            FORMAT_FLAG_IS_SIGNED_INTEGER | FORMAT_FLAG_IS_PACKED
+        );
+        assert_eq!(format.sample_rate, 44100.0);
+        assert_eq!(format.channels_per_frame, 2);
+        assert_eq!(format.bits_per_channel, 16);
+        assert_eq!(format.bytes_per_frame, 4);
+        assert_eq!(format.bytes_per_packet, 4);
+        assert_eq!(format.frames_per_packet, 1);
+
+        assert_eq!(read_all(&mut source, 7), data);
+        assert_eq!(source.estimated_duration().unwrap(), 64.0 / 44100.0);
+    }
+
+    #[test]
+    fn reads_integer_sample_sizes() {
+        for (bits, flags) in [
+            (8, 0),
+            (16, FORMAT_FLAG_IS_SIGNED_INTEGER),
+            (24, FORMAT_FLAG_IS_SIGNED_INTEGER),
+            (32, FORMAT_FLAG_IS_SIGNED_INTEGER),
+        ] {
+            let data = vec![1u8; bits as usize * 3];
+            let mut source = open(&Wav::pcm(1, bits, data.clone()));
+            assert_eq!(source.format().bits_per_channel, bits as u32);
+            assert_eq!(source.format().format_flags, flags | FORMAT_FLAG_IS_PACKED);
+            assert_eq!(read_all(&mut source, 5), data);
+        }
+    }
+
+    #[test]
+    fn reads_float_sample_sizes() {
+        for bits in [32, 64] {
+            let data = vec![2u8; bits as usize * 2];
+            let mut source = open(&Wav::float(2, bits, data.clone()));
+            assert_eq!(source.format().bits_per_channel, bits as u32);
+            assert_eq!(
+                source.format().format_flags,
+                FORMAT_FLAG_IS_FLOAT | FORMAT_FLAG_IS_PACKED
+            );
+            assert_eq!(read_all(&mut source, 3), data);
+        }
+    }
+
+    #[test]
+    fn reads_extensible_format() {
+        let fixture = Wav {
+            extensible: true,
+            ..Wav::float(2, 32, vec![0; 64])
+        };
+        let source = open(&fixture);
+        assert_eq!(
+            source.format().format_flags,
+            FORMAT_FLAG_IS_FLOAT | FORMAT_FLAG_IS_PACKED
+        );
+        assert_eq!(source.format().bits_per_channel, 32);
+    }
+
+    #[test]
+    fn reads_info_metadata() {
+        let fixture = Wav {
+            info: vec![
+                (b"INAM", "Song"),
+                (b"IART", "Band"),
+                (b"ICMT", "Odd"),
+                (b"IXYZ", "Ignored"),
+            ],
+            ..Wav::pcm(2, 16, vec![0; 16])
+        };
+        let metadata = open(&fixture).metadata().unwrap();
+        let expected = [("title", "Song"), ("artist", "Band"), ("comments", "Odd")];
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        assert_eq!(metadata, expected);
+    }
+
+    #[test]
+    fn reading_past_end_returns_no_packets() {
+        let mut source = open(&Wav::pcm(2, 16, vec![0; 40]));
+        let mut storage = vec![0u8; 64];
+        let mut buffer = PacketBuffer {
+            data: &mut storage,
+            byte_size: 0,
+            packet_descriptions: None,
+            packet_description_count: 0,
+            trim_start: 0,
+            trim_end: 0,
+        };
+        assert_eq!(source.read_packets(8, 16, &mut buffer).unwrap(), 2);
+        assert_eq!(buffer.byte_size, 8);
+        assert_eq!(source.read_packets(10, 16, &mut buffer).unwrap(), 0);
+    }
+
+    #[test]
+    fn clamps_data_chunk_overrunning_file() {
+        let mut bytes = Wav::pcm(1, 16, vec![0; 10]).build();
+        let data_size_offset = bytes.windows(4).position(|w| w == b"data").unwrap() + 4;
+        bytes[data_size_offset..data_size_offset + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut source = WavSource::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(read_all(&mut source, 100).len(), 10);
+    }
+
+    #[test]
+    fn rejects_unsupported_encoding() {
+        let fixture = Wav {
+            format_tag: 0x0002, // Microsoft ADPCM
+            ..Wav::pcm(2, 16, vec![0; 16])
+        };
+        let result = WavSource::new(Cursor::new(fixture.build()));
+        assert!(matches!(result, Err(PlaybackError::UnsupportedFormat)));
+    }
+
+    #[test]
+    fn rejects_missing_chunks() {
+        let bytes = chunk(
+            b"RIFF",
+            &[b"WAVE".as_slice(), &chunk(b"data", &[0; 4])].concat(),
+        );
+        let result = WavSource::new(Cursor::new(bytes));
+        assert!(matches!(
+            result,
+            Err(PlaybackError::Decode(DecodeError::Missing(_)))
+        ));
+    }
+
+    #[test]
+    fn sniffs_wav_header() {
+        assert!(is_wav(&Wav::pcm(1, 8, vec![0]).build()));
+        assert!(!is_wav(b"fLaC\0\0\0\x22"));
+        assert!(!is_wav(b"RIFF"));
+    }
+
+    #[test]
+    fn opens_fixture_file() {
+        let path = std::env::temp_dir().join(format!("afqueue-wav-{}.wav", std::process::id()));
+        std::fs::write(&path, Wav::pcm(2, 16, vec![0; 400]).build()).unwrap();
+        let source = WavSource::open(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(source.unwrap().format().channels_per_frame, 2);
+    }
+}
@@ -0,0 +1,83 @@
+//! Just enough of a WAVE writer to build files for tests.
+
+use super::{WAVE_FORMAT_EXTENSIBLE, WAVE_FORMAT_IEEE_FLOAT, WAVE_FORMAT_PCM};
+
+/// A RIFF/WAVE file, built up with an odd sized chunk ahead of the rest to
+/// check padding is respected.
+pub struct Wav {
+    pub format_tag: u16,
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub bits: u16,
+    pub extensible: bool,
+    pub info: Vec<(&'static [u8; 4], &'static str)>,
+    pub data: Vec<u8>,
+}
+
+impl Wav {
+    /// Integer PCM at 44.1kHz, with `data` already laid out as frames.
+    pub fn pcm(channels: u16, bits: u16, data: Vec<u8>) -> Self {
+        Wav {
+            format_tag: WAVE_FORMAT_PCM,
+            channels,
+            sample_rate: 44100,
+            bits,
+            extensible: false,
+            info: Vec::new(),
+            data,
+        }
+    }
+
+    pub fn float(channels: u16, bits: u16, data: Vec<u8>) -> Self {
+        Wav {
+            format_tag: WAVE_FORMAT_IEEE_FLOAT,
+            ..Wav::pcm(channels, bits, data)
+        }
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let block_align = self.channels * self.bits / 8;
+        let mut fmt = Vec::new();
+        let tag = match self.extensible {
+            true => WAVE_FORMAT_EXTENSIBLE,
+            false => self.format_tag,
+        };
+        fmt.extend(tag.to_le_bytes());
+        fmt.extend(self.channels.to_le_bytes());
+        fmt.extend(self.sample_rate.to_le_bytes());
+        fmt.extend((self.sample_rate * block_align as u32).to_le_bytes());
+        fmt.extend(block_align.to_le_bytes());
+        fmt.extend(self.bits.to_le_bytes());
+        if self.extensible {
+            fmt.extend(22u16.to_le_bytes());
+            fmt.extend(self.bits.to_le_bytes());
+            fmt.extend(0u32.to_le_bytes());
+            fmt.extend(self.format_tag.to_le_bytes());
+            fmt.extend(b"\x00\x00\x00\x00\x10\x00\x80\x00\x00\xAA\x00\x38\x9B\x71");
+        }
+
+        let mut info = b"INFO".to_vec();
+        for (id, value) in &self.info {
+            info.extend(chunk(id, &[value.as_bytes(), b"\0"].concat()));
+        }
+
+        let mut body = b"WAVE".to_vec();
+        body.extend(chunk(b"junk", b"abc"));
+        body.extend(chunk(b"fmt ", &fmt));
+        body.extend(chunk(b"data", &self.data));
+        if !self.info.is_empty() {
+            body.extend(chunk(b"LIST", &info));
+        }
+        chunk(b"RIFF", &body)
+    }
+}
+
+pub fn chunk(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut chunk = id.to_vec();
+    chunk.extend((body.len() as u32).to_le_bytes());
+    chunk.extend(body);
+    if body.len() % 2 == 1 {
+        chunk.push(0);
+    }
+    chunk
+}
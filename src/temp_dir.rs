@@ -0,0 +1,38 @@
+//! Scratch directories for tests to write files into.
+
+use std::fs;
+use std::path::PathBuf;
+
+/// A directory named after the test using it, removed again once dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Create the directory, with each of `files` holding `contents`.
+    ///
+    /// Files can be in subdirectories, which are created along the way.
+    pub fn new(test: &str, files: &[&str], contents: &[u8]) -> Self {
+        let dir = std::env::temp_dir().join(format!("afqueue-{}-{test}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for file in files {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        TempDir(dir)
+    }
+
+    pub fn path(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+
+    /// Path of `file` within the directory.
+    pub fn join(&self, file: &str) -> PathBuf {
+        self.0.join(file)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.0).ok();
+    }
+}
@@ -5,6 +5,18 @@ use std::mem::MaybeUninit;
 
 use crate::ffi::ioctl::{ioctl, WinSize, TIOCGWINSZ};
 use crate::ffi::termios::{self, tcgetattr, tcsetattr, Termios};
+use crate::player::gain_to_decibels;
+use crate::playlist::{Repeat, Shuffle, Track};
+
+mod buffer;
+mod layout;
+mod text;
+#[cfg(test)]
+pub mod vt100;
+
+use self::buffer::{Colour, FrameBuffer, Style};
+use self::layout::{Layout, Pane, PaneSpec, FILL};
+use self::text::{display_width, fit, truncate, truncate_start};
 
 // Terminal escape codes
 const ESCAPE: &str = "\x1b[";
@@ -13,36 +25,63 @@ const AUTOWRAP_DISABLE: &str = "?7l";
 const HIDE_CURSOR: &str = "?25l";
 const SHOW_CURSOR: &str = "?25h";
 const CLEAR_SCREEN: &str = "2J";
-const CLEAR_LINE_REMAINDER: &str = "K";
 const MOVE_CURSOR: &str = "H";
 
-const COLOUR_RED: &str = "0;31m";
-const COLOUR_GREEN: &str = "0;32m";
-const COLOUR_YELLOW: &str = "0;33m";
-const COLOUR_RESET: &str = "0m";
+const METER_CHANNELS: usize = 2;
+// Progress follows the playback state symbol and a space
+const PROGRESS_COLUMN: usize = 3;
 
-const NEW_LINE: &str = "\r\n";
+// Previously played tracks to keep in view above the current one
+const QUEUE_CONTEXT_ROWS: usize = 2;
+const QUEUE_CURRENT_MARKER: &str = "▶ ";
+const QUEUE_OTHER_MARKER: &str = "  ";
 
-const FILENAME_ROW: usize = 1;
-const METER_ROW: usize = 2;
-const STATUS_ROW: usize = 6;
-const VOLUME_ROW: usize = 7;
-const METADATA_ROW: usize = 9;
+const HELP_TITLE: &str = " Key bindings ";
+const HELP_FOOTER: &str = " Press any key to close ";
+const HELP_COLUMN_GAP: usize = 2;
+// Border and a space of padding either side
+const HELP_FRAME_WIDTH: usize = 4;
+const HELP_FRAME_HEIGHT: usize = 2;
 
 //TODO: Colourised meter?
 
-pub struct TerminalUI<'a> {
-    stdout_fd: i32,
-    handle: io::StdoutLock<'a>,
+/// Somewhere to find out the size of the terminal being drawn to.
+pub trait TerminalSize {
+    fn read_size(&self) -> io::Result<WinSize>;
+}
+
+/// A real terminal, put into raw mode for as long as the UI is active.
+pub struct Tty {
+    fd: i32,
     original_termios: Termios,
+}
+
+impl TerminalSize for Tty {
+    fn read_size(&self) -> io::Result<WinSize> {
+        read_term_size(self.fd)
+    }
+}
+
+pub struct TerminalUI<W: Write = io::StdoutLock<'static>, S: TerminalSize = Tty> {
+    handle: W,
+    terminal: S,
     size: WinSize,
+    layout: Layout,
+    queue_scroll: isize,
+    /// Where progress goes, after the playback state
+    progress_column: usize,
+    /// What is being drawn for the next flush
+    frame: FrameBuffer,
+    /// What the terminal is currently showing
+    shown: FrameBuffer,
 }
 
-impl<'a> TerminalUI<'a> {
+impl TerminalUI {
+    /// Take over the terminal attached to stdout.
     pub fn activate() -> io::Result<Self> {
         let stdout = io::stdout();
         //TODO: Use new rust 1.70 feature to assert this is a tty
-        let mut handle = stdout.lock();
+        let handle = stdout.lock();
         let stdout_fd = stdout.as_raw_fd();
 
         let mut termios = read_current_termios(stdout_fd)?;
@@ -51,37 +90,123 @@ impl<'a> TerminalUI<'a> {
 
         set_termios(stdout_fd, &termios)?;
 
-        let size = read_term_size(stdout_fd)?;
+        let tty = Tty {
+            fd: stdout_fd,
+            original_termios,
+        };
+        TerminalUI::new(handle, tty)
+    }
+
+    pub fn deactivate(mut self) -> io::Result<()> {
+        set_termios(self.terminal.fd, &self.terminal.original_termios)?;
+        self.restore_screen()
+    }
+}
+
+impl<W: Write, S: TerminalSize> TerminalUI<W, S> {
+    /// Draw to `handle`, which is expected to be a terminal already in raw
+    /// mode, with its size given by `terminal`.
+    pub fn new(mut handle: W, terminal: S) -> io::Result<Self> {
+        let size = terminal.read_size()?;
+        let frame = FrameBuffer::new(size.ws_row as usize, size.ws_col as usize);
 
         write!(handle, "{ESCAPE}{HIDE_CURSOR}")?;
         write!(handle, "{ESCAPE}{AUTOWRAP_DISABLE}")?;
 
         Ok(TerminalUI {
-            stdout_fd,
             handle,
-            original_termios,
+            terminal,
             size,
+            layout: Layout::default(),
+            queue_scroll: 0,
+            progress_column: PROGRESS_COLUMN,
+            frame,
+            // Nothing is known about what is on screen, so the first flush
+            // redraws everything
+            shown: FrameBuffer::new(0, 0),
         })
     }
 
     pub fn clear_screen(&mut self) -> io::Result<()> {
-        write!(self.handle, "{ESCAPE}{CLEAR_SCREEN}")?;
+        self.frame.clear();
         Ok(())
     }
 
     pub fn update_size(&mut self) -> io::Result<()> {
-        self.size = read_term_size(self.stdout_fd)?;
+        self.size = self.terminal.read_size()?;
+        self.frame = FrameBuffer::new(self.size.ws_row as usize, self.size.ws_col as usize);
         Ok(())
     }
 
+    fn width(&self) -> usize {
+        self.size.ws_col as usize
+    }
+
+    /// Divide up the screen between panes, based on its current size and
+    /// how many rows of metadata there are to show.
+    pub fn update_layout(&mut self, metadata_rows: usize) {
+        let specs = [
+            PaneSpec {
+                pane: Pane::Filename,
+                priority: 0,
+                min_height: 1,
+                preferred_height: 1,
+                gap: 0,
+            },
+            PaneSpec {
+                pane: Pane::Meter,
+                priority: 2,
+                min_height: METER_CHANNELS,
+                preferred_height: METER_CHANNELS,
+                gap: 1,
+            },
+            PaneSpec {
+                pane: Pane::Status,
+                priority: 1,
+                min_height: 1,
+                preferred_height: 1,
+                gap: 1,
+            },
+            PaneSpec {
+                pane: Pane::Volume,
+                priority: 3,
+                min_height: 1,
+                preferred_height: 1,
+                gap: 0,
+            },
+            // Metadata and queue each have a heading, so need at least two rows
+            // to show anything useful
+            PaneSpec {
+                pane: Pane::Metadata,
+                priority: 4,
+                min_height: 2.min(1 + metadata_rows),
+                preferred_height: 1 + metadata_rows,
+                gap: 1,
+            },
+            PaneSpec {
+                pane: Pane::Queue,
+                priority: 5,
+                min_height: 2,
+                preferred_height: FILL,
+                gap: 1,
+            },
+        ];
+        self.layout = Layout::compute(self.size.ws_row as usize, &specs);
+    }
+
     pub fn display_filename(&mut self, filename: &str) -> io::Result<()> {
-        write!(self.handle, "{ESCAPE}{};1{MOVE_CURSOR}", FILENAME_ROW)?;
-        write!(self.handle, "Playing: {}", filename)?;
+        let Some(region) = self.layout.region(Pane::Filename) else {
+            return Ok(());
+        };
+        let line = fit(&format!("Playing: {filename}"), self.width());
+        self.frame.print(region.top, 1, &line, Style::PLAIN);
         Ok(())
     }
 
     pub fn display_meter(&mut self, levels: &[f32; 2]) -> io::Result<()> {
-        write!(self.handle, "{ESCAPE}{};1{MOVE_CURSOR}", METER_ROW)?;
+        let Some(region) = self.layout.region(Pane::Meter) else {
+            return Ok(());
+        };
 
         //TODO: pull into fields
         let max_bar_length = self.size.ws_col as f32;
@@ -89,34 +214,55 @@ impl<'a> TerminalUI<'a> {
         let green_cols = (total_cols * 70) / 100;
         let amber_cols = (total_cols * 15) / 100;
 
-        for channel_power in levels {
+        for (row, channel_power) in levels.iter().enumerate().take(region.height) {
             let bar_length = (max_bar_length * channel_power).round() as usize;
-            write!(self.handle, "{NEW_LINE}")?;
-            write!(self.handle, "{ESCAPE}{COLOUR_GREEN}")?;
+            let row = region.top + row;
             //TODO: Is there a nicer less branchy way to do this... with maths?
-            for n in 0..bar_length {
-                if n == green_cols + 1 {
-                    write!(self.handle, "{ESCAPE}{COLOUR_YELLOW}")?;
-                }
-                if n == green_cols + amber_cols + 1 {
-                    write!(self.handle, "{ESCAPE}{COLOUR_RED}")?;
-                }
-                write!(self.handle, "█")?;
+            for n in 0..bar_length.min(total_cols) {
+                let colour = if n > green_cols + amber_cols {
+                    Colour::Red
+                } else if n > green_cols {
+                    Colour::Yellow
+                } else {
+                    Colour::Green
+                };
+                self.frame.print(row, n + 1, "█", Style::coloured(colour));
             }
-            write!(self.handle, "{ESCAPE}{CLEAR_LINE_REMAINDER}")?;
+            self.frame.clear_to_end(row, bar_length + 1);
         }
-        write!(self.handle, "{ESCAPE}{COLOUR_RESET}")?;
 
         Ok(())
     }
 
-    pub fn display_playback_state(&mut self, paused: bool) -> io::Result<()> {
-        write!(self.handle, "{ESCAPE}{};1{MOVE_CURSOR}", STATUS_ROW)?;
-        if paused {
-            write!(self.handle, "⏸")?;
-        } else {
-            write!(self.handle, "⏵")?;
+    /// Show whether playback is paused, followed by any shuffle or repeat
+    /// mode in use. Progress needs redrawing afterwards, as it moves along to
+    /// make room for the modes.
+    pub fn display_playback_state(
+        &mut self,
+        paused: bool,
+        shuffle: Shuffle,
+        repeat: Repeat,
+    ) -> io::Result<()> {
+        let Some(region) = self.layout.region(Pane::Status) else {
+            return Ok(());
+        };
+        let mut state = String::from(if paused { "⏸" } else { "⏵" });
+        let shuffle = match shuffle {
+            Shuffle::Off => "",
+            Shuffle::Tracks => "🔀",
+            Shuffle::Albums => "💿",
+        };
+        let repeat = match repeat {
+            Repeat::Off => "",
+            Repeat::All => "🔁",
+            Repeat::One => "🔂",
+        };
+        if !shuffle.is_empty() || !repeat.is_empty() {
+            state = format!("{state} {shuffle}{repeat}");
         }
+        // Along with a space before the progress
+        state.push(' ');
+        self.progress_column = self.frame.print(region.top, 1, &state, Style::PLAIN);
         Ok(())
     }
 
@@ -125,43 +271,257 @@ impl<'a> TerminalUI<'a> {
         playback_time: f64,
         total_duration: f64,
     ) -> io::Result<()> {
-        let playback_secs = playback_time % 60.0;
-        let playback_mins = (playback_time / 60.0).floor();
-        let total_secs = total_duration % 60.0;
-        let total_mins = (total_duration / 60.0).floor();
-
-        write!(self.handle, "{ESCAPE}{};3{MOVE_CURSOR}", STATUS_ROW)?;
-        write!(self.handle, "{playback_mins:02.0}:{playback_secs:02.0}")?;
-        write!(self.handle, " / {total_mins:02.0}:{total_secs:02.0}")?;
+        let Some(region) = self.layout.region(Pane::Status) else {
+            return Ok(());
+        };
+        let progress = format!(
+            "{} / {}",
+            format_time(playback_time),
+            format_time(total_duration)
+        );
+        let width = self.width().saturating_sub(self.progress_column - 1);
+        let end = self.frame.print(
+            region.top,
+            self.progress_column,
+            &truncate(&progress, width),
+            Style::PLAIN,
+        );
+        self.frame.clear_to_end(region.top, end);
         Ok(())
     }
 
-    pub fn display_volume(&mut self, volume: f32) -> io::Result<()> {
-        write!(self.handle, "{ESCAPE}{};1{MOVE_CURSOR}", VOLUME_ROW)?;
-        let vol_percent = volume * 100.0;
-        write!(self.handle, "Volume: {vol_percent}%")?;
-        write!(self.handle, "{ESCAPE}{CLEAR_LINE_REMAINDER}")?;
+    /// Show the volume in decibels, along with the gain as a percentage.
+    pub fn display_volume(&mut self, gain: f32, muted: bool) -> io::Result<()> {
+        let Some(region) = self.layout.region(Pane::Volume) else {
+            return Ok(());
+        };
+        let volume = match (muted, gain > 0.0) {
+            (true, _) => "muted".to_string(),
+            (false, true) => {
+                let decibels = gain_to_decibels(gain);
+                let percent = (gain * 100.0).round();
+                format!("{decibels:.1} dB ({percent}%)")
+            }
+            (false, false) => "off".to_string(),
+        };
+        let line = fit(&format!("Volume: {volume}"), self.width());
+        self.frame.print(region.top, 1, &line, Style::PLAIN);
         Ok(())
     }
 
     pub fn display_metadata(&mut self, metadata: &[(String, String)]) -> io::Result<()> {
-        write!(self.handle, "{ESCAPE}{};1{MOVE_CURSOR}", METADATA_ROW)?;
-        write!(self.handle, "Properties:")?;
-        write!(self.handle, "{NEW_LINE}")?;
-        for (k, v) in metadata {
-            write!(self.handle, "{k}: {v}")?;
-            write!(self.handle, "{NEW_LINE}")?;
+        let Some(region) = self.layout.region(Pane::Metadata) else {
+            return Ok(());
+        };
+        let width = self.width();
+        self.frame
+            .print(region.top, 1, &truncate("Properties:", width), Style::PLAIN);
+
+        let rows = region.height - 1;
+        for (row, (k, v)) in metadata.iter().enumerate().take(rows) {
+            // Make it clear when there are more properties than room
+            let line = match row > 0 && row + 1 == rows && metadata.len() > rows {
+                true => "…".to_string(),
+                false => truncate(&format!("{k}: {v}"), width),
+            };
+            self.frame
+                .print(region.top + 1 + row, 1, &line, Style::PLAIN);
+        }
+        Ok(())
+    }
+
+    /// Show a window onto the queue of tracks, with the current one
+    /// highlighted and the selected one underlined. The window follows the
+    /// selection, give or take any scrolling.
+    pub fn display_queue(
+        &mut self,
+        tracks: &[Track],
+        current: usize,
+        selected: usize,
+    ) -> io::Result<()> {
+        let Some(region) = self.layout.region(Pane::Queue) else {
+            return Ok(());
+        };
+        // The first row is taken up by the heading
+        let visible = region.height - 1;
+
+        // Keep a few tracks above the selected one in view, so long as that
+        // leaves room for the selected one itself
+        let context = QUEUE_CONTEXT_ROWS.min(visible.saturating_sub(1));
+        let following = selected.saturating_sub(context) as isize;
+        let last_top = tracks.len().saturating_sub(visible) as isize;
+        let top = (following + self.queue_scroll).clamp(0, last_top);
+        // Don't let scrolling build up past either end
+        self.queue_scroll = top - following;
+        let top = top as usize;
+
+        let width = self.width();
+        let heading = format!("Queue ({}/{}):", current + 1, tracks.len());
+        self.frame
+            .print(region.top, 1, &fit(&heading, width), Style::PLAIN);
+
+        let number_width = tracks.len().to_string().len();
+        let label_width = width.saturating_sub(display_width(QUEUE_CURRENT_MARKER));
+
+        for row in 0..visible {
+            let screen_row = region.top + 1 + row;
+            let index = top + row;
+            let Some(track) = tracks.get(index) else {
+                self.frame.clear_to_end(screen_row, 1);
+                continue;
+            };
+            let name = format!("{:>number_width$}. {}", index + 1, track.name());
+            // Durations go against the right hand edge, if there is room
+            let label = match track.duration.map(format_time) {
+                Some(duration) if label_width > display_width(&duration) + 1 => {
+                    let name_width = label_width - display_width(&duration) - 1;
+                    format!("{} {duration}", fit(&name, name_width))
+                }
+                _ => fit(&name, label_width),
+            };
+
+            let (marker, mut style) = if index == current {
+                let style = Style {
+                    reverse: true,
+                    ..Style::PLAIN
+                };
+                (QUEUE_CURRENT_MARKER, style)
+            } else if index < current {
+                let style = Style {
+                    dim: true,
+                    ..Style::PLAIN
+                };
+                (QUEUE_OTHER_MARKER, style)
+            } else {
+                (QUEUE_OTHER_MARKER, Style::PLAIN)
+            };
+            style.underline = index == selected;
+            let col = self.frame.print(screen_row, 1, marker, Style::PLAIN);
+            self.frame.print(screen_row, col, &label, style);
+        }
+        Ok(())
+    }
+
+    /// Take over the bottom row to ask for some text, with a cursor after
+    /// what has been typed so far.
+    pub fn display_prompt(&mut self, label: &str, text: &str) -> io::Result<()> {
+        let row = self.size.ws_row as usize;
+        let width = self.width();
+        if row == 0 || width == 0 {
+            return Ok(());
+        }
+        let col = self
+            .frame
+            .print(row, 1, &truncate(label, width), Style::PLAIN);
+        // Keep the end in view, as that is where typing happens
+        let room = width.saturating_sub(col);
+        let col = self
+            .frame
+            .print(row, col, &truncate_start(text, room), Style::PLAIN);
+        let cursor = Style {
+            reverse: true,
+            ..Style::PLAIN
+        };
+        let end = self.frame.print(row, col, " ", cursor);
+        self.frame.clear_to_end(row, end);
+        Ok(())
+    }
+
+    /// Take over the bottom row to say something.
+    pub fn display_notice(&mut self, notice: &str) -> io::Result<()> {
+        let row = self.size.ws_row as usize;
+        if row == 0 {
+            return Ok(());
+        }
+        let line = fit(notice, self.width());
+        self.frame.print(row, 1, &line, Style::PLAIN);
+        Ok(())
+    }
+
+    /// Move the queue window by a number of pages, negative being up.
+    pub fn scroll_queue(&mut self, pages: isize) {
+        let visible = match self.layout.region(Pane::Queue) {
+            Some(region) => region.height - 1,
+            None => 0,
+        };
+        self.queue_scroll += pages * visible.max(1) as isize;
+    }
+
+    /// Go back to having the queue follow the selected track.
+    pub fn reset_queue_scroll(&mut self) {
+        self.queue_scroll = 0;
+    }
+
+    /// Draw a box over the middle of the screen listing key bindings, as pairs
+    /// of keys and what they do. Anything that doesn't fit is cut short.
+    pub fn display_help(&mut self, bindings: &[(String, String)]) -> io::Result<()> {
+        let cols = self.size.ws_col as usize;
+        let rows = self.size.ws_row as usize;
+        if cols <= HELP_FRAME_WIDTH || rows <= HELP_FRAME_HEIGHT {
+            return Ok(());
         }
+
+        let keys_width = bindings
+            .iter()
+            .map(|(keys, _)| display_width(keys))
+            .max()
+            .unwrap_or(0);
+        let mut lines: Vec<String> = bindings
+            .iter()
+            .map(|(keys, action)| {
+                let padding = keys_width - display_width(keys) + HELP_COLUMN_GAP;
+                format!("{keys}{:padding$}{action}", "")
+            })
+            .collect();
+
+        let content_width = lines
+            .iter()
+            .map(|line| display_width(line))
+            .chain([display_width(HELP_TITLE), display_width(HELP_FOOTER)])
+            .max()
+            .unwrap_or(0);
+        let inner_width = content_width.min(cols - HELP_FRAME_WIDTH);
+        let width = inner_width + HELP_FRAME_WIDTH;
+
+        let visible = lines.len().min(rows - HELP_FRAME_HEIGHT);
+        if visible < lines.len() {
+            lines.truncate(visible);
+            if let Some(last) = lines.last_mut() {
+                *last = "…".to_string();
+            }
+        }
+        let height = visible + HELP_FRAME_HEIGHT;
+
+        let top = (rows - height) / 2 + 1;
+        let left = (cols - width) / 2 + 1;
+        let border = "─".repeat(width - 2);
+
+        let title = truncate(HELP_TITLE, width - 2);
+        let top_line = format!("┌{}┐", overlay(&border, &title));
+        self.frame.print(top, left, &top_line, Style::PLAIN);
+
+        for (row, line) in lines.iter().enumerate() {
+            let line = format!("│ {} │", fit(line, inner_width));
+            self.frame.print(top + row + 1, left, &line, Style::PLAIN);
+        }
+
+        let footer = truncate(HELP_FOOTER, width - 2);
+        let bottom_line = format!("└{}┘", overlay(&border, &footer));
+        self.frame
+            .print(top + height - 1, left, &bottom_line, Style::PLAIN);
         Ok(())
     }
 
+    /// Send whatever has changed since the last flush to the terminal.
     pub fn flush(&mut self) -> io::Result<()> {
+        self.frame.render_diff(&self.shown, &mut self.handle)?;
+        self.shown.clone_from(&self.frame);
         self.handle.flush()?;
         Ok(())
     }
 
-    pub fn deactivate(mut self) -> io::Result<()> {
-        set_termios(self.stdout_fd, &self.original_termios)?;
+    /// Put the screen back how it was found.
+    fn restore_screen(&mut self) -> io::Result<()> {
         write!(self.handle, "{ESCAPE}{CLEAR_SCREEN}")?;
         write!(self.handle, "{ESCAPE}1;1{MOVE_CURSOR}")?;
         write!(self.handle, "{ESCAPE}{SHOW_CURSOR}")?;
@@ -171,6 +531,26 @@ impl<'a> TerminalUI<'a> {
     }
 }
 
+/// Format a number of seconds as minutes and seconds.
+fn format_time(seconds: f64) -> String {
+    let secs = seconds % 60.0;
+    let mins = (seconds / 60.0).floor();
+    format!("{mins:02.0}:{secs:02.0}")
+}
+
+/// Write `label` over the middle of `line`, keeping its overall length.
+fn overlay(line: &str, label: &str) -> String {
+    let line: Vec<char> = line.chars().collect();
+    let label_length = label.chars().count().min(line.len());
+    let start = (line.len() - label_length) / 2;
+    line[..start]
+        .iter()
+        .copied()
+        .chain(label.chars().take(label_length))
+        .chain(line[start + label_length..].iter().copied())
+        .collect()
+}
+
 fn read_current_termios(file_descriptor: i32) -> io::Result<Termios> {
     unsafe {
         let mut termios = MaybeUninit::uninit();
@@ -236,3 +616,426 @@ fn enable_raw_mode(termios: &mut Termios) {
     // setting up "raw terminal output". However, this aleady seems to be
     // the case for Terminal.app
 }
+
+#[cfg(test)]
+mod tests {
+    use super::vt100::{Attributes, VirtualTerminal};
+    use super::*;
+
+    const GREEN: Option<u8> = Some(32);
+    const YELLOW: Option<u8> = Some(33);
+    const RED: Option<u8> = Some(31);
+
+    type VirtualUI = TerminalUI<VirtualTerminal, VirtualTerminal>;
+
+    fn activate(rows: usize, cols: usize) -> (VirtualUI, VirtualTerminal) {
+        let terminal = VirtualTerminal::new(rows, cols);
+        let ui = TerminalUI::new(terminal.clone(), terminal.clone()).unwrap();
+        (ui, terminal)
+    }
+
+    fn metadata(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|&(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn queue(names: &[&str]) -> Vec<Track> {
+        names
+            .iter()
+            .map(|name| Track::new(format!("/music/{name}")))
+            .collect()
+    }
+
+    /// Draw a whole screen, the same way the boombox does.
+    fn redraw<W: Write, S: TerminalSize>(
+        ui: &mut TerminalUI<W, S>,
+        levels: [f32; 2],
+        metadata: &[(String, String)],
+        tracks: &[Track],
+        current: usize,
+    ) {
+        ui.update_layout(metadata.len());
+        ui.clear_screen().unwrap();
+        ui.display_filename(&tracks[current].path).unwrap();
+        ui.display_meter(&levels).unwrap();
+        ui.display_playback_state(false, Shuffle::Off, Repeat::Off)
+            .unwrap();
+        ui.display_playback_progress(75.0, 200.0).unwrap();
+        ui.display_volume(0.5, false).unwrap();
+        ui.display_metadata(metadata).unwrap();
+        ui.display_queue(tracks, current, current).unwrap();
+        ui.flush().unwrap();
+    }
+
+    #[test]
+    fn full_screen() {
+        let (mut ui, terminal) = activate(16, 30);
+        let metadata = metadata(&[("Artist", "Someone"), ("Title", "Something")]);
+        let tracks = queue(&["one.flac", "two.flac", "three.flac"]);
+        redraw(&mut ui, [0.5, 0.2], &metadata, &tracks, 1);
+        assert_eq!(
+            terminal.lines(),
+            [
+                "Playing: /music/two.flac",
+                "",
+                "███████████████",
+                "██████",
+                "",
+                "⏵ 01:15 / 03:20",
+                "Volume: -6.0 dB (50%)",
+                "",
+                "Properties:",
+                "Artist: Someone",
+                "Title: Something",
+                "",
+                "Queue (2/3):",
+                "  1. one.flac",
+                "▶ 2. two.flac",
+                "  3. three.flac",
+            ]
+        );
+        assert!(!terminal.cursor_visible());
+    }
+
+    #[test]
+    fn meter_changes_colour_towards_the_top() {
+        let (mut ui, terminal) = activate(10, 20);
+        redraw(&mut ui, [1.0, 0.25], &[], &queue(&["a.wav"]), 0);
+        assert_eq!(terminal.lines()[2..4], ["████████████████████", "█████"]);
+
+        let colours: Vec<Option<u8>> = (1..=20)
+            .map(|col| terminal.attributes(3, col).foreground)
+            .collect();
+        let mut expected = vec![GREEN; 15];
+        expected.extend([YELLOW; 3]);
+        expected.extend([RED; 2]);
+        assert_eq!(colours, expected);
+        assert_eq!(terminal.attributes(4, 6), Attributes::default());
+    }
+
+    #[test]
+    fn meter_shrinks() {
+        let (mut ui, terminal) = activate(10, 20);
+        redraw(&mut ui, [1.0, 1.0], &[], &queue(&["a.wav"]), 0);
+        ui.display_meter(&[0.5, 0.0]).unwrap();
+        ui.flush().unwrap();
+        assert_eq!(terminal.lines()[2..4], ["██████████", ""]);
+    }
+
+    #[test]
+    fn progress_and_volume() {
+        let (mut ui, terminal) = activate(10, 30);
+        redraw(&mut ui, [0.0, 0.0], &[], &queue(&["a.wav"]), 0);
+        ui.display_playback_state(true, Shuffle::Off, Repeat::Off)
+            .unwrap();
+        ui.display_playback_progress(3725.0, 4000.0).unwrap();
+        ui.display_volume(0.25, false).unwrap();
+        ui.flush().unwrap();
+        assert_eq!(
+            terminal.lines()[4..7],
+            ["", "⏸ 62:05 / 66:40", "Volume: -12.0 dB (25%)"]
+        );
+
+        ui.display_volume(0.25, true).unwrap();
+        ui.flush().unwrap();
+        assert_eq!(terminal.lines()[6], "Volume: muted");
+        ui.display_volume(0.0, false).unwrap();
+        ui.flush().unwrap();
+        assert_eq!(terminal.lines()[6], "Volume: off");
+    }
+
+    #[test]
+    fn progress_is_cut_short_in_narrow_terminal() {
+        let (mut ui, terminal) = activate(10, 10);
+        redraw(&mut ui, [0.0, 0.0], &[], &queue(&["a.wav"]), 0);
+        assert_eq!(terminal.lines()[4..7], ["", "⏵ 01:15 /…", "Volume: -…"]);
+    }
+
+    #[test]
+    fn metadata_that_does_not_fit() {
+        let (mut ui, terminal) = activate(10, 20);
+        let metadata = metadata(&[
+            ("Artist", "Someone with a long name"),
+            ("Album", "Something"),
+            ("Title", "Something else"),
+            ("Year", "2001"),
+        ]);
+        redraw(&mut ui, [0.0, 0.0], &metadata, &queue(&["a.wav"]), 0);
+        assert_eq!(
+            terminal.lines(),
+            [
+                "Playing: /music/a.w…",
+                "",
+                "",
+                "⏵ 01:15 / 03:20",
+                "Volume: -6.0 dB (50…",
+                "Properties:",
+                "Artist: Someone wit…",
+                "…",
+                "Queue (1/1):",
+                "▶ 1. a.wav",
+            ]
+        );
+    }
+
+    #[test]
+    fn wide_characters_are_truncated_by_width() {
+        let (mut ui, terminal) = activate(10, 20);
+        redraw(
+            &mut ui,
+            [0.0, 0.0],
+            &[],
+            &queue(&["日本語のタイトル.flac"]),
+            0,
+        );
+        assert_eq!(terminal.lines()[0], "Playing: /music/日…");
+    }
+
+    #[test]
+    fn queue_shows_titles_and_durations() {
+        let (mut ui, terminal) = activate(14, 24);
+        let mut tracks = queue(&["one.flac", "two.flac", "three.flac"]);
+        tracks[0].title = Some("The First".to_string());
+        tracks[0].duration = Some(185.0);
+        tracks[1].title = Some("A Second With A Long Title".to_string());
+        tracks[1].duration = Some(3725.0);
+        redraw(&mut ui, [0.0, 0.0], &[], &tracks, 0);
+        assert_eq!(
+            terminal.lines()[10..],
+            [
+                "Queue (1/3):",
+                "▶ 1. The First     03:05",
+                "  2. A Second Wit… 62:05",
+                "  3. three.flac",
+            ]
+        );
+    }
+
+    #[test]
+    fn selection_is_underlined_and_followed() {
+        let (mut ui, terminal) = activate(14, 20);
+        let names: Vec<String> = (1..=9).map(|n| format!("{n}.flac")).collect();
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        let tracks = queue(&names);
+        redraw(&mut ui, [0.0, 0.0], &[], &tracks, 0);
+        ui.display_queue(&tracks, 0, 7).unwrap();
+        ui.flush().unwrap();
+
+        assert_eq!(
+            terminal.lines()[10..],
+            ["Queue (1/9):", "  6. 6.flac", "  7. 7.flac", "  8. 8.flac"]
+        );
+        assert!(terminal.attributes(14, 3).underline);
+        assert!(!terminal.attributes(13, 3).underline);
+    }
+
+    #[test]
+    fn prompt_keeps_the_end_in_view() {
+        let (mut ui, terminal) = activate(10, 20);
+        redraw(&mut ui, [0.0, 0.0], &[], &queue(&["a.wav"]), 0);
+        ui.display_prompt("Add: ", "/music/Someone/Album").unwrap();
+        ui.flush().unwrap();
+
+        assert_eq!(terminal.lines()[9], "Add: …Someone/Album");
+        assert!(terminal.attributes(10, 20).reverse);
+
+        ui.display_notice("Added 3 tracks").unwrap();
+        ui.flush().unwrap();
+        assert_eq!(terminal.lines()[9], "Added 3 tracks");
+    }
+
+    #[test]
+    fn short_terminal() {
+        let (mut ui, terminal) = activate(4, 20);
+        let metadata = metadata(&[("Artist", "Someone")]);
+        redraw(&mut ui, [0.5, 0.5], &metadata, &queue(&["a.wav"]), 0);
+        assert_eq!(
+            terminal.lines(),
+            [
+                "Playing: /music/a.w…",
+                "██████████",
+                "██████████",
+                "⏵ 01:15 / 03:20",
+            ]
+        );
+    }
+
+    #[test]
+    fn tiny_terminal() {
+        let (mut ui, terminal) = activate(2, 12);
+        let metadata = metadata(&[("Artist", "Someone")]);
+        redraw(&mut ui, [0.5, 0.5], &metadata, &queue(&["a.wav"]), 0);
+        assert_eq!(terminal.lines(), ["Playing: /m…", "⏵ 01:15 / 0…"]);
+    }
+
+    #[test]
+    fn help_overlay() {
+        let (mut ui, terminal) = activate(10, 30);
+        redraw(&mut ui, [0.0, 0.0], &[], &queue(&["a.wav"]), 0);
+        let bindings = [
+            ("n".to_string(), "Skip to next track".to_string()),
+            ("q".to_string(), "Quit".to_string()),
+        ];
+        ui.display_help(&bindings).unwrap();
+        ui.flush().unwrap();
+        assert_eq!(
+            terminal.lines(),
+            [
+                "Playing: /music/a.wav",
+                "",
+                "",
+                " ┌────── Key bindings ──────┐",
+                " │ n  Skip to next track    │",
+                "⏵│ q  Quit                  │",
+                "V└─ Press any key to close ─┘",
+                "Properties:",
+                "Queue (1/1):",
+                "▶ 1. a.wav",
+            ]
+        );
+    }
+
+    #[test]
+    fn help_overlay_is_cut_short_to_fit() {
+        let (mut ui, terminal) = activate(6, 18);
+        let bindings: Vec<(String, String)> = ["n", "b", "r", "p", "q"]
+            .iter()
+            .map(|key| (key.to_string(), "Something long to do".to_string()))
+            .collect();
+        ui.display_help(&bindings).unwrap();
+        ui.flush().unwrap();
+        assert_eq!(
+            terminal.lines(),
+            [
+                "┌─ Key bindings ─┐",
+                "│ n  Something … │",
+                "│ b  Something … │",
+                "│ r  Something … │",
+                "│ …              │",
+                "└ Press any key …┘",
+            ]
+        );
+
+        // Without room for the frame there is nothing to show
+        let (mut ui, terminal) = activate(6, 4);
+        ui.display_help(&bindings).unwrap();
+        ui.flush().unwrap();
+        assert_eq!(terminal.lines(), ["", "", "", "", "", ""]);
+    }
+
+    #[test]
+    fn queue_follows_the_current_track_as_it_advances() {
+        let (mut ui, terminal) = activate(14, 20);
+        let names: Vec<String> = (1..=9).map(|n| format!("{n}.flac")).collect();
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        let tracks = queue(&names);
+        let mut window = |current| {
+            redraw(&mut ui, [0.0, 0.0], &[], &tracks, current);
+            terminal.lines()[11..].to_vec()
+        };
+
+        assert_eq!(window(0), ["▶ 1. 1.flac", "  2. 2.flac", "  3. 3.flac"]);
+        // Played tracks stay in view above the current one
+        assert_eq!(window(1), ["  1. 1.flac", "▶ 2. 2.flac", "  3. 3.flac"]);
+        assert_eq!(window(4), ["  3. 3.flac", "  4. 4.flac", "▶ 5. 5.flac"]);
+        // Up against the end there is nothing more to show
+        assert_eq!(window(8), ["  7. 7.flac", "  8. 8.flac", "▶ 9. 9.flac"]);
+        assert!(terminal.attributes(14, 3).reverse);
+        assert!(terminal.attributes(13, 3).dim);
+    }
+
+    #[test]
+    fn queue_scrolls_by_pages_as_far_as_either_end() {
+        let (mut ui, terminal) = activate(14, 20);
+        let names: Vec<String> = (1..=9).map(|n| format!("{n}.flac")).collect();
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        let tracks = queue(&names);
+        redraw(&mut ui, [0.0, 0.0], &[], &tracks, 0);
+        let mut scroll = |pages| {
+            ui.scroll_queue(pages);
+            ui.display_queue(&tracks, 0, 0).unwrap();
+            ui.flush().unwrap();
+            terminal.lines()[11].clone()
+        };
+
+        assert_eq!(scroll(1), "  4. 4.flac");
+        assert_eq!(scroll(5), "  7. 7.flac");
+        // Scrolling past the end doesn't have to be undone before going back
+        assert_eq!(scroll(-1), "  4. 4.flac");
+        assert_eq!(scroll(-5), "▶ 1. 1.flac");
+        assert_eq!(scroll(1), "  4. 4.flac");
+
+        ui.reset_queue_scroll();
+        ui.display_queue(&tracks, 0, 0).unwrap();
+        ui.flush().unwrap();
+        assert_eq!(terminal.lines()[11], "▶ 1. 1.flac");
+    }
+
+    #[test]
+    fn queue_fits_what_room_is_left() {
+        let names: Vec<String> = (1..=9).map(|n| format!("{n}.flac")).collect();
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        let tracks = queue(&names);
+
+        // Room for the heading and only the current track
+        let (mut ui, terminal) = activate(12, 20);
+        redraw(&mut ui, [0.0, 0.0], &[], &tracks, 4);
+        assert_eq!(terminal.lines()[10..], ["Queue (5/9):", "▶ 5. 5.flac"]);
+
+        // Not even that once the gaps between panes have gone, so the queue
+        // goes
+        let (mut ui, terminal) = activate(7, 20);
+        redraw(&mut ui, [0.0, 0.0], &[], &tracks, 4);
+        assert!(!terminal
+            .lines()
+            .iter()
+            .any(|line| line.starts_with("Queue")));
+
+        // A short queue leaves the rest of the pane empty
+        let (mut ui, terminal) = activate(14, 20);
+        redraw(&mut ui, [0.0, 0.0], &[], &tracks[..2], 1);
+        assert_eq!(
+            terminal.lines()[10..],
+            ["Queue (2/2):", "  1. 1.flac", "▶ 2. 2.flac", ""]
+        );
+    }
+
+    #[test]
+    fn unchanged_screen_is_not_sent_again() {
+        let (mut ui, terminal) = activate(16, 30);
+        let metadata = metadata(&[("Artist", "Someone")]);
+        let tracks = queue(&["one.flac", "two.flac"]);
+        redraw(&mut ui, [0.5, 0.5], &metadata, &tracks, 0);
+
+        let before = terminal.bytes_written();
+        redraw(&mut ui, [0.5, 0.5], &metadata, &tracks, 0);
+        assert_eq!(terminal.bytes_written(), before);
+    }
+
+    #[test]
+    fn resizing_redraws_everything() {
+        let (mut ui, terminal) = activate(16, 30);
+        let metadata = metadata(&[("Artist", "Someone")]);
+        let tracks = queue(&["one.flac", "two.flac"]);
+        redraw(&mut ui, [0.5, 0.5], &metadata, &tracks, 0);
+
+        terminal.resize(8, 20);
+        ui.update_size().unwrap();
+        redraw(&mut ui, [0.5, 0.5], &metadata, &tracks, 0);
+        assert_eq!(
+            terminal.lines(),
+            [
+                "Playing: /music/one…",
+                "██████████",
+                "██████████",
+                "",
+                "⏵ 01:15 / 03:20",
+                "Volume: -6.0 dB (50…",
+                "Properties:",
+                "Artist: Someone",
+            ]
+        );
+    }
+}
@@ -0,0 +1,319 @@
+//! Off-screen grid of cells that the UI is drawn into.
+//!
+//! Rather than writing straight to the terminal, each frame is drawn into a
+//! back buffer, which is then compared with what the terminal is already
+//! showing. Only cells that differ are sent, which avoids flicker and keeps
+//! the amount of output small when little has changed.
+
+use std::io::{self, Write};
+
+use super::text::char_width;
+use super::{CLEAR_SCREEN, ESCAPE, MOVE_CURSOR};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Colour {
+    #[default]
+    Default,
+    Red,
+    Green,
+    Yellow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Style {
+    pub colour: Colour,
+    pub dim: bool,
+    pub reverse: bool,
+    pub underline: bool,
+}
+
+impl Style {
+    pub const PLAIN: Style = Style {
+        colour: Colour::Default,
+        dim: false,
+        reverse: false,
+        underline: false,
+    };
+
+    pub fn coloured(colour: Colour) -> Self {
+        Style {
+            colour,
+            ..Style::PLAIN
+        }
+    }
+
+    /// Select graphic rendition sequence, resetting any previous style.
+    fn write_sgr(&self, out: &mut impl Write) -> io::Result<()> {
+        write!(out, "{ESCAPE}0")?;
+        match self.colour {
+            Colour::Default => {}
+            Colour::Red => write!(out, ";31")?,
+            Colour::Green => write!(out, ";32")?,
+            Colour::Yellow => write!(out, ";33")?,
+        }
+        if self.dim {
+            write!(out, ";2")?;
+        }
+        if self.reverse {
+            write!(out, ";7")?;
+        }
+        if self.underline {
+            write!(out, ";4")?;
+        }
+        write!(out, "m")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Glyph {
+    Char(char),
+    /// Right hand half of a double width character in the cell to the left
+    Continuation,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cell {
+    pub glyph: Glyph,
+    pub style: Style,
+}
+
+impl Cell {
+    const BLANK: Cell = Cell {
+        glyph: Glyph::Char(' '),
+        style: Style::PLAIN,
+    };
+}
+
+/// A grid of cells, addressed by row and column numbered from 1 like the
+/// terminal itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameBuffer {
+    rows: usize,
+    cols: usize,
+    cells: Vec<Cell>,
+}
+
+impl FrameBuffer {
+    pub fn new(rows: usize, cols: usize) -> Self {
+        FrameBuffer {
+            rows,
+            cols,
+            cells: vec![Cell::BLANK; rows * cols],
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.cells.fill(Cell::BLANK);
+    }
+
+    fn index(&self, row: usize, col: usize) -> Option<usize> {
+        let in_bounds = (1..=self.rows).contains(&row) && (1..=self.cols).contains(&col);
+        in_bounds.then(|| (row - 1) * self.cols + (col - 1))
+    }
+
+    /// Write `text` starting at the given position, clipping anything that
+    /// runs off the right hand edge. Returns the column after the text.
+    pub fn print(&mut self, row: usize, col: usize, text: &str, style: Style) -> usize {
+        let mut col = col;
+        for c in text.chars() {
+            let width = char_width(c);
+            // Zero width characters have no cell of their own, so are dropped
+            if width == 0 {
+                continue;
+            }
+            // Don't leave half of a wide character hanging off the edge
+            if col + width - 1 > self.cols {
+                break;
+            }
+            self.set(row, col, Glyph::Char(c), style);
+            if width == 2 {
+                self.set(row, col + 1, Glyph::Continuation, style);
+            }
+            col += width;
+        }
+        col
+    }
+
+    /// Blank out the rest of a row, from `col` onwards.
+    pub fn clear_to_end(&mut self, row: usize, col: usize) {
+        for col in col..=self.cols {
+            self.set(row, col, Glyph::Char(' '), Style::PLAIN);
+        }
+    }
+
+    fn set(&mut self, row: usize, col: usize, glyph: Glyph, style: Style) {
+        let Some(index) = self.index(row, col) else {
+            return;
+        };
+
+        // Overwriting either half of a wide character breaks the other half
+        match self.cells[index].glyph {
+            Glyph::Continuation if glyph != Glyph::Continuation => {
+                if let Some(left) = self.index(row, col - 1) {
+                    self.cells[left].glyph = Glyph::Char(' ');
+                }
+            }
+            Glyph::Char(c) if char_width(c) == 2 => {
+                if let Some(right) = self.index(row, col + 1) {
+                    self.cells[right].glyph = Glyph::Char(' ');
+                }
+            }
+            _ => {}
+        }
+
+        self.cells[index] = Cell { glyph, style };
+    }
+
+    /// Write whatever is needed to turn the terminal from showing `shown` to
+    /// showing this buffer. If `shown` is a different size, for example after
+    /// the terminal has been resized, the whole screen is redrawn.
+    pub fn render_diff(&self, shown: &FrameBuffer, out: &mut impl Write) -> io::Result<()> {
+        let blank;
+        let shown = if shown.rows != self.rows || shown.cols != self.cols {
+            Style::PLAIN.write_sgr(out)?;
+            write!(out, "{ESCAPE}{CLEAR_SCREEN}")?;
+            blank = FrameBuffer::new(self.rows, self.cols);
+            &blank
+        } else {
+            shown
+        };
+
+        let mut cursor = None;
+        let mut style = None;
+        for row in 1..=self.rows {
+            for col in 1..=self.cols {
+                let index = (row - 1) * self.cols + (col - 1);
+                let cell = self.cells[index];
+                let Glyph::Char(c) = cell.glyph else {
+                    // Drawn along with the character to its left
+                    continue;
+                };
+                if cell == shown.cells[index] && !self.wide_neighbour_changed(shown, index) {
+                    continue;
+                }
+
+                if cursor != Some((row, col)) {
+                    write!(out, "{ESCAPE}{row};{col}{MOVE_CURSOR}")?;
+                }
+                if style != Some(cell.style) {
+                    cell.style.write_sgr(out)?;
+                    style = Some(cell.style);
+                }
+                write!(out, "{c}")?;
+
+                // The cursor stays put after writing to the last column, so
+                // where it ends up is only known for sure before that.
+                let next = col + char_width(c).max(1);
+                cursor = (next <= self.cols).then_some((row, next));
+            }
+        }
+
+        if style.is_some_and(|style| style != Style::PLAIN) {
+            Style::PLAIN.write_sgr(out)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the right hand half of a wide character at `index` has changed,
+    /// meaning the whole character needs to be drawn again.
+    fn wide_neighbour_changed(&self, shown: &FrameBuffer, index: usize) -> bool {
+        self.cells[index].glyph != Glyph::Continuation
+            && self.cells.get(index + 1).map(|cell| cell.glyph) == Some(Glyph::Continuation)
+            && self.cells[index + 1] != shown.cells[index + 1]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: Style = Style {
+        colour: Colour::Red,
+        ..Style::PLAIN
+    };
+
+    fn diff(next: &FrameBuffer, shown: &FrameBuffer) -> String {
+        let mut out = Vec::new();
+        next.render_diff(shown, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn printed(rows: usize, cols: usize, lines: &[(usize, usize, &str)]) -> FrameBuffer {
+        let mut buffer = FrameBuffer::new(rows, cols);
+        for &(row, col, text) in lines {
+            buffer.print(row, col, text, Style::PLAIN);
+        }
+        buffer
+    }
+
+    #[test]
+    fn only_changed_cells_are_sent() {
+        let shown = printed(2, 6, &[(1, 1, "hello"), (2, 1, "world")]);
+        assert_eq!(diff(&shown, &shown), "");
+
+        let mut next = shown.clone();
+        next.print(1, 4, "p", Style::PLAIN);
+        next.clear_to_end(1, 5);
+        // Neighbouring cells go out together, without moving the cursor
+        assert_eq!(diff(&next, &shown), "\x1b[1;4H\x1b[0mp ");
+
+        let mut next = shown.clone();
+        next.print(1, 1, "h", RED);
+        next.print(2, 5, "D", Style::PLAIN);
+        assert_eq!(diff(&next, &shown), "\x1b[1;1H\x1b[0;31mh\x1b[2;5H\x1b[0mD");
+    }
+
+    #[test]
+    fn style_is_reset_after_drawing() {
+        let shown = FrameBuffer::new(1, 4);
+        let mut next = shown.clone();
+        next.print(1, 2, "ab", RED);
+        assert_eq!(diff(&next, &shown), "\x1b[1;2H\x1b[0;31mab\x1b[0m");
+    }
+
+    #[test]
+    fn overwriting_half_of_a_wide_character_blanks_the_other() {
+        let shown = printed(1, 6, &[(1, 1, "日本語")]);
+
+        // The right half of 日, and the left half of 語
+        let mut next = shown.clone();
+        next.print(1, 2, "a", Style::PLAIN);
+        next.print(1, 5, "b", Style::PLAIN);
+        assert_eq!(diff(&next, &shown), "\x1b[1;1H\x1b[0m a\x1b[1;5Hb ");
+
+        // A wide character is sent whole, even where only its style changed
+        let mut next = shown.clone();
+        next.print(1, 3, "本", RED);
+        assert_eq!(diff(&next, &shown), "\x1b[1;3H\x1b[0;31m本\x1b[0m");
+    }
+
+    #[test]
+    fn resizing_redraws_everything() {
+        let shown = printed(1, 3, &[(1, 1, "abc")]);
+        let next = printed(2, 3, &[(1, 1, "abc"), (2, 2, "d")]);
+        assert_eq!(
+            diff(&next, &shown),
+            "\x1b[0m\x1b[2J\x1b[1;1H\x1b[0mabc\x1b[2;2Hd"
+        );
+    }
+
+    #[test]
+    fn drawing_is_clipped_at_the_edges() {
+        let mut buffer = FrameBuffer::new(2, 4);
+        assert_eq!(buffer.print(1, 3, "abc", Style::PLAIN), 5);
+        // Half a wide character would hang off the edge, so none is drawn
+        assert_eq!(buffer.print(2, 4, "日", Style::PLAIN), 4);
+        // Off the bottom, and the right
+        buffer.print(3, 1, "e", Style::PLAIN);
+        buffer.print(2, 5, "f", Style::PLAIN);
+        buffer.clear_to_end(2, 9);
+
+        assert_eq!(buffer, printed(2, 4, &[(1, 3, "ab")]));
+        // The cursor stays on the last column, so is moved to the next row
+        let next = printed(2, 4, &[(1, 3, "ab"), (2, 1, "c")]);
+        assert_eq!(
+            diff(&next, &FrameBuffer::new(2, 4)),
+            "\x1b[1;3H\x1b[0mab\x1b[2;1Hc"
+        );
+    }
+}
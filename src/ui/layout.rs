@@ -0,0 +1,202 @@
+//! Allocation of screen rows to the panes making up the UI.
+//!
+//! Every pane has a minimum height it needs to be useful, and a preferred
+//! height it would like if there is room. Panes are considered in priority
+//! order, so when the terminal is too short it is the least important panes
+//! that get squeezed, and then dropped altogether. Whatever is left over goes
+//! towards letting panes grow to fit their content, then to blank rows
+//! separating panes, and finally to panes that fill the screen.
+
+/// A part of the screen with a job of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pane {
+    Filename,
+    Meter,
+    Status,
+    Volume,
+    Metadata,
+    Queue,
+}
+
+const PANE_COUNT: usize = 6;
+
+/// Preferred height of a pane that would like to take up all remaining room.
+pub const FILL: usize = usize::MAX;
+
+/// A horizontal strip of the screen, with rows numbered from 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub top: usize,
+    pub height: usize,
+}
+
+/// Room a pane needs to display its content.
+#[derive(Debug, Clone, Copy)]
+pub struct PaneSpec {
+    pub pane: Pane,
+    /// Lower is more important
+    pub priority: u8,
+    pub min_height: usize,
+    /// Use `FILL` to take up all remaining room
+    pub preferred_height: usize,
+    /// Blank rows to leave above the pane, if there is room
+    pub gap: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct Layout {
+    regions: [Option<Region>; PANE_COUNT],
+}
+
+impl Layout {
+    /// Fit panes into `rows`, with `specs` given in the order they appear on
+    /// screen from top to bottom.
+    pub fn compute(rows: usize, specs: &[PaneSpec]) -> Self {
+        let mut by_priority: Vec<usize> = (0..specs.len()).collect();
+        by_priority.sort_by_key(|&index| specs[index].priority);
+
+        // Only include panes while there is enough room for all of them
+        let mut heights = vec![None; specs.len()];
+        let mut remaining = rows;
+        for &index in &by_priority {
+            let min_height = specs[index].min_height;
+            if min_height <= remaining {
+                heights[index] = Some(min_height);
+                remaining -= min_height;
+            }
+        }
+
+        // Then let them grow to show all of their content, before spacing them
+        // out, other than above the first pane shown. Only then can panes that
+        // fill the screen take what's left.
+        grow(specs, &by_priority, &mut heights, &mut remaining, false);
+
+        let first_shown = heights.iter().position(Option::is_some);
+        let mut gaps = vec![0; specs.len()];
+        for &index in &by_priority {
+            let gap = specs[index].gap;
+            if heights[index].is_some() && Some(index) != first_shown && gap <= remaining {
+                gaps[index] = gap;
+                remaining -= gap;
+            }
+        }
+
+        grow(specs, &by_priority, &mut heights, &mut remaining, true);
+
+        let mut layout = Layout::default();
+        let mut next_row = 1;
+        for (index, spec) in specs.iter().enumerate() {
+            if let Some(height) = heights[index] {
+                let top = next_row + gaps[index];
+                layout.regions[spec.pane as usize] = Some(Region { top, height });
+                next_row = top + height;
+            }
+        }
+        layout
+    }
+
+    /// Where `pane` should be drawn, or `None` if there wasn't room for it.
+    pub fn region(&self, pane: Pane) -> Option<Region> {
+        self.regions[pane as usize]
+    }
+}
+
+/// Grow included panes towards their preferred height, in priority order,
+/// either those with a bounded preference or those wanting to fill the screen.
+fn grow(
+    specs: &[PaneSpec],
+    by_priority: &[usize],
+    heights: &mut [Option<usize>],
+    remaining: &mut usize,
+    fill: bool,
+) {
+    for &index in by_priority {
+        let preferred_height = specs[index].preferred_height;
+        if (preferred_height == FILL) != fill {
+            continue;
+        }
+        if let Some(height) = &mut heights[index] {
+            let growth = preferred_height.saturating_sub(*height).min(*remaining);
+            *height += growth;
+            *remaining -= growth;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(pane: Pane, priority: u8, min_height: usize, preferred_height: usize) -> PaneSpec {
+        PaneSpec {
+            pane,
+            priority,
+            min_height,
+            preferred_height,
+            gap: 1,
+        }
+    }
+
+    /// A heading, a pane that would like a few rows, and one that fills.
+    fn specs() -> [PaneSpec; 3] {
+        [
+            spec(Pane::Filename, 0, 1, 1),
+            spec(Pane::Metadata, 1, 2, 4),
+            spec(Pane::Queue, 2, 2, FILL),
+        ]
+    }
+
+    fn regions(layout: &Layout) -> [Option<(usize, usize)>; 3] {
+        [Pane::Filename, Pane::Metadata, Pane::Queue].map(|pane| {
+            layout
+                .region(pane)
+                .map(|region| (region.top, region.height))
+        })
+    }
+
+    #[test]
+    fn room_left_over_goes_to_the_pane_that_fills() {
+        let layout = Layout::compute(20, &specs());
+        assert_eq!(
+            regions(&layout),
+            [Some((1, 1)), Some((3, 4)), Some((8, 13))]
+        );
+    }
+
+    #[test]
+    fn gaps_go_before_panes_fill() {
+        // Exactly enough for everything at its preferred height, with gaps
+        let layout = Layout::compute(9, &specs());
+        assert_eq!(regions(&layout), [Some((1, 1)), Some((3, 4)), Some((8, 2))]);
+
+        // One row short, so the lowest priority gap goes
+        let layout = Layout::compute(8, &specs());
+        assert_eq!(regions(&layout), [Some((1, 1)), Some((3, 4)), Some((7, 2))]);
+    }
+
+    #[test]
+    fn panes_shrink_before_being_dropped() {
+        let layout = Layout::compute(5, &specs());
+        assert_eq!(regions(&layout), [Some((1, 1)), Some((2, 2)), Some((4, 2))]);
+
+        // Not enough for the least important pane at its smallest, with what
+        // is left going on growing rather than gaps
+        let layout = Layout::compute(4, &specs());
+        assert_eq!(regions(&layout), [Some((1, 1)), Some((2, 3)), None]);
+
+        let layout = Layout::compute(1, &specs());
+        assert_eq!(regions(&layout), [Some((1, 1)), None, None]);
+
+        let layout = Layout::compute(0, &specs());
+        assert_eq!(regions(&layout), [None, None, None]);
+    }
+
+    #[test]
+    fn priority_decides_what_is_dropped_not_position() {
+        let mut specs = specs();
+        specs[0].priority = 3;
+        let layout = Layout::compute(4, &specs);
+        // No gap is left above whichever pane ends up at the top
+        assert_eq!(regions(&layout), [None, Some((1, 2)), Some((3, 2))]);
+    }
+}
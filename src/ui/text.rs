@@ -0,0 +1,243 @@
+//! Measuring and fitting text by the number of terminal columns it takes up.
+//!
+//! Most characters take up a single column, but East Asian wide characters and
+//! emoji take up two, while combining marks and other zero width characters
+//! take up none.
+
+const ELLIPSIS: char = '…';
+
+// Ranges are inclusive, and sorted so they can be binary searched.
+
+const ZERO_WIDTH: &[(u32, u32)] = &[
+    (0x0300, 0x036f), // Combining diacritical marks
+    (0x0483, 0x0489),
+    (0x0591, 0x05bd),
+    (0x0610, 0x061a),
+    (0x064b, 0x065f),
+    (0x0e31, 0x0e31),
+    (0x0e34, 0x0e3a),
+    (0x0e47, 0x0e4e),
+    (0x1ab0, 0x1aff),
+    (0x1dc0, 0x1dff),
+    (0x200b, 0x200f), // Zero width spaces, joiners and direction marks
+    (0x2028, 0x202e),
+    (0x2060, 0x2064),
+    (0x20d0, 0x20ff), // Combining marks for symbols
+    (0xfe00, 0xfe0f), // Variation selectors
+    (0xfe20, 0xfe2f),
+    (0xfeff, 0xfeff),
+    (0xe0100, 0xe01ef),
+];
+
+const DOUBLE_WIDTH: &[(u32, u32)] = &[
+    (0x1100, 0x115f), // Hangul Jamo
+    (0x231a, 0x231b),
+    (0x2329, 0x232a),
+    (0x23e9, 0x23ec),
+    (0x23f0, 0x23f0),
+    (0x23f3, 0x23f3),
+    (0x25fd, 0x25fe),
+    (0x2614, 0x2615),
+    (0x2648, 0x2653),
+    (0x267f, 0x267f),
+    (0x2693, 0x2693),
+    (0x26a1, 0x26a1),
+    (0x26aa, 0x26ab),
+    (0x26bd, 0x26be),
+    (0x26c4, 0x26c5),
+    (0x26ce, 0x26ce),
+    (0x26d4, 0x26d4),
+    (0x26ea, 0x26ea),
+    (0x26f2, 0x26f3),
+    (0x26f5, 0x26f5),
+    (0x26fa, 0x26fa),
+    (0x26fd, 0x26fd),
+    (0x2705, 0x2705),
+    (0x270a, 0x270b),
+    (0x2728, 0x2728),
+    (0x274c, 0x274c),
+    (0x274e, 0x274e),
+    (0x2753, 0x2755),
+    (0x2757, 0x2757),
+    (0x2795, 0x2797),
+    (0x27b0, 0x27b0),
+    (0x27bf, 0x27bf),
+    (0x2b1b, 0x2b1c),
+    (0x2b50, 0x2b50),
+    (0x2b55, 0x2b55),
+    (0x2e80, 0x303e), // CJK radicals, symbols and punctuation
+    (0x3041, 0x33ff), // Kana, CJK compatibility
+    (0x3400, 0x4dbf), // CJK extension A
+    (0x4e00, 0x9fff), // CJK unified ideographs
+    (0xa000, 0xa4cf), // Yi
+    (0xa960, 0xa97f),
+    (0xac00, 0xd7a3), // Hangul syllables
+    (0xf900, 0xfaff), // CJK compatibility ideographs
+    (0xfe10, 0xfe19),
+    (0xfe30, 0xfe6f),
+    (0xff00, 0xff60), // Fullwidth forms
+    (0xffe0, 0xffe6),
+    (0x16fe0, 0x16fe4),
+    (0x17000, 0x18cff), // Tangut
+    (0x1b000, 0x1b2ff), // Kana supplement
+    (0x1f004, 0x1f004),
+    (0x1f0cf, 0x1f0cf),
+    (0x1f18e, 0x1f18e),
+    (0x1f191, 0x1f19a),
+    (0x1f200, 0x1f251),
+    (0x1f300, 0x1f64f), // Emoji
+    (0x1f680, 0x1f6ff),
+    (0x1f7e0, 0x1f7eb),
+    (0x1f90c, 0x1f9ff),
+    (0x1fa70, 0x1faff),
+    (0x20000, 0x3fffd), // CJK extensions B onwards
+];
+
+fn in_ranges(ranges: &[(u32, u32)], c: char) -> bool {
+    let c = c as u32;
+    ranges
+        .binary_search_by(|&(start, end)| {
+            if end < c {
+                std::cmp::Ordering::Less
+            } else if start > c {
+                std::cmp::Ordering::Greater
+            } else {
+                std::cmp::Ordering::Equal
+            }
+        })
+        .is_ok()
+}
+
+/// Number of columns `c` occupies.
+pub fn char_width(c: char) -> usize {
+    if c.is_control() || in_ranges(ZERO_WIDTH, c) {
+        0
+    } else if in_ranges(DOUBLE_WIDTH, c) {
+        2
+    } else {
+        1
+    }
+}
+
+/// Number of columns `text` occupies.
+pub fn display_width(text: &str) -> usize {
+    text.chars().map(char_width).sum()
+}
+
+/// Cut `text` down to at most `width` columns, marking where it was cut.
+pub fn truncate(text: &str, width: usize) -> String {
+    if display_width(text) <= width {
+        return text.to_string();
+    }
+    if width == 0 {
+        return String::new();
+    }
+
+    // Leave room for the ellipsis
+    let mut remaining = width - 1;
+    let mut truncated = String::new();
+    for c in text.chars() {
+        let c_width = char_width(c);
+        if c_width > remaining {
+            break;
+        }
+        remaining -= c_width;
+        truncated.push(c);
+    }
+    truncated.push(ELLIPSIS);
+    truncated
+}
+
+/// Cut `text` down to at most `width` columns by dropping characters from the
+/// start, for when it is the end that matters.
+pub fn truncate_start(text: &str, width: usize) -> String {
+    if display_width(text) <= width {
+        return text.to_string();
+    }
+    if width == 0 {
+        return String::new();
+    }
+
+    let mut remaining = width - 1;
+    let mut kept = Vec::new();
+    // Zero width characters come after the one they combine with, so have to
+    // wait to see if that is kept too
+    let mut combining = Vec::new();
+    for c in text.chars().rev() {
+        let c_width = char_width(c);
+        if c_width == 0 {
+            combining.push(c);
+            continue;
+        }
+        if c_width > remaining {
+            break;
+        }
+        remaining -= c_width;
+        kept.append(&mut combining);
+        kept.push(c);
+    }
+    std::iter::once(ELLIPSIS)
+        .chain(kept.into_iter().rev())
+        .collect()
+}
+
+/// Truncate `text` to `width` columns, then pad it with spaces to fill them.
+pub fn fit(text: &str, width: usize) -> String {
+    let mut fitted = truncate(text, width);
+    let padding = width - display_width(&fitted);
+    fitted.extend(std::iter::repeat_n(' ', padding));
+    fitted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn characters_are_measured_in_columns() {
+        assert_eq!(char_width('a'), 1);
+        assert_eq!(char_width('日'), 2);
+        assert_eq!(char_width('🎵'), 2);
+        assert_eq!(char_width('\u{301}'), 0);
+        assert_eq!(char_width('\u{200d}'), 0);
+        assert_eq!(char_width('\t'), 0);
+        assert_eq!(display_width("日本 e\u{301}"), 6);
+    }
+
+    #[test]
+    fn text_that_fits_exactly_is_left_alone() {
+        assert_eq!(truncate("abcd", 4), "abcd");
+        assert_eq!(truncate("日本", 4), "日本");
+        assert_eq!(truncate_start("日本", 4), "日本");
+        assert_eq!(fit("日本", 4), "日本");
+        // Zero width characters don't count against the room
+        assert_eq!(truncate("cafe\u{301}", 4), "cafe\u{301}");
+    }
+
+    #[test]
+    fn text_is_cut_short_with_an_ellipsis() {
+        assert_eq!(truncate("abcdef", 4), "abc…");
+        assert_eq!(truncate_start("abcdef", 4), "…def");
+        assert_eq!(truncate("abcdef", 1), "…");
+        assert_eq!(truncate("abcdef", 0), "");
+        assert_eq!(truncate_start("abcdef", 0), "");
+    }
+
+    #[test]
+    fn wide_characters_are_not_split() {
+        // Half of a wide character won't do, so the row comes up short
+        assert_eq!(truncate("日本語", 4), "日…");
+        assert_eq!(truncate_start("日本語", 4), "…語");
+        assert_eq!(fit("日本語", 4), "日… ");
+        assert_eq!(fit("a", 3), "a  ");
+    }
+
+    #[test]
+    fn combining_marks_stay_with_what_they_combine_with() {
+        assert_eq!(truncate("ae\u{301}bc", 3), "ae\u{301}…");
+        assert_eq!(truncate("ae\u{301}bc", 2), "a…");
+        assert_eq!(truncate_start("xae\u{301}b", 3), "…e\u{301}b");
+        // Rather than being left on the ellipsis
+        assert_eq!(truncate_start("ae\u{301}b", 2), "…b");
+    }
+}
@@ -0,0 +1,301 @@
+//! Just enough of a VT100 style terminal to check what the UI draws.
+//!
+//! Escape codes written to a `VirtualTerminal` are interpreted into a grid of
+//! cells, which tests can then compare against what they expect to see.
+//! Anything the UI isn't expected to send is treated as a bug and panics.
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use super::text::char_width;
+use super::TerminalSize;
+use crate::ffi::ioctl::WinSize;
+
+/// How a cell is drawn, going by the last select graphic rendition sequence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Attributes {
+    /// Foreground colour as its SGR code, for example 31 for red
+    pub foreground: Option<u8>,
+    pub dim: bool,
+    pub reverse: bool,
+    pub underline: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Cell {
+    /// `None` for the right hand half of a double width character
+    glyph: Option<char>,
+    attributes: Attributes,
+}
+
+impl Cell {
+    const BLANK: Cell = Cell {
+        glyph: Some(' '),
+        attributes: Attributes {
+            foreground: None,
+            dim: false,
+            reverse: false,
+            underline: false,
+        },
+    };
+}
+
+struct Screen {
+    rows: usize,
+    cols: usize,
+    cells: Vec<Vec<Cell>>,
+    /// Zero based row and column
+    cursor: (usize, usize),
+    attributes: Attributes,
+    autowrap: bool,
+    /// Set after writing to the last column, as the wrap only happens when
+    /// the next character arrives
+    wrap_pending: bool,
+    cursor_visible: bool,
+    /// Bytes of an escape sequence or character still waiting to be completed
+    pending: Vec<u8>,
+    bytes_written: usize,
+    /// Text on screen as of each flush
+    flushed: Vec<Vec<String>>,
+}
+
+/// A terminal that only exists in memory. Clones share the same screen, so
+/// one can be handed to the UI while another is used to look at the result.
+#[derive(Clone)]
+pub struct VirtualTerminal(Rc<RefCell<Screen>>);
+
+impl VirtualTerminal {
+    pub fn new(rows: usize, cols: usize) -> Self {
+        VirtualTerminal(Rc::new(RefCell::new(Screen {
+            rows,
+            cols,
+            cells: vec![vec![Cell::BLANK; cols]; rows],
+            cursor: (0, 0),
+            attributes: Attributes::default(),
+            autowrap: true,
+            wrap_pending: false,
+            cursor_visible: true,
+            pending: Vec::new(),
+            bytes_written: 0,
+            flushed: Vec::new(),
+        })))
+    }
+
+    /// Change the size of the screen, keeping whatever still fits.
+    pub fn resize(&self, rows: usize, cols: usize) {
+        let mut screen = self.0.borrow_mut();
+        screen.cells.resize(rows, vec![Cell::BLANK; cols]);
+        for line in &mut screen.cells {
+            line.resize(cols, Cell::BLANK);
+        }
+        screen.rows = rows;
+        screen.cols = cols;
+        screen.cursor.0 = screen.cursor.0.min(rows.saturating_sub(1));
+        screen.cursor.1 = screen.cursor.1.min(cols.saturating_sub(1));
+    }
+
+    /// Text on each row of the screen, without trailing spaces.
+    pub fn lines(&self) -> Vec<String> {
+        self.0.borrow().lines()
+    }
+
+    /// What `lines` would have given at each flush, oldest first.
+    pub fn history(&self) -> Vec<Vec<String>> {
+        self.0.borrow().flushed.clone()
+    }
+
+    /// How the cell at a row and column, numbered from 1, is drawn.
+    pub fn attributes(&self, row: usize, col: usize) -> Attributes {
+        self.0.borrow().cells[row - 1][col - 1].attributes
+    }
+
+    pub fn cursor_visible(&self) -> bool {
+        self.0.borrow().cursor_visible
+    }
+
+    /// Total number of bytes the terminal has been sent.
+    pub fn bytes_written(&self) -> usize {
+        self.0.borrow().bytes_written
+    }
+}
+
+impl Write for VirtualTerminal {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut screen = self.0.borrow_mut();
+        screen.bytes_written += buf.len();
+        screen.pending.extend_from_slice(buf);
+        screen.interpret();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut screen = self.0.borrow_mut();
+        let lines = screen.lines();
+        screen.flushed.push(lines);
+        Ok(())
+    }
+}
+
+impl TerminalSize for VirtualTerminal {
+    fn read_size(&self) -> io::Result<WinSize> {
+        let screen = self.0.borrow();
+        Ok(WinSize {
+            ws_row: screen.rows as u16,
+            ws_col: screen.cols as u16,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        })
+    }
+}
+
+impl Screen {
+    fn lines(&self) -> Vec<String> {
+        self.cells
+            .iter()
+            .map(|line| {
+                let text: String = line.iter().filter_map(|cell| cell.glyph).collect();
+                text.trim_end().to_string()
+            })
+            .collect()
+    }
+
+    /// Act on as much of the pending input as is complete.
+    fn interpret(&mut self) {
+        loop {
+            let consumed = match self.pending.first() {
+                None => return,
+                Some(0x1b) => self.escape_sequence(),
+                Some(_) => self.character(),
+            };
+            match consumed {
+                Some(length) => {
+                    self.pending.drain(..length);
+                }
+                None => return,
+            }
+        }
+    }
+
+    /// Handle a control sequence, returning its length if it was complete.
+    fn escape_sequence(&mut self) -> Option<usize> {
+        let introducer = *self.pending.get(1)?;
+        assert_eq!(introducer, b'[', "only CSI escape sequences are supported");
+        let end = self.pending[2..]
+            .iter()
+            .position(|&byte| (0x40..=0x7e).contains(&byte))?
+            + 2;
+        let sequence = String::from_utf8(self.pending[2..end].to_vec()).unwrap();
+        let command = self.pending[end] as char;
+
+        let (private, parameters) = match sequence.strip_prefix('?') {
+            Some(parameters) => (true, parameters),
+            None => (false, sequence.as_str()),
+        };
+        let parameters: Vec<usize> = parameters
+            .split(';')
+            .map(|parameter| match parameter {
+                "" => 0,
+                parameter => parameter.parse().unwrap(),
+            })
+            .collect();
+        let parameter = |index: usize, default: usize| match parameters.get(index) {
+            Some(0) | None => default,
+            Some(&value) => value,
+        };
+
+        match (private, command) {
+            (false, 'H') => {
+                let row = parameter(0, 1).min(self.rows);
+                let col = parameter(1, 1).min(self.cols);
+                self.cursor = (row - 1, col - 1);
+                self.wrap_pending = false;
+            }
+            (false, 'J') if parameters == [2] => {
+                for line in &mut self.cells {
+                    line.fill(Cell::BLANK);
+                }
+            }
+            (false, 'K') if parameters == [0] => {
+                let (row, col) = self.cursor;
+                self.cells[row][col..].fill(Cell::BLANK);
+            }
+            (false, 'm') => {
+                for &code in &parameters {
+                    match code {
+                        0 => self.attributes = Attributes::default(),
+                        2 => self.attributes.dim = true,
+                        4 => self.attributes.underline = true,
+                        7 => self.attributes.reverse = true,
+                        30..=37 => self.attributes.foreground = Some(code as u8),
+                        _ => panic!("unsupported graphic rendition {code}"),
+                    }
+                }
+            }
+            (true, 'h' | 'l') => {
+                let enabled = command == 'h';
+                match parameters[..] {
+                    [7] => self.autowrap = enabled,
+                    [25] => self.cursor_visible = enabled,
+                    _ => panic!("unsupported private mode {sequence}"),
+                }
+            }
+            _ => panic!("unsupported escape sequence {sequence}{command}"),
+        }
+        Some(end + 1)
+    }
+
+    /// Draw a character, returning its length if it was complete.
+    fn character(&mut self) -> Option<usize> {
+        let length = match self.pending[0] {
+            byte if byte < 0x80 => 1,
+            byte if byte >= 0xf0 => 4,
+            byte if byte >= 0xe0 => 3,
+            _ => 2,
+        };
+        let bytes = self.pending.get(..length)?;
+        let c = std::str::from_utf8(bytes).unwrap().chars().next().unwrap();
+        assert!(!c.is_control(), "unexpected control character {c:?}");
+
+        let width = char_width(c);
+        if width == 0 {
+            return Some(length);
+        }
+
+        if self.wrap_pending {
+            self.wrap_pending = false;
+            self.cursor = ((self.cursor.0 + 1).min(self.rows - 1), 0);
+        }
+        let (row, col) = self.cursor;
+        let line = &mut self.cells[row];
+        // Like a real terminal, writing over half of a double width character
+        // leaves the other half blank
+        if line[col].glyph.is_none() {
+            line[col - 1] = Cell::BLANK;
+        }
+        let end = (col + width).min(self.cols);
+        if end < self.cols && line[end].glyph.is_none() {
+            line[end] = Cell::BLANK;
+        }
+        line[col] = Cell {
+            glyph: Some(c),
+            attributes: self.attributes,
+        };
+        if width == 2 && col + 1 < self.cols {
+            line[col + 1] = Cell {
+                glyph: None,
+                attributes: self.attributes,
+            };
+        }
+
+        let next = col + width;
+        if next < self.cols {
+            self.cursor.1 = next;
+        } else {
+            // Without autowrap, the cursor stays in the last column and
+            // anything else written there overwrites what it finds
+            self.wrap_pending = self.autowrap;
+        }
+        Some(length)
+    }
+}